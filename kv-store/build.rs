@@ -0,0 +1,5 @@
+fn main() {
+    // protoc ships with the build instead of being a system requirement
+    unsafe { std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("bundled protoc")) };
+    tonic_prost_build::compile_protos("proto/kv.proto").expect("failed to compile proto/kv.proto");
+}
@@ -0,0 +1,94 @@
+/*
+Role-based access control. Admins may do anything. Everyone else gets what their grants allow:
+each grant names a namespace ("*" for all of them), a key prefix (empty for every key) and the
+actions it permits. Grants are stored with the user (users.rs) and copied into the token's
+Claims at login and refresh, so checking a request needs no lookup; a change applies from the
+user's next access token.
+
+The admin action covers the other three within its scope, and is what managing a namespace
+(create, inspect, drop) takes on that namespace. A prefix request (scan, watch) needs a grant
+whose prefix covers the whole requested prefix, not just some keys under it.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::coordinator::KvError;
+use crate::middleware::types::Claims;
+use crate::users::Role;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Read,
+    Write,
+    Delete,
+    Admin,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Action::Read => "read",
+            Action::Write => "write",
+            Action::Delete => "delete",
+            Action::Admin => "admin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Grant {
+    // A namespace name, or "*"
+    pub namespace: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub actions: Vec<Action>,
+}
+
+impl Grant {
+    fn allows(&self, action: Action, namespace: &str, key: &[u8]) -> bool {
+        (self.namespace == "*" || self.namespace == namespace)
+            && key.starts_with(self.prefix.as_bytes())
+            && self.actions.iter().any(|granted| *granted == action || *granted == Action::Admin)
+    }
+}
+
+pub fn validate(grants: &[Grant]) -> Result<(), KvError> {
+    for grant in grants {
+        if grant.namespace.is_empty() || grant.actions.is_empty() {
+            return Err(KvError::BadRequest("a grant needs a namespace (or \"*\") and at least one action".to_string()));
+        }
+    }
+    Ok(())
+}
+
+pub fn allowed(claims: &Claims, action: Action, namespace: &str, key: &[u8]) -> bool {
+    claims.role == Role::Admin || claims.grants.iter().any(|grant| grant.allows(action, namespace, key))
+}
+
+// Whether any grant reaches into the namespace
+pub fn visible(claims: &Claims, namespace: &str) -> bool {
+    claims.role == Role::Admin || claims.grants.iter().any(|grant| grant.namespace == "*" || grant.namespace == namespace)
+}
+
+// `key` may also be a prefix: the grant must cover every key starting with it
+pub fn authorize(claims: &Claims, action: Action, namespace: &str, key: &[u8]) -> Result<(), KvError> {
+    if allowed(claims, action, namespace, key) {
+        return Ok(());
+    }
+    Err(KvError::Forbidden(format!("{} denied on '{}' in namespace '{}'", action, String::from_utf8_lossy(key), namespace)))
+}
+
+// Every key in [start, end) shares the bounds' common prefix; an open bound shares nothing
+pub fn range_prefix<'a>(start: Option<&'a [u8]>, end: Option<&[u8]>) -> &'a [u8] {
+    match (start, end) {
+        (Some(start), Some(end)) => {
+            let common = start.iter().zip(end).take_while(|(a, b)| a == b).count();
+            &start[..common]
+        }
+        _ => &[],
+    }
+}
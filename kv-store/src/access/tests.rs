@@ -0,0 +1,68 @@
+use crate::middleware::types::Claims;
+use crate::users::Role;
+use super::{allowed, authorize, range_prefix, validate, visible, Action, Grant};
+
+fn grant(namespace: &str, prefix: &str, actions: &[Action]) -> Grant {
+    Grant { namespace: namespace.to_string(), prefix: prefix.to_string(), actions: actions.to_vec() }
+}
+
+fn claims(role: Role, grants: Vec<Grant>) -> Claims {
+    Claims { email: "someone@x".to_string(), role, grants, exp: usize::MAX, jti: "jti".to_string(), sid: "sid".to_string() }
+}
+
+#[test]
+fn grants_match_namespace_prefix_and_action() {
+    let user = claims(Role::User, vec![
+        grant("orders", "eu/", &[Action::Read, Action::Write]),
+        grant("*", "public/", &[Action::Read]),
+    ]);
+    assert!(allowed(&user, Action::Read, "orders", b"eu/1"));
+    assert!(allowed(&user, Action::Write, "orders", b"eu/1"));
+    assert!(!allowed(&user, Action::Delete, "orders", b"eu/1"));
+    // Wrong prefix, wrong namespace
+    assert!(!allowed(&user, Action::Read, "orders", b"us/1"));
+    assert!(!allowed(&user, Action::Read, "billing", b"eu/1"));
+    // "*" reaches every namespace, but only under its prefix
+    assert!(allowed(&user, Action::Read, "billing", b"public/x"));
+    assert!(!allowed(&user, Action::Write, "billing", b"public/x"));
+}
+
+#[test]
+fn admin_grants_and_role_cover_everything_in_scope() {
+    let ns_admin = claims(Role::User, vec![grant("orders", "", &[Action::Admin])]);
+    for action in [Action::Read, Action::Write, Action::Delete, Action::Admin] {
+        assert!(allowed(&ns_admin, action, "orders", b"any"));
+    }
+    assert!(!allowed(&ns_admin, Action::Read, "billing", b"any"));
+    let admin = claims(Role::Admin, Vec::new());
+    assert!(allowed(&admin, Action::Delete, "billing", b"any"));
+    assert!(visible(&admin, "billing"));
+    assert!(visible(&ns_admin, "orders"));
+    assert!(!visible(&ns_admin, "billing"));
+}
+
+#[test]
+fn prefix_requests_need_the_whole_prefix() {
+    let user = claims(Role::User, vec![grant("orders", "eu/", &[Action::Read])]);
+    assert!(authorize(&user, Action::Read, "orders", b"eu/2024/").is_ok());
+    // Some keys under "e" are outside "eu/"
+    let denied = authorize(&user, Action::Read, "orders", b"e").unwrap_err();
+    assert!(denied.to_string().contains("read denied on 'e' in namespace 'orders'"), "{}", denied);
+    assert!(authorize(&user, Action::Read, "orders", b"").is_err());
+}
+
+#[test]
+fn ranges_share_their_bounds_common_prefix() {
+    assert_eq!(range_prefix(Some(b"eu/a"), Some(b"eu/m")), b"eu/");
+    assert_eq!(range_prefix(Some(b"eu/"), Some(b"eu0")), b"eu");
+    assert_eq!(range_prefix(Some(b"a"), Some(b"b")), b"");
+    assert_eq!(range_prefix(Some(b"eu/"), None), b"");
+    assert_eq!(range_prefix(None, Some(b"eu/")), b"");
+}
+
+#[test]
+fn grants_need_a_namespace_and_actions() {
+    assert!(validate(&[grant("*", "", &[Action::Read])]).is_ok());
+    assert!(validate(&[grant("", "", &[Action::Read])]).is_err());
+    assert!(validate(&[grant("orders", "x", &[])]).is_err());
+}
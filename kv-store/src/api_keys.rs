@@ -0,0 +1,257 @@
+/*
+API keys for clients that can't log in interactively. A key is "kvk_<id>_<secret>": the id is
+public and names the key in the admin API, the secret is 32 random bytes shown only once, at
+creation. Like users, each key is a JSON record in every node's __api_keys tree, keyed by id, and
+holds only a sha256 of the secret (the secret is random, so a slow hash buys nothing).
+
+A key carries grants like a user's (see access.rs), never the admin role, and may expire.
+Deleting it revokes it, connections that authenticated with it included. Like namespace
+definitions, the keys are also kept in memory (load_api_keys at startup), so checking one reads no
+storage. The time a key was last used is noted in memory at most once per LAST_USED_RESOLUTION_MS
+and written back by the expiry sweeper (persist_last_used), so requests never wait on a write.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, RwLock};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use metrics::counter;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::access::{self, Grant};
+use crate::config::HASH_RING;
+use crate::coordinator::KvError;
+use crate::middleware::types::Claims;
+use crate::store::now_ms;
+use crate::users::Role;
+
+const API_KEYS_TREE: &str = "__api_keys";
+pub const KEY_PREFIX: &str = "kvk_";
+// The email of an API key's Claims is this and the key's id
+const CLAIMS_PREFIX: &str = "apikey:";
+const MAX_NAME_LEN: usize = 128;
+const LAST_USED_RESOLUTION_MS: u64 = 60 * 1000;
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    // base64 of the sha256 of the secret
+    pub secret_hash: String,
+    pub grants: Vec<Grant>,
+    // Email of the admin who created it
+    pub created_by: String,
+    pub created_at: u64,
+    // Epoch milliseconds
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub last_used_at: Option<u64>,
+}
+
+// What the API shows of a key: everything but the hash
+#[derive(Serialize, Debug)]
+pub struct ApiKeyView {
+    pub id: String,
+    pub name: String,
+    pub grants: Vec<Grant>,
+    pub created_by: String,
+    pub created_at: u64,
+    pub expires_at: Option<u64>,
+    pub last_used_at: Option<u64>,
+}
+
+impl From<&ApiKey> for ApiKeyView {
+    fn from(key: &ApiKey) -> Self {
+        ApiKeyView {
+            id: key.id.clone(),
+            name: key.name.clone(),
+            grants: key.grants.clone(),
+            created_by: key.created_by.clone(),
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            last_used_at: key.last_used_at,
+        }
+    }
+}
+
+// Serializes read-modify-writes of key records, so recording a use can't bring back a deleted key
+static API_KEYS_LOCK: Mutex<()> = Mutex::new(());
+
+// Every key by id, as in API_KEYS_TREE, except that last_used_at may be ahead of the stored one
+static API_KEYS: Lazy<RwLock<BTreeMap<String, ApiKey>>> = Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+// Keys whose last_used_at hasn't been written back yet
+static USED: Lazy<Mutex<BTreeSet<String>>> = Lazy::new(|| Mutex::new(BTreeSet::new()));
+
+fn secret_hash(secret: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn load(id: &str) -> Result<Option<ApiKey>, KvError> {
+    let ring = HASH_RING.read().unwrap();
+    let mut last_error = None;
+    for node_id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&node_id) else { continue };
+        match node.db.open_tree(API_KEYS_TREE).and_then(|tree| tree.get(id.as_bytes())) {
+            Ok(Some(record)) => {
+                let key = serde_json::from_slice(&record)
+                    .map_err(|e| KvError::Internal(format!("bad API key record for '{}': {}", id, e)))?;
+                return Ok(Some(key));
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(e.into()),
+        None => Ok(None),
+    }
+}
+
+fn save(key: &ApiKey) -> Result<(), KvError> {
+    let record = serde_json::to_vec(key).map_err(|e| KvError::Internal(format!("failed to encode API key: {}", e)))?;
+    let ring = HASH_RING.read().unwrap();
+    for node_id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&node_id) else { continue };
+        node.db.open_tree(API_KEYS_TREE)?.insert(key.id.as_bytes(), record.as_slice())?;
+        node.db.flush()?;
+    }
+    Ok(())
+}
+
+// Read the keys back from disk (call once at startup, before any listener); any node's copy will do
+pub fn load_api_keys() {
+    let ring = HASH_RING.read().unwrap();
+    let mut keys = API_KEYS.write().unwrap();
+    for node_id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&node_id) else { continue };
+        let Ok(tree) = node.db.open_tree(API_KEYS_TREE) else { continue };
+        for (id, record) in tree.iter().flatten() {
+            match serde_json::from_slice::<ApiKey>(&record) {
+                Ok(key) => {
+                    keys.entry(String::from_utf8_lossy(&id).to_string()).or_insert(key);
+                }
+                Err(e) => eprintln!("Skipping bad API key record on {}: {}", node_id, e),
+            }
+        }
+    }
+    println!("Loaded {} API keys", keys.len());
+}
+
+pub fn list() -> Vec<ApiKey> {
+    API_KEYS.read().unwrap().values().cloned().collect()
+}
+
+pub fn get(id: &str) -> Result<ApiKey, KvError> {
+    API_KEYS.read().unwrap()
+        .get(id)
+        .cloned()
+        .ok_or_else(|| KvError::UnknownApiKey(id.to_string()))
+}
+
+// The new key and, only this once, the full key to hand to the client
+pub fn create(name: &str, grants: Vec<Grant>, ttl_seconds: Option<u64>, created_by: &str) -> Result<(ApiKey, String), KvError> {
+    if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(KvError::BadRequest(format!("an API key needs a name of up to {} bytes", MAX_NAME_LEN)));
+    }
+    access::validate(&grants)?;
+    let now = now_ms();
+    let id = format!("{:016x}", rand::random::<u64>());
+    let secret = URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>());
+    let key = ApiKey {
+        id,
+        name: name.trim().to_string(),
+        secret_hash: secret_hash(&secret),
+        grants,
+        created_by: created_by.to_string(),
+        created_at: now,
+        expires_at: ttl_seconds.map(|secs| now + secs.saturating_mul(1000)),
+        last_used_at: None,
+    };
+    let _guard = API_KEYS_LOCK.lock().unwrap();
+    save(&key)?;
+    API_KEYS.write().unwrap().insert(key.id.clone(), key.clone());
+    println!("Created API key '{}' ({}) for {}", key.id, key.name, created_by);
+    Ok((key.clone(), format!("{}{}_{}", KEY_PREFIX, key.id, secret)))
+}
+
+pub fn delete(id: &str) -> Result<(), KvError> {
+    let _guard = API_KEYS_LOCK.lock().unwrap();
+    // Out of memory first, so the key stops working even if removing it from disk fails
+    if API_KEYS.write().unwrap().remove(id).is_none() {
+        return Err(KvError::UnknownApiKey(id.to_string()));
+    }
+    USED.lock().unwrap().remove(id);
+    let ring = HASH_RING.read().unwrap();
+    for node_id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&node_id) else { continue };
+        node.db.open_tree(API_KEYS_TREE)?.remove(id.as_bytes())?;
+        node.db.flush()?;
+    }
+    println!("Deleted API key '{}'", id);
+    Ok(())
+}
+
+// Claims for a valid, unexpired key, with its grants; notes the use
+pub fn verify(full_key: &str) -> Option<Claims> {
+    let claims = full_key.strip_prefix(KEY_PREFIX)
+        .and_then(|rest| rest.split_once('_'))
+        .and_then(|(id, secret)| authenticate(id, secret));
+    if claims.is_none() {
+        counter!("api_key_auth_failures_total", 1);
+    }
+    claims
+}
+
+fn authenticate(id: &str, secret: &str) -> Option<Claims> {
+    let now = now_ms();
+    let mut keys = API_KEYS.write().unwrap();
+    let key = keys.get_mut(id)?;
+    if key.secret_hash != secret_hash(secret) || key.expires_at.is_some_and(|at| at <= now) {
+        return None;
+    }
+    if key.last_used_at.is_none_or(|at| at + LAST_USED_RESOLUTION_MS <= now) {
+        key.last_used_at = Some(now);
+        USED.lock().unwrap().insert(key.id.clone());
+    }
+    Some(Claims {
+        email: format!("{}{}", CLAIMS_PREFIX, key.id),
+        role: Role::User,
+        grants: key.grants.clone(),
+        exp: key.expires_at.map_or(usize::MAX, |at| (at / 1000) as usize),
+        jti: key.id.clone(),
+        // Keys have no login session; /logout refuses them
+        sid: String::new(),
+    })
+}
+
+// Whether the Claims came from an API key that has since been deleted
+pub fn is_deleted(claims: &Claims) -> bool {
+    claims.sid.is_empty()
+        && claims.email.starts_with(CLAIMS_PREFIX)
+        && !API_KEYS.read().unwrap().contains_key(&claims.jti)
+}
+
+// Writes back the last-used times noted since the previous call; blocks on storage. Best effort:
+// a key that fails is retried next time.
+pub fn persist_last_used() {
+    let used = std::mem::take(&mut *USED.lock().unwrap());
+    for id in used {
+        let _guard = API_KEYS_LOCK.lock().unwrap();
+        let Some(last_used_at) = API_KEYS.read().unwrap().get(&id).map(|key| key.last_used_at) else { continue };
+        let result = load(&id).and_then(|key| match key {
+            Some(mut key) => {
+                key.last_used_at = last_used_at;
+                save(&key)
+            }
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            eprintln!("Failed to record the use of API key '{}': {}", id, e);
+            USED.lock().unwrap().insert(id);
+        }
+    }
+}
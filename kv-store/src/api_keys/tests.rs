@@ -0,0 +1,71 @@
+use crate::access::{Action, Grant};
+use crate::coordinator::KvError;
+use crate::middleware::{still_valid, verify_credential};
+use super::{create, delete, get, list, load, persist_last_used, verify, KEY_PREFIX};
+
+fn reader() -> Vec<Grant> {
+    vec![Grant { namespace: "default".to_string(), prefix: "jobs/".to_string(), actions: vec![Action::Read] }]
+}
+
+#[test]
+fn verifies_keys_with_their_grants() {
+    let (key, full_key) = create("nightly export", reader(), None, "admin@x").unwrap();
+    assert!(full_key.starts_with(&format!("{}{}_", KEY_PREFIX, key.id)));
+    // Only a hash of the secret is kept
+    assert!(!full_key.ends_with(&key.secret_hash));
+
+    let claims = verify(&full_key).unwrap();
+    assert_eq!(claims.email, format!("apikey:{}", key.id));
+    assert_eq!(claims.grants, reader());
+    assert!(claims.sid.is_empty());
+    assert!(verify_credential(&full_key).is_some());
+    assert!(list().iter().any(|listed| listed.id == key.id));
+}
+
+#[test]
+fn rejects_wrong_and_malformed_keys() {
+    let (key, full_key) = create("ci", reader(), None, "admin@x").unwrap();
+    let wrong_secret = format!("{}{}_{}", KEY_PREFIX, key.id, "x".repeat(43));
+    let unknown_id = full_key.replacen(&key.id, "0000000000000000", 1);
+    for bad in [wrong_secret.as_str(), &unknown_id, "kvk_", "kvk_noseparator", &format!("{}{}_", KEY_PREFIX, key.id), "kvk__secret", &full_key[..full_key.len() - 1]] {
+        assert!(verify(bad).is_none(), "{}", bad);
+    }
+    // Without the prefix it isn't taken for a key at all
+    assert!(verify(full_key.trim_start_matches(KEY_PREFIX)).is_none());
+}
+
+#[test]
+fn expired_keys_stop_working() {
+    let (_, full_key) = create("short lived", reader(), Some(0), "admin@x").unwrap();
+    assert!(verify(&full_key).is_none());
+}
+
+#[test]
+fn deleting_a_key_revokes_it() {
+    let (key, full_key) = create("to delete", reader(), None, "admin@x").unwrap();
+    let claims = verify(&full_key).unwrap();
+    assert!(still_valid(&claims));
+
+    delete(&key.id).unwrap();
+    assert!(verify(&full_key).is_none());
+    // Connections that authenticated with it drop it at their next command
+    assert!(!still_valid(&claims));
+    assert!(matches!(get(&key.id), Err(KvError::UnknownApiKey(_))));
+    assert!(matches!(delete(&key.id), Err(KvError::UnknownApiKey(_))));
+    assert!(load(&key.id).unwrap().is_none());
+}
+
+#[test]
+fn records_use_in_memory_and_writes_it_back_later() {
+    let (key, full_key) = create("busy", reader(), None, "admin@x").unwrap();
+    verify(&full_key).unwrap();
+    let used_at = get(&key.id).unwrap().last_used_at;
+    assert!(used_at.is_some());
+    // Within the resolution a second use changes nothing
+    verify(&full_key).unwrap();
+    assert_eq!(get(&key.id).unwrap().last_used_at, used_at);
+
+    assert_eq!(load(&key.id).unwrap().unwrap().last_used_at, None);
+    persist_last_used();
+    assert_eq!(load(&key.id).unwrap().unwrap().last_used_at, used_at);
+}
@@ -0,0 +1,142 @@
+/*
+Change feed behind /v1/watch and the gRPC Watch stream, read from the WAL the same way the replication workers read it.
+Every write that goes through the WAL (eventual writes, EXPIRE/PERSIST, keys reaped after they
+expired) is an event numbered by its WAL sequence, so a client that reconnects with the last
+sequence it saw gets everything after it replayed from the file before the live events. Strong
+writes are committed through the Raft logs instead and are not in the feed. A watch lasts only as
+long as the login it was opened with (see next_for).
+*/
+use std::collections::VecDeque;
+use std::time::Duration;
+use metrics::{decrement_gauge, increment_gauge};
+use tokio::sync::watch;
+use crate::middleware::still_valid;
+use crate::middleware::types::Claims;
+use crate::routes_resp::{Wal, WalOp, WatchEventKind};
+use crate::wal::{subscribe_wal, wal_head, WalTail};
+
+// WAL entries read per pass
+const READ_BATCH: usize = 256;
+// Fallback if a head notification is missed
+const IDLE_POLL: Duration = Duration::from_secs(1);
+// How often a watcher with nothing to send re-checks the caller's login
+const CREDENTIAL_CHECK: Duration = Duration::from_secs(1);
+
+// Which keys of which namespace a watcher wants
+pub struct WatchFilter {
+    pub namespace: String,
+    pub key: Option<Vec<u8>>,
+    // Empty matches every key
+    pub prefix: Option<Vec<u8>>,
+}
+
+impl WatchFilter {
+    fn matches(&self, op: &WalOp) -> bool {
+        op.namespace() == self.namespace
+            && self.key.as_deref().is_none_or(|key| op.key() == key)
+            && self.prefix.as_deref().is_none_or(|prefix| op.key().starts_with(prefix))
+    }
+}
+
+// One change, as raw bytes; each API encodes it its own way
+pub struct Change {
+    pub seq: usize,
+    pub kind: WatchEventKind,
+    pub namespace: String,
+    pub key: Vec<u8>,
+    // Set only
+    pub value: Option<Vec<u8>>,
+    pub version: Option<u64>,
+    pub content_type: Option<String>,
+    // Epoch milliseconds
+    pub expires_at: Option<u64>,
+}
+
+pub struct Watcher {
+    filter: WatchFilter,
+    // Last sequence handed out (or skipped)
+    after: usize,
+    tail: WalTail,
+    queue: VecDeque<Wal>,
+    head: watch::Receiver<usize>,
+}
+
+impl Watcher {
+    // Events after `since`, or only new ones without it
+    pub fn new(filter: WatchFilter, since: Option<usize>) -> Self {
+        let head = subscribe_wal();
+        let after = since.unwrap_or_else(wal_head);
+        Watcher { filter, after, tail: WalTail::new(), queue: VecDeque::new(), head }
+    }
+
+    pub async fn next(&mut self) -> Change {
+        loop {
+            while let Some(entry) = self.queue.pop_front() {
+                if entry.sequence_number <= self.after {
+                    continue;
+                }
+                self.after = entry.sequence_number;
+                if self.filter.matches(&entry.opration)
+                    && let Some(change) = change(entry) {
+                    return change;
+                }
+            }
+
+            self.head.borrow_and_update();
+            let entries = self.tail.read_new(READ_BATCH);
+            if entries.is_empty() {
+                tokio::select! {
+                    _ = self.head.changed() => {}
+                    _ = tokio::time::sleep(IDLE_POLL) => {}
+                }
+            }
+            self.queue.extend(entries);
+        }
+    }
+
+    // Like next(), but None once `claims` expire, are revoked or belong to a deleted API key.
+    // Checked before every change and every CREDENTIAL_CHECK while none arrive.
+    pub async fn next_for(&mut self, claims: &Claims) -> Option<Change> {
+        loop {
+            if !still_valid(claims) {
+                return None;
+            }
+            // next() only waits with its queue empty, so dropping it there loses nothing
+            tokio::select! {
+                change = self.next() => return still_valid(claims).then_some(change),
+                _ = tokio::time::sleep(CREDENTIAL_CHECK) => {}
+            }
+        }
+    }
+}
+
+fn change(entry: Wal) -> Option<Change> {
+    let seq = entry.sequence_number;
+    let version = entry.version;
+    let (kind, namespace, key, value, content_type, expires_at) = match entry.opration {
+        WalOp::Set { namespace, key, value, content_type, expires_at, .. } => {
+            (WatchEventKind::Set, namespace, key, Some(value), content_type, expires_at)
+        }
+        WalOp::Delete { namespace, key, .. } => (WatchEventKind::Delete, namespace, key, None, None, None),
+        WalOp::Expire { namespace, key, expires_at } => (WatchEventKind::Expire, namespace, key, None, None, expires_at),
+        // Never logged, incr() logs the resulting SET
+        WalOp::Incr { .. } => return None,
+    };
+    Some(Change { seq, kind, namespace, key, value, version, content_type, expires_at })
+}
+
+// Counts open watchers per transport for as long as it lives
+pub struct WatcherGuard(&'static str);
+
+impl WatcherGuard {
+    pub fn new(transport: &'static str) -> Self {
+        increment_gauge!("watchers", 1.0, "transport" => transport);
+        WatcherGuard(transport)
+    }
+}
+
+impl Drop for WatcherGuard {
+    fn drop(&mut self) {
+        decrement_gauge!("watchers", 1.0, "transport" => self.0);
+    }
+}
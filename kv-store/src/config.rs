@@ -1,9 +1,11 @@
 use once_cell::sync::Lazy;
 use super::hashring::HashRing;
-use std::{collections::HashMap, sync::RwLock};
+use std::{collections::HashMap, env, sync::RwLock, time::Duration};
+use dotenv::dotenv;
 
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct NodeHealth {
     pub id: String,
     pub last_heartbeat: u64, // epoch milliseconds
@@ -17,11 +19,127 @@ pub static HEALTH_TABLE: Lazy<RwLock<HashMap<String, NodeHealth>>> = Lazy::new(|
 
 pub static HASH_RING: Lazy<RwLock<HashRing>> = Lazy::new(|| {
     let mut ring = HashRing::new(100); // 100 vnodes per node
-    ring.add_node("node0", sled::open("db/node0").unwrap());
-    ring.add_node("node1", sled::open("db/node1").unwrap());
-    ring.add_node("node2", sled::open("db/node2").unwrap());
-    ring.add_node("node3", sled::open("db/node3").unwrap());
-    ring.add_node("node4", sled::open("db/node4").unwrap());
+    for id in ["node0", "node1", "node2", "node3", "node4"] {
+        ring.add_node(id, open_node_db(id));
+    }
     RwLock::new(ring)
 });
 
+// Unit tests get throwaway databases rather than the ones under db/
+fn open_node_db(id: &str) -> sled::Db {
+    if cfg!(test) {
+        return sled::Config::new().temporary(true).open().unwrap();
+    }
+    sled::open(format!("db/{}", id)).unwrap()
+}
+
+// Upper bound for a graceful shutdown (drain requests, replication, flush), SHUTDOWN_TIMEOUT_SECS in .env
+pub fn shutdown_timeout() -> Duration {
+    dotenv().ok();
+    let secs = env::var("SHUTDOWN_TIMEOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(30);
+    Duration::from_secs(secs)
+}
+
+// How often expired keys are reaped, EXPIRY_SWEEP_INTERVAL_SECS in .env
+pub fn expiry_sweep_interval() -> Duration {
+    dotenv().ok();
+    let secs = env::var("EXPIRY_SWEEP_INTERVAL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(5);
+    Duration::from_secs(secs)
+}
+
+// Port of the Redis protocol listener, REDIS_PORT in .env; off when unset or 0
+pub fn redis_port() -> u16 {
+    dotenv().ok();
+    env::var("REDIS_PORT")
+        .ok()
+        .and_then(|v| v.parse::<u16>().ok())
+        .unwrap_or(0)
+}
+
+// Port of the memcached text protocol listener, MEMCACHED_PORT in .env; off when unset or 0
+pub fn memcached_port() -> u16 {
+    dotenv().ok();
+    env::var("MEMCACHED_PORT")
+        .ok()
+        .and_then(|v| v.parse::<u16>().ok())
+        .unwrap_or(0)
+}
+
+// Port of the gRPC server, GRPC_PORT in .env; off when unset or 0
+pub fn grpc_port() -> u16 {
+    dotenv().ok();
+    env::var("GRPC_PORT")
+        .ok()
+        .and_then(|v| v.parse::<u16>().ok())
+        .unwrap_or(0)
+}
+
+// How long an Idempotency-Key's outcome is replayed, IDEMPOTENCY_WINDOW_SECS in .env
+pub fn idempotency_window() -> Duration {
+    dotenv().ok();
+    let secs = env::var("IDEMPOTENCY_WINDOW_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(24 * 60 * 60);
+    Duration::from_secs(secs)
+}
+
+// Wrong passwords in a row before an account locks, LOGIN_MAX_FAILURES in .env
+pub fn login_max_failures() -> u32 {
+    dotenv().ok();
+    env::var("LOGIN_MAX_FAILURES")
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(5)
+}
+
+// How long a locked account stays locked, LOGIN_LOCKOUT_SECS in .env
+pub fn login_lockout() -> Duration {
+    dotenv().ok();
+    let secs = env::var("LOGIN_LOCKOUT_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(15 * 60);
+    Duration::from_secs(secs)
+}
+
+// Lifetime of a JWT access token, ACCESS_TOKEN_TTL_SECS in .env
+pub fn access_token_ttl() -> Duration {
+    dotenv().ok();
+    let secs = env::var("ACCESS_TOKEN_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(15 * 60);
+    Duration::from_secs(secs)
+}
+
+// Lifetime of a refresh token, renewed with each exchange, REFRESH_TOKEN_TTL_SECS in .env
+pub fn refresh_token_ttl() -> Duration {
+    dotenv().ok();
+    let secs = env::var("REFRESH_TOKEN_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|n| *n > 0)
+        .unwrap_or(30 * 24 * 60 * 60);
+    Duration::from_secs(secs)
+}
+
+// Whether anyone may create an account through /signup, SIGNUP_ENABLED in .env
+pub fn signup_enabled() -> bool {
+    dotenv().ok();
+    env::var("SIGNUP_ENABLED").map_or(true, |v| v != "false" && v != "0")
+}
+
+// First admin, created at startup while there are no users: ADMIN_EMAIL and ADMIN_PASSWORD in .env
+pub fn bootstrap_admin() -> Option<(String, String)> {
+    dotenv().ok();
+    Some((env::var("ADMIN_EMAIL").ok()?, env::var("ADMIN_PASSWORD").ok()?))
+}
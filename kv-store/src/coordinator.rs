@@ -0,0 +1,684 @@
+/*
+Request coordination shared by every front end (legacy JSON routes, /v1/kv). Picks the partition's
+leader on the ring, checks that enough of its replica set is alive, writes the leader copy and
+the WAL entry (eventual) or goes through the partition's Raft group (strong).
+
+Every call names a namespace. Its settings pick the replica set size, raise the consistency level,
+fill in a default TTL, turn plain writes into create-only (first_write_wins) and enforce the quota.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use metrics::counter;
+use once_cell::sync::Lazy;
+use sled::{transaction::{ConflictableTransactionError, TransactionError}, Db, IVec, Transactional};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::task::JoinSet;
+use crate::config::{HASH_RING, HEALTH_TABLE};
+use crate::hashring::HashRing;
+use crate::namespace::{self, Namespace};
+use crate::raft::{group_for_key, raft, RaftError};
+use crate::routes_resp::{Condition, Consistency, WalOp, WriteMode};
+use crate::store::{self, apply_op, ApplyOutcome, KeyRange, ValueMeta};
+use crate::replication::log_writes;
+
+#[derive(Clone, Debug)]
+pub enum KvError {
+    NotFound,
+    Conflict(String),
+    NoQuorum(String),
+    PreconditionFailed(String),
+    BadRequest(String),
+    UnknownNamespace(String),
+    UnknownUser(String),
+    UnknownApiKey(String),
+    QuotaExceeded(String),
+    // INCR on a value that isn't a 64-bit integer, or the result would overflow
+    NotAnInteger,
+    // Missing or wrong credentials
+    Unauthorized(String),
+    // Authenticated, but not allowed to do this
+    Forbidden(String),
+    // Too many failed logins; the message says until when
+    AccountLocked(String),
+    Internal(String),
+}
+
+impl KvError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            KvError::NotFound => StatusCode::NOT_FOUND,
+            KvError::Conflict(_) => StatusCode::CONFLICT,
+            KvError::NoQuorum(_) => StatusCode::SERVICE_UNAVAILABLE,
+            KvError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            KvError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            KvError::UnknownNamespace(_) => StatusCode::NOT_FOUND,
+            KvError::UnknownUser(_) => StatusCode::NOT_FOUND,
+            KvError::UnknownApiKey(_) => StatusCode::NOT_FOUND,
+            KvError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            KvError::NotAnInteger => StatusCode::CONFLICT,
+            KvError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            KvError::Forbidden(_) => StatusCode::FORBIDDEN,
+            KvError::AccountLocked(_) => StatusCode::LOCKED,
+            KvError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvError::NotFound => write!(f, "Key not found in any node"),
+            KvError::Conflict(msg) => write!(f, "Conflict: {}", msg),
+            KvError::NoQuorum(msg) => write!(f, "No quorum: {}", msg),
+            KvError::PreconditionFailed(msg) => write!(f, "Precondition failed: {}", msg),
+            KvError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            KvError::UnknownNamespace(name) => write!(f, "Namespace not found: {}", name),
+            KvError::UnknownUser(email) => write!(f, "User not found: {}", email),
+            KvError::UnknownApiKey(id) => write!(f, "API key not found: {}", id),
+            KvError::QuotaExceeded(msg) => write!(f, "Quota exceeded: {}", msg),
+            KvError::NotAnInteger => write!(f, "Value is not an integer or out of range"),
+            KvError::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            KvError::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
+            KvError::AccountLocked(msg) => write!(f, "Account locked: {}", msg),
+            KvError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<RaftError> for KvError {
+    fn from(e: RaftError) -> Self {
+        match e {
+            RaftError::NotLeader(_) | RaftError::Unavailable(_) => KvError::NoQuorum(e.to_string()),
+            RaftError::Storage(_) => KvError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<sled::Error> for KvError {
+    fn from(e: sled::Error) -> Self {
+        KvError::Internal(format!("Database error: {}", e))
+    }
+}
+
+pub struct KvValue {
+    pub value: IVec,
+    pub meta: ValueMeta,
+}
+
+fn raft_group(key: &[u8]) -> Result<String, KvError> {
+    group_for_key(key).ok_or_else(|| KvError::NoQuorum("No node available".to_string()))
+}
+
+fn check_quorum(key: &[u8], replication_factor: usize) -> Result<(), KvError> {
+    let ring = HASH_RING.read().unwrap();
+    let leader = ring.get_node(key).ok_or_else(|| KvError::NoQuorum("No node available".to_string()))?;
+    quorum_for(&ring, &leader.id, replication_factor)
+}
+
+// A majority of the leader's replica set must look alive; nodes without a health entry yet count as alive
+pub fn quorum_for(ring: &HashRing, leader_id: &str, replication_factor: usize) -> Result<(), KvError> {
+    let replicas = ring.get_replicas(leader_id, replication_factor);
+    let health = HEALTH_TABLE.read().unwrap();
+    let alive = replicas.iter()
+        .filter(|id| health.get(*id).is_none_or(|h| h.is_alive))
+        .count();
+    if alive < replicas.len() / 2 + 1 {
+        return Err(KvError::NoQuorum(format!("{}/{} replicas alive", alive, replicas.len())));
+    }
+    Ok(())
+}
+
+/*
+Apply `op` to one copy (value + metadata and the write-mode check in one transaction), unflushed.
+Also returns the key's metadata as the same transaction left it (default once deleted). A key
+locked by a cross-partition transaction that is between its two phases is refused.
+*/
+fn apply_on(db: &Db, op: &WalOp) -> Result<(ApplyOutcome, ValueMeta), KvError> {
+    let trees = store::trees(db, op.namespace())?;
+    let locks = db.open_tree(store::TXN_LOCKS_TREE)?;
+    (&trees.data, &trees.meta, &locks)
+        .transaction(|(data, meta, locks)| {
+            if let Some(txn) = store::locked_by(locks, op.namespace(), op.key())? {
+                return Err(ConflictableTransactionError::Abort(KvError::Conflict(format!("key is locked by transaction {}", txn))));
+            }
+            let outcome = apply_op(data, meta, op)?;
+            Ok((outcome, store::current_meta(meta, op.key())?))
+        })
+        .map_err(|e| match e {
+            TransactionError::Storage(e) => KvError::from(e),
+            TransactionError::Abort(e) => e,
+        })
+}
+
+fn write_leader(op: &WalOp) -> Result<(ApplyOutcome, ValueMeta), KvError> {
+    let ring = HASH_RING.read().unwrap();
+    let leader = ring.get_node(op.key()).ok_or_else(|| KvError::NoQuorum("No node available".to_string()))?;
+    let applied = apply_on(&leader.db, op)?;
+    leader.db.flush().ok();
+    Ok(applied)
+}
+
+// Writes that did not happen become errors
+pub fn check_outcome(outcome: ApplyOutcome) -> Result<ApplyOutcome, KvError> {
+    match outcome {
+        ApplyOutcome::AlreadyExists => Err(KvError::Conflict("Key already exists".to_string())),
+        ApplyOutcome::Missing => Err(KvError::NotFound),
+        ApplyOutcome::ConditionFailed => Err(KvError::PreconditionFailed("current version or value does not match".to_string())),
+        ApplyOutcome::NotAnInteger => Err(KvError::NotAnInteger),
+        applied => Ok(applied),
+    }
+}
+
+/*
+The leader already decided whether the write happens, so replicas apply its result
+unconditionally: a plain overwrite (or delete) without the mode and CAS condition. A replica
+missing older history still converges to the leader's copy.
+*/
+pub fn as_applied(op: WalOp) -> WalOp {
+    match op {
+        WalOp::Set { namespace, key, value, content_type, expires_at, .. } => {
+            WalOp::Set { namespace, key, value, content_type, mode: WriteMode::Upsert, condition: None, expires_at }
+        }
+        WalOp::Delete { namespace, key, .. } => WalOp::Delete { namespace, key, condition: None },
+        // incr() logs the resulting value instead
+        op @ (WalOp::Expire { .. } | WalOp::Incr { .. }) => op,
+    }
+}
+
+pub fn count(namespace: &str, op: &'static str) {
+    counter!("kv_operations_total", 1, "namespace" => namespace.to_string(), "op" => op);
+}
+
+/*
+Quota check before a write. Updating an existing key never hits the key limit, so the leader is
+asked whether the key exists; namespaces without a quota skip the lookup.
+*/
+pub fn check_quota(ns: &Namespace, key: &[u8], value_len: usize) -> Result<(), KvError> {
+    if ns.quota.max_keys.is_none() && ns.quota.max_bytes.is_none() {
+        return Ok(());
+    }
+    let exists = {
+        let ring = HASH_RING.read().unwrap();
+        let leader = ring.get_node(key).ok_or_else(|| KvError::NoQuorum("No node available".to_string()))?;
+        store::read(&store::trees(&leader.db, &ns.name)?, key)?.is_some()
+    };
+    ns.check_quota(value_len, !exists)
+}
+
+// Followers (and watchers) pick the change up from the WAL; `version` is the leader's, if the key still exists
+fn log_op(op: WalOp, version: Option<u64>) -> Result<(), KvError> {
+    log_writes(vec![(op, version)]).map(|_| ()).map_err(|e| KvError::Internal(format!("WAL disk write failed: {}", e)))
+}
+
+/*
+Eventual writes hold their keys' locks from the leader's apply until the WAL entry is written, so
+two writes to one key are logged in the order the leader applied them and followers end up with
+the leader's last value. Keys share a fixed set of stripes; callers with several keys take them in
+stripe order, so they can't deadlock.
+*/
+const WRITE_LOCK_STRIPES: usize = 1024;
+static WRITE_LOCKS: Lazy<Vec<Arc<Mutex<()>>>> = Lazy::new(|| (0..WRITE_LOCK_STRIPES).map(|_| Arc::new(Mutex::new(()))).collect());
+
+pub type WriteLocks = Vec<OwnedMutexGuard<()>>;
+
+fn stripes<'a>(keys: impl IntoIterator<Item = &'a [u8]>) -> Vec<usize> {
+    let mut stripes: Vec<usize> = keys.into_iter()
+        .map(|key| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish() as usize % WRITE_LOCK_STRIPES
+        })
+        .collect();
+    stripes.sort_unstable();
+    stripes.dedup();
+    stripes
+}
+
+pub async fn lock_keys<'a>(keys: impl IntoIterator<Item = &'a [u8]>) -> WriteLocks {
+    let mut locks = Vec::new();
+    for stripe in stripes(keys) {
+        locks.push(WRITE_LOCKS[stripe].clone().lock_owned().await);
+    }
+    locks
+}
+
+// lock_keys for blocking threads
+fn blocking_lock_keys<'a>(keys: impl IntoIterator<Item = &'a [u8]>) -> WriteLocks {
+    stripes(keys).into_iter().map(|stripe| WRITE_LOCKS[stripe].clone().blocking_lock_owned()).collect()
+}
+
+// Everything about a write besides the key and value
+#[derive(Default)]
+pub struct PutOptions {
+    pub content_type: Option<String>,
+    pub mode: WriteMode,
+    pub condition: Option<Condition>,
+    // Epoch milliseconds, see expiry()
+    pub expires_at: Option<u64>,
+}
+
+// The namespace's default TTL for writes that don't set an expiry
+pub fn with_default_ttl(ns: &Namespace, expires_at: Option<u64>) -> Option<u64> {
+    expires_at.or_else(|| ns.default_ttl_seconds.map(|ttl| store::now_ms() + ttl * 1000))
+}
+
+// ttl_seconds / expires_at from a request into an absolute expiry; at most one may be given
+pub fn expiry(ttl_seconds: Option<u64>, expires_at: Option<DateTime<Utc>>) -> Result<Option<u64>, KvError> {
+    match (ttl_seconds, expires_at) {
+        (Some(_), Some(_)) => Err(KvError::BadRequest("give either ttl_seconds or expires_at, not both".to_string())),
+        (Some(0), None) => Err(KvError::BadRequest("ttl_seconds must be positive".to_string())),
+        (Some(ttl), None) => Ok(Some(store::now_ms() + ttl * 1000)),
+        (None, Some(at)) => Ok(Some(at.timestamp_millis().max(0) as u64)),
+        (None, None) => Ok(None),
+    }
+}
+
+/*
+Created or Updated. Conflict for create-only on an existing key, NotFound for update-only on a
+missing one, PreconditionFailed when the CAS condition does not match. Nothing is written on error.
+*/
+pub async fn put(namespace: &str, key: &[u8], value: Vec<u8>, options: PutOptions, consistency: Consistency) -> Result<ApplyOutcome, KvError> {
+    let ns = namespace::get(namespace)?;
+    count(namespace, "put");
+    let PutOptions { content_type, mode, condition, expires_at } = options;
+    check_quota(&ns, key, value.len())?;
+    let value_len = value.len();
+    let expires_at = with_default_ttl(&ns, expires_at);
+    let op = ns.resolve(WalOp::Set { namespace: ns.name.clone(), key: key.to_vec(), value, content_type, mode, condition, expires_at });
+
+    let outcome = if ns.consistency(consistency) == Consistency::Strong {
+        // Every member checks the mode and condition against the same log prefix, so they all agree
+        check_outcome(raft().write(&raft_group(key)?, op).await?)?
+    } else {
+        check_quorum(key, ns.replication_factor)?;
+        let _locks = lock_keys([key]).await;
+        let (outcome, meta) = write_leader(&op)?;
+        let outcome = check_outcome(outcome)?;
+        log_op(as_applied(op), Some(meta.version))?;
+        outcome
+    };
+    if outcome == ApplyOutcome::Created {
+        namespace::record_created(namespace, value_len);
+    }
+    Ok(outcome)
+}
+
+pub async fn get(namespace: &str, key: &[u8], consistency: Consistency) -> Result<KvValue, KvError> {
+    let ns = namespace::get(namespace)?;
+    count(namespace, "get");
+    if ns.consistency(consistency) == Consistency::Strong {
+        let (value, meta) = raft().read(&raft_group(key)?, namespace, key).await?.ok_or(KvError::NotFound)?;
+        return Ok(KvValue { value, meta });
+    }
+
+    // Primary first, then its followers
+    let ring = HASH_RING.read().unwrap();
+    let leader = ring.get_node(key).ok_or_else(|| KvError::NoQuorum("No node available".to_string()))?;
+    if let Some((value, meta)) = store::read(&store::trees(&leader.db, namespace)?, key)? {
+        return Ok(KvValue { value, meta });
+    }
+    for replica_id in ring.get_follower_node_ids(key, ns.replication_factor) {
+        let Some(replica) = ring.get_node_by_id(&replica_id) else { continue };
+        // A follower that errors might be down, try the next one
+        if let Ok(Some((value, meta))) = store::trees(&replica.db, namespace).and_then(|trees| store::read(&trees, key)) {
+            println!("Found key '{}' in replica node '{}'", String::from_utf8_lossy(key), replica_id);
+            return Ok(KvValue { value, meta });
+        }
+    }
+    Err(KvError::NotFound)
+}
+
+pub async fn delete(namespace: &str, key: &[u8], condition: Option<Condition>, consistency: Consistency) -> Result<(), KvError> {
+    let ns = namespace::get(namespace)?;
+    count(namespace, "delete");
+    let op = WalOp::Delete { namespace: ns.name.clone(), key: key.to_vec(), condition };
+    if ns.consistency(consistency) == Consistency::Strong {
+        return check_outcome(raft().write(&raft_group(key)?, op).await?).map(|_| ());
+    }
+
+    check_quorum(key, ns.replication_factor)?;
+    let _locks = lock_keys([key]).await;
+    let (outcome, _) = write_leader(&op)?;
+    if outcome == ApplyOutcome::ConditionFailed {
+        return check_outcome(outcome).map(|_| ());
+    }
+    // Logged even when the leader had nothing, so a follower holding a stale copy converges
+    log_op(as_applied(op), None)?;
+    check_outcome(outcome).map(|_| ())
+}
+
+// Set the expiry of an existing key, or clear it with None (PERSIST); NotFound if the key is missing
+pub async fn expire(namespace: &str, key: &[u8], expires_at: Option<u64>, consistency: Consistency) -> Result<(), KvError> {
+    let ns = namespace::get(namespace)?;
+    count(namespace, "expire");
+    let op = WalOp::Expire { namespace: ns.name.clone(), key: key.to_vec(), expires_at };
+    if ns.consistency(consistency) == Consistency::Strong {
+        return check_outcome(raft().write(&raft_group(key)?, op).await?).map(|_| ());
+    }
+
+    check_quorum(key, ns.replication_factor)?;
+    let _locks = lock_keys([key]).await;
+    let (outcome, meta) = write_leader(&op)?;
+    check_outcome(outcome)?;
+    log_op(op, Some(meta.version))
+}
+
+// Delete a key that expired by `now` from every copy: through its Raft group in strong namespaces,
+// otherwise on the leader and then through the WAL. False if it was rewritten meanwhile.
+pub async fn reap_expired(namespace: &str, key: &[u8], now: u64) -> Result<bool, KvError> {
+    let ns = namespace::get(namespace)?;
+    // Keys of a strong namespace are only ever written through their Raft group
+    if ns.consistency == Consistency::Strong {
+        let op = WalOp::Delete { namespace: ns.name, key: key.to_vec(), condition: Some(Condition::ExpiredBy(now)) };
+        // The condition only holds for a key that is still there, expired; the delete reports that as Missing
+        return Ok(raft().write(&raft_group(key)?, op).await? != ApplyOutcome::ConditionFailed);
+    }
+    let key = key.to_vec();
+    tokio::task::spawn_blocking(move || reap_on_leader(&ns.name, &key, now))
+        .await
+        .map_err(|e| KvError::Internal(format!("reap task failed: {}", e)))?
+}
+
+fn reap_on_leader(namespace: &str, key: &[u8], now: u64) -> Result<bool, KvError> {
+    let _locks = blocking_lock_keys([key]);
+    let removed = {
+        let ring = HASH_RING.read().unwrap();
+        let leader = ring.get_node(key).ok_or_else(|| KvError::NoQuorum("No node available".to_string()))?;
+        let trees = store::trees(&leader.db, namespace)?;
+        (&trees.data, &trees.meta)
+            .transaction(|(data, meta)| store::remove_if_expired::<()>(data, meta, key, now))
+            .map_err(|e| match e {
+                TransactionError::Storage(e) => KvError::from(e),
+                TransactionError::Abort(_) => KvError::Internal("transaction aborted".to_string()),
+            })?
+    };
+    if removed {
+        log_op(WalOp::Delete { namespace: namespace.to_string(), key: key.to_vec(), condition: None }, None)?;
+    }
+    Ok(removed)
+}
+
+/*
+INCR/DECR/INCRBY, returning the new value. On the eventual path the leader applies the delta and
+the WAL gets the result as a plain SET, with the key's content type and expiry read in the same
+transaction, so a replica that missed earlier writes still converges. Raft members apply the delta
+itself, in log order.
+*/
+// DECR/DECRBY
+pub fn negate(by: i64) -> Result<i64, KvError> {
+    by.checked_neg().ok_or_else(|| KvError::BadRequest(format!("can't decrement by {}", by)))
+}
+
+pub async fn incr(namespace: &str, key: &[u8], delta: i64, consistency: Consistency) -> Result<i64, KvError> {
+    let ns = namespace::get(namespace)?;
+    count(namespace, "incr");
+    // Room for any i64
+    check_quota(&ns, key, 20)?;
+    let op = WalOp::Incr { namespace: ns.name.clone(), key: key.to_vec(), delta, expires_at: with_default_ttl(&ns, None) };
+
+    let outcome = if ns.consistency(consistency) == Consistency::Strong {
+        raft().write(&raft_group(key)?, op).await?
+    } else {
+        check_quorum(key, ns.replication_factor)?;
+        let _locks = lock_keys([key]).await;
+        let (outcome, meta) = write_leader(&op)?;
+        if let ApplyOutcome::Incremented(value) = outcome {
+            log_op(WalOp::Set {
+                namespace: ns.name.clone(),
+                key: key.to_vec(),
+                value: value.to_string().into_bytes(),
+                content_type: meta.content_type,
+                mode: WriteMode::Upsert,
+                condition: None,
+                expires_at: meta.expires_at,
+            }, Some(meta.version))?;
+        }
+        outcome
+    };
+    match check_outcome(outcome)? {
+        ApplyOutcome::Incremented(value) => Ok(value),
+        other => Err(KvError::Internal(format!("increment applied as {:?}", other))),
+    }
+}
+
+/*
+Batches. Keys are bucketed by their leader under a single ring lock and every node's bucket runs
+on its own blocking task. Results come back per key, in request order. Writes are applied on the
+leaders first and all of them then go into the WAL with one group commit.
+*/
+pub const MAX_BATCH_KEYS: usize = 1000;
+
+struct NodeBatch<T> {
+    leader: String,
+    db: Db,
+    // Only as many as the namespace's replication factor asks for
+    followers: Vec<(String, Db)>,
+    // (position in the request, item)
+    items: Vec<(usize, T)>,
+}
+
+// Keys with no node at all stay out of every bucket and keep their initial error
+fn group_by_node<T>(items: Vec<T>, replication_factor: usize, key: impl Fn(&T) -> &[u8]) -> Vec<NodeBatch<T>> {
+    let ring = HASH_RING.read().unwrap();
+    let mut groups: BTreeMap<String, NodeBatch<T>> = BTreeMap::new();
+    for (index, item) in items.into_iter().enumerate() {
+        let Some(leader) = ring.get_node(key(&item)) else { continue };
+        groups.entry(leader.id.clone())
+            .or_insert_with(|| NodeBatch {
+                leader: leader.id.clone(),
+                db: leader.db.clone(),
+                followers: ring.get_follower_node_ids(key(&item), replication_factor)
+                    .into_iter()
+                    .filter_map(|id| Some((id.clone(), ring.get_node_by_id(&id)?.db.clone())))
+                    .collect(),
+                items: Vec::new(),
+            })
+            .items.push((index, item));
+    }
+    groups.into_values().collect()
+}
+
+fn no_node<T>(len: usize) -> Vec<Result<T, KvError>> {
+    (0..len).map(|_| Err(KvError::NoQuorum("No node available".to_string()))).collect()
+}
+
+pub async fn batch_get(namespace: &str, keys: Vec<Vec<u8>>, consistency: Consistency) -> Result<Vec<Result<KvValue, KvError>>, KvError> {
+    let ns = namespace::get(namespace)?;
+    count(namespace, "batch_get");
+    let mut results = no_node(keys.len());
+    let mut tasks = JoinSet::new();
+
+    if ns.consistency(consistency) == Consistency::Strong {
+        for (index, key) in keys.into_iter().enumerate() {
+            let namespace = namespace.to_string();
+            tasks.spawn(async move { vec![(index, get(&namespace, &key, Consistency::Strong).await)] });
+        }
+    } else {
+        for batch in group_by_node(keys, ns.replication_factor, |k| k.as_slice()) {
+            let namespace = namespace.to_string();
+            tasks.spawn_blocking(move || {
+                batch.items.into_iter().map(|(index, key)| {
+                    // Leader first, then its followers, like get()
+                    let found = std::iter::once(&batch.db)
+                        .chain(batch.followers.iter().map(|(_, db)| db))
+                        .find_map(|db| store::trees(db, &namespace).and_then(|trees| store::read(&trees, &key)).ok().flatten());
+                    let result = found.map(|(value, meta)| KvValue { value, meta }).ok_or(KvError::NotFound);
+                    (index, result)
+                }).collect::<Vec<_>>()
+            });
+        }
+    }
+
+    while let Some(done) = tasks.join_next().await {
+        match done {
+            Ok(batch) => batch.into_iter().for_each(|(index, result)| results[index] = result),
+            Err(e) => eprintln!("batch get task failed: {}", e),
+        }
+    }
+    Ok(results)
+}
+
+// A skipped SET (mode/condition) changes nothing and is not logged; a DELETE is logged unless its condition failed
+pub fn should_log(op: &WalOp, outcome: ApplyOutcome) -> bool {
+    match outcome {
+        ApplyOutcome::Created | ApplyOutcome::Updated | ApplyOutcome::Deleted | ApplyOutcome::Incremented(_) => true,
+        ApplyOutcome::Missing => matches!(op, WalOp::Delete { .. }),
+        ApplyOutcome::AlreadyExists | ApplyOutcome::ConditionFailed | ApplyOutcome::NotAnInteger => false,
+    }
+}
+
+async fn batch_write(ns: &Namespace, ops: Vec<Result<WalOp, KvError>>, consistency: Consistency) -> Vec<Result<ApplyOutcome, KvError>> {
+    let mut results = no_node(ops.len());
+    // Items rejected up front (quota) keep their error
+    let ops: Vec<(usize, WalOp)> = ops.into_iter().enumerate()
+        .filter_map(|(index, op)| op.map_err(|e| results[index] = Err(e)).ok().map(|op| (index, op)))
+        .collect();
+    let mut tasks = JoinSet::new();
+    // Raft orders strong writes itself
+    let strong = ns.consistency(consistency) == Consistency::Strong;
+    let _locks = if strong { Vec::new() } else { lock_keys(ops.iter().map(|(_, op)| op.key())).await };
+
+    if strong {
+        for (index, op) in ops {
+            tasks.spawn(async move {
+                let result = match raft_group(op.key()) {
+                    Ok(group) => raft().write(&group, op).await
+                        .map(|outcome| (outcome, ValueMeta::default()))
+                        .map_err(KvError::from),
+                    Err(e) => Err(e),
+                };
+                // Raft already replicated it, nothing to log
+                vec![(index, result, None)]
+            });
+        }
+    } else {
+        for batch in group_by_node(ops, ns.replication_factor, |(_, op)| op.key()) {
+            let quorum = quorum_for(&HASH_RING.read().unwrap(), &batch.leader, ns.replication_factor);
+            tasks.spawn_blocking(move || {
+                let applied: Vec<_> = batch.items.into_iter().map(|(_, (index, op))| {
+                    match &quorum {
+                        Ok(()) => {
+                            let result = apply_on(&batch.db, &op);
+                            (index, result, Some(op))
+                        }
+                        Err(e) => (index, Err(e.clone()), None),
+                    }
+                }).collect();
+                batch.db.flush().ok();
+                applied
+            });
+        }
+    }
+
+    let mut to_log = Vec::new();
+    while let Some(done) = tasks.join_next().await {
+        match done {
+            Ok(batch) => {
+                for (index, result, op) in batch {
+                    if let (Ok((outcome, meta)), Some(op)) = (&result, op)
+                        && should_log(&op, *outcome) {
+                        let version = (meta.version > 0).then_some(meta.version);
+                        to_log.push((index, (as_applied(op), version)));
+                    }
+                    results[index] = result.and_then(|(outcome, _)| check_outcome(outcome));
+                }
+            }
+            Err(e) => eprintln!("batch write task failed: {}", e),
+        }
+    }
+
+    // Request order, one fsync
+    to_log.sort_by_key(|(index, _)| *index);
+    let (indexes, ops): (Vec<usize>, Vec<(WalOp, Option<u64>)>) = to_log.into_iter().unzip();
+    if let Err(e) = log_writes(ops) {
+        for index in indexes {
+            results[index] = Err(KvError::Internal(format!("WAL disk write failed: {}", e)));
+        }
+    }
+    results
+}
+
+pub async fn batch_put(namespace: &str, items: Vec<(Vec<u8>, Vec<u8>, PutOptions)>, consistency: Consistency) -> Result<Vec<Result<ApplyOutcome, KvError>>, KvError> {
+    let ns = namespace::get(namespace)?;
+    count(namespace, "batch_put");
+    let sizes: Vec<usize> = items.iter().map(|(_, value, _)| value.len()).collect();
+    let ops = items.into_iter()
+        .map(|(key, value, PutOptions { content_type, mode, condition, expires_at })| {
+            check_quota(&ns, &key, value.len())?;
+            let expires_at = with_default_ttl(&ns, expires_at);
+            Ok(ns.resolve(WalOp::Set { namespace: ns.name.clone(), key, value, content_type, mode, condition, expires_at }))
+        })
+        .collect();
+    let results = batch_write(&ns, ops, consistency).await;
+    for (result, size) in results.iter().zip(sizes) {
+        if matches!(result, Ok(ApplyOutcome::Created)) {
+            namespace::record_created(namespace, size);
+        }
+    }
+    Ok(results)
+}
+
+pub async fn batch_delete(namespace: &str, keys: Vec<(Vec<u8>, Option<Condition>)>, consistency: Consistency) -> Result<Vec<Result<ApplyOutcome, KvError>>, KvError> {
+    let ns = namespace::get(namespace)?;
+    count(namespace, "batch_delete");
+    let ops = keys.into_iter()
+        .map(|(key, condition)| Ok(WalOp::Delete { namespace: ns.name.clone(), key, condition }))
+        .collect();
+    Ok(batch_write(&ns, ops, consistency).await)
+}
+
+pub const DEFAULT_SCAN_LIMIT: usize = 100;
+pub const MAX_SCAN_LIMIT: usize = 1000;
+
+pub struct ScanPage {
+    pub items: Vec<(Vec<u8>, KvValue)>,
+    // Set when there may be more keys after the last item
+    pub more: bool,
+}
+
+/*
+Scans fan out to every node in parallel. A node only reports the keys it leads, which leaves out
+follower copies (and follower copies of keys the leader already deleted), and the per-node runs
+are merged in key order. Asking every node for limit + 1 keys is enough for the first `limit`
+overall and tells whether another page exists. Scans always read the eventual path.
+*/
+pub async fn scan(namespace: &str, range: KeyRange, limit: usize) -> Result<ScanPage, KvError> {
+    namespace::get(namespace)?;
+    count(namespace, "scan");
+    let nodes: Vec<(String, Db)> = {
+        let ring = HASH_RING.read().unwrap();
+        ring.get_all_node_ids()
+            .into_iter()
+            .filter_map(|id| Some((id.clone(), ring.get_node_by_id(&id)?.db.clone())))
+            .collect()
+    };
+
+    let mut tasks = JoinSet::new();
+    for (id, db) in nodes {
+        let range = range.clone();
+        let namespace = namespace.to_string();
+        tasks.spawn_blocking(move || {
+            let trees = store::trees(&db, &namespace)?;
+            let ring = HASH_RING.read().unwrap();
+            store::scan_node(&trees, &range, limit + 1, |key| ring.get_node(key).is_some_and(|leader| leader.id == id))
+        });
+    }
+
+    let mut items = Vec::new();
+    while let Some(done) = tasks.join_next().await {
+        let found = done.map_err(|e| KvError::Internal(format!("scan task failed: {}", e)))??;
+        items.extend(found.into_iter().map(|(key, value, meta)| (key, KvValue { value, meta })));
+    }
+    items.sort_by(|a, b| a.0.cmp(&b.0));
+    items.dedup_by(|a, b| a.0 == b.0);
+    let more = items.len() > limit;
+    items.truncate(limit);
+    Ok(ScanPage { items, more })
+}
@@ -0,0 +1,22 @@
+use std::time::Duration;
+use super::{lock_keys, stripes};
+
+#[test]
+fn takes_each_stripe_once_in_order() {
+    let taken = stripes([&b"b"[..], b"a", b"b", b"c"]);
+    assert!(taken.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", taken);
+    assert_eq!(taken, stripes([&b"c"[..], b"a", b"b"]));
+}
+
+#[tokio::test]
+async fn a_key_is_written_by_one_caller_at_a_time() {
+    let key = format!("lock-{:016x}", rand::random::<u64>()).into_bytes();
+    let held = lock_keys([key.as_slice()]).await;
+    // Any key set that includes it waits, whatever order the keys come in
+    let other = format!("other-{:016x}", rand::random::<u64>()).into_bytes();
+    let waiting = tokio::time::timeout(Duration::from_millis(100), lock_keys([other.as_slice(), key.as_slice()])).await;
+    assert!(waiting.is_err());
+    drop(held);
+    let locks = tokio::time::timeout(Duration::from_secs(1), lock_keys([other.as_slice(), key.as_slice()])).await.unwrap();
+    assert!(!locks.is_empty());
+}
@@ -0,0 +1,48 @@
+/*
+Keys and values are arbitrary bytes. JSON can only carry text, so JSON requests say how their
+keys/values are written with `"encoding": "utf8"` (default) or `"base64"`, and responses say how
+they wrote them back: as requested, except that anything that isn't valid UTF-8 always goes out
+as base64 (standard alphabet, padded).
+*/
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use crate::coordinator::KvError;
+use crate::routes_resp::{Condition, IfMatch};
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+impl Encoding {
+    pub fn decode(self, field: &str, text: String) -> Result<Vec<u8>, KvError> {
+        match self {
+            Encoding::Utf8 => Ok(text.into_bytes()),
+            Encoding::Base64 => STANDARD.decode(text.as_bytes())
+                .map_err(|_| KvError::BadRequest(format!("{} is not valid base64", field))),
+        }
+    }
+
+    pub fn condition(self, if_match: Option<IfMatch>) -> Result<Option<Condition>, KvError> {
+        Ok(match if_match {
+            None => None,
+            Some(IfMatch::Version(version)) => Some(Condition::Version(version)),
+            Some(IfMatch::Value(value)) => Some(Condition::Value(self.decode("if_match value", value)?)),
+        })
+    }
+
+    // The encoding a response uses for all of `parts`
+    pub fn for_output(self, parts: &[&[u8]]) -> Encoding {
+        if parts.iter().all(|part| std::str::from_utf8(part).is_ok()) { self } else { Encoding::Base64 }
+    }
+
+    pub fn encode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Base64 => STANDARD.encode(bytes),
+        }
+    }
+}
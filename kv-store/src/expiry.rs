@@ -0,0 +1,76 @@
+/*
+Background reaping of expired keys. Expired keys are already invisible to reads; this frees the
+space. Each pass looks at the keys a node leads and deletes the expired ones the way the namespace
+writes: through Raft for strong namespaces, otherwise on the leader and through the WAL, so every
+replica drops its copy too. The scans read whole trees, so they run on a blocking thread.
+*/
+use metrics::counter;
+use tokio::sync::watch;
+use crate::config::{expiry_sweep_interval, HASH_RING};
+use crate::coordinator::reap_expired;
+use crate::middleware::idempotency::purge_expired;
+use crate::{api_keys, namespace};
+use crate::tokens;
+use crate::store::{expired_keys, now_ms, trees};
+
+// Expired keys of the namespace, each from the node that leads it
+fn expired_on_leaders(namespace: &str, now: u64) -> Vec<Vec<u8>> {
+    let ring = HASH_RING.read().unwrap();
+    ring.get_all_node_ids()
+        .iter()
+        .filter_map(|id| ring.get_node_by_id(id))
+        .flat_map(|node| {
+            trees(&node.db, namespace)
+                .and_then(|trees| expired_keys(&trees, now))
+                .unwrap_or_default()
+                .into_iter()
+                // Replicas see the same expiry; only the leader's copy decides
+                .filter(|key| ring.get_node(key).is_some_and(|leader| leader.id == node.id))
+        })
+        .collect()
+}
+
+async fn sweep(namespace: String) {
+    let now = now_ms();
+    let expired = {
+        let namespace = namespace.clone();
+        tokio::task::spawn_blocking(move || expired_on_leaders(&namespace, now)).await.unwrap_or_default()
+    };
+    for key in expired {
+        match reap_expired(&namespace, &key, now).await {
+            Ok(true) => counter!("expired_keys_reaped_total", 1, "namespace" => namespace.clone()),
+            Ok(false) => {}
+            Err(e) => eprintln!("Failed to reap expired key '{}/{}': {}", namespace, String::from_utf8_lossy(&key), e),
+        }
+    }
+}
+
+pub async fn expiry_sweeper(mut stop: watch::Receiver<bool>) {
+    let interval = expiry_sweep_interval();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {
+                for name in namespace::names() {
+                    sweep(name).await;
+                }
+                // Quotas and the namespace gauges are recounted on the same schedule
+                let housekeeping = tokio::task::spawn_blocking(|| {
+                    namespace::refresh_usage();
+                    let purged = purge_expired();
+                    if purged > 0 {
+                        counter!("idempotency_keys_purged_total", purged as u64);
+                    }
+                    let purged = tokens::purge_expired();
+                    if purged > 0 {
+                        counter!("tokens_purged_total", purged as u64);
+                    }
+                    api_keys::persist_last_used();
+                });
+                if let Err(e) = housekeeping.await {
+                    eprintln!("Expiry housekeeping failed: {}", e);
+                }
+            }
+            _ = stop.changed() => return,
+        }
+    }
+}
@@ -0,0 +1,402 @@
+/*
+gRPC API defined in proto/kv.proto, on its own port (GRPC_PORT, off unless set). Methods make the
+same coordinator calls as the HTTP routes and accept the same credentials: `authorization: Bearer
+<JWT>` or `authorization: ApiKey <key>` metadata, checked by an interceptor before any method runs, and the token's grants apply as over
+HTTP (PermissionDenied otherwise). Scan pages through the coordinator
+as the client reads; Watch streams the WAL change feed. Both streams end with Unauthenticated once
+the login expires or is revoked, or its API key is deleted.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use chrono::DateTime;
+use futures_util::stream::{self, BoxStream};
+use metrics::counter;
+use tokio::sync::watch;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+use crate::changefeed::{Change, WatchFilter, Watcher, WatcherGuard};
+use crate::coordinator::{self, KvError, KvValue, PutOptions, MAX_BATCH_KEYS, MAX_SCAN_LIMIT};
+use crate::access::{authorize, range_prefix, Action};
+use crate::middleware::types::Claims;
+use crate::middleware::{still_valid, verify_authorization};
+use crate::namespace::{self, DEFAULT_NAMESPACE};
+use crate::routes_resp::{Condition, Consistency, WatchEventKind, WriteMode};
+use crate::store::{ApplyOutcome, KeyRange};
+
+pub mod pb {
+    tonic::include_proto!("kv.v1");
+}
+
+use pb::compare_and_swap_request::Expected;
+use pb::kv_store_server::{KvStore, KvStoreServer};
+use pb::watch_request::Target;
+
+impl From<KvError> for Status {
+    fn from(e: KvError) -> Self {
+        let code = match &e {
+            KvError::NotFound | KvError::UnknownNamespace(_) | KvError::UnknownUser(_) | KvError::UnknownApiKey(_) => Code::NotFound,
+            KvError::Conflict(_) => Code::AlreadyExists,
+            KvError::NoQuorum(_) => Code::Unavailable,
+            KvError::PreconditionFailed(_) | KvError::NotAnInteger => Code::FailedPrecondition,
+            KvError::BadRequest(_) => Code::InvalidArgument,
+            KvError::QuotaExceeded(_) => Code::ResourceExhausted,
+            KvError::Unauthorized(_) | KvError::AccountLocked(_) => Code::Unauthenticated,
+            KvError::Forbidden(_) => Code::PermissionDenied,
+            KvError::Internal(_) => Code::Internal,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+// Same check as the HTTP auth middleware; leaves the Claims in the request extensions
+fn check_auth(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let claims = request.metadata().get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(verify_authorization);
+    match claims {
+        Some(claims) => {
+            request.extensions_mut().insert(claims);
+            Ok(request)
+        }
+        None => Err(Status::unauthenticated("missing or invalid bearer token or API key")),
+    }
+}
+
+fn claims<T>(request: &Request<T>) -> Result<Claims, Status> {
+    request.extensions().get::<Claims>().cloned().ok_or_else(|| Status::unauthenticated("missing or invalid bearer token"))
+}
+
+fn authorize_all<'a>(claims: &Claims, action: Action, namespace: &str, mut keys: impl Iterator<Item = &'a Vec<u8>>) -> Result<(), KvError> {
+    keys.try_for_each(|key| authorize(claims, action, namespace, key))
+}
+
+fn count(method: &'static str) {
+    counter!("grpc_requests_total", 1, "method" => method);
+}
+
+fn namespace_or_default(namespace: String) -> String {
+    if namespace.is_empty() { DEFAULT_NAMESPACE.to_string() } else { namespace }
+}
+
+fn consistency(consistency: pb::Consistency) -> Consistency {
+    match consistency {
+        pb::Consistency::Eventual => Consistency::Eventual,
+        pb::Consistency::Strong => Consistency::Strong,
+    }
+}
+
+fn write_mode(mode: pb::WriteMode) -> WriteMode {
+    match mode {
+        pb::WriteMode::Upsert => WriteMode::Upsert,
+        pb::WriteMode::CreateOnly => WriteMode::CreateOnly,
+        pb::WriteMode::UpdateOnly => WriteMode::UpdateOnly,
+    }
+}
+
+fn expiry(ttl_seconds: Option<u64>, expires_at_ms: Option<u64>) -> Result<Option<u64>, KvError> {
+    let expires_at = expires_at_ms
+        .map(|ms| DateTime::from_timestamp_millis(ms as i64)
+            .ok_or_else(|| KvError::BadRequest("expires_at_ms is out of range".to_string())))
+        .transpose()?;
+    coordinator::expiry(ttl_seconds, expires_at)
+}
+
+fn value(found: KvValue) -> pb::Value {
+    pb::Value {
+        value: found.value.to_vec(),
+        content_type: found.meta.content_type,
+        version: found.meta.version,
+        expires_at_ms: found.meta.expires_at,
+    }
+}
+
+fn item_status<T>(result: &Result<T, KvError>) -> pb::ItemStatus {
+    match result {
+        Ok(_) => pb::ItemStatus { code: Code::Ok as u32, error: String::new() },
+        Err(e) => pb::ItemStatus { code: Status::from(e.clone()).code() as u32, error: e.to_string() },
+    }
+}
+
+fn check_batch_len(len: usize) -> Result<(), Status> {
+    if len == 0 || len > MAX_BATCH_KEYS {
+        return Err(Status::invalid_argument(format!("a batch takes 1 to {} keys, got {}", MAX_BATCH_KEYS, len)));
+    }
+    Ok(())
+}
+
+fn watch_event(change: Change) -> pb::WatchEvent {
+    let event_type = match change.kind {
+        WatchEventKind::Set => pb::EventType::Set,
+        WatchEventKind::Delete => pb::EventType::Delete,
+        WatchEventKind::Expire => pb::EventType::Expire,
+    };
+    pb::WatchEvent {
+        seq: change.seq as u64,
+        r#type: event_type as i32,
+        namespace: change.namespace,
+        key: change.key,
+        value: change.value.unwrap_or_default(),
+        version: change.version,
+        content_type: change.content_type,
+        expires_at_ms: change.expires_at,
+    }
+}
+
+// Where a Scan stream is: the current page and how many items the client still wants
+struct ScanState {
+    claims: Claims,
+    namespace: String,
+    range: KeyRange,
+    keys_only: bool,
+    remaining: u64,
+    page: VecDeque<(Vec<u8>, KvValue)>,
+    done: bool,
+}
+
+// Ends a stream that outlived the login it was opened with
+fn login_ended() -> Status {
+    Status::unauthenticated("login expired or revoked")
+}
+
+pub struct GrpcKvStore;
+
+#[tonic::async_trait]
+impl KvStore for GrpcKvStore {
+    type ScanStream = BoxStream<'static, Result<pb::ScanItem, Status>>;
+    type WatchStream = BoxStream<'static, Result<pb::WatchEvent, Status>>;
+
+    async fn get(&self, request: Request<pb::GetRequest>) -> Result<Response<pb::GetResponse>, Status> {
+        count("get");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        let consistency = consistency(req.consistency());
+        let namespace = namespace_or_default(req.namespace);
+        authorize(&claims, Action::Read, &namespace, &req.key)?;
+        let found = coordinator::get(&namespace, &req.key, consistency).await?;
+        Ok(Response::new(pb::GetResponse { value: Some(value(found)) }))
+    }
+
+    async fn put(&self, request: Request<pb::PutRequest>) -> Result<Response<pb::PutResponse>, Status> {
+        count("put");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        let (mode, consistency) = (write_mode(req.mode()), consistency(req.consistency()));
+        let namespace = namespace_or_default(req.namespace);
+        authorize(&claims, Action::Write, &namespace, &req.key)?;
+        let options = PutOptions {
+            content_type: req.content_type,
+            mode,
+            condition: None,
+            expires_at: expiry(req.ttl_seconds, req.expires_at_ms)?,
+        };
+        let outcome = coordinator::put(&namespace, &req.key, req.value, options, consistency).await?;
+        Ok(Response::new(pb::PutResponse { created: outcome == ApplyOutcome::Created }))
+    }
+
+    async fn delete(&self, request: Request<pb::DeleteRequest>) -> Result<Response<pb::DeleteResponse>, Status> {
+        count("delete");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        let consistency = consistency(req.consistency());
+        let namespace = namespace_or_default(req.namespace);
+        authorize(&claims, Action::Delete, &namespace, &req.key)?;
+        coordinator::delete(&namespace, &req.key, None, consistency).await?;
+        Ok(Response::new(pb::DeleteResponse {}))
+    }
+
+    async fn compare_and_swap(&self, request: Request<pb::CompareAndSwapRequest>) -> Result<Response<pb::CompareAndSwapResponse>, Status> {
+        count("compare_and_swap");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        let consistency = consistency(req.consistency());
+        let namespace = namespace_or_default(req.namespace);
+        authorize(&claims, if req.delete { Action::Delete } else { Action::Write }, &namespace, &req.key)?;
+        let condition = match req.expected {
+            Some(Expected::ExpectedVersion(version)) => Condition::Version(version),
+            Some(Expected::ExpectedValue(value)) => Condition::Value(value),
+            None => return Err(Status::invalid_argument("expected_version or expected_value is required")),
+        };
+        let result = if req.delete {
+            coordinator::delete(&namespace, &req.key, Some(condition), consistency).await
+        } else {
+            let options = PutOptions {
+                content_type: req.content_type,
+                mode: WriteMode::Upsert,
+                condition: Some(condition),
+                expires_at: expiry(req.ttl_seconds, None)?,
+            };
+            coordinator::put(&namespace, &req.key, req.value, options, consistency).await.map(|_| ())
+        };
+        match result {
+            Ok(()) => Ok(Response::new(pb::CompareAndSwapResponse { swapped: true })),
+            Err(KvError::PreconditionFailed(_)) => Ok(Response::new(pb::CompareAndSwapResponse { swapped: false })),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn batch_get(&self, request: Request<pb::BatchGetRequest>) -> Result<Response<pb::BatchGetResponse>, Status> {
+        count("batch_get");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        check_batch_len(req.keys.len())?;
+        let consistency = consistency(req.consistency());
+        let namespace = namespace_or_default(req.namespace);
+        authorize_all(&claims, Action::Read, &namespace, req.keys.iter())?;
+        let found = coordinator::batch_get(&namespace, req.keys.clone(), consistency).await?;
+        let results = req.keys.into_iter().zip(found)
+            .map(|(key, result)| pb::BatchGetResult {
+                key,
+                status: Some(item_status(&result)),
+                value: result.ok().map(value),
+            })
+            .collect();
+        Ok(Response::new(pb::BatchGetResponse { results }))
+    }
+
+    async fn batch_put(&self, request: Request<pb::BatchPutRequest>) -> Result<Response<pb::BatchWriteResponse>, Status> {
+        count("batch_put");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        check_batch_len(req.items.len())?;
+        let consistency = consistency(req.consistency());
+        let namespace = namespace_or_default(req.namespace);
+        authorize_all(&claims, Action::Write, &namespace, req.items.iter().map(|item| &item.key))?;
+        let keys: Vec<Vec<u8>> = req.items.iter().map(|item| item.key.clone()).collect();
+        let mut items = Vec::with_capacity(req.items.len());
+        for item in req.items {
+            let options = PutOptions {
+                mode: write_mode(item.mode()),
+                content_type: item.content_type,
+                condition: None,
+                expires_at: expiry(item.ttl_seconds, None)?,
+            };
+            items.push((item.key, item.value, options));
+        }
+        let outcomes = coordinator::batch_put(&namespace, items, consistency).await?;
+        let results = keys.into_iter().zip(outcomes)
+            .map(|(key, result)| pb::BatchWriteResult {
+                key,
+                status: Some(item_status(&result)),
+                created: matches!(result, Ok(ApplyOutcome::Created)),
+            })
+            .collect();
+        Ok(Response::new(pb::BatchWriteResponse { results }))
+    }
+
+    async fn batch_delete(&self, request: Request<pb::BatchDeleteRequest>) -> Result<Response<pb::BatchWriteResponse>, Status> {
+        count("batch_delete");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        check_batch_len(req.keys.len())?;
+        let consistency = consistency(req.consistency());
+        let namespace = namespace_or_default(req.namespace);
+        authorize_all(&claims, Action::Delete, &namespace, req.keys.iter())?;
+        let items = req.keys.iter().map(|key| (key.clone(), None)).collect();
+        let outcomes = coordinator::batch_delete(&namespace, items, consistency).await?;
+        let results = req.keys.into_iter().zip(outcomes)
+            .map(|(key, result)| pb::BatchWriteResult { key, status: Some(item_status(&result)), created: false })
+            .collect();
+        Ok(Response::new(pb::BatchWriteResponse { results }))
+    }
+
+    async fn scan(&self, request: Request<pb::ScanRequest>) -> Result<Response<Self::ScanStream>, Status> {
+        count("scan");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        let namespace = namespace_or_default(req.namespace);
+        // Fail the call itself rather than the first message
+        namespace::get(&namespace)?;
+        let prefix = match &req.prefix {
+            Some(prefix) => prefix.as_slice(),
+            None => range_prefix(req.start.as_deref(), req.end.as_deref()),
+        };
+        authorize(&claims, Action::Read, &namespace, prefix)?;
+        let state = ScanState {
+            claims,
+            namespace,
+            range: KeyRange { prefix: req.prefix, start: req.start, end: req.end, after: None },
+            keys_only: req.keys_only,
+            remaining: if req.limit == 0 { u64::MAX } else { req.limit },
+            page: VecDeque::new(),
+            done: false,
+        };
+
+        let items = stream::unfold(state, |mut state| async move {
+            loop {
+                if state.remaining == 0 {
+                    return None;
+                }
+                if !still_valid(&state.claims) {
+                    state.remaining = 0;
+                    return Some((Err(login_ended()), state));
+                }
+                if let Some((key, found)) = state.page.pop_front() {
+                    state.remaining -= 1;
+                    let value = (!state.keys_only).then(|| value(found));
+                    return Some((Ok(pb::ScanItem { key, value }), state));
+                }
+                if state.done {
+                    return None;
+                }
+                let limit = state.remaining.min(MAX_SCAN_LIMIT as u64) as usize;
+                match coordinator::scan(&state.namespace, state.range.clone(), limit).await {
+                    Ok(page) => {
+                        state.done = !page.more || page.items.is_empty();
+                        state.range.after = page.items.last().map(|(key, _)| key.clone());
+                        state.page.extend(page.items);
+                    }
+                    Err(e) => {
+                        state.done = true;
+                        return Some((Err(e.into()), state));
+                    }
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(items)))
+    }
+
+    async fn watch(&self, request: Request<pb::WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        count("watch");
+        let claims = claims(&request)?;
+        let req = request.into_inner();
+        let namespace = namespace_or_default(req.namespace);
+        namespace::get(&namespace)?;
+        let filter = match req.target {
+            Some(Target::Key(key)) => WatchFilter { namespace, key: Some(key), prefix: None },
+            Some(Target::Prefix(prefix)) => WatchFilter { namespace, key: None, prefix: Some(prefix) },
+            None => return Err(Status::invalid_argument("key or prefix is required")),
+        };
+        let target = filter.key.as_deref().or(filter.prefix.as_deref()).unwrap_or_default();
+        authorize(&claims, Action::Read, &filter.namespace, target)?;
+        let watcher = Watcher::new(filter, req.since.map(|since| since as usize));
+
+        // The state is gone once the error went out, which ends the stream
+        let events = stream::unfold(Some((watcher, WatcherGuard::new("grpc"), claims)), |state| async move {
+            let (mut watcher, guard, claims) = state?;
+            let Some(change) = watcher.next_for(&claims).await else {
+                return Some((Err(login_ended()), None));
+            };
+            counter!("watch_events_total", 1, "transport" => "grpc");
+            Some((Ok(watch_event(change)), Some((watcher, guard, claims))))
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+// Serves until `stop` is set
+pub async fn grpc_server(port: u16, mut stop: watch::Receiver<bool>) {
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    println!("gRPC listening on port {}", port);
+    let result = Server::builder()
+        .add_service(KvStoreServer::with_interceptor(GrpcKvStore, check_auth))
+        .serve_with_shutdown(addr, async move {
+            let _ = stop.changed().await;
+        })
+        .await;
+    if let Err(e) = result {
+        eprintln!("gRPC server failed: {}", e);
+    }
+}
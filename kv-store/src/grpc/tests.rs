@@ -0,0 +1,107 @@
+use std::time::Duration;
+use futures_util::StreamExt;
+use tonic::{Code, Request};
+use crate::access::{Action, Grant};
+use crate::coordinator::KvError;
+use crate::middleware::types::Claims;
+use crate::tokens::revoke_token;
+use crate::users::Role;
+use super::pb::{self, kv_store_server::KvStore, watch_request::Target};
+use super::{check_auth, check_batch_len, expiry, item_status, namespace_or_default, GrpcKvStore};
+
+fn with_authorization(value: &str) -> Request<()> {
+    let mut request = Request::new(());
+    request.metadata_mut().insert("authorization", value.parse().unwrap());
+    request
+}
+
+fn reader(prefix: &str) -> Claims {
+    Claims {
+        email: "reader@x".to_string(),
+        role: Role::User,
+        grants: vec![Grant { namespace: "default".to_string(), prefix: prefix.to_string(), actions: vec![Action::Read] }],
+        exp: usize::MAX,
+        jti: "jti".to_string(),
+        sid: "sid".to_string(),
+    }
+}
+
+fn get_request(key: &str, claims: Option<Claims>) -> Request<pb::GetRequest> {
+    let mut request = Request::new(pb::GetRequest { key: key.as_bytes().to_vec(), ..Default::default() });
+    if let Some(claims) = claims {
+        request.extensions_mut().insert(claims);
+    }
+    request
+}
+
+#[test]
+fn interceptor_rejects_missing_and_invalid_credentials() {
+    let missing = check_auth(Request::new(())).unwrap_err();
+    assert_eq!(missing.code(), Code::Unauthenticated);
+    // A malformed API key is refused before any lookup
+    for value in ["Bearer not-a-jwt", "Basic dXNlcjpwYXNz", "ApiKey kvk_malformed", "ApiKey nope"] {
+        assert_eq!(check_auth(with_authorization(value)).unwrap_err().code(), Code::Unauthenticated, "{}", value);
+    }
+}
+
+#[tokio::test]
+async fn methods_check_claims_and_grants() {
+    let unauthenticated = GrpcKvStore.get(get_request("k", None)).await.unwrap_err();
+    assert_eq!(unauthenticated.code(), Code::Unauthenticated);
+    let outside = GrpcKvStore.get(get_request("private:k", Some(reader("public:")))).await.unwrap_err();
+    assert_eq!(outside.code(), Code::PermissionDenied);
+}
+
+#[test]
+fn maps_errors_to_status_codes() {
+    let cases = [
+        (KvError::NotFound, Code::NotFound),
+        (KvError::UnknownNamespace("ns".to_string()), Code::NotFound),
+        (KvError::Conflict("exists".to_string()), Code::AlreadyExists),
+        (KvError::NoQuorum("down".to_string()), Code::Unavailable),
+        (KvError::NotAnInteger, Code::FailedPrecondition),
+        (KvError::BadRequest("bad".to_string()), Code::InvalidArgument),
+        (KvError::QuotaExceeded("full".to_string()), Code::ResourceExhausted),
+        (KvError::Unauthorized("who".to_string()), Code::Unauthenticated),
+        (KvError::Forbidden("no".to_string()), Code::PermissionDenied),
+        (KvError::Internal("oops".to_string()), Code::Internal),
+    ];
+    for (error, code) in cases {
+        assert_eq!(tonic::Status::from(error.clone()).code(), code, "{}", error);
+    }
+    let failed = item_status::<()>(&Err(KvError::NotFound));
+    assert_eq!(failed.code, Code::NotFound as u32);
+    assert_eq!(item_status(&Ok(())).code, Code::Ok as u32);
+}
+
+#[test]
+fn validates_request_fields() {
+    assert_eq!(namespace_or_default(String::new()), "default");
+    assert_eq!(namespace_or_default("ns".to_string()), "ns");
+    assert!(check_batch_len(0).is_err());
+    assert!(check_batch_len(1).is_ok());
+    assert!(check_batch_len(crate::coordinator::MAX_BATCH_KEYS + 1).is_err());
+    assert_eq!(expiry(None, Some(1_700_000_000_000)).unwrap(), Some(1_700_000_000_000));
+    assert!(matches!(expiry(None, Some(i64::MAX as u64)), Err(KvError::BadRequest(_))));
+    assert!(matches!(expiry(Some(10), Some(1)), Err(KvError::BadRequest(_))));
+}
+
+#[tokio::test]
+async fn streams_end_when_the_login_is_revoked() {
+    let claims = Claims { jti: format!("jti-{:016x}", rand::random::<u64>()), ..reader("") };
+    let mut watch = Request::new(pb::WatchRequest { target: Some(Target::Prefix(b"nothing-writes-here/".to_vec())), ..Default::default() });
+    watch.extensions_mut().insert(claims.clone());
+    let mut events = GrpcKvStore.watch(watch).await.unwrap().into_inner();
+    let mut scan = Request::new(pb::ScanRequest { prefix: Some(b"nothing-writes-here/".to_vec()), ..Default::default() });
+    scan.extensions_mut().insert(claims.clone());
+    let mut items = GrpcKvStore.scan(scan).await.unwrap().into_inner();
+
+    // Already waiting for a change that never comes, so the idle re-check is what ends it
+    assert!(tokio::time::timeout(Duration::from_millis(200), events.next()).await.is_err());
+    revoke_token(&claims.jti).unwrap();
+    let ended = tokio::time::timeout(Duration::from_secs(5), events.next()).await.unwrap();
+    assert_eq!(ended.unwrap().unwrap_err().code(), Code::Unauthenticated);
+    assert!(events.next().await.is_none());
+    assert_eq!(items.next().await.unwrap().unwrap_err().code(), Code::Unauthenticated);
+    assert!(items.next().await.is_none());
+}
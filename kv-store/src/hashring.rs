@@ -5,6 +5,22 @@ use super::ring::get_node_for_key;
 
 pub type Hash = u64;
 
+// Copies of every key (leader included) unless its namespace says otherwise
+pub const DEFAULT_REPLICATION_FACTOR: usize = 3;
+
+/*
+Redis-style hash tags: if the key has a non-empty {tag} (the first '{' and the first '}' after it),
+only the tag is hashed, so {user42}:profile and {user42}:email share a partition and can be
+written in one transaction. Anything else hashes whole.
+*/
+pub fn hash_tag(key: &[u8]) -> &[u8] {
+    let Some(open) = key.iter().position(|b| *b == b'{') else { return key };
+    match key[open + 1..].iter().position(|b| *b == b'}') {
+        Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+        _ => key,
+    }
+}
+
 pub struct Node {
    pub id: String,
    pub db: Db,
@@ -48,20 +64,21 @@ impl HashRing {
 
         for i in 0..self.vnode_count {
             let vnode_key = format!("{}-{}", node_id, i);//eg. nodeA-1
-            let hash = get_node_for_key(&vnode_key); //get hash for nodeA-1
+            let hash = get_node_for_key(vnode_key.as_bytes()); //get hash for nodeA-1
             self.ring.insert(hash, node_id.to_string());//store in ring ,hash->nodeA
         }
     }
 
+    #[allow(dead_code)]
     pub fn remove_node(&mut self, node_id: &str) {
         self.node_map.remove(node_id);
         //ring.retain->work like filter
         //Jo entry "hash => node_id" match karti ho, usko ring se hata do
         self.ring.retain(|_, id| id != node_id);
     }
-     // get node where data will store
-    pub fn get_node(&self, key: &str) -> Option<&Node> {
-        let hash = get_node_for_key(key);
+     // get node where data will store; keys with the same {hash tag} land on the same node
+    pub fn get_node(&self, key: &[u8]) -> Option<&Node> {
+        let hash = get_node_for_key(hash_tag(key));
         // clockwise search in ring
         let node_id = self.ring.range(hash..)
             .next()
@@ -72,13 +89,26 @@ impl HashRing {
     }
 
     // Get node by ID for replica access
-  pub fn get_follower_node_ids(&self, key: &str) -> Vec<String> {
+  pub fn get_follower_node_ids(&self, key: &[u8], replication_factor: usize) -> Vec<String> {
     let leader = match self.get_node(key) {
         Some(node) => node,
         None => return Vec::new(),
     };
-    let leader_id = &leader.id;
+    let mut replicas = self.get_replicas(&leader.id, replication_factor);
+    if replicas.is_empty() {
+        return Vec::new();
+    }
+    replicas.remove(0);
+    replicas
+}
 
+    // Partition owned by `leader_id` lives on the leader plus the next two nodes in id order
+  pub fn get_replica_set(&self, leader_id: &str) -> Vec<String> {
+    self.get_replicas(leader_id, DEFAULT_REPLICATION_FACTOR)
+}
+
+    // Leader plus the next `replication_factor - 1` nodes in id order (capped at the node count)
+  pub fn get_replicas(&self, leader_id: &str, replication_factor: usize) -> Vec<String> {
     let mut node_ids: Vec<String> = self.node_map.keys().cloned().collect();
     node_ids.sort();
 
@@ -88,10 +118,9 @@ impl HashRing {
     };
 
     let total = node_ids.len();
-    let follower1 = node_ids[(leader_index + 1) % total].clone();
-    let follower2 = node_ids[(leader_index + 2) % total].clone();
-
-    vec![follower1, follower2]
+    (0..replication_factor.clamp(1, total))
+        .map(|offset| node_ids[(leader_index + offset) % total].clone())
+        .collect()
 }
 pub fn get_all_node_ids(&self) -> Vec<String> {
         self.node_map.keys().cloned().collect()
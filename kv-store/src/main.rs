@@ -8,19 +8,54 @@ mod wal;
 mod hashring;
 mod gprotocol;
 mod recovery;
+mod shutdown;
+mod raft;
+mod store;
+mod coordinator;
+mod routes_kv;
+mod expiry;
+mod routes_batch;
+mod routes_scan;
+mod namespace;
+mod routes_namespace;
+mod encoding;
+mod changefeed;
+mod routes_watch;
+mod redis;
+mod grpc;
+mod memcached;
+mod txn;
+mod routes_txn;
+mod users;
+mod routes_users;
+mod access;
+mod tokens;
+mod api_keys;
 use sysinfo::{System};
 use axum::{
     middleware::from_fn, response::IntoResponse, routing::{get, post}, Router
 };
-use tokio::sync::mpsc::{channel,Receiver,Sender};
+use tokio::sync::{oneshot, watch};
+use tokio::time::{timeout_at, Instant};
 use tower_http::trace::TraceLayer;
-use middleware::auth_middlware;
-use routes::{set_value, delete_value, get_value, login_handler};
+use middleware::{auth_middlware, idempotency::idempotency_middleware};
+use routes::{set_value, delete_value, get_value, get_ttl, persist_value, incr_value, decr_value, login_handler, refresh_handler, logout_handler, raft_status};
+use routes_batch::{batch_delete, batch_get, batch_set};
+use routes_scan::scan;
+use routes_txn::transaction;
+use routes_watch::watch;
+use routes_kv::{kv_decr, kv_delete, kv_get, kv_head, kv_incr, kv_put, kv_ttl_delete, kv_ttl_get, kv_ttl_put};
+use routes_namespace::{create_namespace, drop_namespace, get_namespace, list_namespaces};
+use routes_users::{
+    create_api_key, create_user, delete_api_key, delete_user, get_api_key, get_user, list_api_keys, list_users,
+    revoke, signup, unlock_user, update_user,
+};
 use metrics_exporter_prometheus::{PrometheusBuilder};
 use metrics::{gauge};
 use gprotocol::{start_local_health_checker,start_heartbeat_updater};
 
-use crate::{replication::replication_worker, routes_resp::Wal};
+use crate::{replication::{replication_lag, replication_worker}, wal::init_wal_sequence};
+use crate::{config::shutdown_timeout, shutdown::{flush_storage, shutdown_signal}};
 
 #[tokio::main]
 async fn main() {
@@ -33,8 +68,32 @@ async fn main() {
     tokio::spawn(start_local_health_checker(my_id.clone()));
     
 
-    let(tx,rx):(Sender<Wal>,Receiver<Wal>)=channel(100);
-    tokio::spawn(replication_worker(rx));
+    // Replication tails the WAL from each node's persisted cursor, so numbering must continue from disk
+    init_wal_sequence();
+    // Before replication starts: it needs every namespace's replication factor
+    namespace::load_namespaces();
+    // Also before replication: recovered commits are logged to the WAL
+    recovery::recover_transactions();
+    users::bootstrap();
+    // Before any listener: tokens revoked before a restart must stay revoked
+    tokens::load_revocations();
+    api_keys::load_api_keys();
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let mut replication = tokio::spawn(replication_worker(stop_rx.clone()));
+    tokio::spawn(expiry::expiry_sweeper(stop_rx.clone()));
+    let redis_port = config::redis_port();
+    if redis_port != 0 {
+        tokio::spawn(redis::redis_listener(redis_port, stop_rx.clone()));
+    }
+    let memcached_port = config::memcached_port();
+    if memcached_port != 0 {
+        tokio::spawn(memcached::memcached_listener(memcached_port, stop_rx.clone()));
+    }
+    let grpc_port = config::grpc_port();
+    if grpc_port != 0 {
+        tokio::spawn(grpc::grpc_server(grpc_port, stop_rx));
+    }
+    raft::start_raft();
     //todo-whole promethus setpup
     //syscall wala system
     // Build recorder 
@@ -44,6 +103,7 @@ async fn main() {
 
     // Emit a metric
      let handle = recorder.handle();
+    metrics::set_boxed_recorder(Box::new(recorder)).expect("metrics recorder already installed");
        tokio::spawn(async {
         let mut sys = System::new_all();
         loop {
@@ -60,13 +120,45 @@ async fn main() {
 
     // Set up Axum app
     let set_value_routes = Router::new()
-        .route("/set-value", post(set_value))
-        .with_state(tx.clone());
+        .route("/set-value", post(set_value));
     let delete_value_route=Router::new()
-        .route("/delete-value", post(delete_value))
-        .with_state(tx.clone());
+        .route("/delete-value", post(delete_value));
     let other_protected_routes = Router::new()
-        .route("/get-value", post(get_value));
+        .route("/get-value", post(get_value))
+        .route("/ttl", post(get_ttl))
+        .route("/persist", post(persist_value))
+        .route("/incr", post(incr_value))
+        .route("/decr", post(decr_value))
+        .route("/raft/status", get(raft_status))
+        .route("/logout", post(logout_handler));
+    let kv_routes = Router::new()
+        .route("/v1/kv/{key}", get(kv_get).put(kv_put).delete(kv_delete).head(kv_head))
+        .route("/v1/kv/{key}/ttl", get(kv_ttl_get).put(kv_ttl_put).delete(kv_ttl_delete))
+        .route("/v1/kv/{key}/incr", post(kv_incr))
+        .route("/v1/kv/{key}/decr", post(kv_decr))
+        .route("/v1/ns/{namespace}/kv/{key}", get(kv_get).put(kv_put).delete(kv_delete).head(kv_head))
+        .route("/v1/ns/{namespace}/kv/{key}/ttl", get(kv_ttl_get).put(kv_ttl_put).delete(kv_ttl_delete))
+        .route("/v1/ns/{namespace}/kv/{key}/incr", post(kv_incr))
+        .route("/v1/ns/{namespace}/kv/{key}/decr", post(kv_decr))
+        .route("/v1/batch/get", post(batch_get))
+        .route("/v1/batch/set", post(batch_set))
+        .route("/v1/batch/delete", post(batch_delete))
+        .route("/v1/txn", post(transaction))
+        .route("/v1/scan", get(scan))
+        .route("/v1/watch", get(watch));
+    let admin_routes = Router::new()
+        .route("/admin/namespaces", post(create_namespace).get(list_namespaces))
+        .route("/admin/namespaces/{name}", get(get_namespace).delete(drop_namespace))
+        .route("/admin/users", get(list_users).post(create_user))
+        .route("/admin/users/{email}", get(get_user).patch(update_user).delete(delete_user))
+        .route("/admin/users/{email}/unlock", post(unlock_user))
+        .route("/admin/revoke", post(revoke))
+        .route("/admin/api-keys", get(list_api_keys))
+        .route("/admin/api-keys/{id}", get(get_api_key).delete(delete_api_key));
+    // The response holds the new key's secret, which must not be kept on disk for replays
+    let unreplayable_routes = Router::new()
+        .route("/admin/api-keys", post(create_api_key))
+        .layer(from_fn(auth_middlware));
        
        
     
@@ -74,11 +166,18 @@ async fn main() {
         .merge(set_value_routes)
         .merge(delete_value_route)
         .merge(other_protected_routes)
+        .merge(kv_routes)
+        .merge(admin_routes)
+        // Layers run bottom-up: auth first, so idempotency knows the caller
+        .layer(from_fn(idempotency_middleware))
         .layer(from_fn(auth_middlware));
     
     let app = Router::new()
         .merge(protected_routes)
+        .merge(unreplayable_routes)
         .route("/login", post(login_handler))
+        .route("/signup", post(signup))
+        .route("/refresh", post(refresh_handler))
         .route("/metrics", get(move || async move {
            metrics_handle.render().into_response()
         }))
@@ -86,5 +185,42 @@ async fn main() {
 
     // Bind and run server
     let tcp_listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(tcp_listener, app).await.unwrap();
+    let (signal_tx, signal_rx) = oneshot::channel();
+    let server = axum::serve(tcp_listener, app).with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        let _ = signal_tx.send(());
+    });
+    let mut server = tokio::spawn(server.into_future());
+
+    // Wait for a signal (or the server dying on its own), then everything below shares one deadline
+    tokio::select! {
+        result = &mut server => {
+            if let Ok(Err(e)) = result {
+                eprintln!("Server error: {}", e);
+            }
+        }
+        _ = signal_rx => {}
+    }
+    let deadline = Instant::now() + shutdown_timeout();
+
+    // Stop accepting connections and let in-flight requests finish
+    if !server.is_finished() && timeout_at(deadline, &mut server).await.is_err() {
+        eprintln!("In-flight requests did not finish before the shutdown deadline");
+        server.abort();
+    }
+
+    // Let followers catch up to the WAL head; cursors are persisted per entry, so stopping early loses nothing
+    let _ = stop_tx.send(true);
+    if timeout_at(deadline, &mut replication).await.is_err() {
+        eprintln!("Replication did not catch up before the shutdown deadline");
+        replication.abort();
+    }
+    for (node_id, lag) in replication_lag() {
+        if lag > 0 {
+            println!("{} is {} WAL entries behind, resuming on next start", node_id, lag);
+        }
+    }
+
+    flush_storage();
+    println!("Shutdown complete");
 }
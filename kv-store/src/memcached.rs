@@ -0,0 +1,471 @@
+/*
+memcached text protocol listener on its own port (MEMCACHED_PORT, off unless set), so services on
+memcached clients can switch over without code changes. Commands go through the coordinator in the
+default namespace with eventual consistency, and map onto the store's own features:
+
+  get/gets          read, gets also returns the key's version as the cas unique
+  set/add/replace   upsert / create-only / update-only writes
+  cas               write only if the version still matches the cas unique
+  delete, touch     delete, set or clear the expiry
+  incr/decr         unsigned 64-bit arithmetic on a decimal value, as memcached does it
+
+Client flags are kept in the value's content type (unset for flags 0, so HTTP readers see plain
+values). exptime follows memcached: 0 never expires, up to 30 days is relative seconds, anything
+larger a unix timestamp, and negative or past times expire the item at once.
+
+Authentication uses memcached's text-protocol convention: until a client has authenticated, every
+command gets "CLIENT_ERROR unauthenticated" except a `set` of any key whose data is
+"<username> <JWT or API key>", which authenticates the connection instead of storing anything.
+After that its grants apply as over HTTP, and a denied command gets a CLIENT_ERROR naming the
+action. Once the credential expires or is revoked the connection is unauthenticated again.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::io;
+use metrics::{counter, decrement_gauge, increment_gauge};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use crate::coordinator::{self, KvError, KvValue, PutOptions, MAX_BATCH_KEYS};
+use crate::access::{authorize, Action};
+use crate::middleware::types::Claims;
+use crate::middleware::{still_valid, verify_credential};
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::routes_resp::{Condition, Consistency, WriteMode};
+use crate::store::now_ms;
+
+// Same limits as memcached's defaults
+const MAX_LINE_LEN: usize = 2048;
+const MAX_KEY_LEN: usize = 250;
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+// Before authenticating, where the only useful data block is "<username> <credential>", so a client
+// that hasn't logged in can't make the server buffer large values (as the Redis listener does)
+const MAX_UNAUTHENTICATED_VALUE_LEN: usize = 16 * 1024;
+// Larger exptimes are unix timestamps
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+// Content type that carries non-zero client flags
+const FLAGS_CONTENT_TYPE: &str = "application/x-memcached; flags=";
+// incr/decr compare-and-swap attempts before giving up under contention
+const MAX_ARITH_RETRIES: usize = 64;
+
+#[derive(Clone, Copy, PartialEq)]
+enum StoreKind {
+    Set,
+    Add,
+    Replace,
+    Cas,
+}
+
+enum Command {
+    Get { keys: Vec<Vec<u8>>, with_cas: bool },
+    Store { kind: StoreKind, key: Vec<u8>, flags: u32, exptime: i64, cas_unique: Option<u64>, data: Vec<u8> },
+    Delete { key: Vec<u8> },
+    Arith { key: Vec<u8>, delta: u64, incr: bool },
+    Touch { key: Vec<u8>, exptime: i64 },
+    Version,
+    Quit,
+}
+
+// A parsed request; `noreply` suppresses the reply of the commands that take it
+struct Request {
+    name: &'static str,
+    command: Command,
+    noreply: bool,
+}
+
+fn client_error(message: &str) -> Vec<u8> {
+    format!("CLIENT_ERROR {}\r\n", message).into_bytes()
+}
+
+fn server_error(e: KvError) -> Vec<u8> {
+    match e {
+        KvError::QuotaExceeded(_) => b"SERVER_ERROR out of memory storing object\r\n".to_vec(),
+        KvError::BadRequest(message) | KvError::Forbidden(message) => client_error(&message),
+        // Reply lines can't carry line breaks
+        e => format!("SERVER_ERROR {}\r\n", e.to_string().replace(['\r', '\n'], " ")).into_bytes(),
+    }
+}
+
+fn bad_format() -> Vec<u8> {
+    client_error("bad command line format")
+}
+
+// One line without its line ending; None on a clean EOF
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if (&mut *reader).take(MAX_LINE_LEN as u64).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_num<T: std::str::FromStr>(token: &[u8]) -> Option<T> {
+    std::str::from_utf8(token).ok()?.parse::<T>().ok()
+}
+
+fn valid_key(key: &[u8]) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN && !key.iter().any(u8::is_ascii_control)
+}
+
+/*
+The next request. Storage commands read their data block as well; an error reply that still
+leaves the connection in sync comes back as Err, and only I/O problems end the connection. Until
+the connection has authenticated, a data block over MAX_UNAUTHENTICATED_VALUE_LEN ends it too.
+*/
+async fn read_request<R: AsyncBufReadExt + Unpin>(reader: &mut R, authenticated: bool) -> io::Result<Option<Result<Request, Vec<u8>>>> {
+    loop {
+        let Some(line) = read_line(reader).await? else { return Ok(None) };
+        let mut tokens: Vec<&[u8]> = line.split(|b| *b == b' ').filter(|t| !t.is_empty()).collect();
+        let Some(name) = tokens.first().map(|t| String::from_utf8_lossy(t).to_lowercase()) else { continue };
+        let args = tokens.split_off(1);
+        return Ok(Some(match name.as_str() {
+            "set" | "add" | "replace" | "cas" => read_store(reader, &name, args, authenticated).await?,
+            _ => parse_command(&name, args),
+        }));
+    }
+}
+
+// <command> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply], then <bytes> of data and \r\n
+async fn read_store<R: AsyncBufReadExt + Unpin>(reader: &mut R, name: &str, mut args: Vec<&[u8]>, authenticated: bool) -> io::Result<Result<Request, Vec<u8>>> {
+    let (kind, name, fields) = match name {
+        "set" => (StoreKind::Set, "set", 4),
+        "add" => (StoreKind::Add, "add", 4),
+        "replace" => (StoreKind::Replace, "replace", 4),
+        _ => (StoreKind::Cas, "cas", 5),
+    };
+    let noreply = args.len() == fields + 1 && args[fields] == b"noreply";
+    if noreply {
+        args.pop();
+    }
+    if args.len() != fields {
+        return Ok(Err(bad_format()));
+    }
+    // Without a length the data block can't be skipped, so memcached gives up on the line the same way
+    let Some(len) = parse_num::<usize>(args[3]) else { return Ok(Err(bad_format())) };
+    // Refused from the command line alone, before the block is read
+    if !authenticated && len > MAX_UNAUTHENTICATED_VALUE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "data block too large before authentication"));
+    }
+
+    // The data block is always consumed so the next command lines up
+    if len > MAX_VALUE_LEN {
+        tokio::io::copy(&mut (&mut *reader).take(len as u64 + 2), &mut tokio::io::sink()).await?;
+        return Ok(Err(b"SERVER_ERROR object too large for cache\r\n".to_vec()));
+    }
+    let mut data = vec![0; len + 2];
+    reader.read_exact(&mut data).await?;
+    if !data.ends_with(b"\r\n") {
+        return Ok(Err(client_error("bad data chunk")));
+    }
+    data.truncate(len);
+
+    let key = args[0].to_vec();
+    let (Some(flags), Some(exptime)) = (parse_num::<u32>(args[1]), parse_num::<i64>(args[2])) else {
+        return Ok(Err(bad_format()));
+    };
+    let cas_unique = match kind {
+        StoreKind::Cas => match parse_num::<u64>(args[4]) {
+            Some(unique) => Some(unique),
+            None => return Ok(Err(bad_format())),
+        },
+        _ => None,
+    };
+    if !valid_key(&key) {
+        return Ok(Err(bad_format()));
+    }
+    Ok(Ok(Request { name, command: Command::Store { kind, key, flags, exptime, cas_unique, data }, noreply }))
+}
+
+fn parse_command(name: &str, mut args: Vec<&[u8]>) -> Result<Request, Vec<u8>> {
+    let noreply = args.last() == Some(&&b"noreply"[..]) && matches!(name, "delete" | "incr" | "decr" | "touch");
+    if noreply {
+        args.pop();
+    }
+    let key = |arg: &[u8]| if valid_key(arg) { Ok(arg.to_vec()) } else { Err(bad_format()) };
+    let (name, command) = match (name, args.as_slice()) {
+        ("get" | "gets", keys) if !keys.is_empty() => {
+            let keys = keys.iter().map(|k| key(k)).collect::<Result<Vec<_>, _>>()?;
+            let with_cas = name == "gets";
+            (if with_cas { "gets" } else { "get" }, Command::Get { keys, with_cas })
+        }
+        // Old clients send a hold time of 0 after the key
+        ("delete", [k] | [k, b"0"]) => ("delete", Command::Delete { key: key(k)? }),
+        ("incr" | "decr", [k, delta]) => {
+            let delta = parse_num::<u64>(delta).ok_or_else(|| client_error("invalid numeric delta argument"))?;
+            let incr = name == "incr";
+            (if incr { "incr" } else { "decr" }, Command::Arith { key: key(k)?, delta, incr })
+        }
+        ("touch", [k, exptime]) => {
+            let exptime = parse_num::<i64>(exptime).ok_or_else(|| client_error("invalid exptime argument"))?;
+            ("touch", Command::Touch { key: key(k)?, exptime })
+        }
+        ("version", []) => ("version", Command::Version),
+        ("quit", []) => ("quit", Command::Quit),
+        ("get" | "gets" | "delete" | "incr" | "decr" | "touch" | "version" | "quit", _) => return Err(bad_format()),
+        _ => return Err(b"ERROR\r\n".to_vec()),
+    };
+    Ok(Request { name, command, noreply })
+}
+
+// exptime into an absolute expiry in epoch milliseconds
+fn expiry(exptime: i64) -> Option<u64> {
+    let now = now_ms();
+    match exptime {
+        0 => None,
+        // Already expired: the item reads as absent and the sweeper removes it
+        t if t < 0 => Some(now),
+        t if t <= MAX_RELATIVE_EXPTIME => Some(now + t as u64 * 1000),
+        t => Some((t as u64).saturating_mul(1000)),
+    }
+}
+
+fn content_type(flags: u32) -> Option<String> {
+    (flags != 0).then(|| format!("{}{}", FLAGS_CONTENT_TYPE, flags))
+}
+
+fn flags(found: &KvValue) -> u32 {
+    found.meta.content_type.as_deref()
+        .and_then(|content_type| content_type.strip_prefix(FLAGS_CONTENT_TYPE))
+        .and_then(|flags| flags.parse().ok())
+        .unwrap_or(0)
+}
+
+fn check_access(claims: &Claims, command: &Command) -> Result<(), KvError> {
+    match command {
+        Command::Get { keys, .. } => keys.iter().try_for_each(|key| authorize(claims, Action::Read, DEFAULT_NAMESPACE, key)),
+        Command::Store { key, .. } | Command::Arith { key, .. } | Command::Touch { key, .. } => {
+            authorize(claims, Action::Write, DEFAULT_NAMESPACE, key)
+        }
+        Command::Delete { key } => authorize(claims, Action::Delete, DEFAULT_NAMESPACE, key),
+        Command::Version | Command::Quit => Ok(()),
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    // Set by the authenticating set; its grants are checked on every command
+    claims: Option<Claims>,
+}
+
+impl Session {
+    async fn execute(&mut self, request: Request) -> Vec<u8> {
+        counter!("memcached_commands_total", 1, "command" => request.name);
+        // A token that expired or was revoked since it authenticated logs the connection out
+        if self.claims.as_ref().is_some_and(|claims| !still_valid(claims)) {
+            self.claims = None;
+        }
+        let Some(claims) = &self.claims else {
+            return match request.command {
+                Command::Store { kind: StoreKind::Set, data, .. } => self.auth(&data),
+                Command::Version => version(),
+                _ => client_error("unauthenticated"),
+            };
+        };
+        if let Err(e) = check_access(claims, &request.command) {
+            return server_error(e);
+        }
+        match request.command {
+            Command::Get { keys, with_cas } => get(keys, with_cas).await,
+            Command::Store { kind, key, flags, exptime, cas_unique, data } => store(kind, key, flags, exptime, cas_unique, data).await,
+            Command::Delete { key } => delete(key).await,
+            Command::Arith { key, delta, incr } => arith(key, delta, incr).await,
+            Command::Touch { key, exptime } => touch(key, exptime).await,
+            Command::Version => version(),
+            Command::Quit => Vec::new(),
+        }
+    }
+
+    // The data of the authenticating set is "<username> <password>"; the password is a JWT or an API key
+    fn auth(&mut self, data: &[u8]) -> Vec<u8> {
+        let token = std::str::from_utf8(data).ok()
+            .and_then(|credentials| credentials.split_once(' '))
+            .map(|(_, token)| token.trim());
+        match token.and_then(verify_credential) {
+            Some(claims) => {
+                self.claims = Some(claims);
+                b"STORED\r\n".to_vec()
+            }
+            None => client_error("authentication failure"),
+        }
+    }
+}
+
+fn version() -> Vec<u8> {
+    format!("VERSION kv-store {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes()
+}
+
+// VALUE <key> <flags> <bytes> [<cas unique>] per hit, missing keys are left out, then END
+async fn get(keys: Vec<Vec<u8>>, with_cas: bool) -> Vec<u8> {
+    let mut out = Vec::new();
+    for chunk in keys.chunks(MAX_BATCH_KEYS) {
+        let results = match coordinator::batch_get(DEFAULT_NAMESPACE, chunk.to_vec(), Consistency::Eventual).await {
+            Ok(results) => results,
+            Err(e) => return server_error(e),
+        };
+        for (key, result) in chunk.iter().zip(results) {
+            let found = match result {
+                Ok(found) => found,
+                Err(KvError::NotFound) => continue,
+                Err(e) => return server_error(e),
+            };
+            out.extend_from_slice(b"VALUE ");
+            out.extend_from_slice(key);
+            out.extend_from_slice(format!(" {} {}", flags(&found), found.value.len()).as_bytes());
+            if with_cas {
+                out.extend_from_slice(format!(" {}", found.meta.version).as_bytes());
+            }
+            out.extend_from_slice(b"\r\n");
+            out.extend_from_slice(&found.value);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+    out.extend_from_slice(b"END\r\n");
+    out
+}
+
+async fn store(kind: StoreKind, key: Vec<u8>, flags: u32, exptime: i64, cas_unique: Option<u64>, data: Vec<u8>) -> Vec<u8> {
+    let mode = match kind {
+        StoreKind::Add => WriteMode::CreateOnly,
+        StoreKind::Replace => WriteMode::UpdateOnly,
+        StoreKind::Set | StoreKind::Cas => WriteMode::Upsert,
+    };
+    let options = PutOptions {
+        content_type: content_type(flags),
+        mode,
+        condition: cas_unique.map(Condition::Version),
+        expires_at: expiry(exptime),
+    };
+    match coordinator::put(DEFAULT_NAMESPACE, &key, data, options, Consistency::Eventual).await {
+        Ok(_) => b"STORED\r\n".to_vec(),
+        Err(KvError::Conflict(_) | KvError::NotFound) if kind != StoreKind::Cas => b"NOT_STORED\r\n".to_vec(),
+        // A failed condition is either a changed item or a missing one
+        Err(KvError::PreconditionFailed(_)) => match coordinator::get(DEFAULT_NAMESPACE, &key, Consistency::Eventual).await {
+            Err(KvError::NotFound) => b"NOT_FOUND\r\n".to_vec(),
+            _ => b"EXISTS\r\n".to_vec(),
+        },
+        Err(e) => server_error(e),
+    }
+}
+
+async fn delete(key: Vec<u8>) -> Vec<u8> {
+    match coordinator::delete(DEFAULT_NAMESPACE, &key, None, Consistency::Eventual).await {
+        Ok(()) => b"DELETED\r\n".to_vec(),
+        Err(KvError::NotFound) => b"NOT_FOUND\r\n".to_vec(),
+        Err(e) => server_error(e),
+    }
+}
+
+/*
+memcached counters are unsigned 64-bit: incr wraps around, decr stops at 0, and a missing key is
+NOT_FOUND rather than a new counter. coordinator::incr is signed and creates missing keys, so this
+is a read followed by a write conditional on the version that was read, retried if another write
+got in between. Flags and expiry stay as they were.
+*/
+async fn arith(key: Vec<u8>, delta: u64, incr: bool) -> Vec<u8> {
+    for _ in 0..MAX_ARITH_RETRIES {
+        let found = match coordinator::get(DEFAULT_NAMESPACE, &key, Consistency::Eventual).await {
+            Ok(found) => found,
+            Err(KvError::NotFound) => return b"NOT_FOUND\r\n".to_vec(),
+            Err(e) => return server_error(e),
+        };
+        let Some(current) = parse_num::<u64>(&found.value) else {
+            return client_error("cannot increment or decrement non-numeric value");
+        };
+        let next = if incr { current.wrapping_add(delta) } else { current.saturating_sub(delta) };
+        let options = PutOptions {
+            content_type: found.meta.content_type,
+            mode: WriteMode::UpdateOnly,
+            condition: Some(Condition::Version(found.meta.version)),
+            expires_at: found.meta.expires_at,
+        };
+        match coordinator::put(DEFAULT_NAMESPACE, &key, next.to_string().into_bytes(), options, Consistency::Eventual).await {
+            Ok(_) => return format!("{}\r\n", next).into_bytes(),
+            // Changed or deleted since the read; the next read tells which
+            Err(KvError::PreconditionFailed(_) | KvError::NotFound) => continue,
+            Err(e) => return server_error(e),
+        }
+    }
+    b"SERVER_ERROR too much contention on key\r\n".to_vec()
+}
+
+async fn touch(key: Vec<u8>, exptime: i64) -> Vec<u8> {
+    match coordinator::expire(DEFAULT_NAMESPACE, &key, expiry(exptime), Consistency::Eventual).await {
+        Ok(()) => b"TOUCHED\r\n".to_vec(),
+        Err(KvError::NotFound) => b"NOT_FOUND\r\n".to_vec(),
+        Err(e) => server_error(e),
+    }
+}
+
+// Replies are buffered and flushed once the client has no more pipelined commands waiting
+async fn serve_connection(stream: TcpStream, mut stop: watch::Receiver<bool>) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session::default();
+    loop {
+        let request = tokio::select! {
+            request = read_request(&mut reader, session.claims.is_some()) => request,
+            _ = stop.changed() => return Ok(()),
+        };
+        let reply = match request {
+            Ok(Some(Ok(request))) if matches!(request.command, Command::Quit) => return writer.flush().await,
+            Ok(Some(Ok(request))) => {
+                let noreply = request.noreply;
+                let reply = session.execute(request).await;
+                if noreply { Vec::new() } else { reply }
+            }
+            Ok(Some(Err(reply))) => reply,
+            Ok(None) => return writer.flush().await,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                writer.write_all(&client_error(&e.to_string())).await?;
+                return writer.flush().await;
+            }
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&reply).await?;
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+// Accepts connections until `stop` is set
+pub async fn memcached_listener(port: u16, stop: watch::Receiver<bool>) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("memcached listener could not bind port {}: {}", port, e);
+            return;
+        }
+    };
+    println!("memcached protocol listening on port {}", port);
+    let mut stopping = stop.clone();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("memcached accept failed: {}", e);
+                    continue;
+                }
+            },
+            _ = stopping.changed() => return,
+        };
+        stream.set_nodelay(true).ok();
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            increment_gauge!("memcached_connections", 1.0);
+            if let Err(e) = serve_connection(stream, stop).await {
+                eprintln!("memcached connection {} failed: {}", peer, e);
+            }
+            decrement_gauge!("memcached_connections", 1.0);
+        });
+    }
+}
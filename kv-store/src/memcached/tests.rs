@@ -0,0 +1,157 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use crate::access::{Action, Grant};
+use crate::middleware::types::Claims;
+use crate::store::now_ms;
+use crate::tokens::revoke_token;
+use crate::users::Role;
+use super::{
+    check_access, expiry, read_request, serve_connection, Command, Request, Session, StoreKind, MAX_RELATIVE_EXPTIME,
+    MAX_UNAUTHENTICATED_VALUE_LEN, MAX_VALUE_LEN,
+};
+
+// Every request in the input as an authenticated connection reads it, each parsed or as its error reply
+async fn read_all(mut input: &[u8]) -> Vec<Result<Request, Vec<u8>>> {
+    let mut requests = Vec::new();
+    while let Some(request) = read_request(&mut input, true).await.unwrap() {
+        requests.push(request);
+    }
+    requests
+}
+
+fn reply(result: &Result<Request, Vec<u8>>) -> &str {
+    match result {
+        Ok(request) => panic!("parsed a {} request", request.name),
+        Err(reply) => std::str::from_utf8(reply).unwrap(),
+    }
+}
+
+#[tokio::test]
+async fn frames_storage_commands_with_their_data_block() {
+    let requests = read_all(b"set k 5 0 7\r\nab\r\ncd!\r\ncas k 0 0 1 42 noreply\r\nx\r\nget a b\r\n").await;
+    assert_eq!(requests.len(), 3);
+    match &requests[0] {
+        Ok(Request { command: Command::Store { kind: StoreKind::Set, key, flags: 5, data, cas_unique: None, .. }, noreply: false, .. }) => {
+            assert_eq!(key, b"k");
+            // Data blocks are binary safe, line breaks included
+            assert_eq!(data, b"ab\r\ncd!");
+        }
+        _ => panic!("set not parsed"),
+    }
+    assert!(matches!(&requests[1], Ok(Request { command: Command::Store { kind: StoreKind::Cas, cas_unique: Some(42), .. }, noreply: true, .. })));
+    assert!(matches!(&requests[2], Ok(Request { command: Command::Get { keys, with_cas: false }, .. }) if keys.len() == 2));
+}
+
+#[tokio::test]
+async fn stays_in_sync_after_bad_data_blocks() {
+    // Wrong length, then a value over the limit: both are consumed and the next command still parses
+    let mut input = b"set k 0 0 2\r\nabc\r\n".to_vec();
+    input.extend_from_slice(format!("set big 0 0 {}\r\n", MAX_VALUE_LEN + 1).as_bytes());
+    input.extend(std::iter::repeat_n(b'x', MAX_VALUE_LEN + 1));
+    input.extend_from_slice(b"\r\nversion\r\n");
+    let requests = read_all(&input).await;
+    assert_eq!(reply(&requests[0]), "CLIENT_ERROR bad data chunk\r\n");
+    // The short block's leftover "\n" reads as an empty line and is skipped
+    assert_eq!(reply(&requests[1]), "SERVER_ERROR object too large for cache\r\n");
+    assert!(matches!(requests[2], Ok(Request { command: Command::Version, .. })));
+    assert_eq!(requests.len(), 3);
+}
+
+#[tokio::test]
+async fn caps_data_blocks_until_authenticated() {
+    let set = |len: usize| {
+        let mut input = format!("set k 0 0 {}\r\n", len).into_bytes();
+        input.extend(std::iter::repeat_n(b'x', len));
+        input.extend_from_slice(b"\r\n");
+        input
+    };
+    let small = set(MAX_UNAUTHENTICATED_VALUE_LEN);
+    assert!(matches!(read_request(&mut &small[..], false).await, Ok(Some(Ok(_)))));
+    let large = set(MAX_UNAUTHENTICATED_VALUE_LEN + 1);
+    let e = read_request(&mut &large[..], false).await.err().expect("a large block was read before auth");
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    assert!(matches!(read_request(&mut &large[..], true).await, Ok(Some(Ok(_)))));
+
+    // Over a connection the header alone gets an error and the connection closes
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let (_stop_tx, stop) = watch::channel(false);
+    let server = tokio::spawn(serve_connection(stream, stop));
+    client.write_all(format!("set k 0 0 {}\r\n", MAX_UNAUTHENTICATED_VALUE_LEN + 1).as_bytes()).await.unwrap();
+    let mut replies = String::new();
+    client.read_to_string(&mut replies).await.unwrap();
+    assert_eq!(replies, "CLIENT_ERROR data block too large before authentication\r\n");
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn rejects_bad_command_lines() {
+    let requests = read_all(b"bogus\r\nget\r\nset k x 0 1\r\nv\r\nincr k -1\r\ndelete k 5\r\n").await;
+    let replies: Vec<&str> = requests.iter().map(reply).collect();
+    assert_eq!(replies, [
+        "ERROR\r\n",
+        "CLIENT_ERROR bad command line format\r\n",
+        "CLIENT_ERROR bad command line format\r\n",
+        "CLIENT_ERROR invalid numeric delta argument\r\n",
+        "CLIENT_ERROR bad command line format\r\n",
+    ]);
+}
+
+#[test]
+fn maps_exptime_like_memcached() {
+    let before = now_ms();
+    assert_eq!(expiry(0), None);
+    assert!(expiry(-1).is_some_and(|at| at >= before && at <= now_ms()));
+    assert!(expiry(60).is_some_and(|at| at >= before + 60_000 && at <= now_ms() + 60_000));
+    // Thirty days is still relative; one second more is a unix timestamp
+    assert!(expiry(MAX_RELATIVE_EXPTIME).is_some_and(|at| at >= before + MAX_RELATIVE_EXPTIME as u64 * 1000));
+    assert_eq!(expiry(MAX_RELATIVE_EXPTIME + 1), Some((MAX_RELATIVE_EXPTIME as u64 + 1) * 1000));
+    assert_eq!(expiry(1_700_000_000), Some(1_700_000_000_000));
+}
+
+#[tokio::test]
+async fn refuses_commands_before_auth() {
+    let mut session = Session::default();
+    for request in read_all(b"get k\r\ndelete k\r\nincr k 1\r\nadd k 0 0 1\r\nv\r\n").await {
+        assert_eq!(session.execute(request.unwrap()).await, b"CLIENT_ERROR unauthenticated\r\n");
+    }
+    let auth = read_all(b"set auth 0 0 16\r\nuser not-a-token\r\n").await.pop().unwrap().unwrap();
+    assert_eq!(session.execute(auth).await, b"CLIENT_ERROR authentication failure\r\n");
+    assert!(session.claims.is_none());
+    let version = read_all(b"version\r\n").await.pop().unwrap().unwrap();
+    assert!(session.execute(version).await.starts_with(b"VERSION "));
+}
+
+#[tokio::test]
+async fn an_expired_or_revoked_login_ends_with_the_next_command() {
+    let jti = format!("jti-{:016x}", rand::random::<u64>());
+    revoke_token(&jti).unwrap();
+    let expired = Claims { email: "admin@x".to_string(), role: Role::Admin, grants: Vec::new(), exp: 1, jti: "jti".to_string(), sid: "sid".to_string() };
+    let revoked = Claims { exp: usize::MAX, jti, ..expired.clone() };
+    for claims in [expired, revoked] {
+        let mut session = Session { claims: Some(claims) };
+        let get = read_all(b"get k\r\n").await.pop().unwrap().unwrap();
+        assert_eq!(session.execute(get).await, b"CLIENT_ERROR unauthenticated\r\n");
+        assert!(session.claims.is_none());
+    }
+}
+
+#[test]
+fn checks_grants_per_command() {
+    let claims = Claims {
+        email: "reader@x".to_string(),
+        role: Role::User,
+        grants: vec![Grant { namespace: "default".to_string(), prefix: "public:".to_string(), actions: vec![Action::Read] }],
+        exp: usize::MAX,
+        jti: "jti".to_string(),
+        sid: "sid".to_string(),
+    };
+    let get = |keys: &[&str]| Command::Get { keys: keys.iter().map(|k| k.as_bytes().to_vec()).collect(), with_cas: false };
+    assert!(check_access(&claims, &get(&["public:a", "public:b"])).is_ok());
+    // One key outside the grant fails the whole get
+    assert!(check_access(&claims, &get(&["public:a", "private:b"])).is_err());
+    assert!(check_access(&claims, &Command::Delete { key: b"public:a".to_vec() }).is_err());
+    assert!(check_access(&claims, &Command::Version).is_ok());
+}
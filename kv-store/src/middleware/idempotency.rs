@@ -0,0 +1,195 @@
+/*
+Idempotency-Key support for writes. A request carrying the header runs once; its response is
+stored, and a retry with the same key gets that stored response back (marked with
+Idempotent-Replayed: true) instead of running again. That makes retrying an INCR or a CAS after a
+timeout safe.
+
+Records live in a sled tree on the ring node the key hashes to, so they survive a restart, and
+they are kept for config::idempotency_window(). Keys are per caller (the token's email). A key
+reused with a different method, path or body is refused with 422, and a retry arriving while the
+first request still runs gets 409. Responses with a 5xx status are not stored: nothing reliable
+happened, so the retry runs again. The same goes for responses marked with `ServerFailure`, which
+routes that answer 200 with a status of "error" use when the failure was on our side.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use axum::body::{to_bytes, Body};
+use axum::extract::Request;
+use axum::http::{HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use metrics::counter;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::config::{idempotency_window, HASH_RING};
+use crate::coordinator::KvError;
+use crate::routes_resp::{ErrorResponse, Status};
+use crate::store::now_ms;
+use super::types::Claims;
+
+pub const IDEMPOTENCY_TREE: &str = "__idempotency";
+const MAX_KEY_LEN: usize = 255;
+// Same as axum's default limit for the JSON extractor
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+struct Recorded {
+    // sha256 over method, path and body
+    fingerprint: Vec<u8>,
+    stored_at: u64,
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Vec<u8>,
+}
+
+// Response extension for a server-side failure reported under a non-5xx status
+#[derive(Clone, Copy)]
+pub struct ServerFailure;
+
+// Marks `response` as not worth storing when `error` was our fault rather than the caller's
+pub fn mark_failure(mut response: Response, error: &KvError) -> Response {
+    if error.status_code().is_server_error() {
+        response.extensions_mut().insert(ServerFailure);
+    }
+    response
+}
+
+// Keys whose first request is still running
+static IN_FLIGHT: Lazy<Mutex<HashSet<Vec<u8>>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+// Releases the key even if the request is dropped halfway
+struct InFlight(Vec<u8>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.lock().unwrap().remove(&self.0);
+    }
+}
+
+fn tree_for(key: &[u8]) -> Result<sled::Tree, KvError> {
+    let ring = HASH_RING.read().unwrap();
+    let node = ring.get_node(key).ok_or_else(|| KvError::NoQuorum("No node available".to_string()))?;
+    Ok(node.db.open_tree(IDEMPOTENCY_TREE)?)
+}
+
+fn lookup(key: &[u8]) -> Result<Option<Recorded>, KvError> {
+    let Some(raw) = tree_for(key)?.get(key)? else { return Ok(None) };
+    let recorded: Recorded = bincode::deserialize(&raw)
+        .map_err(|e| KvError::Internal(format!("corrupt idempotency record: {}", e)))?;
+    let window = idempotency_window().as_millis() as u64;
+    Ok((recorded.stored_at + window > now_ms()).then_some(recorded))
+}
+
+fn remember(key: &[u8], recorded: &Recorded) -> Result<(), KvError> {
+    let tree = tree_for(key)?;
+    let encoded = bincode::serialize(recorded).expect("idempotency records are always serializable");
+    tree.insert(key, encoded)?;
+    tree.flush()?;
+    Ok(())
+}
+
+fn replay(recorded: Recorded) -> Response {
+    let mut response = Response::new(Body::from(recorded.body));
+    *response.status_mut() = StatusCode::from_u16(recorded.status).unwrap_or(StatusCode::OK);
+    for (name, value) in recorded.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::from_bytes(&value)) {
+            response.headers_mut().append(name, value);
+        }
+    }
+    response.headers_mut().insert("idempotent-replayed", HeaderValue::from_static("true"));
+    response
+}
+
+fn unprocessable(error: &str) -> Response {
+    let body = ErrorResponse { status: Status::Error, error: error.to_string() };
+    (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response()
+}
+
+// Runs after auth_middlware, which leaves the caller's Claims in the request extensions
+pub async fn idempotency_middleware(req: Request, next: Next) -> Response {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return next.run(req).await;
+    }
+    let Some(header) = req.headers().get("idempotency-key") else {
+        return next.run(req).await;
+    };
+    let idempotency_key = match header.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key.to_string(),
+        _ => return KvError::BadRequest(format!("Idempotency-Key must be 1-{} visible ASCII characters", MAX_KEY_LEN)).into_response(),
+    };
+    let caller = req.extensions().get::<Claims>().map(|claims| claims.email.clone()).unwrap_or_default();
+    let key = [caller.as_bytes(), &[0], idempotency_key.as_bytes()].concat();
+
+    let (parts, body) = req.into_parts();
+    let Ok(body) = to_bytes(body, MAX_BODY).await else {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "request body too large").into_response();
+    };
+    let mut hasher = Sha256::new();
+    hasher.update(parts.method.as_str());
+    hasher.update([0]);
+    hasher.update(parts.uri.to_string());
+    hasher.update([0]);
+    hasher.update(&body);
+    let fingerprint = hasher.finalize().to_vec();
+
+    if !IN_FLIGHT.lock().unwrap().insert(key.clone()) {
+        return KvError::Conflict("a request with this Idempotency-Key is still in progress".to_string()).into_response();
+    }
+    let _in_flight = InFlight(key.clone());
+    match lookup(&key) {
+        Ok(Some(recorded)) if recorded.fingerprint != fingerprint => {
+            return unprocessable("Idempotency-Key was already used for a different request");
+        }
+        Ok(Some(recorded)) => {
+            counter!("idempotency_replays_total", 1);
+            return replay(recorded);
+        }
+        Ok(None) => {}
+        Err(e) => return e.into_response(),
+    }
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if response.status().is_server_error() || response.extensions().get::<ServerFailure>().is_some() {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(e) => return KvError::Internal(format!("failed to buffer response: {}", e)).into_response(),
+    };
+    let recorded = Recorded {
+        fingerprint,
+        stored_at: now_ms(),
+        status: parts.status.as_u16(),
+        headers: parts.headers.iter().map(|(name, value)| (name.to_string(), value.as_bytes().to_vec())).collect(),
+        body: body.to_vec(),
+    };
+    // The write already happened; failing to remember it only loses the protection for retries
+    if let Err(e) = remember(&key, &recorded) {
+        eprintln!("Failed to store the outcome for an Idempotency-Key: {}", e);
+    }
+    Response::from_parts(parts, Body::from(body))
+}
+
+// Drop records older than the window on every node; returns how many went
+pub fn purge_expired() -> usize {
+    let cutoff = now_ms().saturating_sub(idempotency_window().as_millis() as u64);
+    let ring = HASH_RING.read().unwrap();
+    let mut purged = 0;
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        let Ok(tree) = node.db.open_tree(IDEMPOTENCY_TREE) else { continue };
+        for (key, raw) in tree.iter().flatten() {
+            let expired = bincode::deserialize::<Recorded>(&raw).map_or(true, |recorded| recorded.stored_at <= cutoff);
+            if expired && tree.remove(key).is_ok() {
+                purged += 1;
+            }
+        }
+    }
+    purged
+}
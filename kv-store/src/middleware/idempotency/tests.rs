@@ -0,0 +1,121 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use axum::body::{to_bytes, Body};
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::from_fn;
+use axum::routing::post;
+use axum::{Extension, Router};
+use tower::ServiceExt;
+use crate::middleware::types::Claims;
+use crate::namespace::{self, Namespace};
+use crate::routes::set_value;
+use crate::users::Role;
+use super::idempotency_middleware;
+
+// Counts how often the handler really ran; a body of "fail" gets a 500
+fn app(runs: Arc<AtomicUsize>) -> Router {
+    async fn handler(State(runs): State<Arc<AtomicUsize>>, body: String) -> (StatusCode, String) {
+        let run = runs.fetch_add(1, Ordering::SeqCst) + 1;
+        let status = if body == "fail" { StatusCode::INTERNAL_SERVER_ERROR } else { StatusCode::CREATED };
+        (status, format!("run {} of {}", run, body))
+    }
+    Router::new()
+        .route("/v1/kv/{key}", post(handler))
+        .layer(from_fn(idempotency_middleware))
+        .with_state(runs)
+}
+
+// The legacy /set-value route, called by an admin
+fn legacy_app() -> Router {
+    let claims = Claims { email: "admin@x".to_string(), role: Role::Admin, grants: Vec::new(), exp: usize::MAX, jti: "jti".to_string(), sid: "sid".to_string() };
+    Router::new()
+        .route("/set-value", post(set_value))
+        .layer(from_fn(idempotency_middleware))
+        .layer(Extension(claims))
+}
+
+async fn send(app: &Router, path: &str, key: &str, body: &str) -> (StatusCode, bool, String) {
+    let request = Request::post(path)
+        .header("idempotency-key", key)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string())).unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    let replayed = response.headers().get("idempotent-replayed").is_some();
+    let status = response.status();
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, replayed, String::from_utf8(body.to_vec()).unwrap())
+}
+
+fn unique_key() -> String {
+    format!("test-{:016x}", rand::random::<u64>())
+}
+
+#[tokio::test]
+async fn replays_the_first_outcome() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let app = app(runs.clone());
+    let key = unique_key();
+    let first = send(&app, "/v1/kv/a", &key, "one").await;
+    assert_eq!(first, (StatusCode::CREATED, false, "run 1 of one".to_string()));
+    let retry = send(&app, "/v1/kv/a", &key, "one").await;
+    assert_eq!(retry, (StatusCode::CREATED, true, "run 1 of one".to_string()));
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn refuses_a_key_reused_for_another_request() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let app = app(runs.clone());
+    let key = unique_key();
+    send(&app, "/v1/kv/a", &key, "one").await;
+    let other_body = send(&app, "/v1/kv/a", &key, "two").await;
+    assert_eq!(other_body.0, StatusCode::UNPROCESSABLE_ENTITY);
+    let other_path = send(&app, "/v1/kv/b", &key, "one").await;
+    assert_eq!(other_path.0, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn reruns_after_a_server_error() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let app = app(runs.clone());
+    let key = unique_key();
+    assert_eq!(send(&app, "/v1/kv/a", &key, "fail").await.0, StatusCode::INTERNAL_SERVER_ERROR);
+    let retry = send(&app, "/v1/kv/a", &key, "fail").await;
+    assert_eq!((retry.0, retry.1), (StatusCode::INTERNAL_SERVER_ERROR, false));
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn rejects_malformed_keys() {
+    let app = app(Arc::new(AtomicUsize::new(0)));
+    let too_long = "k".repeat(256);
+    assert_eq!(send(&app, "/v1/kv/a", &too_long, "one").await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(send(&app, "/v1/kv/a", "", "one").await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn reruns_a_legacy_error_that_was_not_the_callers_fault() {
+    // Any value breaks this quota, so the write fails on our side before anything reaches the WAL
+    let name = format!("idem-{:016x}", rand::random::<u64>());
+    let full: Namespace = serde_json::from_value(serde_json::json!({ "name": name, "quota": { "max_bytes": 1 } })).unwrap();
+    namespace::create(full).unwrap();
+    let app = legacy_app();
+    let key = unique_key();
+    let body = serde_json::json!({ "key": "k", "value": "too long", "namespace": name }).to_string();
+    let first = send(&app, "/set-value", &key, &body).await;
+    assert_eq!((first.0, first.1), (StatusCode::OK, false));
+    assert!(first.2.contains("limited to 1 bytes"), "{}", first.2);
+    let retry = send(&app, "/set-value", &key, &body).await;
+    assert_eq!((retry.0, retry.1), (StatusCode::OK, false));
+
+    // A precondition the caller got wrong is their answer to keep
+    let key = unique_key();
+    let missing = format!("missing-{:016x}", rand::random::<u64>());
+    let body = serde_json::json!({ "key": missing, "value": "v", "mode": "update_only" }).to_string();
+    let first = send(&app, "/set-value", &key, &body).await;
+    assert!(first.2.contains("key not present"), "{}", first.2);
+    let retry = send(&app, "/set-value", &key, &body).await;
+    assert_eq!((retry.1, retry.2), (true, first.2));
+}
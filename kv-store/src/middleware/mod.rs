@@ -1,44 +1,69 @@
 use axum::{
-    body::Body, extract::Request, http::StatusCode, middleware::Next, response::{Json, Response}
+    body::Body, extract::Request, http::StatusCode, middleware::Next, response::Response
 };
 use dotenv::dotenv;
 use std::env;
+pub mod idempotency;
 pub mod types;
 
 use jsonwebtoken::{
  decode,Validation,DecodingKey
 };
 use types::Claims;
+use crate::{api_keys, tokens};
+use crate::store::now_ms;
 
-pub async fn auth_middlware(req:Request<Body>,next:Next)->Result<Response,StatusCode>{
+// Claims of a valid, unexpired, unrevoked token; shared by the HTTP middleware and the other listeners
+pub fn verify_token(token: &str) -> Option<Claims> {
+    dotenv().ok();
+    let secret = env::var("JWT_SECRATE").expect("value not loading");
+    decode::<Claims>(token, &DecodingKey::from_secret(secret.as_bytes()), &Validation::default())
+        .ok()
+        .map(|data| data.claims)
+        .filter(|claims| !tokens::is_revoked(claims))
+}
+
+// Whether Claims a connection authenticated with earlier still hold: not expired, not revoked and,
+// for an API key, not deleted since. Listeners that keep a login for the life of a connection
+// check this before every command.
+pub fn still_valid(claims: &Claims) -> bool {
+    claims.exp as u64 > now_ms() / 1000 && !tokens::is_revoked(claims) && !api_keys::is_deleted(claims)
+}
+
+// A JWT or an API key (see api_keys.rs), for listeners that take a bare password
+pub fn verify_credential(secret: &str) -> Option<Claims> {
+    if secret.starts_with(api_keys::KEY_PREFIX) {
+        return api_keys::verify(secret);
+    }
+    verify_token(secret)
+}
+
+// Claims for an Authorization value: "Bearer <JWT>" or "ApiKey <key>"
+pub fn verify_authorization(value: &str) -> Option<Claims> {
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return verify_token(token);
+    }
+    value.strip_prefix("ApiKey ").and_then(api_keys::verify)
+}
+
+pub async fn auth_middlware(mut req:Request<Body>,next:Next)->Result<Response,StatusCode>{
 
 
-    dotenv().ok();
   let header=req.headers();
-  if let Some(auth_header) =header.get("Authorization") {
-    if let Ok(auth_str)=auth_header.to_str(){
-    if auth_str.starts_with("Bearer ") {
-        let token = &auth_str[7..];
-        let secret = env::var("JWT_SECRATE").expect("value not loading");
-        let decode_result = decode::<Claims>(
-          token,
-          &DecodingKey::from_secret(secret.as_bytes()),
-          &Validation::default(),
-        );
-
-        match decode_result {
-          Ok(token_data) => {
-            // Token is valid
+  if let Some(auth_header) = header.get("Authorization")
+    && let Ok(auth_str) = auth_header.to_str() {
+        match verify_authorization(auth_str) {
+          Some(claims) => {
+            // Token or API key is valid; later layers and handlers can see who is calling
+            req.extensions_mut().insert(claims);
             let response = next.run(req).await;
             return Ok(response);
           }
-          Err(_) => {
+          None => {
             // Token is invalid or tampered
             return Err(StatusCode::UNAUTHORIZED);
           }
         }
-    }
-    }
   }
   Err(StatusCode::UNAUTHORIZED)
 }
@@ -1,8 +1,19 @@
 use serde::{Deserialize,Serialize};
+use crate::access::Grant;
+use crate::users::Role;
 
 
-#[derive(Deserialize,Serialize)]
+#[derive(Clone,Deserialize,Serialize)]
 pub struct Claims{
    pub email:String,
-   pub exp:usize
+   // Tokens from before accounts had roles are plain users
+   #[serde(default)]
+   pub role:Role,
+   // What a non-admin may do, see access.rs
+   #[serde(default)]
+   pub grants:Vec<Grant>,
+   pub exp:usize,
+   // This token's id, and its login session's; either can be revoked (see tokens.rs)
+   pub jti:String,
+   pub sid:String
 }
\ No newline at end of file
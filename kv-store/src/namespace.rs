@@ -0,0 +1,256 @@
+/*
+Namespaces (buckets). Each one is a separate pair of sled trees on every node (see store::trees)
+with its own settings: replication factor, minimum consistency, default TTL, conflict resolution
+and quota. The "default" namespace always exists, keeps the node's default tree and can't be
+dropped. Definitions are stored as JSON in every node's __namespaces tree and cached here.
+*/
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use metrics::gauge;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use crate::config::HASH_RING;
+use crate::coordinator::KvError;
+use crate::hashring::DEFAULT_REPLICATION_FACTOR;
+use crate::routes_resp::{Consistency, WalOp, WriteMode};
+use crate::store;
+
+pub const DEFAULT_NAMESPACE: &str = "default";
+const NAMESPACES_TREE: &str = "__namespaces";
+const MAX_NAME_LEN: usize = 64;
+
+// What a plain (unconditional) upsert does to a key that already exists
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictResolution {
+    // The later write replaces the value
+    #[default]
+    LastWriteWins,
+    // The first value sticks, later plain writes get a 409; explicit update_only / CAS still work
+    FirstWriteWins,
+}
+
+// Limits over the live keys of a namespace; None means unlimited
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default)]
+pub struct Quota {
+    #[serde(default)]
+    pub max_keys: Option<u64>,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default)]
+pub struct Usage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct Namespace {
+    pub name: String,
+    #[serde(default = "default_replication_factor")]
+    pub replication_factor: usize,
+    // Requests asking for less get this instead
+    #[serde(default)]
+    pub consistency: Consistency,
+    // Applied to writes that don't set ttl_seconds / expires_at themselves
+    #[serde(default)]
+    pub default_ttl_seconds: Option<u64>,
+    #[serde(default)]
+    pub conflict_resolution: ConflictResolution,
+    #[serde(default)]
+    pub quota: Quota,
+}
+
+fn default_replication_factor() -> usize {
+    DEFAULT_REPLICATION_FACTOR
+}
+
+impl Namespace {
+    fn default_namespace() -> Self {
+        Namespace {
+            name: DEFAULT_NAMESPACE.to_string(),
+            replication_factor: DEFAULT_REPLICATION_FACTOR,
+            consistency: Consistency::Eventual,
+            default_ttl_seconds: None,
+            conflict_resolution: ConflictResolution::LastWriteWins,
+            quota: Quota::default(),
+        }
+    }
+
+    // The request's consistency, raised to the namespace minimum
+    pub fn consistency(&self, requested: Consistency) -> Consistency {
+        requested.max(self.consistency)
+    }
+
+    // Fold the namespace's conflict resolution into a write
+    pub fn resolve(&self, op: WalOp) -> WalOp {
+        match op {
+            WalOp::Set { namespace, key, value, content_type, mode: WriteMode::Upsert, condition: None, expires_at }
+                if self.conflict_resolution == ConflictResolution::FirstWriteWins => {
+                WalOp::Set { namespace, key, value, content_type, mode: WriteMode::CreateOnly, condition: None, expires_at }
+            }
+            op => op,
+        }
+    }
+
+    // Approximate: usage is refreshed in the background, plus what this process created since.
+    // Only a new key counts against max_keys.
+    pub fn check_quota(&self, value_len: usize, new_key: bool) -> Result<(), KvError> {
+        let usage = usage(&self.name);
+        if let Some(max) = self.quota.max_keys
+            && new_key && usage.keys >= max {
+            return Err(KvError::QuotaExceeded(format!("namespace '{}' is limited to {} keys", self.name, max)));
+        }
+        if let Some(max) = self.quota.max_bytes
+            && usage.bytes + value_len as u64 > max {
+            return Err(KvError::QuotaExceeded(format!("namespace '{}' is limited to {} bytes", self.name, max)));
+        }
+        Ok(())
+    }
+}
+
+static NAMESPACES: Lazy<RwLock<BTreeMap<String, Namespace>>> = Lazy::new(|| {
+    let mut namespaces = BTreeMap::new();
+    namespaces.insert(DEFAULT_NAMESPACE.to_string(), Namespace::default_namespace());
+    RwLock::new(namespaces)
+});
+
+static USAGE: Lazy<RwLock<BTreeMap<String, Usage>>> = Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+// Read the definitions back from disk (call once at startup); any node's copy will do
+pub fn load_namespaces() {
+    let ring = HASH_RING.read().unwrap();
+    let mut namespaces = NAMESPACES.write().unwrap();
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        let Ok(tree) = node.db.open_tree(NAMESPACES_TREE) else { continue };
+        for (name, definition) in tree.iter().flatten() {
+            match serde_json::from_slice::<Namespace>(&definition) {
+                Ok(namespace) => {
+                    namespaces.entry(String::from_utf8_lossy(&name).to_string()).or_insert(namespace);
+                }
+                Err(e) => eprintln!("Skipping bad namespace definition on {}: {}", id, e),
+            }
+        }
+    }
+    println!("Loaded {} namespaces", namespaces.len());
+}
+
+pub fn get(name: &str) -> Result<Namespace, KvError> {
+    NAMESPACES.read().unwrap()
+        .get(name)
+        .cloned()
+        .ok_or_else(|| KvError::UnknownNamespace(name.to_string()))
+}
+
+pub fn exists(name: &str) -> bool {
+    NAMESPACES.read().unwrap().contains_key(name)
+}
+
+pub fn list() -> Vec<Namespace> {
+    NAMESPACES.read().unwrap().values().cloned().collect()
+}
+
+pub fn names() -> Vec<String> {
+    NAMESPACES.read().unwrap().keys().cloned().collect()
+}
+
+fn validate(namespace: &Namespace, nodes: usize) -> Result<(), KvError> {
+    let name_ok = !namespace.name.is_empty()
+        && namespace.name.len() <= MAX_NAME_LEN
+        && namespace.name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-');
+    if !name_ok {
+        return Err(KvError::BadRequest(format!("namespace names are 1-{} characters of [a-z0-9_-]", MAX_NAME_LEN)));
+    }
+    if namespace.replication_factor == 0 || namespace.replication_factor > nodes {
+        return Err(KvError::BadRequest(format!("replication_factor must be between 1 and {}", nodes)));
+    }
+    // Raft groups span exactly the default replica set
+    if namespace.consistency == Consistency::Strong && namespace.replication_factor != DEFAULT_REPLICATION_FACTOR {
+        return Err(KvError::BadRequest(format!("strong namespaces need replication_factor {}", DEFAULT_REPLICATION_FACTOR)));
+    }
+    if namespace.default_ttl_seconds == Some(0) {
+        return Err(KvError::BadRequest("default_ttl_seconds must be positive".to_string()));
+    }
+    Ok(())
+}
+
+pub fn create(namespace: Namespace) -> Result<Namespace, KvError> {
+    let ring = HASH_RING.read().unwrap();
+    validate(&namespace, ring.get_all_node_ids().len())?;
+    let mut namespaces = NAMESPACES.write().unwrap();
+    if namespaces.contains_key(&namespace.name) {
+        return Err(KvError::Conflict(format!("namespace '{}' already exists", namespace.name)));
+    }
+
+    let definition = serde_json::to_vec(&namespace)
+        .map_err(|e| KvError::Internal(format!("failed to encode namespace: {}", e)))?;
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        node.db.open_tree(NAMESPACES_TREE)?.insert(namespace.name.as_bytes(), definition.as_slice())?;
+        store::trees(&node.db, &namespace.name)?;
+        node.db.flush()?;
+    }
+    namespaces.insert(namespace.name.clone(), namespace.clone());
+    println!("Created namespace '{}'", namespace.name);
+    Ok(namespace)
+}
+
+// Forget the definition and drop the namespace's trees on every node
+pub fn drop_namespace(name: &str) -> Result<(), KvError> {
+    if name == DEFAULT_NAMESPACE {
+        return Err(KvError::BadRequest("the default namespace can't be dropped".to_string()));
+    }
+    let ring = HASH_RING.read().unwrap();
+    if NAMESPACES.write().unwrap().remove(name).is_none() {
+        return Err(KvError::UnknownNamespace(name.to_string()));
+    }
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        node.db.open_tree(NAMESPACES_TREE)?.remove(name.as_bytes())?;
+        store::drop_trees(&node.db, name)?;
+        node.db.flush()?;
+    }
+    USAGE.write().unwrap().remove(name);
+    println!("Dropped namespace '{}'", name);
+    Ok(())
+}
+
+pub fn usage(name: &str) -> Usage {
+    USAGE.read().unwrap().get(name).copied().unwrap_or_default()
+}
+
+// Count a newly created key until the next refresh
+pub fn record_created(name: &str, bytes: usize) {
+    let mut usage = USAGE.write().unwrap();
+    let entry = usage.entry(name.to_string()).or_default();
+    entry.keys += 1;
+    entry.bytes += bytes as u64;
+}
+
+// Recount every namespace from the keys each node leads and publish the gauges
+pub fn refresh_usage() {
+    for name in names() {
+        let mut total = Usage::default();
+        {
+            let ring = HASH_RING.read().unwrap();
+            for id in ring.get_all_node_ids() {
+                let Some(node) = ring.get_node_by_id(&id) else { continue };
+                let Ok(trees) = store::trees(&node.db, &name) else { continue };
+                let leads = |key: &[u8]| ring.get_node(key).is_some_and(|leader| leader.id == id);
+                if let Ok((keys, bytes)) = store::usage(&trees, leads) {
+                    total.keys += keys;
+                    total.bytes += bytes;
+                }
+            }
+        }
+        // Dropped while we were counting
+        if !exists(&name) {
+            continue;
+        }
+        gauge!("namespace_keys", total.keys as f64, "namespace" => name.clone());
+        gauge!("namespace_bytes", total.bytes as f64, "namespace" => name.clone());
+        USAGE.write().unwrap().insert(name, total);
+    }
+}
@@ -0,0 +1,178 @@
+/*
+Strongly consistent path. Every ring partition (the keys whose primary is node X) is backed by a
+Raft group made of its replica set (HashRing::get_replica_set). Each member keeps its Raft log and
+hard state in dedicated sled trees and applies committed entries to its default tree, the same
+state machine the eventual path uses. A key should be written through one path only.
+*/
+mod network;
+mod node;
+mod storage;
+#[cfg(test)]
+mod tests;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use once_cell::sync::OnceCell;
+use sled::{Db, IVec};
+use tokio::sync::{mpsc::unbounded_channel, oneshot};
+use tokio::time::{timeout_at, Instant};
+use crate::config::HASH_RING;
+use crate::routes_resp::WalOp;
+use crate::store::{self, ApplyOutcome, ValueMeta};
+use network::LocalNetwork;
+use node::{RaftInput, RaftNode};
+use storage::RaftStorage;
+
+pub use node::{RaftConfig, RaftError, RaftStatus};
+
+static RAFT: OnceCell<RaftCluster> = OnceCell::new();
+
+pub struct RaftCluster {
+    network: Arc<LocalNetwork>,
+    config: RaftConfig,
+    // group -> members; the first member is the partition's primary
+    groups: BTreeMap<String, Vec<String>>,
+    dbs: HashMap<String, Db>,
+}
+
+impl RaftCluster {
+    // `owns(group, key)` decides which state-machine keys belong to a group (used for snapshots)
+    pub fn start(
+        groups: Vec<(String, Vec<(String, Db)>)>,
+        config: RaftConfig,
+        owns: impl Fn(&str, &[u8]) -> bool + Send + Sync + 'static,
+    ) -> sled::Result<Self> {
+        let network = Arc::new(LocalNetwork::default());
+        let owns = Arc::new(owns);
+        let mut cluster = RaftCluster { network: network.clone(), config: config.clone(), groups: BTreeMap::new(), dbs: HashMap::new() };
+
+        for (group, members) in groups {
+            let ids: Vec<String> = members.iter().map(|(id, _)| id.clone()).collect();
+            for (id, db) in members {
+                let owns = owns.clone();
+                let group_id = group.clone();
+                let storage = RaftStorage::open(db.clone(), &group, Box::new(move |key| owns(&group_id, key)))?;
+                let peers = ids.iter().filter(|p| **p != id).cloned().collect();
+
+                let (tx, rx) = unbounded_channel();
+                network.register(&group, &id, tx);
+                let node = RaftNode::new(&id, &group, peers, config.clone(), network.clone(), storage);
+                tokio::spawn(node::run(node, rx));
+                cluster.dbs.insert(id, db);
+            }
+            cluster.groups.insert(group, ids);
+        }
+        Ok(cluster)
+    }
+
+    #[cfg(test)]
+    pub fn network(&self) -> &LocalNetwork {
+        &self.network
+    }
+
+    // Replicate `op` through the group's log; returns the leader that committed and applied it, and the outcome
+    pub async fn propose(&self, group: &str, op: Option<WalOp>) -> Result<(String, Option<ApplyOutcome>), RaftError> {
+        let members = self.groups.get(group)
+            .ok_or_else(|| RaftError::Unavailable(format!("unknown raft group {}", group)))?;
+        let deadline = Instant::now() + self.config.propose_timeout;
+        let mut hint: Option<String> = None;
+        let mut next = 0;
+
+        loop {
+            // Follow the leader hint if we have one, otherwise try members in turn
+            let member = hint.take().unwrap_or_else(|| {
+                next += 1;
+                members[(next - 1) % members.len()].clone()
+            });
+            if Instant::now() >= deadline {
+                return Err(RaftError::Unavailable("timed out waiting for a committed entry".to_string()));
+            }
+            let reachable = !self.network.is_isolated(&member);
+            let Some(mailbox) = self.network.mailbox(group, &member).filter(|_| reachable) else {
+                tokio::time::sleep(self.config.tick).await;
+                continue;
+            };
+
+            let (reply, response) = oneshot::channel();
+            if mailbox.send(RaftInput::Propose { op: op.clone(), reply }).is_err() {
+                tokio::time::sleep(self.config.tick).await;
+                continue;
+            }
+            match timeout_at(deadline, response).await {
+                Ok(Ok(Ok(outcome))) => return Ok((member, outcome)),
+                Ok(Ok(Err(RaftError::NotLeader(Some(leader))))) if leader != member => hint = Some(leader),
+                Ok(Ok(Err(RaftError::NotLeader(_)))) | Ok(Err(_)) => {
+                    // Election in progress, give it a tick
+                    tokio::time::sleep(self.config.tick).await;
+                }
+                Ok(Ok(Err(e))) => return Err(e),
+                Err(_) => return Err(RaftError::Unavailable("timed out waiting for a committed entry".to_string())),
+            }
+        }
+    }
+
+    pub async fn write(&self, group: &str, op: WalOp) -> Result<ApplyOutcome, RaftError> {
+        let (leader, outcome) = self.propose(group, Some(op)).await?;
+        outcome.ok_or_else(|| RaftError::Storage(format!("{} applied a write as a no-op", leader)))
+    }
+
+    // Linearizable read: commit a no-op barrier, then read the leader's applied state
+    pub async fn read(&self, group: &str, namespace: &str, key: &[u8]) -> Result<Option<(IVec, ValueMeta)>, RaftError> {
+        let (leader, _) = self.propose(group, None).await?;
+        let db = self.dbs.get(&leader).ok_or_else(|| RaftError::Storage(format!("no db for {}", leader)))?;
+        store::trees(db, namespace)
+            .and_then(|trees| store::read(&trees, key))
+            .map_err(|e| RaftError::Storage(e.to_string()))
+    }
+
+    pub async fn status(&self) -> BTreeMap<String, BTreeMap<String, RaftStatus>> {
+        let mut result = BTreeMap::new();
+        for (group, members) in &self.groups {
+            let mut statuses = BTreeMap::new();
+            for member in members {
+                let Some(mailbox) = self.network.mailbox(group, member) else { continue };
+                let (reply, response) = oneshot::channel();
+                if mailbox.send(RaftInput::Status(reply)).is_ok()
+                    && let Ok(status) = response.await {
+                    statuses.insert(member.clone(), status);
+                }
+            }
+            result.insert(group.clone(), statuses);
+        }
+        result
+    }
+}
+
+// One group per ring node, over that node's replica set, sharing the nodes' sled Dbs
+pub fn start_raft() {
+    let groups = {
+        let ring = HASH_RING.read().unwrap();
+        ring.get_all_node_ids()
+            .into_iter()
+            .map(|group| {
+                let members = ring.get_replica_set(&group)
+                    .into_iter()
+                    .filter_map(|id| Some((id.clone(), ring.get_node_by_id(&id)?.db.clone())))
+                    .collect();
+                (group, members)
+            })
+            .collect()
+    };
+
+    let owns = |group: &str, key: &[u8]| {
+        HASH_RING.read().unwrap().get_node(key).is_some_and(|n| n.id == group)
+    };
+    let cluster = RaftCluster::start(groups, RaftConfig::default(), owns).expect("failed to open raft storage");
+    if RAFT.set(cluster).is_err() {
+        eprintln!("raft already started");
+    }
+}
+
+pub fn raft() -> &'static RaftCluster {
+    RAFT.get().expect("raft not started")
+}
+
+// Group that owns a key: the key's primary node on the ring
+pub fn group_for_key(key: &[u8]) -> Option<String> {
+    HASH_RING.read().unwrap().get_node(key).map(|n| n.id.clone())
+}
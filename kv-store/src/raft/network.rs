@@ -0,0 +1,73 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use tokio::sync::mpsc::UnboundedSender;
+use super::node::RaftInput;
+use super::storage::{LogEntry, SnapshotMeta};
+
+#[derive(Clone, Debug)]
+pub enum RaftMessage {
+    RequestVote { last_log_index: u64, last_log_term: u64 },
+    RequestVoteResponse { granted: bool },
+    AppendEntries { prev_log_index: u64, prev_log_term: u64, entries: Vec<LogEntry>, leader_commit: u64 },
+    // On reject, match_index is a hint for where the follower's log ends
+    AppendEntriesResponse { success: bool, match_index: u64 },
+    InstallSnapshot { snapshot: SnapshotMeta, data: Vec<u8> },
+    InstallSnapshotResponse { last_index: u64 },
+}
+
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    pub group: String,
+    pub from: String,
+    pub to: String,
+    pub term: u64,
+    pub message: RaftMessage,
+}
+
+/*
+All ring nodes live in this process, so the Raft transport is a set of in-memory mailboxes keyed
+by (group, member). Delivery can be cut per node to simulate partitions and crashes in tests.
+*/
+#[derive(Default)]
+pub struct LocalNetwork {
+    mailboxes: RwLock<HashMap<(String, String), UnboundedSender<RaftInput>>>,
+    isolated: RwLock<HashSet<String>>,
+}
+
+impl LocalNetwork {
+    pub fn register(&self, group: &str, member: &str, mailbox: UnboundedSender<RaftInput>) {
+        self.mailboxes.write().unwrap().insert((group.to_string(), member.to_string()), mailbox);
+    }
+
+    pub fn mailbox(&self, group: &str, member: &str) -> Option<UnboundedSender<RaftInput>> {
+        self.mailboxes.read().unwrap().get(&(group.to_string(), member.to_string())).cloned()
+    }
+
+    pub fn is_isolated(&self, member: &str) -> bool {
+        self.isolated.read().unwrap().contains(member)
+    }
+
+    // Fire and forget, like a datagram: lost messages are recovered by Raft's own retries
+    pub fn send(&self, envelope: Envelope) {
+        {
+            let isolated = self.isolated.read().unwrap();
+            if isolated.contains(&envelope.from) || isolated.contains(&envelope.to) {
+                return;
+            }
+        }
+        if let Some(mailbox) = self.mailbox(&envelope.group, &envelope.to) {
+            let _ = mailbox.send(RaftInput::Message(envelope));
+        }
+    }
+
+    // Cut a node off from every peer (partition / crash without losing disk state)
+    #[cfg(test)]
+    pub fn isolate(&self, member: &str) {
+        self.isolated.write().unwrap().insert(member.to_string());
+    }
+
+    #[cfg(test)]
+    pub fn heal(&self, member: &str) {
+        self.isolated.write().unwrap().remove(member);
+    }
+}
@@ -0,0 +1,512 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use serde::Serialize;
+use tokio::sync::{mpsc::UnboundedReceiver, oneshot};
+use crate::routes_resp::WalOp;
+use crate::store::ApplyOutcome;
+use super::network::{Envelope, LocalNetwork, RaftMessage};
+use super::storage::{HardState, LogEntry, RaftStorage, SnapshotMeta};
+
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    pub tick: Duration,
+    // Election timeout is randomized in [election_ticks, 2 * election_ticks)
+    pub election_ticks: u32,
+    pub heartbeat_ticks: u32,
+    // Compact the log once this many applied entries sit on top of the last snapshot
+    pub snapshot_threshold: u64,
+    pub max_append_entries: usize,
+    // How long a client request waits for a leader and a committed entry before giving up
+    pub propose_timeout: Duration,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        RaftConfig {
+            tick: Duration::from_millis(50),
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            snapshot_threshold: 1000,
+            max_append_entries: 64,
+            propose_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RaftError {
+    // Carries the leader this member currently knows about, if any
+    NotLeader(Option<String>),
+    // No quorum reachable, or leadership changed before the entry committed
+    Unavailable(String),
+    Storage(String),
+}
+
+impl std::fmt::Display for RaftError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RaftError::NotLeader(Some(leader)) => write!(f, "not the leader, try {}", leader),
+            RaftError::NotLeader(None) => write!(f, "no leader elected"),
+            RaftError::Unavailable(reason) => write!(f, "no quorum: {}", reason),
+            RaftError::Storage(e) => write!(f, "raft storage error: {}", e),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RaftStatus {
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub commit_index: u64,
+    pub applied: u64,
+    pub snapshot_index: u64,
+}
+
+// Outcome of a committed entry; None for no-ops
+pub type ProposeReply = oneshot::Sender<Result<Option<ApplyOutcome>, RaftError>>;
+
+pub enum RaftInput {
+    Message(Envelope),
+    // Ok once the entry is committed and applied on this (leader) member, with what applying it did
+    Propose { op: Option<WalOp>, reply: ProposeReply },
+    Status(oneshot::Sender<RaftStatus>),
+}
+
+pub struct RaftNode {
+    id: String,
+    group: String,
+    peers: Vec<String>,
+    config: RaftConfig,
+    network: Arc<LocalNetwork>,
+    storage: RaftStorage,
+
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    commit_index: u64,
+    applied: u64,
+
+    election_elapsed: u32,
+    election_timeout: u32,
+    heartbeat_elapsed: u32,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    // index -> (term it was proposed in, client waiting for it)
+    waiters: BTreeMap<u64, (u64, ProposeReply)>,
+}
+
+impl RaftNode {
+    pub fn new(id: &str, group: &str, peers: Vec<String>, config: RaftConfig, network: Arc<LocalNetwork>, storage: RaftStorage) -> Self {
+        let HardState { term, voted_for } = storage.hard_state();
+        let applied = storage.applied().max(storage.snapshot_meta().last_index);
+        let mut node = RaftNode {
+            id: id.to_string(),
+            group: group.to_string(),
+            peers,
+            config,
+            network,
+            storage,
+            role: Role::Follower,
+            term,
+            voted_for,
+            leader: None,
+            commit_index: applied,
+            applied,
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            waiters: BTreeMap::new(),
+        };
+        node.reset_election_timer();
+        node
+    }
+
+    fn majority(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        self.election_timeout = rand::random_range(self.config.election_ticks..self.config.election_ticks * 2);
+    }
+
+    fn persist_hard_state(&self) {
+        let state = HardState { term: self.term, voted_for: self.voted_for.clone() };
+        if let Err(e) = self.storage.set_hard_state(&state) {
+            eprintln!("raft {}/{}: failed to persist hard state: {}", self.group, self.id, e);
+        }
+    }
+
+    fn send(&self, to: &str, message: RaftMessage) {
+        self.network.send(Envelope {
+            group: self.group.clone(),
+            from: self.id.clone(),
+            to: to.to_string(),
+            term: self.term,
+            message,
+        });
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        RaftStatus {
+            role: self.role.clone(),
+            term: self.term,
+            leader: self.leader.clone(),
+            commit_index: self.commit_index,
+            applied: self.applied,
+            snapshot_index: self.storage.snapshot_meta().last_index,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.role == Role::Leader {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= self.config.heartbeat_ticks {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_append();
+            }
+            return;
+        }
+
+        self.election_elapsed += 1;
+        if self.election_elapsed >= self.election_timeout {
+            self.start_election();
+        }
+    }
+
+    fn start_election(&mut self) {
+        self.role = Role::Candidate;
+        self.term += 1;
+        self.voted_for = Some(self.id.clone());
+        self.leader = None;
+        self.persist_hard_state();
+        self.reset_election_timer();
+        self.votes = HashSet::from([self.id.clone()]);
+
+        if self.votes.len() >= self.majority() {
+            self.become_leader();
+            return;
+        }
+        let (last_log_index, last_log_term) = (self.storage.last_index(), self.storage.last_term());
+        for peer in self.peers.clone() {
+            self.send(&peer, RaftMessage::RequestVote { last_log_index, last_log_term });
+        }
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<String>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.persist_hard_state();
+        }
+        if self.role == Role::Leader {
+            self.fail_waiters("leadership lost");
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.reset_election_timer();
+    }
+
+    fn become_leader(&mut self) {
+        println!("raft {}: {} elected leader for term {}", self.group, self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id.clone());
+        self.heartbeat_elapsed = 0;
+        let next = self.storage.last_index() + 1;
+        for peer in &self.peers {
+            self.next_index.insert(peer.clone(), next);
+            self.match_index.insert(peer.clone(), 0);
+        }
+        // A no-op from the new term lets earlier entries commit (Raft §5.4.2)
+        self.append_local(None);
+        self.broadcast_append();
+    }
+
+    fn append_local(&mut self, op: Option<WalOp>) -> Option<u64> {
+        let entry = LogEntry { term: self.term, index: self.storage.last_index() + 1, op };
+        let index = entry.index;
+        if let Err(e) = self.storage.append(&[entry]) {
+            eprintln!("raft {}/{}: failed to append: {}", self.group, self.id, e);
+            return None;
+        }
+        self.advance_commit();
+        Some(index)
+    }
+
+    fn fail_waiters(&mut self, reason: &str) {
+        for (_, (_, reply)) in std::mem::take(&mut self.waiters) {
+            let _ = reply.send(Err(RaftError::Unavailable(reason.to_string())));
+        }
+    }
+
+    pub fn handle(&mut self, input: RaftInput) {
+        match input {
+            RaftInput::Message(envelope) => self.step(envelope),
+            RaftInput::Propose { op, reply } => self.propose(op, reply),
+            RaftInput::Status(reply) => {
+                let _ = reply.send(self.status());
+            }
+        }
+    }
+
+    fn propose(&mut self, op: Option<WalOp>, reply: ProposeReply) {
+        if self.role != Role::Leader {
+            let _ = reply.send(Err(RaftError::NotLeader(self.leader.clone())));
+            return;
+        }
+        match self.append_local(op) {
+            Some(index) => {
+                self.waiters.insert(index, (self.term, reply));
+                self.apply_committed();
+                self.broadcast_append();
+            }
+            None => {
+                let _ = reply.send(Err(RaftError::Storage("log append failed".to_string())));
+            }
+        }
+    }
+
+    fn step(&mut self, envelope: Envelope) {
+        if envelope.term > self.term {
+            let leader = match envelope.message {
+                RaftMessage::AppendEntries { .. } | RaftMessage::InstallSnapshot { .. } => Some(envelope.from.clone()),
+                _ => None,
+            };
+            self.become_follower(envelope.term, leader);
+        }
+
+        match envelope.message {
+            RaftMessage::RequestVote { last_log_index, last_log_term } => {
+                self.handle_request_vote(&envelope.from, envelope.term, last_log_index, last_log_term)
+            }
+            RaftMessage::RequestVoteResponse { granted } => {
+                if self.role == Role::Candidate && envelope.term == self.term && granted {
+                    self.votes.insert(envelope.from);
+                    if self.votes.len() >= self.majority() {
+                        self.become_leader();
+                    }
+                }
+            }
+            RaftMessage::AppendEntries { prev_log_index, prev_log_term, entries, leader_commit } => {
+                self.handle_append_entries(&envelope.from, envelope.term, prev_log_index, prev_log_term, entries, leader_commit)
+            }
+            RaftMessage::AppendEntriesResponse { success, match_index } => {
+                if self.role == Role::Leader && envelope.term == self.term {
+                    self.handle_append_response(&envelope.from, success, match_index);
+                }
+            }
+            RaftMessage::InstallSnapshot { snapshot, data } => {
+                self.handle_install_snapshot(&envelope.from, envelope.term, snapshot, &data)
+            }
+            RaftMessage::InstallSnapshotResponse { last_index } => {
+                if self.role == Role::Leader && envelope.term == self.term {
+                    self.handle_append_response(&envelope.from, true, last_index);
+                }
+            }
+        }
+    }
+
+    fn handle_request_vote(&mut self, candidate: &str, term: u64, last_log_index: u64, last_log_term: u64) {
+        let up_to_date = (last_log_term, last_log_index) >= (self.storage.last_term(), self.storage.last_index());
+        let free = self.voted_for.is_none() || self.voted_for.as_deref() == Some(candidate);
+        let granted = term == self.term && free && up_to_date;
+        if granted {
+            self.voted_for = Some(candidate.to_string());
+            self.persist_hard_state();
+            self.reset_election_timer();
+        }
+        self.send(candidate, RaftMessage::RequestVoteResponse { granted });
+    }
+
+    fn handle_append_entries(&mut self, leader: &str, term: u64, prev_log_index: u64, prev_log_term: u64, entries: Vec<LogEntry>, leader_commit: u64) {
+        if term < self.term {
+            self.send(leader, RaftMessage::AppendEntriesResponse { success: false, match_index: 0 });
+            return;
+        }
+        self.become_follower(term, Some(leader.to_string()));
+
+        let last_index = self.storage.last_index();
+        let snapshot_index = self.storage.snapshot_meta().last_index;
+        if prev_log_index > last_index {
+            self.send(leader, RaftMessage::AppendEntriesResponse { success: false, match_index: last_index });
+            return;
+        }
+        // Anything at or below our snapshot is committed and therefore identical to the leader's
+        if prev_log_index >= snapshot_index && self.storage.term_at(prev_log_index) != Some(prev_log_term) {
+            self.send(leader, RaftMessage::AppendEntriesResponse { success: false, match_index: prev_log_index.saturating_sub(1) });
+            return;
+        }
+
+        let mut new_entries = Vec::new();
+        for entry in entries.iter().filter(|e| e.index > snapshot_index) {
+            if new_entries.is_empty() {
+                match self.storage.term_at(entry.index) {
+                    Some(t) if t == entry.term => continue,
+                    Some(_) => {
+                        if let Err(e) = self.storage.truncate_from(entry.index) {
+                            eprintln!("raft {}/{}: failed to truncate log: {}", self.group, self.id, e);
+                            return;
+                        }
+                    }
+                    None => {}
+                }
+            }
+            new_entries.push(entry.clone());
+        }
+        if !new_entries.is_empty() && let Err(e) = self.storage.append(&new_entries) {
+            eprintln!("raft {}/{}: failed to append: {}", self.group, self.id, e);
+            return;
+        }
+
+        let match_index = prev_log_index.max(snapshot_index) + entries.iter().filter(|e| e.index > prev_log_index.max(snapshot_index)).count() as u64;
+        let commit = leader_commit.min(match_index);
+        if commit > self.commit_index {
+            self.commit_index = commit;
+            self.apply_committed();
+        }
+        self.send(leader, RaftMessage::AppendEntriesResponse { success: true, match_index });
+    }
+
+    fn handle_append_response(&mut self, peer: &str, success: bool, match_index: u64) {
+        if success {
+            let matched = self.match_index.entry(peer.to_string()).or_insert(0);
+            *matched = (*matched).max(match_index);
+            let next = *matched + 1;
+            self.next_index.insert(peer.to_string(), next);
+            self.advance_commit();
+            self.apply_committed();
+            if next <= self.storage.last_index() {
+                self.send_append(peer);
+            }
+        } else {
+            let next = self.next_index.get(peer).copied().unwrap_or(1);
+            self.next_index.insert(peer.to_string(), (match_index + 1).min(next.saturating_sub(1)).max(1));
+            self.send_append(peer);
+        }
+    }
+
+    fn handle_install_snapshot(&mut self, leader: &str, term: u64, snapshot: SnapshotMeta, data: &[u8]) {
+        if term < self.term {
+            return;
+        }
+        self.become_follower(term, Some(leader.to_string()));
+
+        if snapshot.last_index > self.commit_index {
+            if let Err(e) = self.storage.install_snapshot(snapshot, data) {
+                eprintln!("raft {}/{}: failed to install snapshot: {}", self.group, self.id, e);
+                return;
+            }
+            println!("raft {}: {} installed snapshot at index {}", self.group, self.id, snapshot.last_index);
+            self.commit_index = snapshot.last_index;
+            self.applied = snapshot.last_index;
+        }
+        self.send(leader, RaftMessage::InstallSnapshotResponse { last_index: self.commit_index });
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(&peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: &str) {
+        let next = self.next_index.get(peer).copied().unwrap_or(1);
+        let snapshot = self.storage.snapshot_meta();
+
+        // The entries this peer needs are compacted away: ship the state machine instead
+        if next <= snapshot.last_index {
+            let Some(last_term) = self.storage.term_at(self.applied) else { return };
+            let meta = SnapshotMeta { last_index: self.applied, last_term };
+            let data = self.storage.snapshot_data();
+            self.send(peer, RaftMessage::InstallSnapshot { snapshot: meta, data });
+            return;
+        }
+
+        let prev_log_index = next - 1;
+        let Some(prev_log_term) = self.storage.term_at(prev_log_index) else { return };
+        let entries = self.storage.entries(next, self.config.max_append_entries);
+        self.send(peer, RaftMessage::AppendEntries {
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit: self.commit_index,
+        });
+    }
+
+    // Highest index stored on a majority that belongs to the current term (Raft §5.4.2)
+    fn advance_commit(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let last = self.storage.last_index();
+        for index in (self.commit_index + 1..=last).rev() {
+            if self.storage.term_at(index) != Some(self.term) {
+                break;
+            }
+            let replicas = 1 + self.match_index.values().filter(|m| **m >= index).count();
+            if replicas >= self.majority() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    fn apply_committed(&mut self) {
+        while self.applied < self.commit_index {
+            let index = self.applied + 1;
+            let Some(entry) = self.storage.entry(index) else { break };
+            let outcome = match self.storage.apply(&entry) {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    eprintln!("raft {}/{}: failed to apply index {}: {}", self.group, self.id, index, e);
+                    break;
+                }
+            };
+            self.applied = index;
+
+            if let Some((term, reply)) = self.waiters.remove(&index) {
+                let result = if term == entry.term {
+                    Ok(outcome)
+                } else {
+                    Err(RaftError::Unavailable("entry overwritten by a new leader".to_string()))
+                };
+                let _ = reply.send(result);
+            }
+        }
+
+        let snapshot_index = self.storage.snapshot_meta().last_index;
+        if self.applied - snapshot_index >= self.config.snapshot_threshold
+            && let Err(e) = self.storage.compact(self.applied) {
+            eprintln!("raft {}/{}: failed to compact log: {}", self.group, self.id, e);
+        }
+    }
+}
+
+// Drive one member: ticks on a timer, everything else arrives through the mailbox
+pub async fn run(mut node: RaftNode, mut inbox: UnboundedReceiver<RaftInput>) {
+    let mut ticker = tokio::time::interval(node.config.tick);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => node.tick(),
+            input = inbox.recv() => match input {
+                Some(input) => node.handle(input),
+                None => return,
+            },
+        }
+    }
+}
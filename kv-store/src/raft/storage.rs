@@ -0,0 +1,235 @@
+use std::collections::BTreeSet;
+use serde::{Deserialize, Serialize};
+use sled::{Batch, Db, Transactional, Tree};
+use crate::routes_resp::WalOp;
+use crate::store::{self, apply_op, ApplyOutcome};
+
+// namespace, key, value, encoded ValueMeta
+type SnapshotRow = (String, Vec<u8>, Vec<u8>, Option<Vec<u8>>);
+
+// Which state-machine keys belong to a group (a member node also stores other partitions' keys)
+pub type OwnsKey = Box<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+const HARD_STATE_KEY: &[u8] = b"hard_state";
+const SNAPSHOT_KEY: &[u8] = b"snapshot";
+const APPLIED_KEY: &[u8] = b"applied";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub index: u64,
+    // None is a no-op (leader barrier / linearizable read)
+    pub op: Option<WalOp>,
+}
+
+// Must be on disk before answering a vote or accepting a new term
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u64,
+    pub voted_for: Option<String>,
+}
+
+// Everything up to and including last_index is folded into the state machine and gone from the log
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct SnapshotMeta {
+    pub last_index: u64,
+    pub last_term: u64,
+}
+
+/*
+Raft state for one group member, kept inside that member's sled Db:
+  __raft_log_<group>   index (u64 BE) -> bincode(LogEntry)
+  __raft_meta_<group>  hard_state / snapshot / applied
+The state machine is the member's namespace trees, the same ones the eventual path writes to.
+*/
+pub struct RaftStorage {
+    db: Db,
+    log: Tree,
+    meta: Tree,
+    owns: OwnsKey,
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    bincode::serialize(value).expect("raft state is always serializable")
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(bytes: &[u8]) -> Option<T> {
+    bincode::deserialize(bytes).ok()
+}
+
+impl RaftStorage {
+    pub fn open(db: Db, group: &str, owns: OwnsKey) -> sled::Result<Self> {
+        let log = db.open_tree(format!("__raft_log_{}", group))?;
+        let meta = db.open_tree(format!("__raft_meta_{}", group))?;
+        Ok(RaftStorage { db, log, meta, owns })
+    }
+
+    fn read_meta<T: for<'de> Deserialize<'de> + Default>(&self, key: &[u8]) -> T {
+        self.meta.get(key).ok().flatten().and_then(|v| decode(&v)).unwrap_or_default()
+    }
+
+    pub fn hard_state(&self) -> HardState {
+        self.read_meta(HARD_STATE_KEY)
+    }
+
+    pub fn set_hard_state(&self, state: &HardState) -> sled::Result<()> {
+        self.meta.insert(HARD_STATE_KEY, encode(state))?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    pub fn snapshot_meta(&self) -> SnapshotMeta {
+        self.read_meta(SNAPSHOT_KEY)
+    }
+
+    pub fn applied(&self) -> u64 {
+        self.read_meta(APPLIED_KEY)
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.log.last().ok().flatten()
+            .and_then(|(k, _)| k.as_ref().try_into().ok().map(u64::from_be_bytes))
+            .unwrap_or_else(|| self.snapshot_meta().last_index)
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or(0)
+    }
+
+    // None when the index is compacted away or not in the log yet
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        let snapshot = self.snapshot_meta();
+        if index == snapshot.last_index {
+            return Some(snapshot.last_term);
+        }
+        if index < snapshot.last_index {
+            return None;
+        }
+        self.entry(index).map(|e| e.term)
+    }
+
+    pub fn entry(&self, index: u64) -> Option<LogEntry> {
+        self.log.get(index.to_be_bytes()).ok().flatten().and_then(|v| decode(&v))
+    }
+
+    pub fn entries(&self, from: u64, max: usize) -> Vec<LogEntry> {
+        self.log.range(from.to_be_bytes()..)
+            .values()
+            .take(max)
+            .filter_map(|v| v.ok().and_then(|v| decode(&v)))
+            .collect()
+    }
+
+    pub fn append(&self, entries: &[LogEntry]) -> sled::Result<()> {
+        let mut batch = Batch::default();
+        for entry in entries {
+            batch.insert(&entry.index.to_be_bytes(), encode(entry));
+        }
+        self.log.apply_batch(batch)?;
+        self.log.flush()?;
+        Ok(())
+    }
+
+    // Drop `index` and everything after it (conflicting suffix from an old leader)
+    pub fn truncate_from(&self, index: u64) -> sled::Result<()> {
+        let mut batch = Batch::default();
+        for key in self.log.range(index.to_be_bytes()..).keys() {
+            batch.remove(key?);
+        }
+        self.log.apply_batch(batch)
+    }
+
+    fn remove_through(&self, index: u64) -> sled::Result<()> {
+        let mut batch = Batch::default();
+        for key in self.log.range(..=index.to_be_bytes()).keys() {
+            batch.remove(key?);
+        }
+        self.log.apply_batch(batch)
+    }
+
+    // Apply a committed entry to the state machine and record it as applied, atomically.
+    // None for no-op entries.
+    pub fn apply(&self, entry: &LogEntry) -> sled::Result<Option<ApplyOutcome>> {
+        let applied = encode(&entry.index);
+        let Some(op) = &entry.op else {
+            self.meta.insert(APPLIED_KEY, applied)?;
+            return Ok(None);
+        };
+        let trees = store::trees(&self.db, op.namespace())?;
+        (&trees.data, &trees.meta, &self.meta)
+            .transaction(|(data, value_meta, meta)| {
+                let outcome = apply_op::<()>(data, value_meta, op)?;
+                meta.insert(APPLIED_KEY, applied.as_slice())?;
+                Ok(Some(outcome))
+            })
+            .map_err(|e| match e {
+                sled::transaction::TransactionError::Storage(e) => e,
+                sled::transaction::TransactionError::Abort(_) => sled::Error::Unsupported("apply aborted".into()),
+            })
+    }
+
+    // Fold the log up to `index` (must be applied) into a snapshot
+    pub fn compact(&self, index: u64) -> sled::Result<()> {
+        let Some(term) = self.term_at(index) else { return Ok(()) };
+        self.meta.insert(SNAPSHOT_KEY, encode(&SnapshotMeta { last_index: index, last_term: term }))?;
+        self.remove_through(index)?;
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    // The group's slice of the state machine (values and their metadata, every namespace), as of `applied()`
+    pub fn snapshot_data(&self) -> Vec<u8> {
+        let mut rows: Vec<SnapshotRow> = Vec::new();
+        for namespace in store::namespaces_on(&self.db) {
+            let Ok(trees) = store::trees(&self.db, &namespace) else { continue };
+            rows.extend(trees.data.iter()
+                .filter_map(|kv| kv.ok())
+                .filter(|(k, _)| (self.owns)(k))
+                .map(|(k, v)| {
+                    let meta = trees.meta.get(&k).ok().flatten().map(|m| m.to_vec());
+                    (namespace.clone(), k.to_vec(), v.to_vec(), meta)
+                }));
+        }
+        encode(&rows)
+    }
+
+    // Replace the group's slice of the state machine with a leader's snapshot
+    pub fn install_snapshot(&self, snapshot: SnapshotMeta, data: &[u8]) -> sled::Result<()> {
+        let rows: Vec<SnapshotRow> = decode(data)
+            .ok_or_else(|| sled::Error::Unsupported("corrupt snapshot".into()))?;
+
+        let mut namespaces: BTreeSet<String> = store::namespaces_on(&self.db).into_iter().collect();
+        namespaces.extend(rows.iter().map(|(namespace, ..)| namespace.clone()));
+        for namespace in namespaces {
+            let trees = store::trees(&self.db, &namespace)?;
+            let mut values = Batch::default();
+            let mut metas = Batch::default();
+            for key in trees.data.iter().keys() {
+                let key = key?;
+                if (self.owns)(&key) {
+                    values.remove(key.clone());
+                    metas.remove(key);
+                }
+            }
+            for (_, key, value, meta) in rows.iter().filter(|(ns, ..)| *ns == namespace) {
+                if let Some(meta) = meta {
+                    metas.insert(key.as_slice(), meta.as_slice());
+                }
+                values.insert(key.as_slice(), value.as_slice());
+            }
+            trees.data.apply_batch(values)?;
+            trees.meta.apply_batch(metas)?;
+        }
+
+        // Keep a matching suffix of the log, drop everything else
+        if self.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+            self.remove_through(snapshot.last_index)?;
+        } else {
+            self.log.clear()?;
+        }
+        self.meta.insert(SNAPSHOT_KEY, encode(&snapshot))?;
+        self.meta.insert(APPLIED_KEY, encode(&snapshot.last_index))?;
+        self.db.flush()?;
+        Ok(())
+    }
+}
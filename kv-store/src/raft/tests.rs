@@ -0,0 +1,249 @@
+use std::time::Duration;
+use sled::Db;
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::routes_resp::{Condition, WalOp, WriteMode};
+use super::{RaftCluster, RaftConfig, RaftError};
+use super::node::Role;
+use crate::store::{now_ms, ApplyOutcome};
+
+const GROUP: &str = "g0";
+
+fn test_config() -> RaftConfig {
+    RaftConfig {
+        tick: Duration::from_millis(10),
+        election_ticks: 5,
+        heartbeat_ticks: 1,
+        snapshot_threshold: 5,
+        max_append_entries: 4,
+        propose_timeout: Duration::from_secs(1),
+    }
+}
+
+// Three members on temporary sled Dbs, all keys owned by the single group
+fn three_node_cluster() -> (RaftCluster, Vec<(String, Db)>) {
+    let members: Vec<(String, Db)> = (0..3)
+        .map(|i| (format!("n{}", i), sled::Config::new().temporary(true).open().unwrap()))
+        .collect();
+    let cluster = RaftCluster::start(vec![(GROUP.to_string(), members.clone())], test_config(), |_, _| true).unwrap();
+    (cluster, members)
+}
+
+fn set(key: &str, value: &str) -> WalOp {
+    WalOp::Set { namespace: DEFAULT_NAMESPACE.to_string(), key: key.into(), value: value.into(), content_type: None, mode: WriteMode::Upsert, condition: None, expires_at: None }
+}
+
+async fn leader(cluster: &RaftCluster) -> String {
+    for _ in 0..200 {
+        // A partitioned leader keeps believing it leads, so take the one with the newest term
+        let status = cluster.status().await;
+        let newest = status[GROUP].iter()
+            .filter(|(_, s)| s.role == Role::Leader)
+            .max_by_key(|(_, s)| s.term);
+        if let Some((id, _)) = newest {
+            return id.clone();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("no leader elected");
+}
+
+async fn eventually_has(db: &Db, key: &str, value: Option<&str>) {
+    for _ in 0..200 {
+        let current = db.get(key).unwrap();
+        if current.as_deref() == value.map(str::as_bytes) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{} never became {:?}", key, value);
+}
+
+#[tokio::test]
+async fn writes_are_replicated_to_every_member() {
+    let (cluster, members) = three_node_cluster();
+
+    cluster.write(GROUP, set("a", "1")).await.unwrap();
+    cluster.write(GROUP, WalOp::Delete { namespace: DEFAULT_NAMESPACE.to_string(), key: "a".into(), condition: None }).await.unwrap();
+    cluster.write(GROUP, set("b", "2")).await.unwrap();
+
+    let (value, _) = cluster.read(GROUP, DEFAULT_NAMESPACE, b"b").await.unwrap().unwrap();
+    assert_eq!(&value[..], b"2");
+    for (_, db) in &members {
+        eventually_has(db, "a", None).await;
+        eventually_has(db, "b", Some("2")).await;
+    }
+}
+
+#[tokio::test]
+async fn new_leader_takes_over_when_leader_is_partitioned() {
+    let (cluster, members) = three_node_cluster();
+    cluster.write(GROUP, set("k", "before")).await.unwrap();
+
+    let old_leader = leader(&cluster).await;
+    cluster.network().isolate(&old_leader);
+    cluster.write(GROUP, set("k", "after")).await.unwrap();
+    let new_leader = leader(&cluster).await;
+    assert_ne!(old_leader, new_leader);
+
+    // Once healed the old leader steps down and catches up
+    cluster.network().heal(&old_leader);
+    let (_, db) = members.iter().find(|(id, _)| *id == old_leader).unwrap();
+    eventually_has(db, "k", Some("after")).await;
+}
+
+#[tokio::test]
+async fn minority_cannot_commit() {
+    let (cluster, members) = three_node_cluster();
+    let current = leader(&cluster).await;
+    for (id, _) in &members {
+        if *id != current {
+            cluster.network().isolate(id);
+        }
+    }
+
+    let result = cluster.write(GROUP, set("k", "v")).await;
+    assert!(matches!(result, Err(RaftError::Unavailable(_))), "{:?}", result);
+}
+
+#[tokio::test]
+async fn lagging_member_catches_up_from_snapshot() {
+    let (cluster, members) = three_node_cluster();
+    let current = leader(&cluster).await;
+    let (lagging, lagging_db) = members.iter().find(|(id, _)| *id != current).unwrap().clone();
+
+    cluster.network().isolate(&lagging);
+    for i in 0..20 {
+        cluster.write(GROUP, set(&format!("key{}", i), &i.to_string())).await.unwrap();
+    }
+    let status = cluster.status().await;
+    assert!(status[GROUP][&current].snapshot_index > 0, "leader never compacted its log");
+
+    cluster.network().heal(&lagging);
+    for i in 0..20 {
+        eventually_has(&lagging_db, &format!("key{}", i), Some(&i.to_string())).await;
+    }
+}
+
+#[tokio::test]
+async fn write_modes_are_checked_by_the_state_machine() {
+    let (cluster, members) = three_node_cluster();
+    let with_mode = |value: &str, mode| WalOp::Set { namespace: DEFAULT_NAMESPACE.to_string(), key: "m".into(), value: value.into(), content_type: None, mode, condition: None, expires_at: None };
+
+    assert_eq!(cluster.write(GROUP, with_mode("1", WriteMode::UpdateOnly)).await.unwrap(), ApplyOutcome::Missing);
+    assert_eq!(cluster.write(GROUP, with_mode("2", WriteMode::CreateOnly)).await.unwrap(), ApplyOutcome::Created);
+    assert_eq!(cluster.write(GROUP, with_mode("3", WriteMode::CreateOnly)).await.unwrap(), ApplyOutcome::AlreadyExists);
+    assert_eq!(cluster.write(GROUP, with_mode("4", WriteMode::UpdateOnly)).await.unwrap(), ApplyOutcome::Updated);
+    for (_, db) in &members {
+        eventually_has(db, "m", Some("4")).await;
+    }
+}
+
+#[tokio::test]
+async fn compare_and_swap_on_version_and_value() {
+    let (cluster, members) = three_node_cluster();
+    let cas = |value: &str, condition| WalOp::Set { namespace: DEFAULT_NAMESPACE.to_string(), key: "c".into(), value: value.into(), content_type: None, mode: WriteMode::Upsert, condition: Some(condition), expires_at: None };
+
+    assert_eq!(cluster.write(GROUP, cas("0", Condition::Version(1))).await.unwrap(), ApplyOutcome::ConditionFailed);
+    cluster.write(GROUP, set("c", "1")).await.unwrap();
+    assert_eq!(cluster.write(GROUP, cas("2", Condition::Version(1))).await.unwrap(), ApplyOutcome::Updated);
+    assert_eq!(cluster.write(GROUP, cas("3", Condition::Version(1))).await.unwrap(), ApplyOutcome::ConditionFailed);
+    assert_eq!(cluster.write(GROUP, cas("3", Condition::Value(b"2".to_vec()))).await.unwrap(), ApplyOutcome::Updated);
+
+    let (_, meta) = cluster.read(GROUP, DEFAULT_NAMESPACE, b"c").await.unwrap().unwrap();
+    assert_eq!(meta.version, 3);
+    let delete = |condition| WalOp::Delete { namespace: DEFAULT_NAMESPACE.to_string(), key: "c".into(), condition: Some(condition) };
+    assert_eq!(cluster.write(GROUP, delete(Condition::Version(2))).await.unwrap(), ApplyOutcome::ConditionFailed);
+    assert_eq!(cluster.write(GROUP, delete(Condition::Version(3))).await.unwrap(), ApplyOutcome::Deleted);
+    for (_, db) in &members {
+        eventually_has(db, "c", None).await;
+    }
+}
+
+#[tokio::test]
+async fn expired_keys_read_as_missing_until_persisted() {
+    let (cluster, _) = three_node_cluster();
+    let expiring = |key: &str, expires_at| WalOp::Set { namespace: DEFAULT_NAMESPACE.to_string(), key: key.into(), value: "v".into(), content_type: None, mode: WriteMode::Upsert, condition: None, expires_at: Some(expires_at) };
+
+    // Far enough ahead that both keys are still live when the writes commit, even on a busy machine
+    let expires_at = now_ms() + 1_000;
+    cluster.write(GROUP, expiring("gone", expires_at)).await.unwrap();
+    cluster.write(GROUP, expiring("kept", expires_at)).await.unwrap();
+    cluster.write(GROUP, WalOp::Expire { namespace: DEFAULT_NAMESPACE.to_string(), key: "kept".into(), expires_at: None }).await.unwrap();
+    assert!(cluster.read(GROUP, DEFAULT_NAMESPACE, b"gone").await.unwrap().is_some());
+
+    tokio::time::sleep(Duration::from_millis((expires_at + 50).saturating_sub(now_ms()))).await;
+    assert!(cluster.read(GROUP, DEFAULT_NAMESPACE, b"gone").await.unwrap().is_none());
+    assert!(cluster.read(GROUP, DEFAULT_NAMESPACE, b"kept").await.unwrap().is_some());
+    // An expired key is absent for write modes too
+    let create = WalOp::Set { namespace: DEFAULT_NAMESPACE.to_string(), key: "gone".into(), value: "new".into(), content_type: None, mode: WriteMode::CreateOnly, condition: None, expires_at: None };
+    assert_eq!(cluster.write(GROUP, create).await.unwrap(), ApplyOutcome::Created);
+}
+
+#[tokio::test]
+async fn namespaces_are_separate_and_included_in_snapshots() {
+    let (cluster, members) = three_node_cluster();
+    let current = leader(&cluster).await;
+    let (lagging, lagging_db) = members.iter().find(|(id, _)| *id != current).unwrap().clone();
+    let in_ns = |key: &str, value: &str| WalOp::Set { namespace: "orders".to_string(), key: key.into(), value: value.into(), content_type: None, mode: WriteMode::Upsert, condition: None, expires_at: None };
+
+    cluster.network().isolate(&lagging);
+    cluster.write(GROUP, set("shared", "default")).await.unwrap();
+    for i in 0..10 {
+        cluster.write(GROUP, in_ns("shared", &i.to_string())).await.unwrap();
+    }
+    let (value, _) = cluster.read(GROUP, DEFAULT_NAMESPACE, b"shared").await.unwrap().unwrap();
+    assert_eq!(&value[..], b"default");
+    let (value, _) = cluster.read(GROUP, "orders", b"shared").await.unwrap().unwrap();
+    assert_eq!(&value[..], b"9");
+
+    cluster.network().heal(&lagging);
+    let orders = lagging_db.open_tree("ns:orders").unwrap();
+    for _ in 0..200 {
+        if orders.get("shared").unwrap().as_deref() == Some(&b"9"[..]) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("{} never received the orders namespace", lagging);
+}
+
+#[tokio::test]
+async fn binary_keys_and_values_are_stored_verbatim() {
+    let (cluster, members) = three_node_cluster();
+    let key = vec![0xff, 0x00, b'k'];
+    let value = vec![0x00, 0xc3, 0x28, 0xff];
+    let op = WalOp::Set { namespace: DEFAULT_NAMESPACE.to_string(), key: key.clone(), value: value.clone(), content_type: None, mode: WriteMode::Upsert, condition: None, expires_at: None };
+    cluster.write(GROUP, op).await.unwrap();
+
+    let (stored, _) = cluster.read(GROUP, DEFAULT_NAMESPACE, &key).await.unwrap().unwrap();
+    assert_eq!(stored.as_ref(), value.as_slice());
+    let cas = WalOp::Delete { namespace: DEFAULT_NAMESPACE.to_string(), key: key.clone(), condition: Some(Condition::Value(value)) };
+    assert_eq!(cluster.write(GROUP, cas).await.unwrap(), ApplyOutcome::Deleted);
+    for (_, db) in &members {
+        for _ in 0..200 {
+            if db.get(&key).unwrap().is_none() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(db.get(&key).unwrap().is_none());
+    }
+}
+
+#[tokio::test]
+async fn counters_apply_deltas_in_order_and_reject_non_integers() {
+    let (cluster, members) = three_node_cluster();
+    let incr = |key: &str, delta| WalOp::Incr { namespace: DEFAULT_NAMESPACE.to_string(), key: key.into(), delta, expires_at: None };
+    assert_eq!(cluster.write(GROUP, incr("hits", 5)).await.unwrap(), ApplyOutcome::Incremented(5));
+    assert_eq!(cluster.write(GROUP, incr("hits", -7)).await.unwrap(), ApplyOutcome::Incremented(-2));
+    for (_, db) in &members {
+        eventually_has(db, "hits", Some("-2")).await;
+    }
+    let (_, meta) = cluster.read(GROUP, DEFAULT_NAMESPACE, b"hits").await.unwrap().unwrap();
+    assert_eq!(meta.version, 2);
+
+    cluster.write(GROUP, set("name", "kv")).await.unwrap();
+    assert_eq!(cluster.write(GROUP, incr("name", 1)).await.unwrap(), ApplyOutcome::NotAnInteger);
+    cluster.write(GROUP, set("max", &i64::MAX.to_string())).await.unwrap();
+    assert_eq!(cluster.write(GROUP, incr("max", 1)).await.unwrap(), ApplyOutcome::NotAnInteger);
+}
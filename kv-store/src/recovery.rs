@@ -1 +1,39 @@
-//how to add recovery logic...
+/*
+Startup recovery, before the node serves anything: cross-partition transactions that a crash left
+between their two phases are finished the way their primary decided (see txn/two_phase.rs), and
+the writes that commits applied go into the WAL so followers get them.
+*/
+use metrics::counter;
+use sled::Db;
+use crate::config::HASH_RING;
+use crate::txn::two_phase::resolve_in_doubt;
+use crate::replication::log_writes;
+
+pub fn recover_transactions() {
+    let nodes: Vec<(String, Db)> = {
+        let ring = HASH_RING.read().unwrap();
+        ring.get_all_node_ids().into_iter()
+            .filter_map(|id| ring.get_node_by_id(&id).map(|node| (id, node.db.clone())))
+            .collect()
+    };
+    let resolved = match resolve_in_doubt(&nodes) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            eprintln!("Resolving in-doubt transactions failed, their keys stay locked: {}", e);
+            return;
+        }
+    };
+    if resolved.committed + resolved.aborted == 0 {
+        return;
+    }
+    counter!("txn_in_doubt_resolved_total", resolved.committed as u64, "decision" => "commit");
+    counter!("txn_in_doubt_resolved_total", resolved.aborted as u64, "decision" => "abort");
+    let logged = resolved.applied.into_iter()
+        .map(|(op, meta)| (op, (meta.version > 0).then_some(meta.version)))
+        .collect();
+    if let Err(e) = log_writes(logged) {
+        eprintln!("Logging recovered transaction writes failed, followers may miss them: {}", e);
+    }
+    println!("Resolved in-doubt transactions on {} participants: {} committed, {} aborted",
+        resolved.committed + resolved.aborted, resolved.committed, resolved.aborted);
+}
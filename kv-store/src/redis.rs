@@ -0,0 +1,693 @@
+/*
+Redis protocol listener on its own port (REDIS_PORT, off unless set), so Redis clients and
+redis-benchmark can talk to the cluster. Connections start in RESP2 and switch to RESP3 with
+HELLO 3. Commands go through the coordinator like the HTTP routes, in the default namespace with
+eventual consistency:
+
+  GET, SET key value [NX|XX] [EX s|PX ms], DEL, EXISTS, MGET, MSET, INCR/DECR/INCRBY/DECRBY,
+  EXPIRE, TTL, SCAN cursor [MATCH pattern] [COUNT n], PING, AUTH, HELLO, QUIT
+
+AUTH takes a JWT from /login or an API key as the password (the username is ignored); until then
+only PING, AUTH, HELLO and QUIT are accepted. Multi-key commands are not atomic, each key is its
+own write. The credential's grants then apply as over HTTP; a command they don't allow gets a
+NOPERM error. Once the credential expires or is revoked the connection is back to needing AUTH.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::collections::BTreeMap;
+use std::io;
+use metrics::{counter, decrement_gauge, increment_gauge};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use crate::coordinator::{self, KvError, PutOptions, MAX_BATCH_KEYS, MAX_SCAN_LIMIT};
+use crate::access::{authorize, Action};
+use crate::middleware::types::Claims;
+use crate::middleware::{still_valid, verify_credential};
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::routes_resp::{Consistency, WriteMode};
+use crate::store::{now_ms, ApplyOutcome, KeyRange};
+
+// Same limits as Redis' defaults
+const MAX_INLINE_LEN: usize = 64 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// Before AUTH, as Redis does since CVE-2021-32675, so a client that hasn't logged in can't make the
+// server allocate large argument buffers
+const MAX_UNAUTHENTICATED_ARGS: usize = 10;
+const MAX_UNAUTHENTICATED_BULK_LEN: usize = 16 * 1024;
+const DEFAULT_SCAN_COUNT: usize = 10;
+// SCAN cursors a connection may leave unfinished; the oldest are forgotten first
+const MAX_OPEN_SCANS: usize = 1024;
+
+enum Reply {
+    Simple(&'static str),
+    // Starts with the error code, e.g. "ERR ..." or "NOAUTH ..."
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    // RESP3 map, a flat array in RESP2
+    Map(Vec<(Reply, Reply)>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK")
+    }
+
+    fn err(message: impl AsRef<str>) -> Self {
+        Reply::Error(format!("ERR {}", message.as_ref()))
+    }
+
+    fn text(text: &str) -> Self {
+        Reply::Bulk(text.as_bytes().to_vec())
+    }
+
+    fn encode(&self, resp3: bool, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(text) => out.extend_from_slice(format!("+{}\r\n", text).as_bytes()),
+            // Error lines can't carry line breaks
+            Reply::Error(message) => out.extend_from_slice(format!("-{}\r\n", message.replace(['\r', '\n'], " ")).as_bytes()),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(bytes) => {
+                out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Null if resp3 => out.extend_from_slice(b"_\r\n"),
+            Reply::Null => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                items.iter().for_each(|item| item.encode(resp3, out));
+            }
+            Reply::Map(pairs) => {
+                let header = if resp3 { format!("%{}\r\n", pairs.len()) } else { format!("*{}\r\n", pairs.len() * 2) };
+                out.extend_from_slice(header.as_bytes());
+                for (key, value) in pairs {
+                    key.encode(resp3, out);
+                    value.encode(resp3, out);
+                }
+            }
+        }
+    }
+}
+
+impl From<KvError> for Reply {
+    fn from(e: KvError) -> Self {
+        match e {
+            KvError::NotAnInteger => Reply::err("value is not an integer or out of range"),
+            KvError::QuotaExceeded(message) => Reply::Error(format!("OOM {}", message)),
+            KvError::NoQuorum(_) => Reply::Error(format!("TRYAGAIN {}", e)),
+            KvError::Forbidden(message) => Reply::Error(format!("NOPERM {}", message)),
+            e => Reply::err(e.to_string()),
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Protocol error: {}", message))
+}
+
+// One line without its line ending; None on a clean EOF
+async fn read_line<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if (&mut *reader).take(MAX_INLINE_LEN as u64).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(protocol_error("too big inline request"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> io::Result<usize> {
+    std::str::from_utf8(digits).ok()
+        .and_then(|d| d.parse::<usize>().ok())
+        .filter(|len| *len <= max)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+// The next command as its arguments, from a RESP array of bulk strings or an inline line. Frames are
+// checked against the smaller limits until the connection has authenticated.
+async fn read_command<R: AsyncBufReadExt + Unpin>(reader: &mut R, authenticated: bool) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let (max_args, max_bulk_len) = match authenticated {
+        true => (MAX_ARGS, MAX_BULK_LEN),
+        false => (MAX_UNAUTHENTICATED_ARGS, MAX_UNAUTHENTICATED_BULK_LEN),
+    };
+    loop {
+        let Some(line) = read_line(reader).await? else { return Ok(None) };
+        if line.first() != Some(&b'*') {
+            let args: Vec<Vec<u8>> = line.split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+
+        let count = parse_len(&line[1..], max_args)?;
+        let mut args = Vec::with_capacity(count.min(64));
+        for _ in 0..count {
+            let header = read_line(reader).await?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+            if header.first() != Some(&b'$') {
+                return Err(protocol_error("expected '$'"));
+            }
+            let len = parse_len(&header[1..], max_bulk_len)?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg).await?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(protocol_error("bulk string not terminated by CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+fn parse_int(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg).ok()
+        .and_then(|n| n.parse::<i64>().ok())
+        .ok_or_else(|| Reply::err("value is not an integer or out of range"))
+}
+
+fn wrong_arity(command: &str) -> Reply {
+    Reply::err(format!("wrong number of arguments for '{}' command", command.to_lowercase()))
+}
+
+/*
+Glob matching for SCAN MATCH, as in Redis: * ? [abc] [^a-z] and \ escapes. The pattern is split
+into tokens so `*` can backtrack in O(pattern * key) instead of recursing.
+*/
+enum GlobToken {
+    Star,
+    Any,
+    Byte(u8),
+    Class { negate: bool, ranges: Vec<(u8, u8)> },
+}
+
+impl GlobToken {
+    fn matches(&self, b: u8) -> bool {
+        match self {
+            GlobToken::Star | GlobToken::Any => true,
+            GlobToken::Byte(c) => *c == b,
+            GlobToken::Class { negate, ranges } => ranges.iter().any(|(lo, hi)| (*lo..=*hi).contains(&b)) != *negate,
+        }
+    }
+}
+
+fn parse_glob(pattern: &[u8]) -> Vec<GlobToken> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < pattern.len() {
+        match pattern[i] {
+            b'*' => tokens.push(GlobToken::Star),
+            b'?' => tokens.push(GlobToken::Any),
+            b'\\' if i + 1 < pattern.len() => {
+                i += 1;
+                tokens.push(GlobToken::Byte(pattern[i]));
+            }
+            // An unterminated class is a literal '['
+            b'[' if pattern[i + 1..].contains(&b']') => {
+                i += 1;
+                let negate = pattern[i] == b'^';
+                if negate {
+                    i += 1;
+                }
+                let mut ranges = Vec::new();
+                while pattern[i] != b']' {
+                    if pattern[i] == b'\\' && pattern[i + 1] != b']' {
+                        i += 1;
+                    }
+                    let lo = pattern[i];
+                    if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|hi| *hi != b']') {
+                        let hi = pattern[i + 2];
+                        ranges.push((lo.min(hi), lo.max(hi)));
+                        i += 3;
+                    } else {
+                        ranges.push((lo, lo));
+                        i += 1;
+                    }
+                }
+                tokens.push(GlobToken::Class { negate, ranges });
+            }
+            b => tokens.push(GlobToken::Byte(b)),
+        }
+        i += 1;
+    }
+    tokens
+}
+
+fn glob_match(tokens: &[GlobToken], key: &[u8]) -> bool {
+    let (mut t, mut k) = (0, 0);
+    // Where the last * was and how much of the key it has swallowed so far
+    let mut star: Option<(usize, usize)> = None;
+    while k < key.len() {
+        match tokens.get(t) {
+            Some(GlobToken::Star) => {
+                star = Some((t, k));
+                t += 1;
+            }
+            Some(token) if token.matches(key[k]) => {
+                t += 1;
+                k += 1;
+            }
+            _ => match star {
+                Some((star_t, star_k)) => {
+                    star = Some((star_t, star_k + 1));
+                    t = star_t + 1;
+                    k = star_k + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    tokens[t..].iter().all(|token| matches!(token, GlobToken::Star))
+}
+
+// The bytes every match starts with, so SCAN MATCH can narrow the range
+fn glob_prefix(tokens: &[GlobToken]) -> Vec<u8> {
+    tokens.iter()
+        .map_while(|token| match token {
+            GlobToken::Byte(b) => Some(*b),
+            _ => None,
+        })
+        .collect()
+}
+
+// What a command does to which keys; SCAN checks the prefix it ends up scanning itself
+fn access_of<'a>(command: &str, args: &'a [Vec<u8>]) -> Option<(Action, Vec<&'a [u8]>)> {
+    let all = || args.iter().map(Vec::as_slice).collect();
+    match command {
+        "GET" | "EXISTS" | "MGET" | "TTL" => Some((Action::Read, all())),
+        "SET" | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "EXPIRE" => Some((Action::Write, args.iter().take(1).map(Vec::as_slice).collect())),
+        "MSET" => Some((Action::Write, args.iter().step_by(2).map(Vec::as_slice).collect())),
+        "DEL" => Some((Action::Delete, all())),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    // Set by AUTH; its grants are checked on every command
+    claims: Option<Claims>,
+    resp3: bool,
+    // SCAN cursor id -> last key returned
+    scans: BTreeMap<u64, Vec<u8>>,
+    next_scan: u64,
+}
+
+impl Session {
+    async fn execute(&mut self, args: Vec<Vec<u8>>) -> Reply {
+        let command = String::from_utf8_lossy(&args[0]).to_uppercase();
+        let args: Vec<Vec<u8>> = args.into_iter().skip(1).collect();
+        counter!("resp_commands_total", 1, "command" => command_label(&command));
+        // A token that expired or was revoked since AUTH logs the connection out
+        if self.claims.as_ref().is_some_and(|claims| !still_valid(claims)) {
+            self.claims = None;
+        }
+
+        match command.as_str() {
+            "PING" => return match args.as_slice() {
+                [] => Reply::Simple("PONG"),
+                [message] => Reply::Bulk(message.clone()),
+                _ => wrong_arity(&command),
+            },
+            "AUTH" => return match args.as_slice() {
+                [token] | [_, token] => self.auth(token),
+                _ => wrong_arity(&command),
+            },
+            "HELLO" => return self.hello(&args),
+            _ => {}
+        }
+        let Some(claims) = &self.claims else {
+            return Reply::Error("NOAUTH Authentication required.".to_string());
+        };
+        if let Some((action, keys)) = access_of(&command, &args)
+            && let Err(e) = keys.iter().try_for_each(|key| authorize(claims, action, DEFAULT_NAMESPACE, key)) {
+            return e.into();
+        }
+
+        let result = match command.as_str() {
+            "GET" => match args.as_slice() {
+                [key] => get(key).await,
+                _ => Err(wrong_arity(&command)),
+            },
+            "SET" if args.len() >= 2 => set(args).await,
+            "DEL" if !args.is_empty() => del(args).await,
+            "EXISTS" if !args.is_empty() => exists(args).await,
+            "MGET" if !args.is_empty() => mget(args).await,
+            "MSET" if !args.is_empty() && args.len().is_multiple_of(2) => mset(args).await,
+            "INCR" | "DECR" => match args.as_slice() {
+                [key] => incr(key, if command == "INCR" { 1 } else { -1 }).await,
+                _ => Err(wrong_arity(&command)),
+            },
+            "INCRBY" | "DECRBY" => match args.as_slice() {
+                [key, by] => match parse_int(by) {
+                    Ok(by) if command == "INCRBY" => incr(key, by).await,
+                    Ok(by) => match coordinator::negate(by) {
+                        Ok(delta) => incr(key, delta).await,
+                        Err(_) => Err(Reply::err("decrement would overflow")),
+                    },
+                    Err(e) => Err(e),
+                },
+                _ => Err(wrong_arity(&command)),
+            },
+            "EXPIRE" => match args.as_slice() {
+                [key, seconds] => expire(key, seconds).await,
+                _ => Err(wrong_arity(&command)),
+            },
+            "TTL" => match args.as_slice() {
+                [key] => ttl(key).await,
+                _ => Err(wrong_arity(&command)),
+            },
+            "SCAN" if !args.is_empty() => self.scan(args).await,
+            "SET" | "DEL" | "EXISTS" | "MGET" | "MSET" | "SCAN" => Err(wrong_arity(&command)),
+            _ => Err(Reply::err(format!("unknown command '{}'", command.to_lowercase()))),
+        };
+        result.unwrap_or_else(|e| e)
+    }
+
+    fn auth(&mut self, token: &[u8]) -> Reply {
+        match std::str::from_utf8(token).ok().and_then(verify_credential) {
+            Some(claims) => {
+                self.claims = Some(claims);
+                Reply::ok()
+            }
+            None => Reply::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string()),
+        }
+    }
+
+    // HELLO [protover [AUTH username password] [SETNAME name]]
+    fn hello(&mut self, args: &[Vec<u8>]) -> Reply {
+        let mut resp3 = self.resp3;
+        if let Some(version) = args.first() {
+            match parse_int(version) {
+                Ok(2) => resp3 = false,
+                Ok(3) => resp3 = true,
+                _ => return Reply::Error("NOPROTO unsupported protocol version".to_string()),
+            }
+        }
+        let mut i = 1;
+        while i < args.len() {
+            match String::from_utf8_lossy(&args[i]).to_uppercase().as_str() {
+                "AUTH" if i + 2 < args.len() => {
+                    if let error @ Reply::Error(_) = self.auth(&args[i + 2]) {
+                        return error;
+                    }
+                    i += 3;
+                }
+                // Client names aren't tracked
+                "SETNAME" if i + 1 < args.len() => i += 2,
+                _ => return Reply::err("syntax error in HELLO option"),
+            }
+        }
+        if self.claims.is_none() {
+            return Reply::Error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".to_string());
+        }
+
+        self.resp3 = resp3;
+        Reply::Map(vec![
+            (Reply::text("server"), Reply::text("kv-store")),
+            (Reply::text("version"), Reply::text(env!("CARGO_PKG_VERSION"))),
+            (Reply::text("proto"), Reply::Integer(if resp3 { 3 } else { 2 })),
+            (Reply::text("mode"), Reply::text("standalone")),
+            (Reply::text("role"), Reply::text("master")),
+            (Reply::text("modules"), Reply::Array(Vec::new())),
+        ])
+    }
+
+    // SCAN cursor [MATCH pattern] [COUNT count]; cursors are per connection, 0 starts and ends a scan
+    async fn scan(&mut self, args: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+        let invalid_cursor = || Reply::err("invalid cursor");
+        let cursor = std::str::from_utf8(&args[0]).ok().and_then(|c| c.parse::<u64>().ok()).ok_or_else(invalid_cursor)?;
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut i = 1;
+        while i < args.len() {
+            match (String::from_utf8_lossy(&args[i]).to_uppercase().as_str(), args.get(i + 1)) {
+                ("MATCH", Some(glob)) => pattern = Some(parse_glob(glob)),
+                ("COUNT", Some(n)) => {
+                    count = parse_int(n)
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| Reply::err("syntax error"))? as usize;
+                }
+                _ => return Err(Reply::err("syntax error")),
+            }
+            i += 2;
+        }
+
+        let after = match cursor {
+            0 => None,
+            cursor => Some(self.scans.get(&cursor).cloned().ok_or_else(invalid_cursor)?),
+        };
+        let range = KeyRange {
+            prefix: pattern.as_deref().map(glob_prefix).filter(|prefix| !prefix.is_empty()),
+            start: None,
+            end: None,
+            after,
+        };
+        if let Some(claims) = &self.claims {
+            authorize(claims, Action::Read, DEFAULT_NAMESPACE, range.prefix.as_deref().unwrap_or_default())?;
+        }
+        let page = coordinator::scan(DEFAULT_NAMESPACE, range, count.min(MAX_SCAN_LIMIT)).await?;
+
+        let next = match page.items.last() {
+            Some((last, _)) if page.more => {
+                self.next_scan += 1;
+                self.scans.insert(self.next_scan, last.clone());
+                if self.scans.len() > MAX_OPEN_SCANS {
+                    self.scans.pop_first();
+                }
+                self.next_scan
+            }
+            _ => 0,
+        };
+        let keys = page.items.into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| pattern.as_deref().is_none_or(|tokens| glob_match(tokens, key)))
+            .map(Reply::Bulk)
+            .collect();
+        Ok(Reply::Array(vec![Reply::text(&next.to_string()), Reply::Array(keys)]))
+    }
+}
+
+// Known commands by name, anything else as one label so clients can't blow up the metric
+fn command_label(command: &str) -> &'static str {
+    const KNOWN: [&str; 16] = ["GET", "SET", "DEL", "EXISTS", "MGET", "MSET", "INCR", "DECR", "INCRBY", "DECRBY",
+        "EXPIRE", "TTL", "SCAN", "PING", "AUTH", "HELLO"];
+    KNOWN.iter().find(|known| **known == command).copied().unwrap_or("OTHER")
+}
+
+async fn get(key: &[u8]) -> Result<Reply, Reply> {
+    match coordinator::get(DEFAULT_NAMESPACE, key, Consistency::Eventual).await {
+        Ok(found) => Ok(Reply::Bulk(found.value.to_vec())),
+        Err(KvError::NotFound) => Ok(Reply::Null),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// SET key value [NX|XX] [EX seconds|PX milliseconds]; NX/XX that don't apply reply nil
+async fn set(args: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+    let mut args = args.into_iter();
+    let (Some(key), Some(value)) = (args.next(), args.next()) else { return Err(wrong_arity("SET")) };
+    let options: Vec<Vec<u8>> = args.collect();
+    let mut mode = WriteMode::Upsert;
+    let mut expires_at = None;
+    let mut i = 0;
+    while i < options.len() {
+        match String::from_utf8_lossy(&options[i]).to_uppercase().as_str() {
+            "NX" if mode == WriteMode::Upsert => mode = WriteMode::CreateOnly,
+            "XX" if mode == WriteMode::Upsert => mode = WriteMode::UpdateOnly,
+            unit @ ("EX" | "PX") if expires_at.is_none() && i + 1 < options.len() => {
+                let scale = if unit == "EX" { 1000 } else { 1 };
+                let ms = parse_int(&options[i + 1])?
+                    .checked_mul(scale)
+                    .filter(|ms| *ms > 0)
+                    .ok_or_else(|| Reply::err("invalid expire time in 'set' command"))?;
+                expires_at = Some(now_ms().saturating_add(ms as u64));
+                i += 1;
+            }
+            _ => return Err(Reply::err("syntax error")),
+        }
+        i += 1;
+    }
+
+    let options = PutOptions { content_type: None, mode, condition: None, expires_at };
+    match coordinator::put(DEFAULT_NAMESPACE, &key, value, options, Consistency::Eventual).await {
+        Ok(_) => Ok(Reply::ok()),
+        Err(KvError::Conflict(_)) if mode == WriteMode::CreateOnly => Ok(Reply::Null),
+        Err(KvError::NotFound) if mode == WriteMode::UpdateOnly => Ok(Reply::Null),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn del(keys: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+    let mut deleted = 0;
+    for chunk in keys.chunks(MAX_BATCH_KEYS) {
+        let items = chunk.iter().map(|key| (key.clone(), None)).collect();
+        for result in coordinator::batch_delete(DEFAULT_NAMESPACE, items, Consistency::Eventual).await? {
+            match result {
+                Ok(ApplyOutcome::Deleted) => deleted += 1,
+                Ok(_) | Err(KvError::NotFound) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(Reply::Integer(deleted))
+}
+
+// Values in key order, nil for missing keys
+async fn read_many(keys: Vec<Vec<u8>>) -> Result<Vec<Option<Vec<u8>>>, Reply> {
+    let mut values = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(MAX_BATCH_KEYS) {
+        for result in coordinator::batch_get(DEFAULT_NAMESPACE, chunk.to_vec(), Consistency::Eventual).await? {
+            match result {
+                Ok(found) => values.push(Some(found.value.to_vec())),
+                Err(KvError::NotFound) => values.push(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Ok(values)
+}
+
+// A key given twice counts twice, as in Redis
+async fn exists(keys: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+    Ok(Reply::Integer(read_many(keys).await?.iter().filter(|value| value.is_some()).count() as i64))
+}
+
+async fn mget(keys: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+    Ok(Reply::Array(read_many(keys).await?.into_iter().map(|value| value.map_or(Reply::Null, Reply::Bulk)).collect()))
+}
+
+async fn mset(args: Vec<Vec<u8>>) -> Result<Reply, Reply> {
+    let mut pairs = args.into_iter();
+    let mut items = Vec::new();
+    while let (Some(key), Some(value)) = (pairs.next(), pairs.next()) {
+        items.push((key, value, PutOptions::default()));
+    }
+    while !items.is_empty() {
+        let rest = items.split_off(items.len().min(MAX_BATCH_KEYS));
+        for result in coordinator::batch_put(DEFAULT_NAMESPACE, items, Consistency::Eventual).await? {
+            result?;
+        }
+        items = rest;
+    }
+    Ok(Reply::ok())
+}
+
+async fn incr(key: &[u8], delta: i64) -> Result<Reply, Reply> {
+    Ok(Reply::Integer(coordinator::incr(DEFAULT_NAMESPACE, key, delta, Consistency::Eventual).await?))
+}
+
+// 1 if the key exists; a non-positive TTL deletes it, as in Redis
+async fn expire(key: &[u8], seconds: &[u8]) -> Result<Reply, Reply> {
+    let seconds = parse_int(seconds)?;
+    let result = if seconds <= 0 {
+        coordinator::delete(DEFAULT_NAMESPACE, key, None, Consistency::Eventual).await
+    } else {
+        let ms = seconds.checked_mul(1000).ok_or_else(|| Reply::err("invalid expire time in 'expire' command"))?;
+        coordinator::expire(DEFAULT_NAMESPACE, key, Some(now_ms().saturating_add(ms as u64)), Consistency::Eventual).await
+    };
+    match result {
+        Ok(()) => Ok(Reply::Integer(1)),
+        Err(KvError::NotFound) => Ok(Reply::Integer(0)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Seconds left, -1 without expiry, -2 for a missing key
+async fn ttl(key: &[u8]) -> Result<Reply, Reply> {
+    match coordinator::get(DEFAULT_NAMESPACE, key, Consistency::Eventual).await {
+        Ok(found) => Ok(Reply::Integer(match found.meta.expires_at {
+            None => -1,
+            Some(at) => (at.saturating_sub(now_ms()).div_ceil(1000)) as i64,
+        })),
+        Err(KvError::NotFound) => Ok(Reply::Integer(-2)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Replies are buffered and flushed once the client has no more pipelined commands waiting
+async fn serve_connection(stream: TcpStream, mut stop: watch::Receiver<bool>) -> io::Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    let mut session = Session::default();
+    let mut out = Vec::new();
+    loop {
+        let args = tokio::select! {
+            args = read_command(&mut reader, session.claims.is_some()) => args,
+            _ = stop.changed() => return Ok(()),
+        };
+        let args = match args {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                out.clear();
+                Reply::err(e.to_string()).encode(session.resp3, &mut out);
+                writer.write_all(&out).await?;
+                return writer.flush().await;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        let reply = if quit { Reply::ok() } else { session.execute(args).await };
+        out.clear();
+        reply.encode(session.resp3, &mut out);
+        writer.write_all(&out).await?;
+        if quit {
+            return writer.flush().await;
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
+    }
+}
+
+// Accepts connections until `stop` is set
+pub async fn redis_listener(port: u16, stop: watch::Receiver<bool>) {
+    let listener = match TcpListener::bind(("0.0.0.0", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Redis listener could not bind port {}: {}", port, e);
+            return;
+        }
+    };
+    println!("Redis protocol listening on port {}", port);
+    let mut stopping = stop.clone();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    eprintln!("Redis accept failed: {}", e);
+                    continue;
+                }
+            },
+            _ = stopping.changed() => return,
+        };
+        stream.set_nodelay(true).ok();
+        let stop = stop.clone();
+        tokio::spawn(async move {
+            increment_gauge!("resp_connections", 1.0);
+            if let Err(e) = serve_connection(stream, stop).await {
+                eprintln!("Redis connection {} failed: {}", peer, e);
+            }
+            decrement_gauge!("resp_connections", 1.0);
+        });
+    }
+}
@@ -0,0 +1,160 @@
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use crate::access::Action;
+use crate::middleware::types::Claims;
+use crate::tokens::revoke_token;
+use crate::users::Role;
+use super::{access_of, glob_match, glob_prefix, parse_glob, read_command, serve_connection, Reply, Session, MAX_UNAUTHENTICATED_BULK_LEN};
+
+async fn read_all(mut input: &[u8], authenticated: bool) -> io::Result<Vec<Vec<Vec<u8>>>> {
+    let mut commands = Vec::new();
+    while let Some(args) = read_command(&mut input, authenticated).await? {
+        commands.push(args);
+    }
+    Ok(commands)
+}
+
+fn args(args: &[&str]) -> Vec<Vec<u8>> {
+    args.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+}
+
+fn encoded(reply: &Reply, resp3: bool) -> String {
+    let mut out = Vec::new();
+    reply.encode(resp3, &mut out);
+    String::from_utf8(out).unwrap()
+}
+
+fn bulk(len: usize) -> Vec<u8> {
+    let mut frame = format!("*2\r\n$3\r\nGET\r\n${}\r\n", len).into_bytes();
+    frame.extend(std::iter::repeat_n(b'k', len));
+    frame.extend_from_slice(b"\r\n");
+    frame
+}
+
+#[tokio::test]
+async fn reads_arrays_and_inline_commands() {
+    let input = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\na\r\nb\r\n\r\nPING  hello\n*0\r\nGET k\r\n";
+    let commands = read_all(input, true).await.unwrap();
+    // Bulk strings are binary safe, blank lines and empty arrays are skipped, inline lines split on whitespace
+    assert_eq!(commands, vec![args(&["SET", "k", "a\r\nb"]), args(&["PING", "hello"]), args(&["GET", "k"])]);
+}
+
+#[tokio::test]
+async fn rejects_malformed_frames() {
+    for input in [&b"*1\r\n$3\r\nGETX\r\n"[..], b"*1\r\n+GET\r\n", b"*x\r\n", b"*2\r\n$3\r\nGET\r\n"] {
+        let e = read_all(input, true).await.unwrap_err();
+        assert!(matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof), "{:?}: {}", input, e);
+    }
+    let e = read_all(b"*1\r\n$3\r\nGETX\r\n", true).await.unwrap_err();
+    assert!(e.to_string().contains("not terminated by CRLF"));
+}
+
+#[tokio::test]
+async fn caps_frames_until_authenticated() {
+    let many = format!("*11\r\n{}", "$1\r\nk\r\n".repeat(11));
+    assert!(read_all(many.as_bytes(), false).await.is_err());
+    assert_eq!(read_all(many.as_bytes(), true).await.unwrap()[0].len(), 11);
+
+    assert_eq!(read_all(&bulk(MAX_UNAUTHENTICATED_BULK_LEN), false).await.unwrap()[0][1].len(), MAX_UNAUTHENTICATED_BULK_LEN);
+    // Refused from the header alone, before anything is allocated for the body
+    let big = bulk(MAX_UNAUTHENTICATED_BULK_LEN + 1);
+    let e = read_all(&big, false).await.unwrap_err();
+    assert!(e.to_string().contains("invalid length"));
+    assert_eq!(read_all(&big, true).await.unwrap()[0][1].len(), MAX_UNAUTHENTICATED_BULK_LEN + 1);
+}
+
+#[tokio::test]
+async fn a_protocol_error_is_the_last_reply() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (stream, _) = listener.accept().await.unwrap();
+    let (_stop_tx, stop) = watch::channel(false);
+    let server = tokio::spawn(serve_connection(stream, stop));
+
+    // Sent together, so the PONG is still unflushed when the bad frame is read
+    client.write_all(b"PING\r\n*1\r\n$3\r\nGETX\r\n").await.unwrap();
+    let mut replies = String::new();
+    client.read_to_string(&mut replies).await.unwrap();
+    assert_eq!(replies, "+PONG\r\n-ERR Protocol error: bulk string not terminated by CRLF\r\n");
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn refuses_commands_before_auth() {
+    let mut session = Session::default();
+    assert!(matches!(session.execute(args(&["PING"])).await, Reply::Simple("PONG")));
+    for command in [&["GET", "k"][..], &["SET", "k", "v"], &["SCAN", "0"], &["NOSUCH"]] {
+        match session.execute(args(command)).await {
+            Reply::Error(message) => assert!(message.starts_with("NOAUTH"), "{}", message),
+            _ => panic!("{:?} ran without AUTH", command),
+        }
+    }
+    match session.execute(args(&["AUTH", "not-a-token"])).await {
+        Reply::Error(message) => assert!(message.starts_with("WRONGPASS"), "{}", message),
+        _ => panic!("a bad token was accepted"),
+    }
+    match session.execute(args(&["HELLO", "3"])).await {
+        Reply::Error(message) => assert!(message.starts_with("NOAUTH"), "{}", message),
+        _ => panic!("HELLO succeeded without AUTH"),
+    }
+    assert!(session.claims.is_none());
+    assert!(!session.resp3);
+}
+
+#[tokio::test]
+async fn a_revoked_login_ends_with_the_next_command() {
+    let jti = format!("jti-{:016x}", rand::random::<u64>());
+    let claims = Claims { email: "admin@x".to_string(), role: Role::Admin, grants: Vec::new(), exp: usize::MAX, jti: jti.clone(), sid: "sid".to_string() };
+    let mut session = Session { claims: Some(claims), ..Session::default() };
+    revoke_token(&jti).unwrap();
+    match session.execute(args(&["GET", "k"])).await {
+        Reply::Error(message) => assert!(message.starts_with("NOAUTH"), "{}", message),
+        _ => panic!("a revoked token was still accepted"),
+    }
+    assert!(session.claims.is_none());
+}
+
+#[test]
+fn maps_commands_to_the_keys_they_touch() {
+    let mset = args(&["a", "1", "b", "2"]);
+    let (action, keys) = access_of("MSET", &mset).unwrap();
+    assert_eq!((action, keys), (Action::Write, vec![&b"a"[..], b"b"]));
+    let set = args(&["k", "v", "EX", "10"]);
+    assert_eq!(access_of("SET", &set).unwrap().1, vec![&b"k"[..]]);
+    assert_eq!(access_of("DEL", &args(&["a", "b"])).unwrap().0, Action::Delete);
+    assert!(access_of("SCAN", &args(&["0"])).is_none());
+}
+
+#[test]
+fn matches_globs() {
+    let cases: &[(&str, &str, bool)] = &[
+        ("user:*", "user:42", true),
+        ("user:*", "users", false),
+        ("h?llo", "hallo", true),
+        ("h[ae]llo", "hillo", false),
+        ("h[^e]llo", "hallo", true),
+        ("h[a-c]llo", "hbllo", true),
+        ("*:x:*", "a:x:b", true),
+        ("a\\*", "a*", true),
+        ("a\\*", "ab", false),
+        ("[open", "[open", true),
+    ];
+    for (pattern, key, expected) in cases {
+        assert_eq!(glob_match(&parse_glob(pattern.as_bytes()), key.as_bytes()), *expected, "{} vs {}", pattern, key);
+    }
+    assert_eq!(glob_prefix(&parse_glob(b"user:\\*x*")), b"user:*x");
+    assert_eq!(glob_prefix(&parse_glob(b"?x")), b"");
+}
+
+#[test]
+fn encodes_resp2_and_resp3() {
+    assert_eq!(encoded(&Reply::Null, false), "$-1\r\n");
+    assert_eq!(encoded(&Reply::Null, true), "_\r\n");
+    let map = Reply::Map(vec![(Reply::text("proto"), Reply::Integer(3))]);
+    assert_eq!(encoded(&map, false), "*2\r\n$5\r\nproto\r\n:3\r\n");
+    assert_eq!(encoded(&map, true), "%1\r\n$5\r\nproto\r\n:3\r\n");
+    // Line breaks would end the error line early
+    assert_eq!(encoded(&Reply::err("bad\r\nthing"), false), "-ERR bad  thing\r\n");
+}
@@ -1,129 +1,393 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
 use std::time::Duration;
-use tokio::sync::mpsc::Receiver;
+use metrics::{counter, gauge};
+use once_cell::sync::Lazy;
+use sled::{transaction::TransactionError, Db, Transactional};
+use tokio::{sync::watch, task::JoinSet, time::Instant};
 use crate::{config::HASH_RING, routes_resp::{Wal, WalOp}};
+use crate::namespace;
+use crate::store::{self, apply_op};
+use crate::wal::{append_wal_batch, subscribe_wal, wal_head, WalTail};
 
-pub async fn replication_worker(mut rx: Receiver<Wal>) {
-    while let Some(entry) = rx.recv().await {
-        match &entry.opration {
-            WalOp::Set { key, value } => {
-                // Get node IDs first
-                let all_node_ids = {
-                    let ring = HASH_RING.read().unwrap();
-                    ring.get_follower_node_ids(&key)
-                };
-                
-                let mut success_count = 0;
-                let mut total_attempts = 0;
-                
-                // Replicate to all nodes
-                for node_id in &all_node_ids {
-                    total_attempts += 1;
-                    let mut retries = 3;
-                    
-                    while retries > 0 {
-                        // Get node and perform operation within lock scope
-                        let operation_result = {
-                            let ring = HASH_RING.read().unwrap();
-                            if let Some(node) = ring.get_node(node_id) {
-                                let result = node.db.insert(key.as_bytes(), value.as_bytes());
-                                if result.is_ok() {
-                                    node.db.flush().is_ok()
-                                } else {
-                                    false
-                                }
-                            } else {
-                                false
-                            }
-                        };
-                        
-                        if operation_result {
-                            success_count += 1;
-                            println!("Set operation replicated to node: {}", node_id);
-                            break;
-                        } else {
-                            println!("Replication failed for node {}", node_id);
-                        }
-                        
-                        retries -= 1;
-                        if retries > 0 {
-                            tokio::time::sleep(Duration::from_millis(500)).await;
-                        }
+// Per-node tree holding replication bookkeeping; "cursor" is the last WAL seq this node has processed
+const REPLICATION_TREE: &str = "__replication";
+const CURSOR_KEY: &[u8] = b"cursor";
+
+// Max WAL entries a replica reads ahead of its cursor; entries are still applied one at a time
+const READ_AHEAD: usize = 256;
+// Exponential backoff between attempts on the same entry: base * 2^(attempt-1), capped, full jitter
+const BACKOFF_BASE: Duration = Duration::from_millis(50);
+const BACKOFF_MAX: Duration = Duration::from_secs(10);
+// Fallback poll when no new WAL entries arrive; also the metrics refresh interval
+const IDLE_POLL: Duration = Duration::from_secs(1);
+// A write that has not reached a majority of its replica set within this window counts as a quorum failure
+const QUORUM_TIMEOUT: Duration = Duration::from_secs(5);
+// Writes still not acked by every follower after this long stop being tracked, so a follower that
+// keeps failing can't grow the pending map without bound
+const PENDING_EXPIRY: Duration = Duration::from_secs(5 * 60);
+
+/*
+Replication is driven by the WAL itself. Every node is a follower for some keys, so every node
+gets its own worker task with its own cursor and ordered queue. A worker tails the WAL from its
+cursor and applies the entries for which its node is a follower (per
+HashRing::get_follower_node_ids, sized by the entry's namespace). Applying an entry and moving the cursor happen in one sled
+transaction, so after a crash or restart a node resumes exactly at the first entry it has not
+applied yet. A failing node only backs off its own worker; the others keep going.
+*/
+enum ApplyError {
+    // Worth retrying (I/O); the entry stays at the head of the queue
+    Transient(String),
+    // Retrying will not help; the entry is skipped on this replica
+    Permanent(String),
+    // Node is no longer part of the ring, the worker exits
+    NodeGone,
+}
+
+// Replication progress of a single write across its followers; dropped once every follower is done with it
+struct PendingWrite {
+    key: Vec<u8>,
+    followers: Vec<String>,
+    acked: HashSet<String>,
+    failed: HashSet<String>,
+    written: Instant,
+    quorum: Option<bool>,
+}
+
+impl PendingWrite {
+    // The leader applied the write before it reached the WAL, so it always counts as one ack
+    fn acks(&self) -> usize {
+        self.acked.len() + 1
+    }
+
+    fn majority(&self) -> usize {
+        let replicas = self.followers.len() + 1;
+        replicas / 2 + 1
+    }
+
+    fn settle_quorum(&mut self) {
+        if self.quorum.is_some() {
+            return;
+        }
+        let outstanding = self.followers.len() - self.acked.len() - self.failed.len();
+        if self.acks() >= self.majority() {
+            self.quorum = Some(true);
+            counter!("replication_quorum_success_total", 1);
+        } else if self.acks() + outstanding < self.majority() || self.written.elapsed() > QUORUM_TIMEOUT {
+            self.quorum = Some(false);
+            counter!("replication_quorum_failure_total", 1);
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.acked.len() + self.failed.len() >= self.followers.len()
+    }
+}
+
+static PENDING_WRITES: Lazy<Mutex<HashMap<usize, PendingWrite>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Followers of an entry's key under its namespace's replication factor; none once the namespace is dropped
+fn followers_of(op: &WalOp) -> Vec<String> {
+    let Ok(ns) = namespace::get(op.namespace()) else { return Vec::new() };
+    HASH_RING.read().unwrap().get_follower_node_ids(op.key(), ns.replication_factor)
+}
+
+fn pending_write_for(entry: &Wal) -> PendingWrite {
+    let key = entry.opration.key().to_vec();
+    let followers = followers_of(&entry.opration);
+    PendingWrite {
+        key,
+        followers,
+        acked: HashSet::new(),
+        failed: HashSet::new(),
+        written: entry.time,
+        quorum: None,
+    }
+}
+
+// Called for every new WAL entry so writes nobody has picked up yet still count as under-replicated
+fn track_write(entry: &Wal) {
+    let mut pending = PENDING_WRITES.lock().unwrap();
+    pending.entry(entry.sequence_number).or_insert_with(|| pending_write_for(entry));
+}
+
+// Writes go to the WAL through here, so their replication is tracked from the start
+pub fn log_writes(oprations: Vec<(WalOp, Option<u64>)>) -> std::io::Result<Vec<Wal>> {
+    let entries = append_wal_batch(oprations)?;
+    entries.iter().for_each(track_write);
+    Ok(entries)
+}
+
+// A worker that exited will never ack: count its node as failed for everything still outstanding
+fn forget_node(node_id: &str) {
+    let mut pending = PENDING_WRITES.lock().unwrap();
+    pending.retain(|_, write| {
+        if write.followers.iter().any(|id| id == node_id) && !write.acked.contains(node_id) {
+            write.failed.insert(node_id.to_string());
+            write.settle_quorum();
+        }
+        !write.is_done()
+    });
+}
+
+fn record_outcome(entry: &Wal, node_id: &str, applied: bool) {
+    let mut pending = PENDING_WRITES.lock().unwrap();
+    // Entries replayed after a restart were never tracked, start tracking them now
+    let write = pending.entry(entry.sequence_number).or_insert_with(|| pending_write_for(entry));
+    if applied {
+        write.acked.insert(node_id.to_string());
+    } else {
+        write.failed.insert(node_id.to_string());
+    }
+    write.settle_quorum();
+    if write.is_done() {
+        pending.remove(&entry.sequence_number);
+    }
+}
+
+// Time out stuck quorums and publish how many distinct keys are missing from at least one follower
+fn refresh_quorum_metrics() {
+    let mut pending = PENDING_WRITES.lock().unwrap();
+    let tracked = pending.len();
+    pending.retain(|_, write| write.written.elapsed() < PENDING_EXPIRY);
+    if pending.len() < tracked {
+        counter!("replication_pending_expired_total", (tracked - pending.len()) as u64);
+    }
+    let mut under_replicated = HashSet::new();
+    for write in pending.values_mut() {
+        write.settle_quorum();
+        under_replicated.insert(write.key.as_slice());
+    }
+    gauge!("replication_under_replicated_keys", under_replicated.len() as f64);
+    gauge!("replication_pending_writes", pending.len() as f64);
+}
+
+struct ReplicaWorker {
+    node_id: String,
+    cursor: usize,
+    tail: WalTail,
+    queue: VecDeque<Wal>,
+    attempts: u32,
+}
+
+impl ReplicaWorker {
+    fn new(node_id: String, cursor: usize) -> Self {
+        ReplicaWorker { node_id, cursor, tail: WalTail::new(), queue: VecDeque::new(), attempts: 0 }
+    }
+
+    fn fill_queue(&mut self) {
+        let room = READ_AHEAD.saturating_sub(self.queue.len());
+        if room == 0 {
+            return;
+        }
+        let cursor = self.cursor;
+        self.queue.extend(self.tail.read_new(room).into_iter().filter(|e| e.sequence_number > cursor));
+    }
+
+    // Apply queued entries in order until the WAL is exhausted or an entry fails
+    fn drain(&mut self) -> Result<(), ApplyError> {
+        let start_cursor = self.cursor;
+        let result = loop {
+            self.fill_queue();
+            let Some(entry) = self.queue.front() else { break Ok(()) };
+
+            // Entries of a dropped namespace are skipped, applying them would recreate its trees
+            if followers_of(&entry.opration).contains(&self.node_id) {
+                match apply_entry(&self.node_id, entry) {
+                    Ok(_) => {
+                        println!("{} replicated to node: {} (seq={}) in {:?}",
+                            op_name(&entry.opration), self.node_id, entry.sequence_number, entry.time.elapsed());
+                        record_outcome(entry, &self.node_id, true);
+                        self.attempts = 0;
                     }
-                    
-                    if retries == 0 {
-                        println!("Failed to replicate SET to node {} after 3 retries", node_id);
+                    Err(ApplyError::Permanent(e)) => {
+                        eprintln!("Giving up on seq {} for node {}: {}", entry.sequence_number, self.node_id, e);
+                        counter!("replication_permanent_failures_total", 1, "replica" => self.node_id.clone());
+                        record_outcome(entry, &self.node_id, false);
+                        self.attempts = 0;
                     }
+                    Err(e) => break Err(e),
                 }
-                
-                let success_ratio = success_count as f32 / total_attempts as f32;
-                if success_ratio >= 0.5 { // Majority success
-                    println!("Set operation replicated successfully for key: {} ({}/{} nodes)", 
-                            key, success_count, total_attempts);
-                } else {
-                    println!("Set operation failed for key: {} ({}/{} nodes)", 
-                            key, success_count, total_attempts);
-                }
-            },
-            
-            WalOp::Delete { key } => {
-                // Get node IDs first
-                let all_node_ids = {
-                    let ring = HASH_RING.read().unwrap();
-                    ring.get_follower_node_ids(&key)
-                };
-                
-                let mut success_count = 0;
-                let mut total_attempts = 0;
-                
-                // Replicate delete to all nodes
-                for node_id in &all_node_ids {
-                    total_attempts += 1;
-                    let mut retries = 3;
-                    
-                    while retries > 0 {
-                        // Get node and perform operation within lock scope
-                        let operation_result = {
-                            let ring = HASH_RING.read().unwrap();
-                            if let Some(node) = ring.get_node(node_id) {
-                                let result = node.db.remove(key.as_bytes());
-                                if result.is_ok() {
-                                    node.db.flush().is_ok()
-                                } else {
-                                    false
-                                }
-                            } else {
-                                false
-                            }
-                        };
-                        
-                        if operation_result {
-                            success_count += 1;
-                            println!("Delete operation replicated to node: {}", node_id);
-                            break;
-                        } else {
-                            println!("Delete replication failed for node {}", node_id);
-                        }
-                        
-                        retries -= 1;
-                        if retries > 0 {
-                            tokio::time::sleep(Duration::from_millis(500)).await;
-                        }
-                    }
-                    
-                    if retries == 0 {
-                        println!("Failed to replicate DELETE to node {} after 3 retries", node_id);
-                    }
+            }
+            self.cursor = entry.sequence_number;
+            self.queue.pop_front();
+        };
+
+        // Applied entries moved the cursor transactionally; skipped ones are persisted here
+        if self.cursor != start_cursor
+            && let Err(e) = self.store_cursor() {
+            eprintln!("Failed to persist replication cursor for {}: {}", self.node_id, e);
+        }
+        result
+    }
+
+    fn report_metrics(&self) {
+        let replica = self.node_id.clone();
+        let lag_seconds = self.queue.front().map(|e| e.time.elapsed().as_secs_f64()).unwrap_or(0.0);
+        gauge!("replication_queue_depth", self.queue.len() as f64, "replica" => replica.clone());
+        gauge!("replication_last_applied_seq", self.cursor as f64, "replica" => replica.clone());
+        gauge!("replication_lag_entries", wal_head().saturating_sub(self.cursor) as f64, "replica" => replica.clone());
+        gauge!("replication_lag_seconds", lag_seconds, "replica" => replica);
+    }
+
+    fn next_backoff(&mut self) -> Duration {
+        self.attempts += 1;
+        counter!("replication_retries_total", 1, "replica" => self.node_id.clone());
+        let exp = BACKOFF_BASE.saturating_mul(1 << self.attempts.min(16).saturating_sub(1)).min(BACKOFF_MAX);
+        Duration::from_millis(rand::random_range(0..=exp.as_millis() as u64))
+    }
+
+    fn store_cursor(&self) -> sled::Result<()> {
+        let ring = HASH_RING.read().unwrap();
+        let node = ring.get_node_by_id(&self.node_id)
+            .ok_or_else(|| sled::Error::Unsupported(format!("unknown node {}", self.node_id)))?;
+        node.db.open_tree(REPLICATION_TREE)?.insert(CURSOR_KEY, &(self.cursor as u64).to_be_bytes())?;
+        Ok(())
+    }
+}
+
+fn op_name(op: &WalOp) -> &'static str {
+    match op {
+        WalOp::Set { .. } => "Set",
+        WalOp::Delete { .. } => "Delete",
+        WalOp::Expire { .. } => "Expire",
+        WalOp::Incr { .. } => "Incr",
+    }
+}
+
+fn load_cursor(db: &Db) -> usize {
+    db.open_tree(REPLICATION_TREE)
+        .ok()
+        .and_then(|tree| tree.get(CURSOR_KEY).ok().flatten())
+        .and_then(|v| v.as_ref().try_into().ok().map(u64::from_be_bytes))
+        .unwrap_or(0) as usize
+}
+
+impl From<sled::Error> for ApplyError {
+    fn from(e: sled::Error) -> Self {
+        match e {
+            sled::Error::Io(_) => ApplyError::Transient(e.to_string()),
+            _ => ApplyError::Permanent(e.to_string()),
+        }
+    }
+}
+
+// Apply the operation and advance the node's cursor atomically
+fn apply_entry(node_id: &str, entry: &Wal) -> Result<(), ApplyError> {
+    let ring = HASH_RING.read().unwrap();
+    let node = ring.get_node_by_id(node_id).ok_or(ApplyError::NodeGone)?;
+    let replication = node.db.open_tree(REPLICATION_TREE)?;
+    let trees = store::trees(&node.db, entry.opration.namespace())?;
+    let cursor = (entry.sequence_number as u64).to_be_bytes();
+
+    (&trees.data, &trees.meta, &replication)
+        .transaction(|(data, meta, replication)| {
+            apply_op::<()>(data, meta, &entry.opration)?;
+            replication.insert(CURSOR_KEY, &cursor)?;
+            Ok(())
+        })
+        .map_err(|e| match e {
+            TransactionError::Storage(e) => ApplyError::from(e),
+            TransactionError::Abort(_) => ApplyError::Permanent("transaction aborted".to_string()),
+        })?;
+    node.db.flush()?;
+    Ok(())
+}
+
+// How many WAL entries each node has not processed yet
+pub fn replication_lag() -> Vec<(String, usize)> {
+    let head = wal_head();
+    let ring = HASH_RING.read().unwrap();
+    ring.get_all_node_ids()
+        .into_iter()
+        .filter_map(|id| {
+            let cursor = load_cursor(&ring.get_node_by_id(&id)?.db);
+            Some((id, head.saturating_sub(cursor)))
+        })
+        .collect()
+}
+
+// Tails the WAL for one replica until `stop` is set, then catches up to the head (if the node is healthy) and returns
+async fn replica_worker(mut worker: ReplicaWorker, mut stop: watch::Receiver<bool>) {
+    let mut head = subscribe_wal();
+    loop {
+        let stopping = *stop.borrow();
+        // Applying fsyncs every entry and catching up can take a while, so keep it off the runtime
+        let drained = tokio::task::spawn_blocking(move || {
+            let result = worker.drain();
+            (worker, result)
+        }).await;
+        let result = match drained {
+            Ok((returned, result)) => {
+                worker = returned;
+                result
+            }
+            Err(e) => {
+                eprintln!("Replication worker panicked: {}", e);
+                return;
+            }
+        };
+        worker.report_metrics();
+        match result {
+            Ok(()) if stopping => return,
+            Ok(()) => {
+                tokio::select! {
+                    _ = head.changed() => {}
+                    _ = stop.changed() => {}
+                    _ = tokio::time::sleep(IDLE_POLL) => {}
                 }
-                
-                let success_ratio = success_count as f32 / total_attempts as f32;
-                if success_ratio >= 0.5 { // Majority success
-                    println!("Delete operation replicated successfully for key: {} ({}/{} nodes)", 
-                            key, success_count, total_attempts);
-                } else {
-                    println!("Delete operation failed for key: {} ({}/{} nodes)", 
-                            key, success_count, total_attempts);
+            }
+            Err(ApplyError::NodeGone) => {
+                eprintln!("Node {} left the ring, stopping its replication worker", worker.node_id);
+                return;
+            }
+            // Cursor is durable, so a failing replica simply resumes on the next start
+            Err(_) if stopping => return,
+            Err(ApplyError::Transient(e)) | Err(ApplyError::Permanent(e)) => {
+                let delay = worker.next_backoff();
+                println!("Replication to node {} failed (attempt {}): {}, retrying in {:?}",
+                    worker.node_id, worker.attempts, e, delay);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = stop.changed() => {}
                 }
             }
         }
     }
 }
+
+// Spawns one worker per node and waits for all of them to finish after `stop` is set
+pub async fn replication_worker(stop: watch::Receiver<bool>) {
+    let workers: Vec<ReplicaWorker> = {
+        let ring = HASH_RING.read().unwrap();
+        ring.get_all_node_ids()
+            .into_iter()
+            .filter_map(|id| {
+                let cursor = load_cursor(&ring.get_node_by_id(&id)?.db);
+                Some(ReplicaWorker::new(id, cursor))
+            })
+            .collect()
+    };
+
+    let mut tasks = JoinSet::new();
+    for worker in workers {
+        let stop = stop.clone();
+        tasks.spawn(async move {
+            let node_id = worker.node_id.clone();
+            replica_worker(worker, stop).await;
+            forget_node(&node_id);
+        });
+    }
+    tasks.spawn(quorum_reporter(stop));
+    while tasks.join_next().await.is_some() {}
+}
+
+async fn quorum_reporter(mut stop: watch::Receiver<bool>) {
+    while !*stop.borrow() {
+        refresh_quorum_metrics();
+        tokio::select! {
+            _ = tokio::time::sleep(IDLE_POLL) => {}
+            _ = stop.changed() => {}
+        }
+    }
+}
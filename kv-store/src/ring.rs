@@ -17,9 +17,9 @@ use super::hashring::Hash;
 //     (hash_num % total_nodes as u64) as usize
 // }
 
-pub fn get_node_for_key(input: &str) -> Hash {
+pub fn get_node_for_key(input: &[u8]) -> Hash {
     let mut hasher = Sha256::new();
-    hasher.update(input.as_bytes());
+    hasher.update(input);
     let result = hasher.finalize();
     u64::from_be_bytes(result[..8].try_into().unwrap())
 }
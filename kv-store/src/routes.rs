@@ -8,272 +8,264 @@
 
 use metrics::{counter, histogram};
 use tokio::time::Instant;
-use std::env;
 
-use chrono::{Utc,Duration};
-use axum::{extract::Json, extract::State};
-use dotenv::dotenv;
-use crate::routes_resp::{Wal, WalOp};
+use axum::extract::{Extension, Json};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use crate::access::{authorize, Action};
+use crate::coordinator::{self, KvError, PutOptions};
+use crate::middleware::idempotency::mark_failure;
+use crate::routes_kv::ttl_response;
+use crate::routes_users::blocking;
+use crate::tokens::{self, Issued};
+use crate::store::ApplyOutcome;
+use crate::raft::{raft, RaftStatus};
+use std::collections::BTreeMap;
 
 use super::middleware::types;
 use super::routes_resp::{SetResponse, IncomingSetRequest,
     IncomingGetRequest,GetResponse,ErrorResponse,IncomingDeleteRequest,
-    DeleteResponse,LoginResponse,IncomingLoginRequest};
-use super::wal::{append_wal};
+    DeleteResponse,LoginResponse,IncomingLoginRequest,IncomingRefreshRequest,IncomingTtlRequest,TtlResponse,
+    IncomingIncrRequest,CounterResponse};
 use super::routes_resp::Status;
-use super::config::HASH_RING;
 use types::Claims;
-use jsonwebtoken::{encode, EncodingKey, Header};
 
 
+// Legacy JSON routes: thin wrappers over the coordinator that keep their original responses
+// (always HTTP 200), except that a request the caller's grants don't allow is a real 403
+
+fn legacy_error(route: &'static str, e: KvError, error: String) -> Response {
+    if matches!(e, KvError::Forbidden(_)) {
+        return e.into_response();
+    }
+    counter!("error_count", 1, "route" => route);
+    mark_failure(Json::from(ErrorResponse { status: Status::Error, error }).into_response(), &e)
+}
+
 pub async fn set_value(
-    State(tx): State<tokio::sync::mpsc::Sender<Wal>>,
+    Extension(claims): Extension<Claims>,
     Json(payload): Json<IncomingSetRequest>
-) -> Json<SetResponse> {
+) -> Result<Response, KvError> {
     let start=Instant::now();
     counter!("route_hit",1,"route"=>"set_value");
-    let key = payload.key;
-    let value = payload.value;
-    // let total_nodes = NODES.len();
+    let key = payload.key.clone();
+    let result = async {
+        let encoding = payload.encoding;
+        let expires_at = coordinator::expiry(payload.ttl_seconds, payload.expires_at)?;
+        let options = PutOptions { content_type: None, mode: payload.mode, condition: encoding.condition(payload.if_match)?, expires_at };
+        let value = encoding.decode("value", payload.value)?;
+        let key = encoding.decode("key", payload.key)?;
+        authorize(&claims, Action::Write, &payload.namespace, &key)?;
+        coordinator::put(&payload.namespace, &key, value, options, payload.consistency).await
+    }.await;
+    let elapsed=start.elapsed().as_secs_f64();
+    histogram!("request_duration_seconds",elapsed, "route" => "set_value");
 
-    // Check if key exists and insert if not
-    let operation_result = {
-        let ring = HASH_RING.read().unwrap();
-        match ring.get_node(&key) {
-            Some(leader) => {
-                // Check if key actually exists (not just if operation is successful)
-                match leader.db.get(key.as_bytes()) {
-                    Ok(Some(_)) => {
-                        // Key exists, return "already present"
-                        Err("Key already present")
-                    },
-                    Ok(None) => {
-                        // Key doesn't exist, insert it
-                        match leader.db.insert(key.as_bytes(), value.as_bytes()) {
-                            Ok(_) => {
-                                leader.db.flush().ok();
-                                Ok("Key inserted successfully")
-                            },
-                            Err(_) => Err("Failed to insert key")
-                        }
-                    },
-                    Err(_) => {
-                        // Database error
-                        Err("Database error")
-                    }
-                }
-            },
-            None => {
-                return Json::from(SetResponse {
-                    status: Status::Error,
-                    message: "No node available".to_string(),
-                });
-            }
+    Ok(match result {
+        Ok(ApplyOutcome::Updated) => Json::from(SetResponse {
+            status: Status::Success,
+            message: "key updated".to_string(),
+        }),
+        Ok(_) => Json::from(SetResponse {
+            status: Status::Success,
+            message: "key stored".to_string(),
+        }),
+        // create_only / update_only preconditions that did not hold: nothing was written
+        Err(e @ KvError::Forbidden(_)) => return Err(e),
+        Err(KvError::Conflict(_)) => Json::from(SetResponse {
+            status: Status::Error,
+            message: "key already present".to_string(),
+        }),
+        Err(KvError::NotFound) => Json::from(SetResponse {
+            status: Status::Error,
+            message: "key not present".to_string(),
+        }),
+        Err(KvError::PreconditionFailed(_)) => Json::from(SetResponse {
+            status: Status::Error,
+            message: "if_match did not match the current version or value".to_string(),
+        }),
+        Err(e) => {
+            counter!("error_count", 1, "route" => "set_value");
+            let response = Json::from(SetResponse {
+                status: Status::Error,
+                message: format!("Failed to set key '{}': {}", key, e),
+            });
+            return Ok(mark_failure(response.into_response(), &e));
         }
-    };
-    
-    let entry=Wal::new(WalOp::Set { key:key.clone(), value:value.clone() });
-     
-    match operation_result {
-        Ok(_message) => {
-            // Key was successfully inserted
-            if let Err(e) = append_wal(&entry) {
-                // Agar WAL write fail ho jaye, to safe hai request fail karna
-                return Json::from(SetResponse {
-                    status: Status::Error,
-                    message: format!("WAL disk write failed: {}", e),
-                });
-            }
-             
-            if tx.send(entry).await.is_err() {
-                eprintln!("WAL send failed");
-            }
-                
-            let elapsed=start.elapsed().as_secs_f64();
-            histogram!("request_duration_seconds",elapsed, "route" => "set_value");
-            Json::from(SetResponse {
+    }.into_response())
+}
+
+pub async fn get_value(Extension(claims): Extension<Claims>, Json(payload):Json<IncomingGetRequest>) -> Result<Json<GetResponse>, Response> {
+    let start=Instant::now();
+    counter!("route_hit",1,"route"=>"get_value");
+    let result = async {
+        let key = payload.encoding.decode("key", payload.key)?;
+        authorize(&claims, Action::Read, &payload.namespace, &key)?;
+        coordinator::get(&payload.namespace, &key, payload.consistency).await
+    }.await;
+    let elapsed = start.elapsed().as_secs_f64();
+    histogram!("request_duration_seconds", elapsed, "route" => "get_value");
+
+    match result {
+        Ok(found) => {
+            let encoding = payload.encoding.for_output(&[&found.value]);
+            Ok(Json::from(GetResponse {
                 status: Status::Success,
-                message: format!("key stored"),
-            })
-        },
-        Err(error_msg) => {
-            if error_msg == "Key already present" {
-                Json::from(SetResponse {
-                    status: Status::Success,
-                    message: format!("key already present"),
-                })
-            } else {
-                counter!("error_count", 1, "route" => "set_value");
-                Json::from(SetResponse {
-                    status: Status::Error,
-                    message: format!("Failed to set key '{}': {}", key, error_msg),
-                })
-            }
+                value: encoding.encode(&found.value),
+                encoding,
+                version: found.meta.version,
+            }))
+        }
+        Err(e) => {
+            let error = e.to_string();
+            Err(legacy_error("get_value", e, error))
         }
     }
-// }
 }
-// }
 
-pub async fn get_value(Json(payload):Json<IncomingGetRequest>) -> Result<Json<GetResponse>,  Json<ErrorResponse>> {
+pub async fn delete_value(
+    Extension(claims): Extension<Claims>,
+    Json(payload): Json<IncomingDeleteRequest>
+) -> Result<Json<DeleteResponse>, Response> {
     let start=Instant::now();
-    counter!("route_hit",1,"route"=>"get_value");   
-    let key = payload.key;
-    
-    // Try primary node first, then replicas
-    let result: Result<Result<Json<GetResponse>, Json<ErrorResponse>>, &'static str> = {
-        let ring = HASH_RING.read().unwrap();
-        let leader = match ring.get_node(&key) {
-            Some(n) => n,
-            None => {
-                return Err(Json::from(ErrorResponse {
-                    status: Status::Error,
-                    error: "No node available".to_string(),
-                }));
-            }
-        };
-        
-        // Try primary node first
-        match leader.db.get(key.clone().as_bytes()) {
-            Ok(Some(value)) => {
-                let value_str = String::from_utf8(value.to_vec()).unwrap_or_else(|_| "Invalid UTF-8".to_string());
-                return Ok(Json::from(GetResponse {
-                    status: Status::Success,
-                    value: value_str,
-                }));
-            }
-            Ok(None) => {
-                // Primary node doesn't have the key, try replicas
-                let all_node_ids = ring.get_follower_node_ids(&key);
-                
-                for replica_id in &all_node_ids {
-                    if *replica_id == leader.id {
-                        continue; // Skip the primary node we already tried
-                    }
-                    
-                    // Try to get the node from ring
-                    if let Some(replica_node) = ring.get_node(replica_id) {
-                        match replica_node.db.get(key.clone().as_bytes()) {
-                            Ok(Some(value)) => {
-                                let value_str = String::from_utf8(value.to_vec()).unwrap_or_else(|_| "Invalid UTF-8".to_string());
-                                println!("Found key '{}' in replica node '{}'", key, replica_id);
-                                return Ok(Json::from(GetResponse {
-                                    status: Status::Success,
-                                    value: value_str,
-                                }));
-                            },
-                            Ok(None) => continue, // Try next replica
-                            Err(_) => continue,   // Node might be down, try next
-                        }
-                    }
-                }
-                
-                // Key not found in any node
-                Err("Key not found in any node")
-            }
-            Err(_e) => Err("Database error")
+    counter!("route_hit",1,"route"=>"delete_value");
+    let key = payload.key.clone();
+    let result = async {
+        let condition = payload.encoding.condition(payload.if_match)?;
+        let key = payload.encoding.decode("key", payload.key)?;
+        authorize(&claims, Action::Delete, &payload.namespace, &key)?;
+        coordinator::delete(&payload.namespace, &key, condition, payload.consistency).await
+    }.await;
+    let elapsed=start.elapsed().as_secs_f64();
+    histogram!("request_duration_seconds",elapsed,"route"=>"delete_value");
+
+    match result {
+        // Deleting a missing key has always been reported as a success here
+        Ok(_) | Err(KvError::NotFound) => Ok(Json::from(DeleteResponse {
+            status: Status::Success,
+            message: "key deleted ".to_string(),
+        })),
+        Err(e) => {
+            let error = format!("Failed to delete key {}: {}", key, e);
+            Err(legacy_error("delete_value", e, error))
         }
-    };
-    
-    // Handle the result
+    }
+}
+
+pub async fn get_ttl(Extension(claims): Extension<Claims>, Json(payload):Json<IncomingTtlRequest>) -> Result<Json<TtlResponse>, Response> {
+    let start=Instant::now();
+    counter!("route_hit",1,"route"=>"get_ttl");
+    let result = async {
+        let key = payload.encoding.decode("key", payload.key)?;
+        authorize(&claims, Action::Read, &payload.namespace, &key)?;
+        coordinator::get(&payload.namespace, &key, payload.consistency).await
+    }.await;
+    let elapsed=start.elapsed().as_secs_f64();
+    histogram!("request_duration_seconds",elapsed,"route"=>"get_ttl");
+
     match result {
-        Ok(response) => response,
-        Err(error_msg) => {
-            let elapsed = start.elapsed().as_secs_f64();
-            histogram!("request_duration_seconds", elapsed, "route" => "get_value");
-            counter!("error_count", 1, "route" => "get_value");
-            
-            let error_response = ErrorResponse {
-                status: Status::Error,
-                error: error_msg.to_string(),
-            };
-            Err(Json::from(error_response))
+        Ok(found) => Ok(Json::from(ttl_response(found.meta.expires_at))),
+        Err(e) => {
+            let error = e.to_string();
+            Err(legacy_error("get_ttl", e, error))
         }
     }
 }
 
-pub async fn delete_value(
-    State(tx): State<tokio::sync::mpsc::Sender<Wal>>,
-    Json(payload): Json<IncomingDeleteRequest>
-) -> Result<Json<DeleteResponse>, Json<ErrorResponse>> {
+pub async fn persist_value(Extension(claims): Extension<Claims>, Json(payload):Json<IncomingTtlRequest>) -> Result<Json<SetResponse>, Response> {
     let start=Instant::now();
-    counter!("route_hit",1,"route"=>"delete_value");
-    let key = payload.key;
-    // Remove from Sled database
-    //  let total_nodes = NODES.len();
-    // let primary_index: usize = get_node_for_key(&key, total_nodes);
-    let delete_result = {
-        let ring = HASH_RING.read().unwrap();
-        match ring.get_node(&key) {
-            Some(leader) => {
-                let result = leader.db.remove(key.clone().as_bytes());
-                if result.is_ok() {
-                    leader.db.flush().ok();
-                }
-                result
-            },
-            None => {
-                return Err(Json::from(ErrorResponse {
-                    status: Status::Error,
-                    error: "No node available".to_string(),
-                }));
-            }
+    counter!("route_hit",1,"route"=>"persist_value");
+    let result = async {
+        let key = payload.encoding.decode("key", payload.key)?;
+        authorize(&claims, Action::Write, &payload.namespace, &key)?;
+        coordinator::expire(&payload.namespace, &key, None, payload.consistency).await
+    }.await;
+    let elapsed=start.elapsed().as_secs_f64();
+    histogram!("request_duration_seconds",elapsed,"route"=>"persist_value");
+
+    match result {
+        Ok(_) => Ok(Json::from(SetResponse {
+            status: Status::Success,
+            message: "expiry cleared".to_string(),
+        })),
+        Err(e) => {
+            let error = e.to_string();
+            Err(legacy_error("persist_value", e, error))
         }
-    };
-   
-        if delete_result.is_ok(){
-            let entry=Wal::new(WalOp::Delete { key: key });
-        
-          if tx.send(entry).await.is_err(){
-            eprintln!("failed to delete")
-          }
-        let elapsed=start.elapsed().as_secs_f64();
-        histogram!("request_duration_seconds",elapsed,"route"=>"delete_value");
-        
-              let response = DeleteResponse {
-                status: Status::Success,
-                message: format!("key deleted "),
-            };
-            Ok(Json::from(response))
-        }else{
-             counter!("error_count", 1, "route" => "delete_value");
-             let error_response = ErrorResponse {
-                    status: Status::Error,
-                    error: format!("Failed to delete key: {}",key),
-                };
-                return Err(Json::from(error_response));
+    }
+}
+
+async fn counter(route: &'static str, claims: Claims, payload: IncomingIncrRequest, decrement: bool) -> Result<Json<CounterResponse>, Response> {
+    let start=Instant::now();
+    counter!("route_hit",1,"route"=>route);
+    let result = async {
+        let key = payload.encoding.decode("key", payload.key)?;
+        authorize(&claims, Action::Write, &payload.namespace, &key)?;
+        let delta = if decrement { coordinator::negate(payload.by)? } else { payload.by };
+        coordinator::incr(&payload.namespace, &key, delta, payload.consistency).await
+    }.await;
+    let elapsed=start.elapsed().as_secs_f64();
+    histogram!("request_duration_seconds",elapsed,"route"=>route);
+
+    match result {
+        Ok(value) => Ok(Json::from(CounterResponse {
+            status: Status::Success,
+            value,
+        })),
+        Err(e) => {
+            let error = e.to_string();
+            Err(legacy_error(route, e, error))
         }
-   
+    }
+}
+
+pub async fn incr_value(Extension(claims): Extension<Claims>, Json(payload):Json<IncomingIncrRequest>) -> Result<Json<CounterResponse>, Response> {
+    counter("incr_value", claims, payload, false).await
 }
 
-pub async fn login_handler(Json(payload):Json<IncomingLoginRequest>)->Result<Json<LoginResponse>,Json<ErrorResponse>>{
+pub async fn decr_value(Extension(claims): Extension<Claims>, Json(payload):Json<IncomingIncrRequest>) -> Result<Json<CounterResponse>, Response> {
+    counter("decr_value", claims, payload, true).await
+}
+
+fn login_response(issued: Issued) -> Json<LoginResponse> {
+    Json::from(LoginResponse{
+        status:Status::Success,
+        token:issued.access_token,
+        refresh_token:issued.refresh_token,
+        expires_in:issued.expires_in
+    })
+}
+
+// Checks the password against the user store (see users.rs), then starts a session (see tokens.rs)
+pub async fn login_handler(Json(payload):Json<IncomingLoginRequest>)->Result<Json<LoginResponse>,KvError>{
     let start=Instant::now();
     counter!("route_hit",1,"route"=>"login_handler");
-    dotenv().ok();
-    let email=payload.email;
-    let claim=Claims{
-        email:email,
-        exp: (Utc::now() + Duration::hours(5)).timestamp() as usize
-    };
-    let secret=env::var("JWT_SECRATE").unwrap();
-    let token=encode(&Header::default(), &claim, &EncodingKey::from_secret(secret.as_ref()));
-    match token {
-        Ok(value)=>{
-            let response=LoginResponse{
-        status:Status::Success,
-        token:value
-    };
+    let issued=blocking(move || tokens::login(&payload.email,&payload.password)).await
+        .inspect_err(|_| counter!("login_failures_total",1))?;
     let elapsed=start.elapsed().as_secs_f64();
     histogram!("request_duration_seconds",elapsed,"route"=>"login_handler");
-      return  Ok(Json::from(response));
-        },
-        Err(e)=>{
-            let response=ErrorResponse{
-                status:Status::Error,
-                error:e.to_string()
-            };
-             counter!("error_count", 1, "route" => "login_handler");
-            return Err(Json::from(response));
-        }
+    Ok(login_response(issued))
+}
+
+// Exchanges a refresh token for a new access and refresh token
+pub async fn refresh_handler(Json(payload):Json<IncomingRefreshRequest>)->Result<Json<LoginResponse>,KvError>{
+    counter!("route_hit",1,"route"=>"refresh_handler");
+    let issued=blocking(move || tokens::refresh(&payload.refresh_token)).await?;
+    Ok(login_response(issued))
+}
+
+// Ends the caller's session: this access token and its refresh token stop working
+pub async fn logout_handler(Extension(claims): Extension<Claims>)->Result<StatusCode,KvError>{
+    counter!("route_hit",1,"route"=>"logout_handler");
+    if claims.sid.is_empty() {
+        return Err(KvError::BadRequest("API keys have no session; an admin revokes them at /admin/api-keys".to_string()));
     }
-}
\ No newline at end of file
+    blocking(move || tokens::revoke_session(&claims.sid)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// Role, term and commit/apply progress of every Raft group member
+pub async fn raft_status() -> Json<BTreeMap<String, BTreeMap<String, RaftStatus>>> {
+    counter!("route_hit",1,"route"=>"raft_status");
+    Json::from(raft().status().await)
+}
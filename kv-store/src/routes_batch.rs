@@ -0,0 +1,163 @@
+/*
+POST /v1/batch/get, /v1/batch/set and /v1/batch/delete. The request as a whole only fails for a
+malformed or oversized batch (400) or a key the caller may not touch (403, nothing is done);
+everything else is reported per key in `results`. A batch where some key failed on the server's
+side (a 5xx code) is not kept for Idempotency-Key replays.
+*/
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use metrics::{counter, histogram};
+use tokio::time::Instant;
+use crate::access::{authorize, Action};
+use crate::coordinator::{self, KvError, KvValue, PutOptions, MAX_BATCH_KEYS};
+use crate::encoding::Encoding;
+use crate::middleware::idempotency::ServerFailure;
+use crate::middleware::types::Claims;
+use crate::routes_resp::{BatchResponse, BatchResult, IncomingBatchDeleteRequest, IncomingBatchGetRequest,
+    IncomingBatchSetRequest, Status};
+use crate::store::ApplyOutcome;
+
+fn authorize_all(claims: &Claims, action: Action, namespace: &str, keys: &[Vec<u8>]) -> Result<(), KvError> {
+    keys.iter().try_for_each(|key| authorize(claims, action, namespace, key))
+}
+
+fn check_size(len: usize) -> Result<(), KvError> {
+    if len == 0 || len > MAX_BATCH_KEYS {
+        return Err(KvError::BadRequest(format!("a batch takes 1 to {} keys, got {}", MAX_BATCH_KEYS, len)));
+    }
+    Ok(())
+}
+
+// The key goes back in the request's encoding, or base64 if it (or the value) isn't UTF-8
+fn empty_result(key: &[u8], encoding: Encoding, status: Status, code: StatusCode) -> BatchResult {
+    let encoding = encoding.for_output(&[key]);
+    BatchResult {
+        key: encoding.encode(key),
+        status,
+        code: code.as_u16(),
+        value: None,
+        encoding,
+        content_type: None,
+        version: None,
+        outcome: None,
+        error: None,
+    }
+}
+
+fn error_result(key: &[u8], encoding: Encoding, error: KvError) -> BatchResult {
+    BatchResult { error: Some(error.to_string()), ..empty_result(key, encoding, Status::Error, error.status_code()) }
+}
+
+fn get_result(key: &[u8], encoding: Encoding, result: Result<KvValue, KvError>) -> BatchResult {
+    match result {
+        Ok(found) => {
+            let encoding = encoding.for_output(&[key, &found.value]);
+            BatchResult {
+                value: Some(encoding.encode(&found.value)),
+                content_type: found.meta.content_type,
+                version: Some(found.meta.version),
+                ..empty_result(key, encoding, Status::Success, StatusCode::OK)
+            }
+        }
+        Err(e) => error_result(key, encoding, e),
+    }
+}
+
+fn write_result(key: &[u8], encoding: Encoding, result: Result<ApplyOutcome, KvError>) -> BatchResult {
+    match result {
+        Ok(outcome) => {
+            let code = if outcome == ApplyOutcome::Created { StatusCode::CREATED } else { StatusCode::NO_CONTENT };
+            BatchResult { outcome: Some(outcome), ..empty_result(key, encoding, Status::Success, code) }
+        }
+        Err(e) => error_result(key, encoding, e),
+    }
+}
+
+// A key that doesn't decode makes the whole batch malformed
+fn decode_keys(encoding: Encoding, keys: Vec<String>) -> Result<Vec<Vec<u8>>, KvError> {
+    keys.into_iter().map(|key| encoding.decode("key", key)).collect()
+}
+
+fn finish(route: &'static str, start: Instant, results: Vec<BatchResult>) -> Response {
+    histogram!("request_duration_seconds", start.elapsed().as_secs_f64(), "route" => route);
+    histogram!("batch_size", results.len() as f64, "route" => route);
+    let failed = results.iter().filter(|r| matches!(r.status, Status::Error)).count();
+    if failed > 0 {
+        counter!("batch_key_errors_total", failed as u64, "route" => route);
+    }
+    // A key that failed on our side makes the batch worth retrying as a whole
+    let server_failure = results.iter().any(|r| r.code >= 500);
+    // Success means the batch ran; check each result's status
+    let mut response = Json(BatchResponse { status: Status::Success, results }).into_response();
+    if server_failure {
+        response.extensions_mut().insert(ServerFailure);
+    }
+    response
+}
+
+pub async fn batch_get(Extension(claims): Extension<Claims>, Json(payload): Json<IncomingBatchGetRequest>) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "batch_get");
+    check_size(payload.keys.len())?;
+    let encoding = payload.encoding;
+    let keys = decode_keys(encoding, payload.keys)?;
+    authorize_all(&claims, Action::Read, &payload.namespace, &keys)?;
+    let results = coordinator::batch_get(&payload.namespace, keys.clone(), payload.consistency).await?;
+    let results: Vec<BatchResult> = keys.iter().zip(results).map(|(key, r)| get_result(key, encoding, r)).collect();
+    Ok(finish("batch_get", start, results))
+}
+
+pub async fn batch_set(Extension(claims): Extension<Claims>, Json(payload): Json<IncomingBatchSetRequest>) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "batch_set");
+    check_size(payload.items.len())?;
+    let encoding = payload.encoding;
+    let keys = decode_keys(encoding, payload.items.iter().map(|item| item.key.clone()).collect())?;
+    authorize_all(&claims, Action::Write, &payload.namespace, &keys)?;
+
+    // Items with a bad expiry, value or condition fail on their own, the rest go through
+    let mut results: Vec<Option<Result<ApplyOutcome, KvError>>> = Vec::with_capacity(keys.len());
+    let mut writes = Vec::new();
+    for (key, item) in keys.iter().zip(payload.items) {
+        let write = (|| {
+            let expires_at = coordinator::expiry(item.ttl_seconds, item.expires_at)?;
+            let condition = encoding.condition(item.if_match)?;
+            let options = PutOptions { content_type: item.content_type, mode: item.mode, condition, expires_at };
+            Ok((key.clone(), encoding.decode("value", item.value)?, options))
+        })();
+        match write {
+            Ok(write) => {
+                writes.push(write);
+                results.push(None);
+            }
+            Err(e) => results.push(Some(Err(e))),
+        }
+    }
+    let mut written = coordinator::batch_put(&payload.namespace, writes, payload.consistency).await?.into_iter();
+    let results: Vec<BatchResult> = keys.iter()
+        .zip(results)
+        .map(|(key, early)| {
+            let result = early.or_else(|| written.next()).expect("one result per write");
+            write_result(key, encoding, result)
+        })
+        .collect();
+    Ok(finish("batch_set", start, results))
+}
+
+pub async fn batch_delete(Extension(claims): Extension<Claims>, Json(payload): Json<IncomingBatchDeleteRequest>) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "batch_delete");
+    check_size(payload.items.len())?;
+    let encoding = payload.encoding;
+    let keys = decode_keys(encoding, payload.items.iter().map(|item| item.key.clone()).collect())?;
+    authorize_all(&claims, Action::Delete, &payload.namespace, &keys)?;
+    let deletes = keys.iter().cloned()
+        .zip(payload.items)
+        .map(|(key, item)| Ok((key, encoding.condition(item.if_match)?)))
+        .collect::<Result<Vec<_>, KvError>>()?;
+    let results = coordinator::batch_delete(&payload.namespace, deletes, payload.consistency).await?;
+    let results: Vec<BatchResult> = keys.iter().zip(results).map(|(key, r)| write_result(key, encoding, r)).collect();
+    Ok(finish("batch_delete", start, results))
+}
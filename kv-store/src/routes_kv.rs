@@ -0,0 +1,256 @@
+/*
+Key resource API: GET/PUT/DELETE/HEAD /v1/kv/{key}. The body is the raw value (any bytes, e.g.
+application/octet-stream) and its Content-Type is stored next to it and echoed back on reads. PUT answers 201 when it created the key and 204
+when it replaced it. Failures use real status codes with an ErrorResponse body: 404 missing key
+(or update_only on a missing key), 409 conflict (create_only on an existing key), 412 when an
+If-Match precondition fails, 503 no quorum.
+
+GET/PUT/DELETE /v1/kv/{key}/ttl inspect, set and clear (PERSIST) a key's expiry.
+
+POST /v1/kv/{key}/incr and /decr add or subtract ?by= (default 1) to an integer value and answer
+with the new value as JSON; a missing key counts as 0, anything that isn't an integer is a 409.
+
+/v1/ns/{namespace}/kv/{key} (and .../ttl) are the same routes inside a namespace; /v1/kv/{key} is
+the default namespace. An unknown namespace is a 404 and a full one a 507.
+
+Every value carries a version, served as its ETag. PUT and DELETE accept If-Match: "<version>"
+(compare-and-swap), If-Match: * (key must exist) and, on PUT, If-None-Match: * (key must not exist).
+*/
+use axum::body::Bytes;
+use axum::extract::{Extension, Path, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use metrics::{counter, histogram};
+use serde::Deserialize;
+use tokio::time::Instant;
+use chrono::{DateTime, Utc};
+use crate::access::{authorize, Action};
+use crate::coordinator::{self, KvError, KvValue, PutOptions};
+use crate::middleware::types::Claims;
+use crate::routes_resp::{default_namespace, default_step, Condition, Consistency, CounterResponse, ErrorResponse, Status, TtlResponse, WriteMode};
+use crate::store::ApplyOutcome;
+
+// Served for values written without a Content-Type (e.g. through the legacy JSON routes)
+const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+
+// The stored Content-Type, or a guess for values that came without one
+fn content_type(found: &KvValue) -> String {
+    match &found.meta.content_type {
+        Some(content_type) => content_type.clone(),
+        None if std::str::from_utf8(&found.value).is_ok() => DEFAULT_CONTENT_TYPE.to_string(),
+        None => BINARY_CONTENT_TYPE.to_string(),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct KeyPath {
+    // Absent on the /v1/kv routes
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    pub key: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct KvQuery {
+    #[serde(default)]
+    pub consistency: Consistency,
+    // PUT only: upsert (default), create_only or update_only
+    #[serde(default)]
+    pub mode: WriteMode,
+    // PUT (and PUT .../ttl) only: relative or absolute expiry
+    pub ttl_seconds: Option<u64>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct CounterQuery {
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_step")]
+    pub by: i64,
+}
+
+impl IntoResponse for KvError {
+    fn into_response(self) -> Response {
+        let body = ErrorResponse { status: Status::Error, error: self.to_string() };
+        (self.status_code(), Json(body)).into_response()
+    }
+}
+
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+// Fold the conditional-request headers into a write mode and CAS condition
+fn preconditions(headers: &HeaderMap, mut mode: WriteMode) -> Result<(WriteMode, Option<Condition>), KvError> {
+    let header_value = |name| headers.get(name).map(|v| v.to_str().map(str::trim));
+    let mut condition = None;
+
+    match header_value(header::IF_MATCH) {
+        None => {}
+        Some(Ok("*")) => mode = WriteMode::UpdateOnly,
+        Some(Ok(tag)) => {
+            let version = tag.trim_start_matches("W/").trim_matches('"').parse::<u64>()
+                .map_err(|_| KvError::BadRequest(format!("If-Match must be * or an ETag, got {}", tag)))?;
+            condition = Some(Condition::Version(version));
+        }
+        Some(Err(_)) => return Err(KvError::BadRequest("If-Match is not valid ASCII".to_string())),
+    }
+    match header_value(header::IF_NONE_MATCH) {
+        None => {}
+        Some(Ok("*")) => mode = WriteMode::CreateOnly,
+        Some(_) => return Err(KvError::BadRequest("If-None-Match only supports *".to_string())),
+    }
+    Ok((mode, condition))
+}
+
+fn finish(route: &'static str, start: Instant, result: &Result<Response, KvError>) {
+    histogram!("request_duration_seconds", start.elapsed().as_secs_f64(), "route" => route);
+    // A miss is an answer, not an error
+    if let Err(e) = result
+        && !matches!(e, KvError::NotFound) {
+        counter!("error_count", 1, "route" => route);
+    }
+}
+
+pub async fn kv_get(Extension(claims): Extension<Claims>, Path(KeyPath { namespace, key }): Path<KeyPath>, Query(query): Query<KvQuery>) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "kv_get");
+    authorize(&claims, Action::Read, &namespace, key.as_bytes())?;
+    let result = coordinator::get(&namespace, key.as_bytes(), query.consistency).await.map(|found| {
+        let content_type = content_type(&found);
+        (
+            [(header::CONTENT_TYPE, content_type), (header::ETAG, etag(found.meta.version))],
+            found.value.to_vec(),
+        ).into_response()
+    });
+    finish("kv_get", start, &result);
+    result
+}
+
+// Same lookup as GET, headers only
+pub async fn kv_head(Extension(claims): Extension<Claims>, Path(KeyPath { namespace, key }): Path<KeyPath>, Query(query): Query<KvQuery>) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "kv_head");
+    authorize(&claims, Action::Read, &namespace, key.as_bytes())?;
+    let result = coordinator::get(&namespace, key.as_bytes(), query.consistency).await.map(|found| {
+        let content_type = content_type(&found);
+        (
+            [
+                (header::CONTENT_TYPE, content_type),
+                (header::CONTENT_LENGTH, found.value.len().to_string()),
+                (header::ETAG, etag(found.meta.version)),
+            ],
+        ).into_response()
+    });
+    finish("kv_head", start, &result);
+    result
+}
+
+pub async fn kv_put(
+    Extension(claims): Extension<Claims>,
+    Path(KeyPath { namespace, key }): Path<KeyPath>,
+    Query(query): Query<KvQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "kv_put");
+    authorize(&claims, Action::Write, &namespace, key.as_bytes())?;
+    let content_type = headers.get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let result = async {
+        let (mode, condition) = preconditions(&headers, query.mode)?;
+        let expires_at = coordinator::expiry(query.ttl_seconds, query.expires_at)?;
+        let options = PutOptions { content_type, mode, condition, expires_at };
+        Ok(match coordinator::put(&namespace, key.as_bytes(), body.to_vec(), options, query.consistency).await? {
+            ApplyOutcome::Created => StatusCode::CREATED.into_response(),
+            _ => StatusCode::NO_CONTENT.into_response(),
+        })
+    }.await;
+    finish("kv_put", start, &result);
+    result
+}
+
+pub async fn kv_delete(Extension(claims): Extension<Claims>, Path(KeyPath { namespace, key }): Path<KeyPath>, Query(query): Query<KvQuery>, headers: HeaderMap) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "kv_delete");
+    authorize(&claims, Action::Delete, &namespace, key.as_bytes())?;
+    // If-Match: * adds nothing here, a missing key is a 404 anyway
+    let result = match preconditions(&headers, WriteMode::Upsert) {
+        Ok((_, condition)) => coordinator::delete(&namespace, key.as_bytes(), condition, query.consistency).await
+            .map(|_| StatusCode::NO_CONTENT.into_response()),
+        Err(e) => Err(e),
+    };
+    finish("kv_delete", start, &result);
+    result
+}
+
+pub fn ttl_response(expires_at: Option<u64>) -> TtlResponse {
+    let now = crate::store::now_ms();
+    TtlResponse {
+        status: Status::Success,
+        // Rounded up so a key that is still readable never reports 0
+        ttl_seconds: expires_at.map(|at| at.saturating_sub(now).div_ceil(1000)),
+        expires_at: expires_at.and_then(|at| DateTime::from_timestamp_millis(at as i64)),
+    }
+}
+
+pub async fn kv_ttl_get(Extension(claims): Extension<Claims>, Path(KeyPath { namespace, key }): Path<KeyPath>, Query(query): Query<KvQuery>) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "kv_ttl_get");
+    authorize(&claims, Action::Read, &namespace, key.as_bytes())?;
+    let result = coordinator::get(&namespace, key.as_bytes(), query.consistency).await
+        .map(|found| Json(ttl_response(found.meta.expires_at)).into_response());
+    finish("kv_ttl_get", start, &result);
+    result
+}
+
+// EXPIRE: needs ttl_seconds or expires_at
+pub async fn kv_ttl_put(Extension(claims): Extension<Claims>, Path(KeyPath { namespace, key }): Path<KeyPath>, Query(query): Query<KvQuery>) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "kv_ttl_put");
+    authorize(&claims, Action::Write, &namespace, key.as_bytes())?;
+    let result = async {
+        let expires_at = coordinator::expiry(query.ttl_seconds, query.expires_at)?
+            .ok_or_else(|| KvError::BadRequest("ttl_seconds or expires_at is required".to_string()))?;
+        coordinator::expire(&namespace, key.as_bytes(), Some(expires_at), query.consistency).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }.await;
+    finish("kv_ttl_put", start, &result);
+    result
+}
+
+// PERSIST
+pub async fn kv_ttl_delete(Extension(claims): Extension<Claims>, Path(KeyPath { namespace, key }): Path<KeyPath>, Query(query): Query<KvQuery>) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "kv_ttl_delete");
+    authorize(&claims, Action::Write, &namespace, key.as_bytes())?;
+    let result = coordinator::expire(&namespace, key.as_bytes(), None, query.consistency).await
+        .map(|_| StatusCode::NO_CONTENT.into_response());
+    finish("kv_ttl_delete", start, &result);
+    result
+}
+
+async fn counter(route: &'static str, claims: Claims, namespace: String, key: String, delta: Result<i64, KvError>, consistency: Consistency) -> Result<Response, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => route);
+    authorize(&claims, Action::Write, &namespace, key.as_bytes())?;
+    let result = async {
+        let value = coordinator::incr(&namespace, key.as_bytes(), delta?, consistency).await?;
+        Ok(Json(CounterResponse { status: Status::Success, value }).into_response())
+    }.await;
+    finish(route, start, &result);
+    result
+}
+
+pub async fn kv_incr(Extension(claims): Extension<Claims>, Path(KeyPath { namespace, key }): Path<KeyPath>, Query(query): Query<CounterQuery>) -> Result<Response, KvError> {
+    counter("kv_incr", claims, namespace, key, Ok(query.by), query.consistency).await
+}
+
+pub async fn kv_decr(Extension(claims): Extension<Claims>, Path(KeyPath { namespace, key }): Path<KeyPath>, Query(query): Query<CounterQuery>) -> Result<Response, KvError> {
+    counter("kv_decr", claims, namespace, key, coordinator::negate(query.by), query.consistency).await
+}
@@ -0,0 +1,51 @@
+/*
+Namespace admin API:
+  POST   /admin/namespaces         create (body: a Namespace, everything but `name` optional)
+  GET    /admin/namespaces         list
+  GET    /admin/namespaces/{name}  settings and approximate usage
+  DELETE /admin/namespaces/{name}  drop the namespace and all its keys on every node
+Each takes the admin action on the namespace; the list only shows namespaces the caller can see.
+*/
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use metrics::counter;
+use crate::access::{authorize, visible, Action};
+use crate::coordinator::KvError;
+use crate::middleware::types::Claims;
+use crate::namespace::{self, Namespace};
+use crate::routes_resp::{NamespaceListResponse, NamespaceResponse, Status};
+
+fn namespace_response(namespace: Namespace) -> NamespaceResponse {
+    let usage = namespace::usage(&namespace.name);
+    NamespaceResponse { status: Status::Success, namespace, usage }
+}
+
+pub async fn create_namespace(Extension(claims): Extension<Claims>, Json(payload): Json<Namespace>) -> Result<Response, KvError> {
+    counter!("route_hit", 1, "route" => "create_namespace");
+    authorize(&claims, Action::Admin, &payload.name, b"")?;
+    let created = namespace::create(payload)?;
+    Ok((StatusCode::CREATED, Json(namespace_response(created))).into_response())
+}
+
+pub async fn list_namespaces(Extension(claims): Extension<Claims>) -> Json<NamespaceListResponse> {
+    counter!("route_hit", 1, "route" => "list_namespaces");
+    let namespaces = namespace::list().into_iter()
+        .filter(|namespace| visible(&claims, &namespace.name))
+        .collect();
+    Json(NamespaceListResponse { status: Status::Success, namespaces })
+}
+
+pub async fn get_namespace(Extension(claims): Extension<Claims>, Path(name): Path<String>) -> Result<Json<NamespaceResponse>, KvError> {
+    counter!("route_hit", 1, "route" => "get_namespace");
+    authorize(&claims, Action::Admin, &name, b"")?;
+    Ok(Json(namespace_response(namespace::get(&name)?)))
+}
+
+pub async fn drop_namespace(Extension(claims): Extension<Claims>, Path(name): Path<String>) -> Result<StatusCode, KvError> {
+    counter!("route_hit", 1, "route" => "drop_namespace");
+    authorize(&claims, Action::Admin, &name, b"")?;
+    namespace::drop_namespace(&name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
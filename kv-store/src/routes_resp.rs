@@ -1,4 +1,11 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use crate::access::Grant;
+use crate::api_keys::ApiKeyView;
+use crate::encoding::Encoding;
+use crate::namespace::{Namespace, Usage, DEFAULT_NAMESPACE};
+use crate::store::ApplyOutcome;
+use crate::users::{Role, UserView};
 use tokio::time::Instant;
 
 #[derive(Serialize, Deserialize)]
@@ -17,6 +24,10 @@ pub struct SetResponse {
 pub struct GetResponse {
     pub status: Status,
     pub value: String,
+    // How `value` is written, see encoding.rs
+    pub encoding: Encoding,
+    // Pass back as `if_match: {"version": ..}` for a compare-and-swap
+    pub version: u64,
 }
 #[derive(Serialize, Deserialize)]
 pub struct DeleteResponse {
@@ -24,10 +35,26 @@ pub struct DeleteResponse {
     pub message: String,
    
 }
+// ttl_seconds and expires_at are null for a key without expiry
+#[derive(Serialize, Deserialize)]
+pub struct TtlResponse {
+    pub status: Status,
+    pub ttl_seconds: Option<u64>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+// The counter's value after INCR/DECR
+#[derive(Serialize, Deserialize)]
+pub struct CounterResponse {
+    pub status: Status,
+    pub value: i64,
+}
 #[derive(Deserialize, Serialize)]
 pub struct LoginResponse{
     pub status:Status,
-    pub token:String
+    // The access token
+    pub token:String,
+    pub refresh_token:String,
+    pub expires_in:u64
 }
 #[derive(Serialize, Deserialize)]
 pub struct ErrorResponse {
@@ -35,37 +62,442 @@ pub struct ErrorResponse {
     pub error: String,
 }
 
-#[derive(Clone, Debug)]
+// How a SET treats an existing key
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteMode {
+    #[default]
+    Upsert,
+    // Only if the key does not exist yet
+    CreateOnly,
+    // Only if the key already exists
+    UpdateOnly,
+}
+
+// Compare-and-swap guard: the key must exist with this version (the ETag) or this exact value
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Version(u64),
+    Value(Vec<u8>),
+    // Internal, for the expiry sweeper: the key expired at or before this time (epoch ms). It
+    // carries the sweeper's clock so every Raft member decides the same way.
+    ExpiredBy(u64),
+}
+
+// Condition as JSON requests write it; the value follows the request's encoding
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum IfMatch {
+    Version(u64),
+    Value(String),
+}
+
+// Every operation names the namespace whose trees it touches
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum WalOp {
-    Set { key: String, value: String },
-    Delete { key: String },
+    // expires_at is absolute (epoch ms) so replicas and WAL replay expire the key at the same instant
+    Set { namespace: String, key: Vec<u8>, value: Vec<u8>, content_type: Option<String>, mode: WriteMode, condition: Option<Condition>, expires_at: Option<u64> },
+    Delete { namespace: String, key: Vec<u8>, condition: Option<Condition> },
+    // Set the expiry of an existing key, or clear it (PERSIST) with None
+    Expire { namespace: String, key: Vec<u8>, expires_at: Option<u64> },
+    // INCR/DECR/INCRBY: add delta to an integer value; a missing key starts at 0 and gets expires_at
+    Incr { namespace: String, key: Vec<u8>, delta: i64, expires_at: Option<u64> },
 }
 
 
 
 
 //Incoming request structures
+pub fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
 #[derive(Clone,Debug)]
 //wal->write ahead log
 pub struct Wal{
-pub sequenceNumber:usize,    
+pub sequence_number:usize,    
 pub opration:WalOp,
-pub time:Instant
+pub time:Instant,
+// The key's version on the leader after the write, for watchers; None for deletes and older entries
+pub version:Option<u64>
+}
+// Eventual: leader write + async WAL replication. Strong: through the partition's Raft group.
+// Ordered weakest first, a namespace's minimum wins over a weaker request.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    #[default]
+    Eventual,
+    Strong,
 }
+
 #[derive(Deserialize, Serialize)]
 pub struct IncomingSetRequest {
     pub key: String,
     pub value: String,
+    #[serde(default)]
+    pub mode: WriteMode,
+    #[serde(default)]
+    pub if_match: Option<IfMatch>,
+    // Relative or absolute expiry, at most one of them
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the keys and values in this request (and the response)
+    #[serde(default)]
+    pub encoding: Encoding,
 }
 #[derive(Deserialize, Serialize)]
 pub struct IncomingGetRequest {
     pub key: String,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the keys and values in this request (and the response)
+    #[serde(default)]
+    pub encoding: Encoding,
 }
 #[derive(Deserialize, Serialize)]
 pub struct IncomingDeleteRequest {
     pub key: String,
+    #[serde(default)]
+    pub if_match: Option<IfMatch>,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the keys and values in this request (and the response)
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+// Used by both /ttl and /persist
+#[derive(Deserialize, Serialize)]
+pub struct IncomingTtlRequest {
+    pub key: String,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the keys and values in this request (and the response)
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+// Used by both /incr and /decr; `by` defaults to 1
+#[derive(Deserialize, Serialize)]
+pub struct IncomingIncrRequest {
+    pub key: String,
+    #[serde(default = "default_step")]
+    pub by: i64,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the key in this request
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+pub fn default_step() -> i64 {
+    1
 }
 #[derive(Deserialize, Serialize)]
 pub struct IncomingLoginRequest{
-    pub email:String
-}
\ No newline at end of file
+    pub email:String,
+    pub password:String
+}
+// /signup, and POST /admin/users (which may also set the role and grants)
+#[derive(Deserialize)]
+pub struct IncomingUserRequest {
+    pub email: String,
+    pub password: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+}
+// PATCH /admin/users/{email}: only what is given changes
+#[derive(Deserialize)]
+pub struct IncomingUserUpdate {
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub role: Option<Role>,
+    #[serde(default)]
+    pub grants: Option<Vec<Grant>>,
+}
+// /refresh
+#[derive(Deserialize)]
+pub struct IncomingRefreshRequest {
+    pub refresh_token: String,
+}
+// POST /admin/revoke: exactly one of them
+#[derive(Deserialize)]
+pub struct IncomingRevokeRequest {
+    #[serde(default)]
+    pub jti: Option<String>,
+    #[serde(default)]
+    pub session: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+// POST /admin/api-keys
+#[derive(Deserialize)]
+pub struct IncomingApiKeyRequest {
+    pub name: String,
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+    // Never expires if absent
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+}
+// Batches: at most MAX_BATCH_KEYS items, results come back per key in request order
+#[derive(Deserialize, Serialize)]
+pub struct IncomingBatchGetRequest {
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the keys and values in this request (and the response)
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+#[derive(Deserialize, Serialize)]
+pub struct BatchSetItem {
+    pub key: String,
+    pub value: String,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub mode: WriteMode,
+    #[serde(default)]
+    pub if_match: Option<IfMatch>,
+    #[serde(default)]
+    pub ttl_seconds: Option<u64>,
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+#[derive(Deserialize, Serialize)]
+pub struct IncomingBatchSetRequest {
+    pub items: Vec<BatchSetItem>,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the keys and values in this request (and the response)
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+#[derive(Deserialize, Serialize)]
+pub struct BatchDeleteItem {
+    pub key: String,
+    #[serde(default)]
+    pub if_match: Option<IfMatch>,
+}
+#[derive(Deserialize, Serialize)]
+pub struct IncomingBatchDeleteRequest {
+    pub items: Vec<BatchDeleteItem>,
+    #[serde(default)]
+    pub consistency: Consistency,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the keys and values in this request (and the response)
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+
+// `code` is the HTTP status the same request on /v1/kv/{key} would have returned
+#[derive(Serialize, Deserialize)]
+pub struct BatchResult {
+    pub key: String,
+    pub status: Status,
+    pub code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    // Of key and value
+    pub encoding: Encoding,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<ApplyOutcome>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+#[derive(Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub status: Status,
+    pub results: Vec<BatchResult>,
+}
+
+// Transactions: conditions are checked and writes applied only if every condition holds
+#[derive(Deserialize, Serialize)]
+pub struct TxnConditionItem {
+    pub key: String,
+    // Exactly one of these
+    #[serde(default)]
+    pub version: Option<u64>,
+    #[serde(default)]
+    pub value: Option<String>,
+    #[serde(default)]
+    pub exists: Option<bool>,
+}
+#[derive(Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TxnWriteItem {
+    Set {
+        key: String,
+        value: String,
+        #[serde(default)]
+        content_type: Option<String>,
+        #[serde(default)]
+        mode: WriteMode,
+        #[serde(default)]
+        ttl_seconds: Option<u64>,
+        #[serde(default)]
+        expires_at: Option<DateTime<Utc>>,
+    },
+    Delete { key: String },
+}
+#[derive(Deserialize, Serialize)]
+pub struct IncomingTxnRequest {
+    #[serde(default)]
+    pub reads: Vec<String>,
+    #[serde(default)]
+    pub conditions: Vec<TxnConditionItem>,
+    #[serde(default)]
+    pub writes: Vec<TxnWriteItem>,
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    // Of the keys and values in this request (and the response)
+    #[serde(default)]
+    pub encoding: Encoding,
+}
+#[derive(Serialize, Deserialize)]
+pub struct TxnReadResult {
+    pub key: String,
+    pub found: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    // Of key and value
+    pub encoding: Encoding,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+#[derive(Serialize, Deserialize)]
+pub struct TxnWriteResult {
+    pub key: String,
+    pub encoding: Encoding,
+    pub outcome: ApplyOutcome,
+    // None once deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+// Reads come back either way, as they were before the transaction's writes
+#[derive(Serialize, Deserialize)]
+pub struct TxnResponse {
+    pub status: Status,
+    pub committed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failed_condition: Option<usize>,
+    pub reads: Vec<TxnReadResult>,
+    pub writes: Vec<TxnWriteResult>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ScanItem {
+    pub key: String,
+    // Left out for keys_only scans
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    // Of key and value
+    pub encoding: Encoding,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+#[derive(Serialize, Deserialize)]
+pub struct ScanResponse {
+    pub status: Status,
+    pub items: Vec<ScanItem>,
+    // Pass back as `cursor` for the next page; null on the last page
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NamespaceResponse {
+    pub status: Status,
+    pub namespace: Namespace,
+    // Approximate, refreshed in the background
+    pub usage: Usage,
+}
+#[derive(Serialize, Deserialize)]
+pub struct NamespaceListResponse {
+    pub status: Status,
+    pub namespaces: Vec<Namespace>,
+}
+#[derive(Serialize)]
+pub struct UserResponse {
+    pub status: Status,
+    pub user: UserView,
+}
+#[derive(Serialize)]
+pub struct UserListResponse {
+    pub status: Status,
+    pub users: Vec<UserView>,
+}
+#[derive(Serialize)]
+pub struct ApiKeyResponse {
+    pub status: Status,
+    pub api_key: ApiKeyView,
+    // The full key, only in the response that created it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+#[derive(Serialize)]
+pub struct ApiKeyListResponse {
+    pub status: Status,
+    pub api_keys: Vec<ApiKeyView>,
+}
+#[derive(Serialize)]
+pub struct RevokeResponse {
+    pub status: Status,
+    // Tokens or sessions revoked
+    pub revoked: usize,
+}
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchEventKind {
+    Set,
+    Delete,
+    // TTL set or cleared (PERSIST); a key reaped after expiring is a delete
+    Expire,
+}
+// One change from /v1/watch; `seq` is the WAL sequence to resume after
+#[derive(Serialize, Deserialize)]
+pub struct WatchEvent {
+    pub seq: usize,
+    pub event: WatchEventKind,
+    pub namespace: String,
+    pub key: String,
+    // Set events only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    // Of key and value
+    pub encoding: Encoding,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
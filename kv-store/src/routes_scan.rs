@@ -0,0 +1,117 @@
+/*
+GET /v1/scan?prefix=&start=&end=&limit=&keys_only=&cursor=
+Keys come back in key order, `start` inclusive and `end` exclusive. `next_cursor` is opaque to
+clients: it encodes where the page stopped and the range it belongs to, so it cannot be replayed
+against a different prefix or range. Reading needs a grant covering the whole prefix or range.
+*/
+use axum::extract::{Extension, Query};
+use axum::Json;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use metrics::{counter, histogram};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use crate::access::{authorize, range_prefix, Action};
+use crate::coordinator::{self, KvError, DEFAULT_SCAN_LIMIT, MAX_SCAN_LIMIT};
+use crate::encoding::Encoding;
+use crate::middleware::types::Claims;
+use crate::routes_resp::{default_namespace, ScanItem, ScanResponse, Status};
+use crate::store::KeyRange;
+
+#[derive(Deserialize)]
+pub struct ScanQuery {
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    pub prefix: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub limit: Option<usize>,
+    #[serde(default)]
+    pub keys_only: bool,
+    // Of prefix/start/end and of the keys and values in the response
+    #[serde(default)]
+    pub encoding: Encoding,
+    pub cursor: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Cursor {
+    // Base64, keys can be any bytes
+    after: String,
+    #[serde(default = "default_namespace")]
+    namespace: String,
+    prefix: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+fn encode_cursor(cursor: &Cursor) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(cursor).expect("cursor is always serializable"))
+}
+
+fn decode_cursor(token: &str) -> Option<Cursor> {
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(token).ok()?).ok()
+}
+
+pub async fn scan(Extension(claims): Extension<Claims>, Query(query): Query<ScanQuery>) -> Result<Json<ScanResponse>, KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "scan");
+    let limit = query.limit.unwrap_or(DEFAULT_SCAN_LIMIT);
+    if limit == 0 || limit > MAX_SCAN_LIMIT {
+        return Err(KvError::BadRequest(format!("limit must be between 1 and {}", MAX_SCAN_LIMIT)));
+    }
+
+    let after = match &query.cursor {
+        None => None,
+        Some(token) => {
+            let cursor = decode_cursor(token)
+                .filter(|c| c.namespace == query.namespace && c.prefix == query.prefix && c.start == query.start && c.end == query.end)
+                .ok_or_else(|| KvError::BadRequest("cursor is invalid or belongs to another scan".to_string()))?;
+            Some(Encoding::Base64.decode("cursor", cursor.after)?)
+        }
+    };
+    let bound = |field, value: &Option<String>| value.clone().map(|v| query.encoding.decode(field, v)).transpose();
+    let range = KeyRange {
+        prefix: bound("prefix", &query.prefix)?,
+        start: bound("start", &query.start)?,
+        end: bound("end", &query.end)?,
+        after,
+    };
+    let covered = match &range.prefix {
+        Some(prefix) => prefix.as_slice(),
+        None => range_prefix(range.start.as_deref(), range.end.as_deref()),
+    };
+    authorize(&claims, Action::Read, &query.namespace, covered)?;
+    let page = coordinator::scan(&query.namespace, range, limit).await?;
+
+    let next_cursor = match page.items.last() {
+        Some((last, _)) if page.more => Some(encode_cursor(&Cursor {
+            after: Encoding::Base64.encode(last),
+            namespace: query.namespace,
+            prefix: query.prefix,
+            start: query.start,
+            end: query.end,
+        })),
+        _ => None,
+    };
+    let items = page.items.into_iter()
+        .map(|(key, found)| match query.keys_only {
+            true => {
+                let encoding = query.encoding.for_output(&[&key]);
+                ScanItem { key: encoding.encode(&key), value: None, encoding, content_type: None, version: None }
+            }
+            false => {
+                let encoding = query.encoding.for_output(&[&key, &found.value]);
+                ScanItem {
+                    key: encoding.encode(&key),
+                    value: Some(encoding.encode(&found.value)),
+                    encoding,
+                    content_type: found.meta.content_type,
+                    version: Some(found.meta.version),
+                }
+            }
+        })
+        .collect();
+
+    histogram!("request_duration_seconds", start.elapsed().as_secs_f64(), "route" => "scan");
+    Ok(Json(ScanResponse { status: Status::Success, items, next_cursor }))
+}
@@ -0,0 +1,103 @@
+/*
+POST /v1/txn: reads, conditions and writes, all or nothing (see txn/mod.rs).
+200 when committed. 412 with `failed_condition` when a condition did not hold, and nothing was
+written; the reads still come back so the client can retry against fresh values. The caller needs
+read access to every read and condition key, and write or delete access to every write.
+*/
+use axum::http::StatusCode;
+use axum::extract::Extension;
+use axum::Json;
+use metrics::{counter, histogram};
+use tokio::time::Instant;
+use crate::access::{authorize, Action};
+use crate::coordinator::{self, KvError};
+use crate::encoding::Encoding;
+use crate::middleware::types::Claims;
+use crate::routes_resp::{IncomingTxnRequest, Status, TxnConditionItem, TxnReadResult, TxnResponse, TxnWriteItem, TxnWriteResult};
+use crate::store::{TxnCheck, TxnCondition};
+use crate::txn::{self, Txn, TxnWrite};
+
+fn condition(encoding: Encoding, item: TxnConditionItem) -> Result<TxnCondition, KvError> {
+    let key = encoding.decode("key", item.key)?;
+    let check = match (item.version, item.value, item.exists) {
+        (Some(version), None, None) => TxnCheck::Version(version),
+        (None, Some(value), None) => TxnCheck::Value(encoding.decode("condition value", value)?),
+        (None, None, Some(exists)) => TxnCheck::Exists(exists),
+        _ => return Err(KvError::BadRequest("a condition takes exactly one of version, value or exists".to_string())),
+    };
+    Ok(TxnCondition { key, check })
+}
+
+fn write(encoding: Encoding, item: TxnWriteItem) -> Result<TxnWrite, KvError> {
+    Ok(match item {
+        TxnWriteItem::Set { key, value, content_type, mode, ttl_seconds, expires_at } => TxnWrite::Set {
+            key: encoding.decode("key", key)?,
+            value: encoding.decode("value", value)?,
+            content_type,
+            mode,
+            expires_at: coordinator::expiry(ttl_seconds, expires_at)?,
+        },
+        TxnWriteItem::Delete { key } => TxnWrite::Delete { key: encoding.decode("key", key)? },
+    })
+}
+
+pub async fn transaction(Extension(claims): Extension<Claims>, Json(payload): Json<IncomingTxnRequest>) -> Result<(StatusCode, Json<TxnResponse>), KvError> {
+    let start = Instant::now();
+    counter!("route_hit", 1, "route" => "txn");
+    let encoding = payload.encoding;
+    let txn = Txn {
+        reads: payload.reads.into_iter().map(|key| encoding.decode("key", key)).collect::<Result<_, _>>()?,
+        conditions: payload.conditions.into_iter().map(|item| condition(encoding, item)).collect::<Result<_, _>>()?,
+        writes: payload.writes.into_iter().map(|item| write(encoding, item)).collect::<Result<_, _>>()?,
+    };
+    let namespace = &payload.namespace;
+    for key in txn.reads.iter().chain(txn.conditions.iter().map(|condition| &condition.key)) {
+        authorize(&claims, Action::Read, namespace, key)?;
+    }
+    for write in &txn.writes {
+        let action = match write {
+            TxnWrite::Set { .. } => Action::Write,
+            TxnWrite::Delete { .. } => Action::Delete,
+        };
+        authorize(&claims, action, namespace, write.key())?;
+    }
+    let read_keys = txn.reads.clone();
+    let write_keys: Vec<Vec<u8>> = txn.writes.iter().map(|write| write.key().to_vec()).collect();
+
+    let result = txn::transact(&payload.namespace, txn).await?;
+
+    let reads = read_keys.iter().zip(result.reads)
+        .map(|(key, found)| match found {
+            Some(found) => {
+                let encoding = encoding.for_output(&[key, &found.value]);
+                TxnReadResult {
+                    key: encoding.encode(key),
+                    found: true,
+                    value: Some(encoding.encode(&found.value)),
+                    encoding,
+                    content_type: found.meta.content_type,
+                    version: Some(found.meta.version),
+                }
+            }
+            None => {
+                let encoding = encoding.for_output(&[key]);
+                TxnReadResult { key: encoding.encode(key), found: false, value: None, encoding, content_type: None, version: None }
+            }
+        })
+        .collect();
+    let writes = write_keys.iter().zip(result.writes)
+        .map(|(key, (outcome, version))| {
+            let encoding = encoding.for_output(&[key]);
+            TxnWriteResult { key: encoding.encode(key), encoding, outcome, version }
+        })
+        .collect();
+
+    let committed = result.failed.is_none();
+    if !committed {
+        counter!("txn_conditions_failed_total", 1);
+    }
+    histogram!("request_duration_seconds", start.elapsed().as_secs_f64(), "route" => "txn");
+    let code = if committed { StatusCode::OK } else { StatusCode::PRECONDITION_FAILED };
+    let status = if committed { Status::Success } else { Status::Error };
+    Ok((code, Json(TxnResponse { status, committed, failed_condition: result.failed, reads, writes })))
+}
@@ -0,0 +1,148 @@
+/*
+Accounts (see users.rs):
+  POST   /signup                        create an account with the user role and no grants, unless SIGNUP_ENABLED=false
+Admin only (a token with the admin role):
+  GET    /admin/users                   list
+  POST   /admin/users                   create, with any role
+  GET    /admin/users/{email}
+  PATCH  /admin/users/{email}           change the password, role and/or grants
+  DELETE /admin/users/{email}           also ends the user's sessions
+  POST   /admin/users/{email}/unlock    end a lockout early
+  POST   /admin/revoke                  {"jti"}, {"session"} or {"email"}: revoke a token, a session
+                                        or every session of a user (see tokens.rs)
+  GET    /admin/api-keys                list API keys (see api_keys.rs)
+  POST   /admin/api-keys                create one: {"name","grants","ttl_seconds"?}; the response is
+                                        the only time the full key is shown
+  GET    /admin/api-keys/{id}           includes when it was last used
+  DELETE /admin/api-keys/{id}           revoke it
+*/
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use metrics::counter;
+use crate::config::signup_enabled;
+use crate::coordinator::KvError;
+use crate::middleware::types::Claims;
+use crate::api_keys;
+use crate::routes_resp::{
+    ApiKeyListResponse, ApiKeyResponse, IncomingApiKeyRequest, IncomingRevokeRequest, IncomingUserRequest,
+    IncomingUserUpdate, RevokeResponse, Status, UserListResponse, UserResponse,
+};
+use crate::tokens;
+use crate::users::{self, Role, User};
+
+fn require_admin(claims: &Claims) -> Result<(), KvError> {
+    if claims.role != Role::Admin {
+        return Err(KvError::Forbidden("managing users and credentials needs the admin role".to_string()));
+    }
+    Ok(())
+}
+
+// Password hashing takes a while; keep it off the async workers
+pub async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T, KvError> + Send + 'static) -> Result<T, KvError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| KvError::Internal(format!("task failed: {}", e)))?
+}
+
+fn user_response(user: &User) -> Json<UserResponse> {
+    Json(UserResponse { status: Status::Success, user: user.into() })
+}
+
+pub async fn signup(Json(payload): Json<IncomingUserRequest>) -> Result<Response, KvError> {
+    counter!("route_hit", 1, "route" => "signup");
+    if !signup_enabled() {
+        return Err(KvError::Forbidden("signup is disabled, ask an admin for an account".to_string()));
+    }
+    let user = blocking(move || users::create(&payload.email, &payload.password, Role::User, Vec::new())).await?;
+    Ok((StatusCode::CREATED, user_response(&user)).into_response())
+}
+
+pub async fn list_users(Extension(claims): Extension<Claims>) -> Result<Json<UserListResponse>, KvError> {
+    counter!("route_hit", 1, "route" => "list_users");
+    require_admin(&claims)?;
+    let users = users::list()?.iter().map(Into::into).collect();
+    Ok(Json(UserListResponse { status: Status::Success, users }))
+}
+
+pub async fn create_user(Extension(claims): Extension<Claims>, Json(payload): Json<IncomingUserRequest>) -> Result<Response, KvError> {
+    counter!("route_hit", 1, "route" => "create_user");
+    require_admin(&claims)?;
+    let user = blocking(move || users::create(&payload.email, &payload.password, payload.role, payload.grants)).await?;
+    Ok((StatusCode::CREATED, user_response(&user)).into_response())
+}
+
+pub async fn get_user(Extension(claims): Extension<Claims>, Path(email): Path<String>) -> Result<Json<UserResponse>, KvError> {
+    counter!("route_hit", 1, "route" => "get_user");
+    require_admin(&claims)?;
+    Ok(user_response(&users::get(&email)?))
+}
+
+pub async fn update_user(Extension(claims): Extension<Claims>, Path(email): Path<String>, Json(payload): Json<IncomingUserUpdate>) -> Result<Json<UserResponse>, KvError> {
+    counter!("route_hit", 1, "route" => "update_user");
+    require_admin(&claims)?;
+    let user = blocking(move || users::update(&email, payload.password.as_deref(), payload.role, payload.grants)).await?;
+    Ok(user_response(&user))
+}
+
+pub async fn delete_user(Extension(claims): Extension<Claims>, Path(email): Path<String>) -> Result<StatusCode, KvError> {
+    counter!("route_hit", 1, "route" => "delete_user");
+    require_admin(&claims)?;
+    let email = users::get(&email)?.email;
+    blocking(move || {
+        tokens::revoke_user(&email)?;
+        users::delete(&email)
+    }).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn unlock_user(Extension(claims): Extension<Claims>, Path(email): Path<String>) -> Result<Json<UserResponse>, KvError> {
+    counter!("route_hit", 1, "route" => "unlock_user");
+    require_admin(&claims)?;
+    Ok(user_response(&users::unlock(&email)?))
+}
+
+pub async fn revoke(Extension(claims): Extension<Claims>, Json(payload): Json<IncomingRevokeRequest>) -> Result<Json<RevokeResponse>, KvError> {
+    counter!("route_hit", 1, "route" => "revoke");
+    require_admin(&claims)?;
+    let revoked = match (payload.jti, payload.session, payload.email) {
+        (Some(jti), None, None) => blocking(move || tokens::revoke_token(&jti).map(|_| 1)).await?,
+        (None, Some(session), None) => blocking(move || tokens::revoke_session(&session).map(|_| 1)).await?,
+        (None, None, Some(email)) => {
+            let email = users::get(&email)?.email;
+            blocking(move || tokens::revoke_user(&email)).await?
+        }
+        _ => return Err(KvError::BadRequest("give exactly one of jti, session or email".to_string())),
+    };
+    Ok(Json(RevokeResponse { status: Status::Success, revoked }))
+}
+
+pub async fn list_api_keys(Extension(claims): Extension<Claims>) -> Result<Json<ApiKeyListResponse>, KvError> {
+    counter!("route_hit", 1, "route" => "list_api_keys");
+    require_admin(&claims)?;
+    let api_keys = api_keys::list().iter().map(Into::into).collect();
+    Ok(Json(ApiKeyListResponse { status: Status::Success, api_keys }))
+}
+
+pub async fn create_api_key(Extension(claims): Extension<Claims>, Json(payload): Json<IncomingApiKeyRequest>) -> Result<Response, KvError> {
+    counter!("route_hit", 1, "route" => "create_api_key");
+    require_admin(&claims)?;
+    let (api_key, key) = api_keys::create(&payload.name, payload.grants, payload.ttl_seconds, &claims.email)?;
+    let body = ApiKeyResponse { status: Status::Success, api_key: (&api_key).into(), key: Some(key) };
+    Ok((StatusCode::CREATED, Json(body)).into_response())
+}
+
+pub async fn get_api_key(Extension(claims): Extension<Claims>, Path(id): Path<String>) -> Result<Json<ApiKeyResponse>, KvError> {
+    counter!("route_hit", 1, "route" => "get_api_key");
+    require_admin(&claims)?;
+    let api_key = api_keys::get(&id)?;
+    Ok(Json(ApiKeyResponse { status: Status::Success, api_key: (&api_key).into(), key: None }))
+}
+
+pub async fn delete_api_key(Extension(claims): Extension<Claims>, Path(id): Path<String>) -> Result<StatusCode, KvError> {
+    counter!("route_hit", 1, "route" => "delete_api_key");
+    require_admin(&claims)?;
+    api_keys::delete(&id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,146 @@
+/*
+GET /v1/watch?key= or ?prefix= streams changes to one key or every key under a prefix (an empty
+prefix watches the whole namespace). Plain requests get Server-Sent Events, one `set`, `delete` or
+`expire` event per change with the WAL sequence as its id; a WebSocket upgrade on the same URL
+gets the same events as JSON text messages. To resume after a disconnect pass the last seen
+sequence as ?since= (or let EventSource send Last-Event-ID) and the missed events are replayed
+first. Without either, only changes from now on are sent. Watching needs read access to the key,
+or to the whole prefix. When the login expires or is revoked (or its API key deleted), SSE sends a
+final `unauthorized` event and a WebSocket is closed with code 4401.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::convert::Infallible;
+use axum::extract::ws::{rejection::WebSocketUpgradeRejection, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Extension, Query};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::stream;
+use metrics::counter;
+use serde::Deserialize;
+use chrono::DateTime;
+use crate::access::{authorize, Action};
+use crate::changefeed::{Change, WatchFilter, Watcher, WatcherGuard};
+use crate::coordinator::KvError;
+use crate::encoding::Encoding;
+use crate::middleware::types::Claims;
+use crate::namespace;
+use crate::routes_resp::{default_namespace, ErrorResponse, Status, WatchEvent};
+
+// Sent when the watch outlives the caller's login
+const LOGIN_ENDED: &str = "login expired or revoked";
+// 4000-4999 are for applications; this one mirrors HTTP 401
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+
+#[derive(Deserialize)]
+pub struct WatchQuery {
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    pub key: Option<String>,
+    pub prefix: Option<String>,
+    // Of key/prefix and of the keys and values in the events
+    #[serde(default)]
+    pub encoding: Encoding,
+    // Replay everything after this WAL sequence
+    pub since: Option<usize>,
+}
+
+fn watcher(claims: &Claims, query: WatchQuery, headers: &HeaderMap) -> Result<Watcher, KvError> {
+    namespace::get(&query.namespace)?;
+    let filter = match (query.key, query.prefix) {
+        (Some(key), None) => WatchFilter { namespace: query.namespace, key: Some(query.encoding.decode("key", key)?), prefix: None },
+        (None, Some(prefix)) => WatchFilter { namespace: query.namespace, key: None, prefix: Some(query.encoding.decode("prefix", prefix)?) },
+        _ => return Err(KvError::BadRequest("give either key or prefix".to_string())),
+    };
+    let watched = filter.key.as_ref().or(filter.prefix.as_ref()).expect("key or prefix is set");
+    authorize(claims, Action::Read, &filter.namespace, watched)?;
+    let last_event_id = headers.get("last-event-id")
+        .map(|id| id.to_str().ok().and_then(|id| id.trim().parse::<usize>().ok())
+            .ok_or_else(|| KvError::BadRequest("Last-Event-ID must be a sequence number".to_string())))
+        .transpose()?;
+    Ok(Watcher::new(filter, query.since.or(last_event_id)))
+}
+
+fn watch_event(change: Change, encoding: Encoding) -> WatchEvent {
+    let encoding = match &change.value {
+        Some(value) => encoding.for_output(&[&change.key, value]),
+        None => encoding.for_output(&[&change.key]),
+    };
+    WatchEvent {
+        seq: change.seq,
+        event: change.kind,
+        namespace: change.namespace,
+        key: encoding.encode(&change.key),
+        value: change.value.map(|value| encoding.encode(&value)),
+        encoding,
+        version: change.version,
+        content_type: change.content_type,
+        expires_at: change.expires_at.and_then(|at| DateTime::from_timestamp_millis(at as i64)),
+    }
+}
+
+fn sse_event(event: &WatchEvent) -> Event {
+    let kind = serde_json::to_value(event.event).ok().and_then(|k| k.as_str().map(str::to_string)).unwrap_or_default();
+    Event::default()
+        .id(event.seq.to_string())
+        .event(kind)
+        .json_data(event)
+        .unwrap_or_else(|_| Event::default().comment("unserializable event"))
+}
+
+fn unauthorized_event() -> Event {
+    Event::default()
+        .event("unauthorized")
+        .json_data(ErrorResponse { status: Status::Error, error: LOGIN_ENDED.to_string() })
+        .unwrap_or_else(|_| Event::default().event("unauthorized"))
+}
+
+// Not an upgrade request (the rejection) means SSE
+pub async fn watch(ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>, Extension(claims): Extension<Claims>, Query(query): Query<WatchQuery>, headers: HeaderMap) -> Result<Response, KvError> {
+    counter!("route_hit", 1, "route" => "watch");
+    let encoding = query.encoding;
+    let watcher = watcher(&claims, query, &headers)?;
+    if let Ok(ws) = ws {
+        return Ok(ws.on_upgrade(move |socket| watch_socket(socket, watcher, claims, encoding)));
+    }
+
+    let guard = WatcherGuard::new("sse");
+    // The state is gone once the unauthorized event went out, which ends the stream
+    let events = stream::unfold(Some((watcher, guard, claims)), move |state| async move {
+        let (mut watcher, guard, claims) = state?;
+        let Some(change) = watcher.next_for(&claims).await else {
+            return Some((Ok::<_, Infallible>(unauthorized_event()), None));
+        };
+        let event = watch_event(change, encoding);
+        counter!("watch_events_total", 1, "transport" => "sse");
+        Some((Ok(sse_event(&event)), Some((watcher, guard, claims))))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()).into_response())
+}
+
+// Until the client closes the socket or the login ends; anything the client sends is ignored
+async fn watch_socket(mut socket: WebSocket, mut watcher: Watcher, claims: Claims, encoding: Encoding) {
+    let _guard = WatcherGuard::new("websocket");
+    loop {
+        tokio::select! {
+            change = watcher.next_for(&claims) => {
+                let Some(change) = change else {
+                    let frame = CloseFrame { code: CLOSE_UNAUTHORIZED, reason: LOGIN_ENDED.into() };
+                    let _ = socket.send(Message::Close(Some(frame))).await;
+                    return;
+                };
+                let Ok(text) = serde_json::to_string(&watch_event(change, encoding)) else { continue };
+                if socket.send(Message::Text(text.into())).await.is_err() {
+                    return;
+                }
+                counter!("watch_events_total", 1, "transport" => "websocket");
+            }
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
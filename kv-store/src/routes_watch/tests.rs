@@ -0,0 +1,38 @@
+use std::time::Duration;
+use axum::body::{Body, BodyDataStream};
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Extension, Router};
+use futures_util::StreamExt;
+use tower::ServiceExt;
+use crate::access::{Action, Grant};
+use crate::api_keys;
+use super::watch;
+
+// SSE text until the server ends the stream
+async fn read_to_end(mut body: BodyDataStream) -> String {
+    let mut text = String::new();
+    while let Some(chunk) = body.next().await {
+        text.push_str(std::str::from_utf8(&chunk.unwrap()).unwrap());
+    }
+    text
+}
+
+#[tokio::test]
+async fn deleting_an_api_key_ends_its_watch() {
+    let grants = vec![Grant { namespace: "default".to_string(), prefix: String::new(), actions: vec![Action::Read] }];
+    let (key, full_key) = api_keys::create("watcher", grants, None, "admin@x").unwrap();
+    let claims = api_keys::verify(&full_key).unwrap();
+    let app = Router::new().route("/v1/watch", get(watch)).layer(Extension(claims));
+    let request = Request::get("/v1/watch?prefix=nothing-writes-here/").body(Body::empty()).unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let reading = tokio::spawn(read_to_end(response.into_body().into_data_stream()));
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!reading.is_finished());
+    api_keys::delete(&key.id).unwrap();
+    let text = tokio::time::timeout(Duration::from_secs(5), reading).await.unwrap().unwrap();
+    assert!(text.starts_with("event: unauthorized\n"), "{}", text);
+}
@@ -0,0 +1,51 @@
+use super::api_keys;
+use super::config::HASH_RING;
+use super::wal::{get_wal_stats, sync_wal, WAL_FILE};
+
+// Resolves on Ctrl+C or SIGTERM (what Kubernetes sends on a rollout)
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => println!("Received Ctrl+C, shutting down"),
+        _ = terminate => println!("Received SIGTERM, shutting down"),
+    }
+}
+
+// Flush every sled tree on every node and fsync the WAL
+pub fn flush_storage() {
+    // Last-used times of API keys are otherwise only written back by the expiry sweeper
+    api_keys::persist_last_used();
+    {
+        let ring = HASH_RING.read().unwrap();
+        for node_id in ring.get_all_node_ids() {
+            // Db::flush writes back the shared page cache, covering every tree in the node
+            if let Some(node) = ring.get_node_by_id(&node_id)
+                && let Err(e) = node.db.flush() {
+                eprintln!("Failed to flush {}: {}", node_id, e);
+            }
+        }
+    }
+
+    match sync_wal() {
+        Ok(_) => {
+            if let Ok(stats) = get_wal_stats(WAL_FILE) {
+                println!("WAL synced: {} entries, {} bytes, last seq {}",
+                    stats.total_entries, stats.file_size_bytes, stats.last_sequence);
+            }
+        }
+        Err(e) => eprintln!("Failed to sync WAL: {}", e),
+    }
+}
@@ -0,0 +1,382 @@
+use std::ops::Bound;
+use serde::{Deserialize, Serialize};
+use sled::{transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree, UnabortableTransactionError}, Db, IVec, Tree};
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::routes_resp::{Condition, WalOp, WriteMode};
+
+// Per-node tree with per-key metadata, written in the same transaction as the value
+pub const META_TREE: &str = "__meta";
+
+// A namespace's values and their metadata on one node
+pub struct NsTrees {
+    pub data: Tree,
+    pub meta: Tree,
+}
+
+/*
+The default namespace is the node's default tree plus __meta, as before namespaces existed.
+Any other namespace gets its own pair: ns:<name> and __meta:<name>.
+*/
+pub fn trees(db: &Db, namespace: &str) -> sled::Result<NsTrees> {
+    if namespace == DEFAULT_NAMESPACE {
+        return Ok(NsTrees { data: (**db).clone(), meta: db.open_tree(META_TREE)? });
+    }
+    Ok(NsTrees {
+        data: db.open_tree(format!("ns:{}", namespace))?,
+        meta: db.open_tree(format!("__meta:{}", namespace))?,
+    })
+}
+
+// Namespaces that have trees on this node, default first
+pub fn namespaces_on(db: &Db) -> Vec<String> {
+    let mut names = vec![DEFAULT_NAMESPACE.to_string()];
+    names.extend(db.tree_names().iter().filter_map(|name| {
+        std::str::from_utf8(name).ok()?.strip_prefix("ns:").map(str::to_string)
+    }));
+    names
+}
+
+pub fn drop_trees(db: &Db, namespace: &str) -> sled::Result<()> {
+    db.drop_tree(format!("ns:{}", namespace))?;
+    db.drop_tree(format!("__meta:{}", namespace))?;
+    Ok(())
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ValueMeta {
+    pub content_type: Option<String>,
+    // Bumped on every write, starts at 1; served as the ETag
+    pub version: u64,
+    // Epoch milliseconds; an expired key reads as absent until the sweeper deletes it
+    pub expires_at: Option<u64>,
+}
+
+impl ValueMeta {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+pub fn now_ms() -> u64 {
+    chrono::Utc::now().timestamp_millis() as u64
+}
+
+fn encode_meta(meta: &ValueMeta) -> Vec<u8> {
+    bincode::serialize(meta).expect("value metadata is always serializable")
+}
+
+fn decode_meta(bytes: &[u8]) -> ValueMeta {
+    bincode::deserialize(bytes).unwrap_or_default()
+}
+
+// What applying an operation did; the last two mean nothing was written
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyOutcome {
+    Created,
+    Updated,
+    Deleted,
+    // create-only SET on a key that exists
+    AlreadyExists,
+    // update-only SET or DELETE on a key that does not exist
+    Missing,
+    // the key's current version/value did not match the operation's Condition
+    ConditionFailed,
+    // INCR applied, with the new value
+    Incremented(i64),
+    // INCR on a value that isn't an integer, or the result would overflow
+    NotAnInteger,
+}
+
+// A condition on a missing key never holds
+fn condition_holds(condition: &Option<Condition>, current: Option<&IVec>, meta: &ValueMeta) -> bool {
+    match (condition, current) {
+        (None, _) => true,
+        // Judged on the metadata alone: by any member's clock the value may already read as missing
+        (Some(Condition::ExpiredBy(at)), _) => meta.expires_at.is_some_and(|expires_at| expires_at <= *at),
+        (Some(_), None) => false,
+        (Some(Condition::Version(version)), Some(_)) => meta.version == *version,
+        (Some(Condition::Value(expected)), Some(value)) => value.as_ref() == expected.as_slice(),
+    }
+}
+
+/*
+The single place that turns a WalOp into sled writes. The leader, the WAL replication workers
+and the Raft state machine all call this inside their own transaction, so every copy of a key
+ends up with the same value and metadata, and the write mode and CAS condition are checked the
+same way everywhere. The compare and the swap run in one sled transaction rather than a
+Tree::compare_and_swap because the value and its version live in two trees. INCR is a
+read-modify-write in the same transaction for the same reason (Tree::update_and_fetch can't bump
+the version alongside).
+*/
+pub fn apply_op<E>(data: &TransactionalTree, meta: &TransactionalTree, op: &WalOp) -> ConflictableTransactionResult<ApplyOutcome, E> {
+    let key = op.key();
+    let current_meta = current_meta(meta, key)?;
+    // An expired key counts as absent for modes and conditions. Raft members judge this by their
+    // own clock at apply time, so a write racing the expiry instant can resolve differently.
+    let current = data.get(key)?.filter(|_| !current_meta.is_expired(now_ms()));
+
+    match op {
+        WalOp::Set { value, content_type, mode, condition, expires_at, .. } => {
+            if !condition_holds(condition, current.as_ref(), &current_meta) {
+                return Ok(ApplyOutcome::ConditionFailed);
+            }
+            let existed = current.is_some();
+            match (mode, existed) {
+                (WriteMode::CreateOnly, true) => return Ok(ApplyOutcome::AlreadyExists),
+                (WriteMode::UpdateOnly, false) => return Ok(ApplyOutcome::Missing),
+                _ => {}
+            }
+            data.insert(key, value.as_slice())?;
+            let value_meta = ValueMeta {
+                content_type: content_type.clone(),
+                version: if existed { current_meta.version + 1 } else { 1 },
+                expires_at: *expires_at,
+            };
+            meta.insert(key, encode_meta(&value_meta))?;
+            Ok(if existed { ApplyOutcome::Updated } else { ApplyOutcome::Created })
+        }
+        WalOp::Delete { condition, .. } => {
+            if condition.is_some() && !condition_holds(condition, current.as_ref(), &current_meta) {
+                return Ok(ApplyOutcome::ConditionFailed);
+            }
+            // An expired copy is removed too, but it was already gone as far as clients can tell
+            data.remove(key)?;
+            meta.remove(key)?;
+            Ok(if current.is_some() { ApplyOutcome::Deleted } else { ApplyOutcome::Missing })
+        }
+        // Set or clear (PERSIST) the expiry without touching the value or its version
+        WalOp::Expire { expires_at, .. } => {
+            if current.is_none() {
+                return Ok(ApplyOutcome::Missing);
+            }
+            let value_meta = ValueMeta { expires_at: *expires_at, ..current_meta };
+            meta.insert(key, encode_meta(&value_meta))?;
+            Ok(ApplyOutcome::Updated)
+        }
+        // Keeps the content type and expiry of an existing value
+        WalOp::Incr { delta, expires_at, .. } => {
+            let value = match &current {
+                None => Some(0),
+                Some(value) => std::str::from_utf8(value).ok().and_then(|v| v.parse::<i64>().ok()),
+            };
+            let Some(next) = value.and_then(|value| value.checked_add(*delta)) else {
+                return Ok(ApplyOutcome::NotAnInteger);
+            };
+            data.insert(key, next.to_string().as_bytes())?;
+            let value_meta = match current {
+                Some(_) => ValueMeta { version: current_meta.version + 1, ..current_meta },
+                None => ValueMeta { content_type: None, version: 1, expires_at: *expires_at },
+            };
+            meta.insert(key, encode_meta(&value_meta))?;
+            Ok(ApplyOutcome::Incremented(next))
+        }
+    }
+}
+
+// A transaction's guard on one key; Exists(false) requires the key to be absent
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TxnCheck {
+    Version(u64),
+    Value(Vec<u8>),
+    Exists(bool),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TxnCondition {
+    pub key: Vec<u8>,
+    pub check: TxnCheck,
+}
+
+// What a transaction saw and did; `failed` is the index of the first condition that did not hold
+pub struct TxnApplied {
+    pub reads: Vec<Option<(IVec, ValueMeta)>>,
+    pub failed: Option<usize>,
+    // Outcome and resulting metadata of every write, empty if a condition failed
+    pub writes: Vec<(ApplyOutcome, ValueMeta)>,
+}
+
+// Why a transaction wrote nothing
+#[derive(Clone, Debug, PartialEq)]
+pub enum TxnAbort {
+    // A write its own mode rejected (create-only on an existing key, update-only on a missing one)
+    Rejected { index: usize, outcome: ApplyOutcome },
+    // A key is held by a cross-partition transaction that hasn't finished
+    Locked { key: Vec<u8>, txn: String },
+}
+
+// Per-node trees of cross-partition transactions (txn/two_phase.rs): the transaction log with
+// intents and decisions, and the locks prepared transactions hold on their keys
+pub const TXN_LOG_TREE: &str = "__txn";
+pub const TXN_LOCKS_TREE: &str = "__txn_locks";
+
+// Locks are per namespace; namespace names never contain a NUL
+pub fn lock_key(namespace: &str, key: &[u8]) -> Vec<u8> {
+    let mut lock = Vec::with_capacity(namespace.len() + 1 + key.len());
+    lock.extend_from_slice(namespace.as_bytes());
+    lock.push(0);
+    lock.extend_from_slice(key);
+    lock
+}
+
+// Id of the transaction holding `key`, if any
+pub fn locked_by(locks: &TransactionalTree, namespace: &str, key: &[u8]) -> Result<Option<String>, UnabortableTransactionError> {
+    Ok(locks.get(lock_key(namespace, key))?.map(|txn| String::from_utf8_lossy(&txn).into_owned()))
+}
+
+pub fn check_holds(check: &TxnCheck, current: Option<&(IVec, ValueMeta)>) -> bool {
+    match (check, current) {
+        (TxnCheck::Exists(exists), current) => current.is_some() == *exists,
+        (_, None) => false,
+        (TxnCheck::Version(version), Some((_, meta))) => meta.version == *version,
+        (TxnCheck::Value(expected), Some((value, _))) => value.as_ref() == expected.as_slice(),
+    }
+}
+
+// The key's value if it exists and hasn't expired, as a transaction sees it
+pub fn live(data: &TransactionalTree, meta: &TransactionalTree, key: &[u8]) -> Result<Option<(IVec, ValueMeta)>, UnabortableTransactionError> {
+    let current_meta = current_meta(meta, key)?;
+    Ok(data.get(key)?.filter(|_| !current_meta.is_expired(now_ms())).map(|value| (value, current_meta)))
+}
+
+/*
+Reads, then conditions, then writes, all inside the caller's transaction over one namespace's
+trees on one node, so nobody sees part of it. Reads see the state before the writes. A key locked
+by an unfinished cross-partition transaction or a failed condition stops before anything is
+written; a write whose own mode rejects it aborts the whole transaction, undoing the writes before it.
+*/
+pub fn apply_txn(data: &TransactionalTree, meta: &TransactionalTree, locks: &TransactionalTree, namespace: &str,
+    reads: &[Vec<u8>], conditions: &[TxnCondition], writes: &[WalOp]) -> ConflictableTransactionResult<TxnApplied, TxnAbort> {
+    let keys = reads.iter().map(Vec::as_slice)
+        .chain(conditions.iter().map(|condition| condition.key.as_slice()))
+        .chain(writes.iter().map(WalOp::key));
+    for key in keys {
+        if let Some(txn) = locked_by(locks, namespace, key)? {
+            return Err(ConflictableTransactionError::Abort(TxnAbort::Locked { key: key.to_vec(), txn }));
+        }
+    }
+
+    let reads = reads.iter().map(|key| live(data, meta, key)).collect::<Result<Vec<_>, _>>()?;
+    for (index, condition) in conditions.iter().enumerate() {
+        if !check_holds(&condition.check, live(data, meta, &condition.key)?.as_ref()) {
+            return Ok(TxnApplied { reads, failed: Some(index), writes: Vec::new() });
+        }
+    }
+
+    let mut applied = Vec::with_capacity(writes.len());
+    for (index, op) in writes.iter().enumerate() {
+        let outcome = apply_op(data, meta, op)?;
+        match outcome {
+            ApplyOutcome::Created | ApplyOutcome::Updated | ApplyOutcome::Deleted | ApplyOutcome::Incremented(_) => {}
+            // Deleting a missing key is fine
+            ApplyOutcome::Missing if matches!(op, WalOp::Delete { .. }) => {}
+            outcome => return Err(ConflictableTransactionError::Abort(TxnAbort::Rejected { index, outcome })),
+        }
+        applied.push((outcome, current_meta(meta, op.key())?));
+    }
+    Ok(TxnApplied { reads, failed: None, writes: applied })
+}
+
+// Removes `key` only if it is still expired, so a write that landed after the sweep looked is kept
+pub fn remove_if_expired<E>(data: &TransactionalTree, meta: &TransactionalTree, key: &[u8], now: u64) -> ConflictableTransactionResult<bool, E> {
+    if !current_meta(meta, key)?.is_expired(now) {
+        return Ok(false);
+    }
+    data.remove(key)?;
+    meta.remove(key)?;
+    Ok(true)
+}
+
+pub fn current_meta(meta: &TransactionalTree, key: &[u8]) -> Result<ValueMeta, UnabortableTransactionError> {
+    Ok(meta.get(key)?.map(|m| decode_meta(&m)).unwrap_or_default())
+}
+
+// Value plus metadata; keys written before the meta tree existed get default metadata.
+// Expired keys read as missing.
+pub fn read(trees: &NsTrees, key: &[u8]) -> sled::Result<Option<(IVec, ValueMeta)>> {
+    let Some(value) = trees.data.get(key)? else { return Ok(None) };
+    let meta = trees.meta
+        .get(key)?
+        .map(|m| decode_meta(&m))
+        .unwrap_or_default();
+    if meta.is_expired(now_ms()) {
+        return Ok(None);
+    }
+    Ok(Some((value, meta)))
+}
+
+// Keys on this node whose expiry has passed
+pub fn expired_keys(trees: &NsTrees, now: u64) -> sled::Result<Vec<Vec<u8>>> {
+    let mut keys = Vec::new();
+    for entry in trees.meta.iter() {
+        let (key, meta) = entry?;
+        if decode_meta(&meta).is_expired(now) {
+            keys.push(key.to_vec());
+        }
+    }
+    Ok(keys)
+}
+
+// Key range for a scan: keys in [start, end) that begin with prefix, all optional
+#[derive(Clone, Default)]
+pub struct KeyRange {
+    pub prefix: Option<Vec<u8>>,
+    pub start: Option<Vec<u8>>,
+    pub end: Option<Vec<u8>>,
+    // Resume strictly after this key
+    pub after: Option<Vec<u8>>,
+}
+
+/*
+Up to `limit` live keys of `range` on this node, in key order, that `keep` accepts. sled keeps the
+trees ordered by raw bytes, so this is a single forward range walk starting at the highest lower bound.
+*/
+pub fn scan_node(trees: &NsTrees, range: &KeyRange, limit: usize, keep: impl Fn(&[u8]) -> bool) -> sled::Result<Vec<(Vec<u8>, IVec, ValueMeta)>> {
+    let lower = [range.prefix.as_deref(), range.start.as_deref()].into_iter().flatten().max();
+    let from = match (lower, range.after.as_deref()) {
+        (Some(lower), Some(after)) if after < lower => Bound::Included(lower.to_vec()),
+        (_, Some(after)) => Bound::Excluded(after.to_vec()),
+        (Some(lower), None) => Bound::Included(lower.to_vec()),
+        (None, None) => Bound::Unbounded,
+    };
+    let now = now_ms();
+    let mut found = Vec::new();
+
+    for entry in trees.data.range::<Vec<u8>, _>((from, Bound::Unbounded)) {
+        let (key, value) = entry?;
+        if range.end.as_ref().is_some_and(|end| key.as_ref() >= end.as_slice()) {
+            break;
+        }
+        if let Some(prefix) = &range.prefix
+            && !key.starts_with(prefix) {
+            break;
+        }
+        if !keep(&key) {
+            continue;
+        }
+        let meta = trees.meta.get(&key)?.map(|m| decode_meta(&m)).unwrap_or_default();
+        if meta.is_expired(now) {
+            continue;
+        }
+        found.push((key.to_vec(), value, meta));
+        if found.len() == limit {
+            break;
+        }
+    }
+    Ok(found)
+}
+
+// Live keys and value bytes on this node that `keep` accepts (used for namespace quotas)
+pub fn usage(trees: &NsTrees, keep: impl Fn(&[u8]) -> bool) -> sled::Result<(u64, u64)> {
+    let now = now_ms();
+    let (mut keys, mut bytes) = (0, 0);
+    for entry in trees.data.iter() {
+        let (key, value) = entry?;
+        let expired = trees.meta.get(&key)?.is_some_and(|m| decode_meta(&m).is_expired(now));
+        if keep(&key) && !expired {
+            keys += 1;
+            bytes += value.len() as u64;
+        }
+    }
+    Ok((keys, bytes))
+}
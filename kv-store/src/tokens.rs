@@ -0,0 +1,266 @@
+/*
+Access and refresh tokens. Login starts a session and returns a short-lived JWT access token
+(config::access_token_ttl()) plus an opaque refresh token. Every access token carries a `jti` of its
+own and the `sid` of its session.
+
+Refresh tokens rotate: each one works once, returning a new pair with a fresh role and grants read
+from the user record. Presenting a refresh token that was already used means it leaked (or the
+client raced itself), so the whole session is revoked. Only a hash of each refresh token is stored.
+
+Revoked `jti`s and sessions go on a revocation list that, like users, is written to every node's
+__revoked_tokens tree. Like namespace definitions it is also kept in memory, loaded at startup
+(load_revocations) and updated on every revocation, so verify_token can check it for every protocol
+and every command without reading storage. An entry only needs to outlive the access tokens it
+blocks, so the expiry sweeper drops it after access_token_ttl().
+*/
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Mutex, RwLock};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dotenv::dotenv;
+use jsonwebtoken::{encode, EncodingKey, Header};
+use metrics::counter;
+use once_cell::sync::Lazy;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::config::{access_token_ttl, refresh_token_ttl, HASH_RING};
+use crate::coordinator::KvError;
+use crate::middleware::types::Claims;
+use crate::store::now_ms;
+use crate::users::{self, User};
+
+const REFRESH_TREE: &str = "__refresh_tokens";
+const REVOKED_TREE: &str = "__revoked_tokens";
+
+#[derive(Serialize, Deserialize)]
+struct RefreshRecord {
+    email: String,
+    session: String,
+    // Epoch milliseconds
+    expires_at: u64,
+    // Already exchanged; kept until it expires so reuse is caught
+    #[serde(default)]
+    used: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Revoked {
+    // Epoch milliseconds; every token this entry blocks has expired by then
+    until: u64,
+}
+
+pub struct Issued {
+    pub access_token: String,
+    pub refresh_token: String,
+    // Seconds until the access token expires
+    pub expires_in: u64,
+}
+
+// Serializes refresh token exchanges, so a token can't be used twice by racing requests
+static REFRESH_LOCK: Mutex<()> = Mutex::new(());
+
+// The revocation list: "<kind>:<id>" -> until, as in REVOKED_TREE
+static REVOKED: Lazy<RwLock<HashMap<String, u64>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn random_id() -> String {
+    URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+fn token_hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+fn revoked_key(kind: &str, id: &str) -> String {
+    format!("{}:{}", kind, id)
+}
+
+// First node that answers, like users.rs
+fn load<T: DeserializeOwned>(tree: &str, key: &[u8]) -> Result<Option<T>, KvError> {
+    let ring = HASH_RING.read().unwrap();
+    let mut last_error = None;
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        match node.db.open_tree(tree).and_then(|tree| tree.get(key)) {
+            Ok(Some(record)) => {
+                let record = serde_json::from_slice(&record)
+                    .map_err(|e| KvError::Internal(format!("bad record in {}: {}", tree, e)))?;
+                return Ok(Some(record));
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(e.into()),
+        None => Ok(None),
+    }
+}
+
+fn save<T: Serialize>(tree: &str, key: &[u8], record: &T) -> Result<(), KvError> {
+    let record = serde_json::to_vec(record).map_err(|e| KvError::Internal(format!("failed to encode record: {}", e)))?;
+    let ring = HASH_RING.read().unwrap();
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        node.db.open_tree(tree)?.insert(key, record.as_slice())?;
+        node.db.flush()?;
+    }
+    Ok(())
+}
+
+// Removes the matching records on every node; returns how many went from the first one
+fn remove_where<T: DeserializeOwned>(tree: &str, matches: impl Fn(&T) -> bool) -> Result<usize, KvError> {
+    let ring = HASH_RING.read().unwrap();
+    let mut removed = None;
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        let tree = node.db.open_tree(tree)?;
+        let mut count = 0;
+        for (key, record) in tree.iter().flatten() {
+            // Unreadable records can't be honoured either, so they go too
+            if serde_json::from_slice(&record).map_or(true, |record| matches(&record)) {
+                tree.remove(key)?;
+                count += 1;
+            }
+        }
+        node.db.flush()?;
+        removed.get_or_insert(count);
+    }
+    Ok(removed.unwrap_or(0))
+}
+
+fn sign(claims: &Claims) -> Result<String, KvError> {
+    dotenv().ok();
+    let secret = env::var("JWT_SECRATE").unwrap();
+    encode(&Header::default(), claims, &EncodingKey::from_secret(secret.as_ref()))
+        .map_err(|e| KvError::Internal(format!("failed to sign token: {}", e)))
+}
+
+// A new token pair for the user, in `session` or a new one
+fn issue(user: User, session: Option<String>) -> Result<Issued, KvError> {
+    let now = now_ms();
+    let session = session.unwrap_or_else(random_id);
+    let refresh_token = random_id();
+    let record = RefreshRecord {
+        email: user.email.clone(),
+        session: session.clone(),
+        expires_at: now + refresh_token_ttl().as_millis() as u64,
+        used: false,
+    };
+    save(REFRESH_TREE, &token_hash(&refresh_token), &record)?;
+
+    let expires_in = access_token_ttl().as_secs();
+    let claims = Claims {
+        email: user.email,
+        role: user.role,
+        grants: user.grants,
+        exp: (now / 1000 + expires_in) as usize,
+        jti: random_id(),
+        sid: session,
+    };
+    Ok(Issued { access_token: sign(&claims)?, refresh_token, expires_in })
+}
+
+pub fn login(email: &str, password: &str) -> Result<Issued, KvError> {
+    issue(users::authenticate(email, password)?, None)
+}
+
+// Exchanges a refresh token for a new pair; a reused one revokes its session
+pub fn refresh(refresh_token: &str) -> Result<Issued, KvError> {
+    let invalid = || KvError::Unauthorized("invalid or expired refresh token".to_string());
+    let key = token_hash(refresh_token);
+    let _guard = REFRESH_LOCK.lock().unwrap();
+    let Some(mut record) = load::<RefreshRecord>(REFRESH_TREE, &key)? else { return Err(invalid()) };
+    if record.expires_at <= now_ms() {
+        return Err(invalid());
+    }
+    if record.used {
+        counter!("refresh_token_reuse_total", 1);
+        println!("Refresh token reused for '{}', revoking its session", record.email);
+        revoke_session(&record.session)?;
+        return Err(invalid());
+    }
+    // A deleted user's sessions end here
+    let user = users::get(&record.email).map_err(|_| invalid())?;
+    record.used = true;
+    save(REFRESH_TREE, &key, &record)?;
+    issue(user, Some(record.session))
+}
+
+fn add_revoked(kind: &str, id: &str) -> Result<(), KvError> {
+    let until = now_ms() + access_token_ttl().as_millis() as u64;
+    let key = revoked_key(kind, id);
+    // In memory first: even if storing it fails, this process stops accepting the token
+    REVOKED.write().unwrap().insert(key.clone(), until);
+    save(REVOKED_TREE, key.as_bytes(), &Revoked { until })?;
+    counter!("tokens_revoked_total", 1, "kind" => kind.to_string());
+    Ok(())
+}
+
+pub fn revoke_token(jti: &str) -> Result<(), KvError> {
+    add_revoked("jti", jti)
+}
+
+// Ends a session: its access tokens stop working and its refresh tokens are dropped
+pub fn revoke_session(session: &str) -> Result<(), KvError> {
+    add_revoked("session", session)?;
+    remove_where::<RefreshRecord>(REFRESH_TREE, |record| record.session == session)?;
+    Ok(())
+}
+
+// Ends every session of the user; returns how many there were
+pub fn revoke_user(email: &str) -> Result<usize, KvError> {
+    let sessions: std::collections::BTreeSet<String> = {
+        let ring = HASH_RING.read().unwrap();
+        let Some(node) = ring.get_all_node_ids().first().and_then(|id| ring.get_node_by_id(id)) else { return Ok(0) };
+        node.db.open_tree(REFRESH_TREE)?
+            .iter()
+            .values()
+            .flatten()
+            .filter_map(|record| serde_json::from_slice::<RefreshRecord>(&record).ok())
+            .filter(|record| record.email == email)
+            .map(|record| record.session)
+            .collect()
+    };
+    for session in &sessions {
+        revoke_session(session)?;
+    }
+    Ok(sessions.len())
+}
+
+// Read the revocation list back from disk (call once at startup, before any listener); entries on
+// any node count
+pub fn load_revocations() {
+    let ring = HASH_RING.read().unwrap();
+    let mut revoked = REVOKED.write().unwrap();
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        let Ok(tree) = node.db.open_tree(REVOKED_TREE) else { continue };
+        for (key, entry) in tree.iter().flatten() {
+            match serde_json::from_slice::<Revoked>(&entry) {
+                Ok(entry) => {
+                    let until = revoked.entry(String::from_utf8_lossy(&key).to_string()).or_insert(entry.until);
+                    *until = (*until).max(entry.until);
+                }
+                Err(e) => eprintln!("Skipping bad revocation entry on {}: {}", id, e),
+            }
+        }
+    }
+    println!("Loaded {} token revocations", revoked.len());
+}
+
+pub fn is_revoked(claims: &Claims) -> bool {
+    let revoked = REVOKED.read().unwrap();
+    revoked.contains_key(&revoked_key("jti", &claims.jti)) || revoked.contains_key(&revoked_key("session", &claims.sid))
+}
+
+// Drop expired refresh tokens and revocation entries on every node; returns how many went
+pub fn purge_expired() -> usize {
+    let now = now_ms();
+    REVOKED.write().unwrap().retain(|_, until| *until > now);
+    let refresh = remove_where::<RefreshRecord>(REFRESH_TREE, |record| record.expires_at <= now);
+    let revoked = remove_where::<Revoked>(REVOKED_TREE, |entry| entry.until <= now);
+    refresh.unwrap_or(0) + revoked.unwrap_or(0)
+}
@@ -0,0 +1,88 @@
+use crate::coordinator::KvError;
+use crate::middleware::{still_valid, verify_token};
+use crate::users::{self, Role};
+use super::{is_revoked, load_revocations, login, refresh, revoke_session, revoke_token, revoke_user, REVOKED};
+
+const PASSWORD: &str = "correct horse";
+
+fn new_user() -> String {
+    let email = format!("user-{:016x}@example.com", rand::random::<u64>());
+    users::create(&email, PASSWORD, Role::User, Vec::new()).unwrap();
+    email
+}
+
+#[test]
+fn refresh_tokens_rotate() {
+    let email = new_user();
+    let first = login(&email, PASSWORD).unwrap();
+    let second = refresh(&first.refresh_token).unwrap();
+    assert_ne!(second.refresh_token, first.refresh_token);
+
+    let (old, new) = (verify_token(&first.access_token).unwrap(), verify_token(&second.access_token).unwrap());
+    assert_eq!(new.email, email);
+    assert_eq!(new.sid, old.sid);
+    assert_ne!(new.jti, old.jti);
+    assert!(refresh(&second.refresh_token).is_ok());
+    assert!(matches!(refresh("never issued"), Err(KvError::Unauthorized(_))));
+}
+
+#[test]
+fn reusing_a_refresh_token_revokes_the_session() {
+    let email = new_user();
+    let first = login(&email, PASSWORD).unwrap();
+    let second = refresh(&first.refresh_token).unwrap();
+    let other_session = login(&email, PASSWORD).unwrap();
+
+    assert!(matches!(refresh(&first.refresh_token), Err(KvError::Unauthorized(_))));
+    // Every token of the session is dead, the newest refresh token included
+    assert!(verify_token(&first.access_token).is_none());
+    assert!(verify_token(&second.access_token).is_none());
+    assert!(refresh(&second.refresh_token).is_err());
+    // Other logins of the same user are left alone
+    assert!(verify_token(&other_session.access_token).is_some());
+    assert!(refresh(&other_session.refresh_token).is_ok());
+}
+
+#[test]
+fn revokes_single_tokens_and_whole_users() {
+    let email = new_user();
+    let first = login(&email, PASSWORD).unwrap();
+    let second = login(&email, PASSWORD).unwrap();
+    let claims = verify_token(&first.access_token).unwrap();
+    revoke_token(&claims.jti).unwrap();
+    assert!(verify_token(&first.access_token).is_none());
+    assert!(!still_valid(&claims));
+    assert!(verify_token(&second.access_token).is_some());
+
+    assert_eq!(revoke_user(&email).unwrap(), 2);
+    assert!(verify_token(&second.access_token).is_none());
+    assert!(refresh(&second.refresh_token).is_err());
+}
+
+#[test]
+fn connections_drop_expired_and_revoked_logins() {
+    let email = new_user();
+    let issued = login(&email, PASSWORD).unwrap();
+    let mut claims = verify_token(&issued.access_token).unwrap();
+    assert!(still_valid(&claims));
+    claims.exp = 1;
+    assert!(!still_valid(&claims));
+
+    let claims = verify_token(&issued.access_token).unwrap();
+    revoke_session(&claims.sid).unwrap();
+    assert!(is_revoked(&claims));
+    assert!(!still_valid(&claims));
+}
+
+#[test]
+fn revocations_survive_a_restart() {
+    let email = new_user();
+    let issued = login(&email, PASSWORD).unwrap();
+    let claims = verify_token(&issued.access_token).unwrap();
+    revoke_token(&claims.jti).unwrap();
+    // As after a restart: only the stored list is left
+    REVOKED.write().unwrap().remove(&format!("jti:{}", claims.jti));
+    assert!(!is_revoked(&claims));
+    load_revocations();
+    assert!(is_revoked(&claims));
+}
@@ -0,0 +1,225 @@
+/*
+Multi-key transactions: reads, conditions and writes that commit together or not at all. When
+all of a transaction's keys have the same primary on the ring (give related keys a common
+{hash tag}, see hashring.rs), the primary runs the whole thing as one sled transaction. Keys on
+several primaries go through two-phase commit, see two_phase.rs. Either way the writes then go
+into the WAL with one group commit, so followers and watchers get them like any other eventual
+write, one key at a time.
+
+Transactions take the eventual path. Namespaces that require strong consistency are refused,
+since their keys must only be written through Raft.
+*/
+pub mod two_phase;
+#[cfg(test)]
+mod tests;
+
+use metrics::counter;
+use sled::transaction::TransactionError;
+use sled::Transactional;
+use crate::config::HASH_RING;
+use crate::coordinator::{self, KvError, KvValue, MAX_BATCH_KEYS};
+use crate::hashring::HashRing;
+use crate::namespace;
+use crate::routes_resp::{Consistency, WalOp, WriteMode};
+use crate::store::{self, apply_txn, ApplyOutcome, TxnAbort, TxnCondition, TXN_LOCKS_TREE};
+use crate::replication::log_writes;
+use two_phase::{Abort, Finished, Part};
+
+pub enum TxnWrite {
+    Set { key: Vec<u8>, value: Vec<u8>, content_type: Option<String>, mode: WriteMode, expires_at: Option<u64> },
+    Delete { key: Vec<u8> },
+}
+
+impl TxnWrite {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            TxnWrite::Set { key, .. } | TxnWrite::Delete { key } => key,
+        }
+    }
+}
+
+pub struct Txn {
+    pub reads: Vec<Vec<u8>>,
+    pub conditions: Vec<TxnCondition>,
+    pub writes: Vec<TxnWrite>,
+}
+
+pub struct TxnResult {
+    // In request order, None for missing keys; the state before the writes
+    pub reads: Vec<Option<KvValue>>,
+    // Index of the first condition that did not hold; nothing was written then
+    pub failed: Option<usize>,
+    // Outcome and resulting version of every write, empty unless committed
+    pub writes: Vec<(ApplyOutcome, Option<u64>)>,
+}
+
+impl Txn {
+    fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.reads.iter().map(Vec::as_slice)
+            .chain(self.conditions.iter().map(|condition| condition.key.as_slice()))
+            .chain(self.writes.iter().map(TxnWrite::key))
+    }
+}
+
+pub async fn transact(namespace: &str, txn: Txn) -> Result<TxnResult, KvError> {
+    let ns = namespace::get(namespace)?;
+    coordinator::count(namespace, "txn");
+    if ns.consistency(Consistency::Eventual) == Consistency::Strong {
+        return Err(KvError::BadRequest(format!("namespace '{}' requires strong consistency, which transactions don't support", ns.name)));
+    }
+    let key_count = txn.keys().count();
+    if key_count == 0 || key_count > MAX_BATCH_KEYS {
+        return Err(KvError::BadRequest(format!("a transaction takes 1 to {} keys, got {}", MAX_BATCH_KEYS, key_count)));
+    }
+
+    let mut sizes = Vec::with_capacity(txn.writes.len());
+    let mut ops = Vec::with_capacity(txn.writes.len());
+    for write in txn.writes {
+        let op = match write {
+            TxnWrite::Set { key, value, content_type, mode, expires_at } => {
+                coordinator::check_quota(&ns, &key, value.len())?;
+                sizes.push(value.len());
+                let expires_at = coordinator::with_default_ttl(&ns, expires_at);
+                ns.resolve(WalOp::Set { namespace: ns.name.clone(), key, value, content_type, mode, condition: None, expires_at })
+            }
+            TxnWrite::Delete { key } => {
+                sizes.push(0);
+                WalOp::Delete { namespace: ns.name.clone(), key, condition: None }
+            }
+        };
+        ops.push(op);
+    }
+    // Until the writes are in the WAL, like any eventual write (see coordinator::lock_keys)
+    let _locks = coordinator::lock_keys(ops.iter().map(WalOp::key)).await;
+
+    // Split by primary, each of which must have its quorum; node id order puts the 2PC primary first
+    let mut parts: Vec<Part> = Vec::new();
+    {
+        let ring = HASH_RING.read().unwrap();
+        for (index, key) in txn.reads.into_iter().enumerate() {
+            let at = part_for(&mut parts, &ring, &key, ns.replication_factor)?;
+            parts[at].reads.push((index, key));
+        }
+        for (index, condition) in txn.conditions.into_iter().enumerate() {
+            let at = part_for(&mut parts, &ring, &condition.key, ns.replication_factor)?;
+            parts[at].conditions.push((index, condition));
+        }
+        for (index, op) in ops.into_iter().enumerate() {
+            let at = part_for(&mut parts, &ring, op.key(), ns.replication_factor)?;
+            parts[at].writes.push((index, op));
+        }
+    }
+    parts.sort_by(|a, b| a.node.cmp(&b.node));
+    let read_count = parts.iter().map(|part| part.reads.len()).sum();
+
+    let finished = if parts.len() == 1 {
+        counter!("txn_total", 1, "protocol" => "single");
+        single_partition(&ns.name, parts.remove(0))?
+    } else {
+        counter!("txn_total", 1, "protocol" => "two_phase");
+        // On its own thread, so a client hanging up can't stop it between phases
+        let txn = format!("{:x}-{:08x}", store::now_ms(), rand::random::<u32>());
+        let name = ns.name.clone();
+        tokio::task::spawn_blocking(move || two_phase::run(&txn, &name, &parts))
+            .await
+            .map_err(|e| KvError::Internal(format!("transaction task failed: {}", e)))?
+    };
+
+    let mut reads: Vec<Option<KvValue>> = (0..read_count).map(|_| None).collect();
+    for (index, read) in finished.reads {
+        reads[index] = read.map(|(value, meta)| KvValue { value, meta });
+    }
+    let applied = match finished.result {
+        Ok(applied) => applied,
+        Err(Abort::Condition(index)) => return Ok(TxnResult { reads, failed: Some(index), writes: Vec::new() }),
+        Err(abort) => {
+            counter!("txn_aborted_total", 1, "reason" => abort_reason(&abort));
+            return Err(abort_error(abort));
+        }
+    };
+
+    // Followers converge on the primaries' results like any batch, one fsync for all of it
+    let logged = applied.iter()
+        .filter(|(_, op, outcome, _)| coordinator::should_log(op, *outcome))
+        .map(|(_, op, _, meta)| (coordinator::as_applied(op.clone()), (meta.version > 0).then_some(meta.version)))
+        .collect();
+    log_writes(logged).map_err(|e| KvError::Internal(format!("WAL disk write failed: {}", e)))?;
+    for ((_, _, outcome, _), size) in applied.iter().zip(sizes) {
+        if *outcome == ApplyOutcome::Created {
+            namespace::record_created(namespace, size);
+        }
+    }
+
+    let writes = applied.into_iter()
+        .map(|(_, _, outcome, meta)| (outcome, (meta.version > 0).then_some(meta.version)))
+        .collect();
+    Ok(TxnResult { reads, failed: None, writes })
+}
+
+fn part_for(parts: &mut Vec<Part>, ring: &HashRing, key: &[u8], replication_factor: usize) -> Result<usize, KvError> {
+    let leader = ring.get_node(key).ok_or_else(|| KvError::NoQuorum("No node available".to_string()))?;
+    if let Some(at) = parts.iter().position(|part| part.node == leader.id) {
+        return Ok(at);
+    }
+    coordinator::quorum_for(ring, &leader.id, replication_factor)?;
+    parts.push(Part { node: leader.id.clone(), db: leader.db.clone(), reads: Vec::new(), conditions: Vec::new(), writes: Vec::new() });
+    Ok(parts.len() - 1)
+}
+
+// Everything on one primary: a single sled transaction, no intents or decision needed
+fn single_partition(namespace: &str, part: Part) -> Result<Finished, KvError> {
+    let trees = store::trees(&part.db, namespace)?;
+    let locks = part.db.open_tree(TXN_LOCKS_TREE)?;
+    let read_keys: Vec<Vec<u8>> = part.reads.iter().map(|(_, key)| key.clone()).collect();
+    let conditions: Vec<TxnCondition> = part.conditions.into_iter().map(|(_, condition)| condition).collect();
+    let ops: Vec<WalOp> = part.writes.into_iter().map(|(_, op)| op).collect();
+    let applied = (&trees.data, &trees.meta, &locks)
+        .transaction(|(data, meta, locks)| apply_txn(data, meta, locks, namespace, &read_keys, &conditions, &ops));
+    let applied = match applied {
+        Ok(applied) => applied,
+        Err(TransactionError::Storage(e)) => return Err(KvError::from(e)),
+        Err(TransactionError::Abort(abort)) => {
+            let reads = part.reads.iter().map(|(index, key)| (*index, store::read(&trees, key).ok().flatten())).collect();
+            return Ok(Finished { reads, result: Err(Abort::Refused(abort)) });
+        }
+    };
+    part.db.flush().ok();
+
+    let reads = part.reads.iter().map(|(index, _)| *index).zip(applied.reads).collect();
+    let result = match applied.failed {
+        Some(index) => Err(Abort::Condition(index)),
+        None => Ok(ops.into_iter().zip(applied.writes).enumerate()
+            .map(|(index, (op, (outcome, meta)))| (index, op, outcome, meta))
+            .collect()),
+    };
+    Ok(Finished { reads, result })
+}
+
+fn abort_reason(abort: &Abort) -> &'static str {
+    match abort {
+        Abort::Condition(_) => "condition",
+        Abort::Refused(TxnAbort::Rejected { .. }) => "rejected",
+        Abort::Refused(TxnAbort::Locked { .. }) => "locked",
+        Abort::Storage(_) => "storage",
+        Abort::InDoubt(_) => "in_doubt",
+        Abort::AbortedByRecovery => "recovery",
+    }
+}
+
+fn abort_error(abort: Abort) -> KvError {
+    match abort {
+        Abort::Refused(TxnAbort::Rejected { index, outcome }) => match coordinator::check_outcome(outcome) {
+            Err(KvError::Conflict(message)) => KvError::Conflict(format!("write {}: {}", index, message)),
+            Err(KvError::NotFound) => KvError::PreconditionFailed(format!("write {}: key not found", index)),
+            Err(e) => e,
+            Ok(outcome) => KvError::Internal(format!("write {} applied as {:?}", index, outcome)),
+        },
+        Abort::Refused(TxnAbort::Locked { key, txn }) => {
+            KvError::Conflict(format!("key '{}' is locked by transaction {}; retry", String::from_utf8_lossy(&key), txn))
+        }
+        Abort::Storage(message) => KvError::NoQuorum(format!("transaction aborted: {}", message)),
+        Abort::InDoubt(message) => KvError::Internal(format!("transaction in doubt, recovery will finish it: {}", message)),
+        Abort::AbortedByRecovery => KvError::Conflict("transaction was aborted by recovery before it committed".to_string()),
+        Abort::Condition(index) => KvError::Internal(format!("condition {} reported as an error", index)),
+    }
+}
@@ -0,0 +1,165 @@
+use sled::Transactional;
+use crate::hashring::hash_tag;
+use crate::namespace::DEFAULT_NAMESPACE;
+use crate::routes_resp::{WalOp, WriteMode};
+use crate::store::{self, apply_txn, ApplyOutcome, TxnAbort, TxnApplied, TxnCheck, TxnCondition, TXN_LOCKS_TREE, TXN_LOG_TREE};
+use super::two_phase::{resolve_in_doubt, two_phase, Abort, Part, Step};
+
+fn set(key: &str, value: &str, mode: WriteMode) -> WalOp {
+    WalOp::Set { namespace: DEFAULT_NAMESPACE.to_string(), key: key.into(), value: value.into(), content_type: None, mode, condition: None, expires_at: None }
+}
+
+fn delete(key: &str) -> WalOp {
+    WalOp::Delete { namespace: DEFAULT_NAMESPACE.to_string(), key: key.into(), condition: None }
+}
+
+fn run(db: &sled::Db, reads: &[&str], conditions: Vec<TxnCondition>, writes: &[WalOp]) -> Result<TxnApplied, TxnAbort> {
+    let trees = store::trees(db, DEFAULT_NAMESPACE).unwrap();
+    let locks = db.open_tree(TXN_LOCKS_TREE).unwrap();
+    let reads: Vec<Vec<u8>> = reads.iter().map(|key| key.as_bytes().to_vec()).collect();
+    (&trees.data, &trees.meta, &locks)
+        .transaction(|(data, meta, locks)| apply_txn(data, meta, locks, DEFAULT_NAMESPACE, &reads, &conditions, writes))
+        .map_err(|e| match e {
+            sled::transaction::TransactionError::Abort(abort) => abort,
+            e => panic!("storage error: {:?}", e),
+        })
+}
+
+fn condition(key: &str, check: TxnCheck) -> TxnCondition {
+    TxnCondition { key: key.into(), check }
+}
+
+#[test]
+fn hash_tags_hash_only_the_tag() {
+    assert_eq!(hash_tag(b"{user42}:profile"), b"user42");
+    assert_eq!(hash_tag(b"index:{user42}"), b"user42");
+    // First tag only, and an empty or unclosed tag hashes the whole key
+    assert_eq!(hash_tag(b"{a}{b}"), b"a");
+    assert_eq!(hash_tag(b"{}:x"), b"{}:x");
+    assert_eq!(hash_tag(b"{open"), b"{open");
+    assert_eq!(hash_tag(b"plain"), b"plain");
+}
+
+#[test]
+fn transactions_commit_together_or_not_at_all() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let profile = set("{u}:profile", "alice", WriteMode::Upsert);
+    let index = set("{u}:email", "a@x", WriteMode::CreateOnly);
+    let applied = run(&db, &[], vec![condition("{u}:profile", TxnCheck::Exists(false))], &[profile, index]).unwrap();
+    assert_eq!(applied.failed, None);
+    assert_eq!(applied.writes.iter().map(|(outcome, meta)| (*outcome, meta.version)).collect::<Vec<_>>(),
+        vec![(ApplyOutcome::Created, 1), (ApplyOutcome::Created, 1)]);
+
+    // A failed condition writes nothing but still returns the reads
+    let rename = set("{u}:profile", "bob", WriteMode::Upsert);
+    let applied = run(&db, &["{u}:profile"], vec![condition("{u}:profile", TxnCheck::Version(7))], std::slice::from_ref(&rename)).unwrap();
+    assert_eq!(applied.failed, Some(0));
+    assert!(applied.writes.is_empty());
+    assert_eq!(applied.reads[0].as_ref().unwrap().0.as_ref(), b"alice");
+    assert_eq!(db.get("{u}:profile").unwrap().unwrap().as_ref(), b"alice");
+
+    // A write rejected by its mode undoes the writes before it
+    let taken = set("{u}:email", "b@x", WriteMode::CreateOnly);
+    let rejected = run(&db, &[], Vec::new(), &[rename.clone(), delete("{u}:missing"), taken]).err().unwrap();
+    assert_eq!(rejected, TxnAbort::Rejected { index: 2, outcome: ApplyOutcome::AlreadyExists });
+    assert_eq!(db.get("{u}:profile").unwrap().unwrap().as_ref(), b"alice");
+
+    let conditions = vec![
+        condition("{u}:profile", TxnCheck::Version(1)),
+        condition("{u}:email", TxnCheck::Value(b"a@x".to_vec())),
+        condition("{u}:other", TxnCheck::Exists(false)),
+    ];
+    let applied = run(&db, &["{u}:email"], conditions, &[rename, delete("{u}:email")]).unwrap();
+    assert_eq!(applied.failed, None);
+    assert_eq!(applied.reads[0].as_ref().unwrap().0.as_ref(), b"a@x");
+    assert_eq!(db.get("{u}:profile").unwrap().unwrap().as_ref(), b"bob");
+    assert!(db.get("{u}:email").unwrap().is_none());
+}
+
+// Three participants, each writing one key; write i goes to node i
+fn participants() -> Vec<Part> {
+    (0..3)
+        .map(|i| Part {
+            node: format!("node{}", i),
+            db: sled::Config::new().temporary(true).open().unwrap(),
+            reads: Vec::new(),
+            conditions: Vec::new(),
+            writes: vec![(i, set(&format!("k{}", i), "new", WriteMode::Upsert))],
+        })
+        .collect()
+}
+
+fn nodes(parts: &[Part]) -> Vec<(String, sled::Db)> {
+    parts.iter().map(|part| (part.node.clone(), part.db.clone())).collect()
+}
+
+fn written(parts: &[Part]) -> Vec<bool> {
+    parts.iter().enumerate().map(|(i, part)| part.db.get(format!("k{}", i)).unwrap().is_some()).collect()
+}
+
+// No locks, intents or decisions left anywhere
+fn settled(parts: &[Part]) -> bool {
+    parts.iter().all(|part| {
+        part.db.open_tree(TXN_LOCKS_TREE).unwrap().is_empty() && part.db.open_tree(TXN_LOG_TREE).unwrap().is_empty()
+    })
+}
+
+#[test]
+fn coordinator_crashes_resolve_all_or_nothing() {
+    // Before the decision nothing may be written; from the decision on, everything
+    let cases = [
+        (Step::Prepared(1), false),
+        (Step::Prepared(3), false),
+        (Step::Decided, true),
+        (Step::Committed(1), true),
+        (Step::Committed(2), true),
+    ];
+    for (crash, commits) in cases {
+        let parts = participants();
+        assert!(two_phase("t1", DEFAULT_NAMESPACE, &parts, Some(crash)).is_none());
+        let resolved = resolve_in_doubt(&nodes(&parts)).unwrap();
+        assert_eq!(written(&parts), vec![commits; 3], "crash at {:?}", crash);
+        assert!(settled(&parts), "crash at {:?}", crash);
+        if commits {
+            // Only the participants that hadn't committed yet are left to recovery
+            let left = match crash { Step::Committed(n) => 3 - n, _ => 3 };
+            assert_eq!((resolved.committed, resolved.aborted, resolved.applied.len()), (left, 0, left));
+        } else {
+            assert_eq!(resolved.committed, 0);
+        }
+        // A second pass finds nothing to do
+        let again = resolve_in_doubt(&nodes(&parts)).unwrap();
+        assert_eq!(again.committed + again.aborted, 0);
+    }
+
+    let parts = participants();
+    let finished = two_phase("t2", DEFAULT_NAMESPACE, &parts, None).unwrap();
+    assert_eq!(finished.result.unwrap().iter().map(|(index, _, outcome, _)| (*index, *outcome)).collect::<Vec<_>>(),
+        vec![(0, ApplyOutcome::Created), (1, ApplyOutcome::Created), (2, ApplyOutcome::Created)]);
+    assert_eq!(written(&parts), vec![true; 3]);
+    assert!(settled(&parts));
+}
+
+#[test]
+fn prepare_refusals_abort_every_participant() {
+    // The last participant rejects its write after the others prepared
+    let mut parts = participants();
+    parts[2].db.insert("k2", "old").unwrap();
+    parts[2].writes = vec![(2, set("k2", "new", WriteMode::CreateOnly))];
+    let finished = two_phase("t1", DEFAULT_NAMESPACE, &parts, None).unwrap();
+    assert_eq!(finished.result.err(), Some(Abort::Refused(TxnAbort::Rejected { index: 2, outcome: ApplyOutcome::AlreadyExists })));
+    assert_eq!(written(&parts), vec![false, false, true]);
+    assert!(settled(&parts));
+
+    // Keys of a prepared transaction refuse every other write until it is resolved
+    let parts = participants();
+    assert!(two_phase("t2", DEFAULT_NAMESPACE, &parts, Some(Step::Prepared(3))).is_none());
+    let locked = TxnAbort::Locked { key: b"k0".to_vec(), txn: "t2".to_string() };
+    assert_eq!(run(&parts[0].db, &[], Vec::new(), &[set("k0", "other", WriteMode::Upsert)]).err(), Some(locked.clone()));
+    let finished = two_phase("t3", DEFAULT_NAMESPACE, &parts, None).unwrap();
+    assert_eq!(finished.result.err(), Some(Abort::Refused(locked)));
+
+    resolve_in_doubt(&nodes(&parts)).unwrap();
+    assert!(settled(&parts));
+    assert!(run(&parts[0].db, &[], Vec::new(), &[set("k0", "other", WriteMode::Upsert)]).is_ok());
+}
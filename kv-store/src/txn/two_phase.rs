@@ -0,0 +1,373 @@
+/*
+Cross-partition transactions: two-phase commit driven by the coordinator, i.e. whoever called
+transact. The participants are the ring nodes leading the transaction's keys, and each keeps a
+transaction log in its own sled tree (store::TXN_LOG_TREE), flushed before a phase counts:
+
+  prepare   the participant checks the conditions and write modes on its keys, locks every one of
+            them (store::TXN_LOCKS_TREE) and logs an intent holding the writes it will apply
+  decide    the outcome is logged on the primary, the participant with the lowest node id, by
+            an insert that only succeeds if there is no decision yet; this is the commit point
+  commit    every participant applies its intent's writes, then drops the intent and the locks
+
+A refusal in prepare (failed condition, rejected write, key locked by another transaction, storage
+error) aborts: the prepared participants drop their intents and nothing is written anywhere.
+While a key is locked, every other write to it is refused.
+
+A coordinator that dies between phases leaves intents behind. resolve_in_doubt, run at startup,
+finishes each one the way the primary decided. With no decision on record the coordinator never
+reached its commit point, so recovery logs an abort first (presumed abort); because both sides
+only ever insert into an empty slot, a coordinator still trying to commit and recovery cannot
+reach different outcomes.
+*/
+use std::collections::{BTreeSet, HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionResult, TransactionError, UnabortableTransactionError};
+use sled::{CompareAndSwapError, Db, IVec, Transactional};
+use crate::coordinator::as_applied;
+use crate::routes_resp::{WalOp, WriteMode};
+use crate::store::{self, apply_op, check_holds, live, lock_key, locked_by, ApplyOutcome, TxnAbort, TxnCondition, ValueMeta,
+    TXN_LOCKS_TREE, TXN_LOG_TREE};
+
+const INTENT_PREFIX: &str = "intent/";
+const DECISION_PREFIX: &str = "decision/";
+
+// One participant's share of a transaction; indexes are positions in the whole transaction
+pub struct Part {
+    pub node: String,
+    pub db: Db,
+    pub reads: Vec<(usize, Vec<u8>)>,
+    pub conditions: Vec<(usize, TxnCondition)>,
+    pub writes: Vec<(usize, WalOp)>,
+}
+
+impl Part {
+    fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.reads.iter().map(|(_, key)| key.as_slice())
+            .chain(self.conditions.iter().map(|(_, condition)| condition.key.as_slice()))
+            .chain(self.writes.iter().map(|(_, op)| op.key()))
+    }
+}
+
+// What a participant promised in prepare, applied as is on commit
+#[derive(Serialize, Deserialize)]
+struct Intent {
+    primary: String,
+    namespace: String,
+    locks: Vec<Vec<u8>>,
+    writes: Vec<(usize, WalOp)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Decision {
+    commit: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Abort {
+    // Index of the condition that did not hold
+    Condition(usize),
+    Refused(TxnAbort),
+    // A participant failed before the decision, so it could be aborted cleanly
+    Storage(String),
+    // The decision could not be logged; the participants stay prepared until recovery
+    InDoubt(String),
+    // Recovery logged an abort before this coordinator reached its commit point
+    AbortedByRecovery,
+}
+
+// Where a test makes the coordinator stop as if it had crashed, see tests.rs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    // After this many participants prepared
+    Prepared(usize),
+    // After the decision is logged, before any participant commits
+    Decided,
+    // After this many participants committed
+    Committed(usize),
+}
+
+pub type Reads = Vec<(usize, Option<(IVec, ValueMeta)>)>;
+// Index of the write, the write as applied, its outcome and the key's metadata after it
+pub type Applied = Vec<(usize, WalOp, ApplyOutcome, ValueMeta)>;
+
+pub struct Finished {
+    // The state before the writes; for participants that did not prepare, read without locks
+    pub reads: Reads,
+    pub result: Result<Applied, Abort>,
+}
+
+fn intent_key(txn: &str) -> Vec<u8> {
+    format!("{}{}", INTENT_PREFIX, txn).into_bytes()
+}
+
+fn decision_key(txn: &str) -> Vec<u8> {
+    format!("{}{}", DECISION_PREFIX, txn).into_bytes()
+}
+
+fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+    bincode::serialize(value).expect("transaction records are always serializable")
+}
+
+fn decode<'a, T: Deserialize<'a>>(bytes: &'a [u8]) -> sled::Result<T> {
+    bincode::deserialize(bytes).map_err(|e| sled::Error::Unsupported(format!("corrupt transaction record: {}", e)))
+}
+
+// These transactions never abort on purpose
+fn storage_error(e: TransactionError<()>) -> sled::Error {
+    match e {
+        TransactionError::Storage(e) => e,
+        TransactionError::Abort(()) => sled::Error::Unsupported("transaction aborted".into()),
+    }
+}
+
+// Reads for a participant that did not prepare; a failing node reads as missing
+fn plain_reads(part: &Part, namespace: &str) -> Reads {
+    part.reads.iter()
+        .map(|(index, key)| (*index, store::trees(&part.db, namespace).and_then(|trees| store::read(&trees, key)).ok().flatten()))
+        .collect()
+}
+
+fn prepare(part: &Part, txn: &str, primary: &str, namespace: &str) -> sled::Result<Result<Reads, Abort>> {
+    let trees = store::trees(&part.db, namespace)?;
+    let locks = part.db.open_tree(TXN_LOCKS_TREE)?;
+    let log = part.db.open_tree(TXN_LOG_TREE)?;
+    let lock_keys: BTreeSet<Vec<u8>> = part.keys().map(|key| lock_key(namespace, key)).collect();
+    let intent = encode(&Intent {
+        primary: primary.to_string(),
+        namespace: namespace.to_string(),
+        locks: lock_keys.iter().cloned().collect(),
+        writes: part.writes.clone(),
+    });
+
+    let prepared = (&trees.data, &trees.meta, &locks, &log)
+        .transaction(|(data, meta, locks, log)| -> ConflictableTransactionResult<Result<Reads, Abort>, ()> {
+            for key in part.keys() {
+                if let Some(holder) = locked_by(locks, namespace, key)? {
+                    return Ok(Err(Abort::Refused(TxnAbort::Locked { key: key.to_vec(), txn: holder })));
+                }
+            }
+            let reads = part.reads.iter()
+                .map(|(index, key)| Ok((*index, live(data, meta, key)?)))
+                .collect::<Result<Reads, UnabortableTransactionError>>()?;
+            for (index, condition) in &part.conditions {
+                if !check_holds(&condition.check, live(data, meta, &condition.key)?.as_ref()) {
+                    return Ok(Err(Abort::Condition(*index)));
+                }
+            }
+            // Commit must not be able to fail, so the write modes are checked now, in write order
+            let mut exists: HashMap<&[u8], bool> = HashMap::new();
+            for (index, op) in &part.writes {
+                let present = match exists.get(op.key()) {
+                    Some(present) => *present,
+                    None => live(data, meta, op.key())?.is_some(),
+                };
+                let refused = match op {
+                    WalOp::Set { mode: WriteMode::CreateOnly, .. } if present => Some(ApplyOutcome::AlreadyExists),
+                    WalOp::Set { mode: WriteMode::UpdateOnly, .. } if !present => Some(ApplyOutcome::Missing),
+                    _ => None,
+                };
+                if let Some(outcome) = refused {
+                    return Ok(Err(Abort::Refused(TxnAbort::Rejected { index: *index, outcome })));
+                }
+                exists.insert(op.key(), matches!(op, WalOp::Set { .. }));
+            }
+
+            for lock in &lock_keys {
+                locks.insert(lock.as_slice(), txn.as_bytes())?;
+            }
+            log.insert(intent_key(txn), intent.as_slice())?;
+            Ok(Ok(reads))
+        })
+        .map_err(storage_error)?;
+    part.db.flush()?;
+    Ok(prepared)
+}
+
+// Logs `commit` on the primary unless a decision is already there; returns the one that stands
+fn decide(primary: &Db, txn: &str, commit: bool) -> sled::Result<bool> {
+    let log = primary.open_tree(TXN_LOG_TREE)?;
+    let decided = match log.compare_and_swap(decision_key(txn), None as Option<&[u8]>, Some(encode(&Decision { commit })))? {
+        Ok(()) => commit,
+        Err(CompareAndSwapError { current: Some(current), .. }) => decode::<Decision>(&current)?.commit,
+        Err(CompareAndSwapError { current: None, .. }) => return Err(sled::Error::Unsupported("decision vanished".into())),
+    };
+    log.flush()?;
+    Ok(decided)
+}
+
+// Once no participant has an intent left
+fn forget(primary: &Db, txn: &str) -> sled::Result<()> {
+    primary.open_tree(TXN_LOG_TREE)?.remove(decision_key(txn))?;
+    Ok(())
+}
+
+// Second phase on one participant: apply the intent's writes if `commit`, then drop intent and locks
+fn finish(db: &Db, txn: &str, commit: bool) -> sled::Result<Applied> {
+    let log = db.open_tree(TXN_LOG_TREE)?;
+    let Some(raw) = log.get(intent_key(txn))? else { return Ok(Vec::new()) };
+    let intent: Intent = decode(&raw)?;
+    let trees = store::trees(db, &intent.namespace)?;
+    let locks = db.open_tree(TXN_LOCKS_TREE)?;
+
+    let applied = (&trees.data, &trees.meta, &locks, &log)
+        .transaction(|(data, meta, locks, log)| -> ConflictableTransactionResult<Applied, ()> {
+            // Someone else finished it meanwhile
+            if log.get(intent_key(txn))?.is_none() {
+                return Ok(Vec::new());
+            }
+            let mut applied = Vec::new();
+            if commit {
+                for (index, op) in &intent.writes {
+                    // Checked in prepare, and the keys have been locked since
+                    let op = as_applied(op.clone());
+                    let outcome = apply_op(data, meta, &op)?;
+                    let value_meta = store::current_meta(meta, op.key())?;
+                    applied.push((*index, op, outcome, value_meta));
+                }
+            }
+            for lock in &intent.locks {
+                locks.remove(lock.as_slice())?;
+            }
+            log.remove(intent_key(txn))?;
+            Ok(applied)
+        })
+        .map_err(storage_error)?;
+    db.flush()?;
+    Ok(applied)
+}
+
+pub fn run(txn: &str, namespace: &str, parts: &[Part]) -> Finished {
+    two_phase(txn, namespace, parts, None).expect("only a crash point stops a transaction early")
+}
+
+// `parts` in node id order, the first one is the primary. None if stopped at `crash`.
+pub fn two_phase(txn: &str, namespace: &str, parts: &[Part], crash: Option<Step>) -> Option<Finished> {
+    let primary = &parts[0];
+    let mut reads = Vec::new();
+    let mut prepared = 0;
+    let mut refused = None;
+    for part in parts {
+        if refused.is_some() {
+            reads.extend(plain_reads(part, namespace));
+            continue;
+        }
+        match prepare(part, txn, &primary.node, namespace) {
+            Ok(Ok(part_reads)) => {
+                reads.extend(part_reads);
+                prepared += 1;
+                if crash == Some(Step::Prepared(prepared)) {
+                    return None;
+                }
+            }
+            Ok(Err(abort)) => {
+                reads.extend(plain_reads(part, namespace));
+                refused = Some(abort);
+            }
+            Err(e) => {
+                reads.extend(plain_reads(part, namespace));
+                refused = Some(Abort::Storage(format!("{} failed to prepare: {}", part.node, e)));
+            }
+        }
+    }
+
+    // Nothing to decide when nobody prepared; otherwise an abort needs no record either (presumed abort)
+    let commit = match refused {
+        Some(abort) => {
+            abort_all(txn, &parts[..prepared]);
+            return Some(Finished { reads, result: Err(abort) });
+        }
+        None => match decide(&primary.db, txn, true) {
+            Ok(commit) => commit,
+            Err(e) => return Some(Finished { reads, result: Err(Abort::InDoubt(format!("{} failed to log the decision: {}", primary.node, e))) }),
+        },
+    };
+    if crash == Some(Step::Decided) {
+        return None;
+    }
+    if !commit {
+        abort_all(txn, parts);
+        forget(&primary.db, txn).ok();
+        return Some(Finished { reads, result: Err(Abort::AbortedByRecovery) });
+    }
+
+    let mut applied = Vec::new();
+    let mut complete = true;
+    for (committed, part) in parts.iter().enumerate() {
+        match finish(&part.db, txn, true) {
+            Ok(part_applied) => applied.extend(part_applied),
+            Err(e) => {
+                // Committed all the same; recovery applies this participant's intent
+                eprintln!("transaction {} committed, but {} could not apply it yet: {}", txn, part.node, e);
+                complete = false;
+            }
+        }
+        if crash == Some(Step::Committed(committed + 1)) {
+            return None;
+        }
+    }
+    if complete {
+        forget(&primary.db, txn).ok();
+    }
+    applied.sort_by_key(|(index, ..)| *index);
+    Some(Finished { reads, result: Ok(applied) })
+}
+
+fn abort_all(txn: &str, parts: &[Part]) {
+    for part in parts {
+        if let Err(e) = finish(&part.db, txn, false) {
+            eprintln!("transaction {} aborted, but {} could not drop its intent yet: {}", txn, part.node, e);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Resolved {
+    pub committed: usize,
+    pub aborted: usize,
+    // The writes recovery applied, with the key's metadata after each
+    pub applied: Vec<(WalOp, ValueMeta)>,
+}
+
+/*
+Finish every intent left on `nodes` the way its primary decided, logging an abort for those with
+no decision, then drop the decisions nobody needs any more. Transactions are counted once per
+participant that still had an intent.
+*/
+pub fn resolve_in_doubt(nodes: &[(String, Db)]) -> sled::Result<Resolved> {
+    let mut resolved = Resolved::default();
+    let mut unresolved = HashSet::new();
+    for (node, db) in nodes {
+        let log = db.open_tree(TXN_LOG_TREE)?;
+        let intents = log.scan_prefix(INTENT_PREFIX).collect::<sled::Result<Vec<_>>>()?;
+        for (key, raw) in intents {
+            let txn = String::from_utf8_lossy(&key[INTENT_PREFIX.len()..]).into_owned();
+            let intent: Intent = decode(&raw)?;
+            let Some((_, primary)) = nodes.iter().find(|(id, _)| *id == intent.primary) else {
+                eprintln!("transaction {} on {}: primary {} is not in the ring, left in doubt", txn, node, intent.primary);
+                unresolved.insert(txn);
+                continue;
+            };
+            let commit = decide(primary, &txn, false)?;
+            let applied = finish(db, &txn, commit)?;
+            if commit {
+                resolved.committed += 1;
+                resolved.applied.extend(applied.into_iter().map(|(_, op, _, meta)| (op, meta)));
+            } else {
+                resolved.aborted += 1;
+            }
+        }
+    }
+
+    for (_, db) in nodes {
+        let log = db.open_tree(TXN_LOG_TREE)?;
+        for entry in log.scan_prefix(DECISION_PREFIX) {
+            let (key, _) = entry?;
+            let txn = String::from_utf8_lossy(&key[DECISION_PREFIX.len()..]).into_owned();
+            if !unresolved.contains(&txn) {
+                log.remove(&key)?;
+            }
+        }
+        db.flush()?;
+    }
+    Ok(resolved)
+}
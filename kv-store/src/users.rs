@@ -0,0 +1,298 @@
+/*
+User accounts for /login. Like namespace definitions, each user is a JSON record in every node's
+__users tree (keyed by the lowercased email), and reads take the first node that answers.
+Passwords are stored as argon2id PHC strings, never in the clear.
+
+config::login_max_failures() wrong passwords in a row lock the account for
+config::login_lockout(); a successful login resets the count, and an admin can unlock early.
+Hashing is deliberately slow, so callers run these functions on a blocking thread.
+*/
+#[cfg(test)]
+mod tests;
+
+use std::sync::Mutex;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{TimeZone, Utc};
+use metrics::counter;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use crate::access::{self, Grant};
+use crate::config::{bootstrap_admin, login_lockout, login_max_failures, HASH_RING};
+use crate::coordinator::KvError;
+use crate::store::now_ms;
+
+const USERS_TREE: &str = "__users";
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_PASSWORD_LEN: usize = 1024;
+const MAX_EMAIL_LEN: usize = 254;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // Manages users, and may do anything else
+    Admin,
+    #[default]
+    User,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct User {
+    pub email: String,
+    pub password_hash: String,
+    pub role: Role,
+    // Ignored for admins, who may do anything
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+    pub created_at: u64,
+    // Wrong passwords since the last successful login
+    #[serde(default)]
+    pub failed_logins: u32,
+    // Epoch milliseconds
+    #[serde(default)]
+    pub locked_until: Option<u64>,
+}
+
+// What the API shows of a user: everything but the hash
+#[derive(Serialize, Debug)]
+pub struct UserView {
+    pub email: String,
+    pub role: Role,
+    pub grants: Vec<Grant>,
+    pub created_at: u64,
+    pub failed_logins: u32,
+    pub locked_until: Option<u64>,
+}
+
+impl From<&User> for UserView {
+    fn from(user: &User) -> Self {
+        UserView {
+            email: user.email.clone(),
+            role: user.role,
+            grants: user.grants.clone(),
+            created_at: user.created_at,
+            failed_logins: user.failed_logins,
+            locked_until: user.locked_until,
+        }
+    }
+}
+
+// Serializes read-modify-writes of user records (failed login counts, admin edits)
+static USERS_LOCK: Mutex<()> = Mutex::new(());
+
+// Checked against when the email is unknown, so a wrong email takes as long as a wrong password
+static DUMMY_HASH: Lazy<String> = Lazy::new(|| hash_password("not a real password").expect("hashing a constant works"));
+
+fn normalize(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+fn validate_email(email: &str) -> Result<(), KvError> {
+    let valid = email.len() <= MAX_EMAIL_LEN
+        && email.split_once('@').is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+        && !email.chars().any(char::is_whitespace);
+    if !valid {
+        return Err(KvError::BadRequest(format!("'{}' is not a valid email address", email)));
+    }
+    Ok(())
+}
+
+fn validate_password(password: &str) -> Result<(), KvError> {
+    let length = password.chars().count();
+    if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&length) {
+        return Err(KvError::BadRequest(format!("passwords are {} to {} characters", MIN_PASSWORD_LEN, MAX_PASSWORD_LEN)));
+    }
+    Ok(())
+}
+
+fn hash_password(password: &str) -> Result<String, KvError> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| KvError::Internal(format!("failed to make a salt: {}", e)))?;
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| KvError::Internal(format!("failed to hash password: {}", e)))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+}
+
+fn load(email: &str) -> Result<Option<User>, KvError> {
+    let ring = HASH_RING.read().unwrap();
+    let mut last_error = None;
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        match node.db.open_tree(USERS_TREE).and_then(|tree| tree.get(email.as_bytes())) {
+            Ok(Some(record)) => {
+                let user = serde_json::from_slice(&record)
+                    .map_err(|e| KvError::Internal(format!("bad user record for '{}': {}", email, e)))?;
+                return Ok(Some(user));
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    match last_error {
+        Some(e) => Err(e.into()),
+        None => Ok(None),
+    }
+}
+
+fn save(user: &User) -> Result<(), KvError> {
+    let record = serde_json::to_vec(user).map_err(|e| KvError::Internal(format!("failed to encode user: {}", e)))?;
+    let ring = HASH_RING.read().unwrap();
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        node.db.open_tree(USERS_TREE)?.insert(user.email.as_bytes(), record.as_slice())?;
+        node.db.flush()?;
+    }
+    Ok(())
+}
+
+pub fn list() -> Result<Vec<User>, KvError> {
+    let ring = HASH_RING.read().unwrap();
+    let Some(node) = ring.get_all_node_ids().first().and_then(|id| ring.get_node_by_id(id)) else { return Ok(Vec::new()) };
+    node.db.open_tree(USERS_TREE)?
+        .iter()
+        .values()
+        .map(|record| {
+            serde_json::from_slice(&record?).map_err(|e| KvError::Internal(format!("bad user record: {}", e)))
+        })
+        .collect()
+}
+
+pub fn get(email: &str) -> Result<User, KvError> {
+    let email = normalize(email);
+    load(&email)?.ok_or(KvError::UnknownUser(email))
+}
+
+pub fn create(email: &str, password: &str, role: Role, grants: Vec<Grant>) -> Result<User, KvError> {
+    let email = normalize(email);
+    validate_email(&email)?;
+    validate_password(password)?;
+    access::validate(&grants)?;
+    let password_hash = hash_password(password)?;
+
+    let _guard = USERS_LOCK.lock().unwrap();
+    if load(&email)?.is_some() {
+        return Err(KvError::Conflict(format!("user '{}' already exists", email)));
+    }
+    let user = User { email, password_hash, role, grants, created_at: now_ms(), failed_logins: 0, locked_until: None };
+    save(&user)?;
+    println!("Created user '{}' ({:?})", user.email, user.role);
+    Ok(user)
+}
+
+// New password, role and/or grants; the role and grants reach tokens at the user's next login or refresh
+pub fn update(email: &str, password: Option<&str>, role: Option<Role>, grants: Option<Vec<Grant>>) -> Result<User, KvError> {
+    if let Some(grants) = &grants {
+        access::validate(grants)?;
+    }
+    let password_hash = match password {
+        Some(password) => {
+            validate_password(password)?;
+            Some(hash_password(password)?)
+        }
+        None => None,
+    };
+    let _guard = USERS_LOCK.lock().unwrap();
+    let mut user = get(email)?;
+    if let Some(password_hash) = password_hash {
+        user.password_hash = password_hash;
+    }
+    if let Some(role) = role {
+        user.role = role;
+    }
+    if let Some(grants) = grants {
+        user.grants = grants;
+    }
+    save(&user)?;
+    Ok(user)
+}
+
+pub fn unlock(email: &str) -> Result<User, KvError> {
+    let _guard = USERS_LOCK.lock().unwrap();
+    let mut user = get(email)?;
+    user.failed_logins = 0;
+    user.locked_until = None;
+    save(&user)?;
+    Ok(user)
+}
+
+pub fn delete(email: &str) -> Result<(), KvError> {
+    let email = normalize(email);
+    let _guard = USERS_LOCK.lock().unwrap();
+    if load(&email)?.is_none() {
+        return Err(KvError::UnknownUser(email));
+    }
+    let ring = HASH_RING.read().unwrap();
+    for id in ring.get_all_node_ids() {
+        let Some(node) = ring.get_node_by_id(&id) else { continue };
+        node.db.open_tree(USERS_TREE)?.remove(email.as_bytes())?;
+        node.db.flush()?;
+    }
+    println!("Deleted user '{}'", email);
+    Ok(())
+}
+
+/*
+The user, if the password is right and the account isn't locked; counts the failure otherwise. The
+password is checked first, and only the right one learns of a lockout, so a locked account looks
+like any other wrong guess and can't be used to find out which emails exist.
+*/
+pub fn authenticate(email: &str, password: &str) -> Result<User, KvError> {
+    let email = normalize(email);
+    let invalid = || KvError::Unauthorized("invalid email or password".to_string());
+    let now = now_ms();
+    let Some(user) = load(&email)? else {
+        verify_password(password, &DUMMY_HASH);
+        return Err(invalid());
+    };
+    let valid = verify_password(password, &user.password_hash);
+    // Guesses while locked neither count nor extend the lockout
+    if let Some(until) = user.locked_until.filter(|until| *until > now) {
+        if !valid {
+            return Err(invalid());
+        }
+        let until = Utc.timestamp_millis_opt(until as i64).single().map(|at| at.to_rfc3339()).unwrap_or_default();
+        return Err(KvError::AccountLocked(format!("too many failed logins, try again after {}", until)));
+    }
+
+    // Re-read under the lock so concurrent logins and admin edits don't overwrite each other
+    let _guard = USERS_LOCK.lock().unwrap();
+    let Some(mut user) = load(&email)? else { return Err(invalid()) };
+    if valid {
+        if user.failed_logins > 0 || user.locked_until.is_some() {
+            user.failed_logins = 0;
+            user.locked_until = None;
+            save(&user)?;
+        }
+        return Ok(user);
+    }
+    user.failed_logins += 1;
+    if user.failed_logins >= login_max_failures() {
+        user.failed_logins = 0;
+        user.locked_until = Some(now + login_lockout().as_millis() as u64);
+        counter!("account_lockouts_total", 1);
+        println!("Locked user '{}' after {} failed logins", email, login_max_failures());
+    }
+    save(&user)?;
+    Err(invalid())
+}
+
+// Call once at startup: with no users yet, ADMIN_EMAIL / ADMIN_PASSWORD become the first admin
+pub fn bootstrap() {
+    match list() {
+        Ok(users) if !users.is_empty() => println!("Loaded {} users", users.len()),
+        Ok(_) => match bootstrap_admin() {
+            Some((email, password)) => {
+                if let Err(e) = create(&email, &password, Role::Admin, Vec::new()) {
+                    eprintln!("Failed to create the bootstrap admin: {}", e);
+                }
+            }
+            None => println!("No users yet; set ADMIN_EMAIL and ADMIN_PASSWORD to create the first admin"),
+        },
+        Err(e) => eprintln!("Failed to read users: {}", e),
+    }
+}
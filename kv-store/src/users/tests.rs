@@ -0,0 +1,52 @@
+use crate::config::login_max_failures;
+use crate::coordinator::KvError;
+use super::{authenticate, create, get, unlock, Role};
+
+const PASSWORD: &str = "correct horse";
+
+fn new_user() -> String {
+    let email = format!("user-{:016x}@example.com", rand::random::<u64>());
+    create(&email, PASSWORD, Role::User, Vec::new()).unwrap();
+    email
+}
+
+#[test]
+fn locks_the_account_after_repeated_failures() {
+    let email = new_user();
+    for _ in 0..login_max_failures() {
+        assert!(matches!(authenticate(&email, "wrong password"), Err(KvError::Unauthorized(_))));
+    }
+    assert!(get(&email).unwrap().locked_until.is_some());
+    assert!(matches!(authenticate(&email, PASSWORD), Err(KvError::AccountLocked(_))));
+
+    unlock(&email).unwrap();
+    assert_eq!(authenticate(&email, PASSWORD).unwrap().email, email);
+}
+
+#[test]
+fn a_locked_account_looks_like_a_wrong_password() {
+    let email = new_user();
+    for _ in 0..login_max_failures() {
+        authenticate(&email, "wrong password").unwrap_err();
+    }
+    let locked = authenticate(&email, "another guess").unwrap_err();
+    let unknown = authenticate("nobody@example.com", "another guess").unwrap_err();
+    assert!(matches!(locked, KvError::Unauthorized(_)));
+    assert_eq!(locked.to_string(), unknown.to_string());
+    // Guesses while locked don't push the lockout further out
+    let until = get(&email).unwrap().locked_until;
+    authenticate(&email, "yet another guess").unwrap_err();
+    assert_eq!(get(&email).unwrap().locked_until, until);
+}
+
+#[test]
+fn a_successful_login_resets_the_count() {
+    let email = new_user();
+    for _ in 0..2 {
+        for _ in 1..login_max_failures() {
+            authenticate(&email, "wrong password").unwrap_err();
+        }
+        authenticate(&email, PASSWORD).unwrap();
+    }
+    assert_eq!(get(&email).unwrap().failed_logins, 0);
+}
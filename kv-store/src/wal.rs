@@ -1,30 +1,59 @@
-use std::fs::{OpenOptions};
-use std::io::{Write, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write, Result};
 use super::routes_resp::{Wal,WalOp};
+use super::namespace::DEFAULT_NAMESPACE;
+use super::encoding::Encoding;
 // use super::ring::get_node_for_key;
 use tokio::time::Instant;
+use tokio::sync::watch;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering};
-use serde_json;
-use chrono::Utc;
+use once_cell::sync::Lazy;
+use chrono::{NaiveDateTime, Utc};
 use sha2::{Sha256, Digest};
 use super::config::HASH_RING;
 
+pub const WAL_FILE: &str = "logs/wal_detailed.log";
+const WAL_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f UTC";
+
+impl WalOp {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WalOp::Set { key, .. } => key,
+            WalOp::Delete { key, .. } => key,
+            WalOp::Expire { key, .. } => key,
+            WalOp::Incr { key, .. } => key,
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        match self {
+            WalOp::Set { namespace, .. } => namespace,
+            WalOp::Delete { namespace, .. } => namespace,
+            WalOp::Expire { namespace, .. } => namespace,
+            WalOp::Incr { namespace, .. } => namespace,
+        }
+    }
+}
+
 static WAL_SEQUENCE_COUNTER: AtomicUsize = AtomicUsize::new(1); 
+// Serializes sequence assignment and the file append so seq order == file order
+static WAL_WRITE_LOCK: Mutex<()> = Mutex::new(());
+// Last durable sequence number; replication workers subscribe to wake up on new entries
+static WAL_HEAD: Lazy<watch::Sender<usize>> = Lazy::new(|| watch::Sender::new(0));
+
 impl Wal {
     // Production-ready detailed format (Only format we'll use)
     pub fn to_log_line(&self) -> String {
-        let timestamp = Utc::now().format("%Y-%m-%d %H:%M:%S%.3f UTC").to_string();
+        let timestamp = Utc::now().format(WAL_TIMESTAMP_FORMAT).to_string();
         
         // Get key from operation for node calculation
-        let key = match &self.opration {
-            WalOp::Set { key, .. } => key,
-            WalOp::Delete { key } => key,
-        };
+        let key = self.opration.key();
         
         // let total_nodes = NODES.len();
         let ring=HASH_RING.read().unwrap();
        
-        let node = match ring.get_node(&key) {
+        let node = match ring.get_node(key) {
         Some(n) => n,
         None => {
             return "".to_string();
@@ -34,33 +63,70 @@ impl Wal {
         // let node_id = format!("node-{}", node_index);
         let node_id=&node.id;
         
+        // Text stays readable; a binary key or value turns both into base64
+        let encoding = match &self.opration {
+            WalOp::Set { key, value, .. } => Encoding::Utf8.for_output(&[key, value]),
+            op => Encoding::Utf8.for_output(&[op.key()]),
+        };
         let operation_data = match &self.opration {
-            WalOp::Set { key, value } => {
+            WalOp::Set { namespace, key, value, content_type, mode, condition, expires_at } => {
                 serde_json::json!({
                     "op": "SET",
-                    "key": key,
-                    "value": value,
+                    "namespace": namespace,
+                    "key": encoding.encode(key),
+                    "value": encoding.encode(value),
+                    "encoding": encoding,
+                    "content_type": content_type,
+                    "mode": mode,
+                    "condition": condition,
+                    "expires_at": expires_at,
                     "key_size": key.len(),
                     "value_size": value.len()
                 })
             },
-            WalOp::Delete { key } => {
+            WalOp::Delete { namespace, key, condition } => {
                 serde_json::json!({
                     "op": "DELETE", 
-                    "key": key,
+                    "namespace": namespace,
+                    "key": encoding.encode(key),
+                    "encoding": encoding,
+                    "condition": condition,
+                    "key_size": key.len()
+                })
+            }
+            WalOp::Expire { namespace, key, expires_at } => {
+                serde_json::json!({
+                    "op": "EXPIRE",
+                    "namespace": namespace,
+                    "key": encoding.encode(key),
+                    "encoding": encoding,
+                    "expires_at": expires_at,
+                    "key_size": key.len()
+                })
+            }
+            WalOp::Incr { namespace, key, delta, expires_at } => {
+                serde_json::json!({
+                    "op": "INCR",
+                    "namespace": namespace,
+                    "key": encoding.encode(key),
+                    "encoding": encoding,
+                    "delta": delta,
+                    "expires_at": expires_at,
                     "key_size": key.len()
                 })
             }
         };
         
+        let mut operation_data = operation_data;
+        if let Some(version) = self.version {
+            operation_data["version"] = version.into();
+        }
+
         // Calculate checksum for integrity
-        let data_str = operation_data.to_string();
-        let mut hasher = Sha256::new();
-        hasher.update(data_str.as_bytes());
-        let checksum = format!("{:x}", hasher.finalize())[..16].to_string(); // First 16 chars
+        let checksum = checksum(&operation_data.to_string());
         
         let log_entry = serde_json::json!({
-            "seq": self.sequenceNumber,
+            "seq": self.sequence_number,
             "timestamp": timestamp,
             "node_id": node_id,
             "operation": operation_data,
@@ -68,21 +134,89 @@ impl Wal {
             "version": "1.0"
         });
         
-        format!("{}\n", log_entry.to_string())
+        format!("{}\n", log_entry)
     }
-     pub fn new(opration: WalOp) -> Self {
+
+    // Parse a line written by to_log_line back into an entry (checksum must match)
+    pub fn from_log_line(line: &str) -> Option<Self> {
+        let entry: serde_json::Value = serde_json::from_str(line).ok()?;
+        let operation = entry.get("operation")?;
+        if entry.get("checksum")?.as_str()? != checksum(&operation.to_string()) {
+            return None;
+        }
+
+        // Optional fields added over time; older lines simply lack them
+        let field = |name: &str| operation.get(name).cloned().unwrap_or_default();
+        let encoding: Encoding = serde_json::from_value(field("encoding")).unwrap_or_default();
+        let bytes = |name: &str| encoding.decode(name, operation.get(name)?.as_str()?.to_string()).ok();
+        let key = bytes("key")?;
+        let namespace = operation.get("namespace").and_then(|n| n.as_str()).unwrap_or(DEFAULT_NAMESPACE).to_string();
+        let opration = match operation.get("op")?.as_str()? {
+            "SET" => WalOp::Set {
+                namespace,
+                key,
+                value: bytes("value")?,
+                content_type: operation.get("content_type").and_then(|c| c.as_str()).map(str::to_string),
+                mode: serde_json::from_value(field("mode")).unwrap_or_default(),
+                condition: serde_json::from_value(field("condition")).ok()?,
+                expires_at: serde_json::from_value(field("expires_at")).ok()?,
+            },
+            "DELETE" => WalOp::Delete { namespace, key, condition: serde_json::from_value(field("condition")).ok()? },
+            "EXPIRE" => WalOp::Expire { namespace, key, expires_at: serde_json::from_value(field("expires_at")).ok()? },
+            "INCR" => WalOp::Incr {
+                namespace,
+                key,
+                delta: operation.get("delta")?.as_i64()?,
+                expires_at: serde_json::from_value(field("expires_at")).ok()?,
+            },
+            _ => return None,
+        };
+
+        // Back-date `time` to when the entry was written so elapsed() measures replication lag
+        let written = entry.get("timestamp")
+            .and_then(|t| t.as_str())
+            .and_then(|t| NaiveDateTime::parse_from_str(t, WAL_TIMESTAMP_FORMAT).ok())
+            .map(|t| t.and_utc());
+        let age = written
+            .and_then(|t| (Utc::now() - t).to_std().ok())
+            .unwrap_or_default();
+        let now = Instant::now();
+
+        Some(Wal {
+            sequence_number: entry.get("seq")?.as_u64()? as usize,
+            opration,
+            time: now.checked_sub(age).unwrap_or(now),
+            version: operation.get("version").and_then(|v| v.as_u64()),
+        })
+    }
+
+     pub fn new(opration: WalOp, version: Option<u64>) -> Self {
         let seq = WAL_SEQUENCE_COUNTER.fetch_add(1, Ordering::SeqCst);
         Wal {
-            sequenceNumber: seq,
+            sequence_number: seq,
             opration,
             time: Instant::now(),
+            version,
         }
     }
 }
 
-// Only detailed WAL logging
-pub fn append_wal(entry: &Wal) -> Result<()> {
-    let data = entry.to_log_line().as_bytes().to_vec();
+// First 16 hex chars of sha256 over the serialized operation
+fn checksum(data_str: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data_str.as_bytes());
+    format!("{:x}", hasher.finalize())[..16].to_string()
+}
+
+// Group commit: consecutive sequence numbers, one write and one fsync for the whole batch.
+// Writers go through replication::log_writes, which also tracks the entries' replication.
+pub fn append_wal_batch(oprations: Vec<(WalOp, Option<u64>)>) -> Result<Vec<Wal>> {
+    if oprations.is_empty() {
+        return Ok(Vec::new());
+    }
+    let _guard = WAL_WRITE_LOCK.lock().unwrap();
+    let entries: Vec<Wal> = oprations.into_iter().map(|(opration, version)| Wal::new(opration, version)).collect();
+    let data: Vec<u8> = entries.iter().flat_map(|e| e.to_log_line().into_bytes()).collect();
     
     // Create logs directory if it doesn't exist
     std::fs::create_dir_all("logs")?;
@@ -90,22 +224,92 @@ pub fn append_wal(entry: &Wal) -> Result<()> {
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open("logs/wal_detailed.log")?;  // Better organized path
+        .open(WAL_FILE)?;  // Better organized path
     
     file.write_all(&data)?;
     file.sync_data()?; // Ensures disk write
+    let last = entries[entries.len() - 1].sequence_number;
+    WAL_HEAD.send_replace(last);
     
     // Performance metrics
-    println!("WAL Entry Written: seq={}, size={} bytes", 
-             entry.sequenceNumber, data.len());
+    println!("WAL Entry Written: seq={}..={}, size={} bytes", 
+             entries[0].sequence_number, last, data.len());
     
-    Ok(())
+    Ok(entries)
+}
+
+// Continue numbering after the last entry already on disk (call once at startup)
+pub fn init_wal_sequence() {
+    let last = get_wal_stats(WAL_FILE).map(|s| s.last_sequence as usize).unwrap_or(0);
+    WAL_SEQUENCE_COUNTER.store(last + 1, Ordering::SeqCst);
+    WAL_HEAD.send_replace(last);
+}
+
+pub fn wal_head() -> usize {
+    *WAL_HEAD.borrow()
+}
+
+pub fn subscribe_wal() -> watch::Receiver<usize> {
+    WAL_HEAD.subscribe()
+}
+
+// Incremental reader over the WAL file; each call returns up to `limit` complete lines appended since the last one
+#[derive(Default)]
+pub struct WalTail {
+    reader: Option<BufReader<File>>,
+    partial: String,
+}
+
+impl WalTail {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read_new(&mut self, limit: usize) -> Vec<Wal> {
+        if self.reader.is_none() {
+            match File::open(WAL_FILE) {
+                Ok(file) => self.reader = Some(BufReader::new(file)),
+                Err(_) => return Vec::new(),
+            }
+        }
+        let reader = self.reader.as_mut().unwrap();
+
+        let mut entries = Vec::new();
+        while entries.len() < limit {
+            match reader.read_line(&mut self.partial) {
+                Ok(0) => break,
+                Ok(_) if self.partial.ends_with('\n') => {
+                    let line = std::mem::take(&mut self.partial);
+                    if line.trim().is_empty() { continue; }
+                    match Wal::from_log_line(&line) {
+                        Some(entry) => entries.push(entry),
+                        None => eprintln!("Skipping corrupt WAL line: {}", line.trim_end()),
+                    }
+                }
+                // Writer is mid-line, keep what we have and finish it on the next call
+                Ok(_) => break,
+                Err(e) => {
+                    eprintln!("WAL read failed: {}", e);
+                    break;
+                }
+            }
+        }
+        entries
+    }
+}
+
+// fsync the WAL file, used on shutdown so nothing is left in the page cache
+pub fn sync_wal() -> Result<()> {
+    match OpenOptions::new().append(true).open(WAL_FILE) {
+        Ok(file) => file.sync_all(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
 }
 
 // WAL Recovery function for detailed logs
 pub fn recover_from_wal(filename: &str) -> Result<Vec<serde_json::Value>> {
     use std::fs;
-    use std::io::{BufRead, BufReader};
     
     let file = fs::File::open(filename)?;
     let reader = BufReader::new(file);